@@ -0,0 +1,42 @@
+use std::process::Command;
+
+/// Settings `src/device_info.rs`, `src/device_name.rs`, `src/battery.rs` and
+/// `src/pairing.rs` pick up with `option_env!`
+const SETTINGS: &[&str] = &[
+    "DIS_MANUFACTURER",
+    "DIS_MODEL",
+    "DIS_HARDWARE_REVISION",
+    "BATTERY_CURVE",
+    "DEVICE_NAME_PREFIX",
+    "BLE_PASSKEY",
+];
+
+fn main() {
+    // The commit the firmware is built from, for the firmware revision
+    let hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .map_or(false, |status| !status.is_empty());
+    println!(
+        "cargo:rustc-env=GIT_HASH={}{}",
+        hash,
+        if dirty { "-dirty" } else { "" }
+    );
+
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+        println!("cargo:rerun-if-changed={}/index", git_dir);
+    }
+    for setting in SETTINGS {
+        println!("cargo:rerun-if-env-changed={}", setting);
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|out| out.trim().to_string())
+}
@@ -0,0 +1,366 @@
+//! Voltages measured with ADC1, corrected with the calibration burnt into eFuse
+//!
+//! Only ADC1 is wrapped, ADC2 is shared with the radio and can't be read while Bluetooth
+//! is running. Readings are averaged over several samples and can be scaled back through
+//! a resistor divider, so callers deal in millivolts at the measured node.
+//!
+//! `Monitor` turns a stream of readings into low-voltage events. It is plain arithmetic
+//! and works the same on the host.
+
+use core::mem::MaybeUninit;
+
+use crate::error::{EspError, EspResult};
+use crate::gpio::{Gpio32, Gpio33, Gpio34, Gpio35, Gpio36, Gpio39, Pin};
+use crate::*;
+
+/// Reference voltage in mV assumed for chips without calibration in eFuse
+pub const DEFAULT_VREF: u32 = 1100;
+
+/// Pins routed to ADC1, GPIO37 and GPIO38 aren't bonded out on most modules
+pub trait AdcPin: Pin {
+    fn channel(&self) -> adc1_channel_t;
+}
+
+macro_rules! adc_pins {
+    ($($pin:ident = $channel:ident;)*) => {
+        $(
+            impl AdcPin for $pin {
+                fn channel(&self) -> adc1_channel_t {
+                    $channel
+                }
+            }
+        )*
+    };
+}
+
+adc_pins! {
+    Gpio36 = adc1_channel_t_ADC1_CHANNEL_0;
+    Gpio39 = adc1_channel_t_ADC1_CHANNEL_3;
+    Gpio32 = adc1_channel_t_ADC1_CHANNEL_4;
+    Gpio33 = adc1_channel_t_ADC1_CHANNEL_5;
+    Gpio34 = adc1_channel_t_ADC1_CHANNEL_6;
+    Gpio35 = adc1_channel_t_ADC1_CHANNEL_7;
+}
+
+/// Input attenuation, the range is what the calibration is accurate for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attenuation {
+    /// 100 to 950 mV
+    Db0,
+    /// 100 to 1250 mV
+    Db2_5,
+    /// 150 to 1750 mV
+    Db6,
+    /// 150 to 2450 mV
+    Db11,
+}
+
+impl Attenuation {
+    fn atten(self) -> adc_atten_t {
+        match self {
+            Attenuation::Db0 => adc_atten_t_ADC_ATTEN_DB_0,
+            Attenuation::Db2_5 => adc_atten_t_ADC_ATTEN_DB_2_5,
+            Attenuation::Db6 => adc_atten_t_ADC_ATTEN_DB_6,
+            Attenuation::Db11 => adc_atten_t_ADC_ATTEN_DB_11,
+        }
+    }
+}
+
+/// Where the calibration of a channel came from, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Calibration {
+    /// Two point values burnt into eFuse
+    TwoPoint,
+    /// Reference voltage burnt into eFuse
+    Vref,
+    /// `DEFAULT_VREF`, off by up to 10% on uncalibrated chips
+    Default,
+}
+
+/// Channel settings, defaults to 11 dB, 16 samples per reading and no divider
+#[derive(Debug, Clone)]
+pub struct AdcConfig {
+    attenuation: Attenuation,
+    samples: u32,
+    divider_top: u32,
+    divider_bottom: u32,
+}
+
+impl AdcConfig {
+    pub fn new() -> AdcConfig {
+        AdcConfig {
+            attenuation: Attenuation::Db11,
+            samples: 16,
+            divider_top: 0,
+            divider_bottom: 1,
+        }
+    }
+
+    pub fn attenuation(mut self, attenuation: Attenuation) -> AdcConfig {
+        self.attenuation = attenuation;
+        self
+    }
+
+    /// Readings averaged into one, at least 1
+    pub fn samples(mut self, samples: u32) -> AdcConfig {
+        self.samples = samples.max(1);
+        self
+    }
+
+    /// The pin sits between `top` and `bottom` of a divider from the measured node to
+    /// ground, any unit as long as both are the same
+    pub fn divider(mut self, top: u32, bottom: u32) -> AdcConfig {
+        self.divider_top = top;
+        self.divider_bottom = bottom.max(1);
+        self
+    }
+}
+
+impl Default for AdcConfig {
+    fn default() -> AdcConfig {
+        AdcConfig::new()
+    }
+}
+
+/// A calibrated ADC1 channel at 12 bits
+pub struct Adc {
+    channel: adc1_channel_t,
+    chars: esp_adc_cal_characteristics_t,
+    calibration: Calibration,
+    config: AdcConfig,
+}
+
+// The characteristics only point into the calibration tables in flash
+unsafe impl Send for Adc {}
+
+impl Adc {
+    pub fn new<P: AdcPin>(pin: P, config: &AdcConfig) -> EspResult<Adc> {
+        let channel = pin.channel();
+        let atten = config.attenuation.atten();
+        let width = adc_bits_width_t_ADC_WIDTH_BIT_12;
+        unsafe {
+            esp!(adc1_config_width(width))?;
+            esp!(adc1_config_channel_atten(channel, atten))?;
+        }
+
+        let mut chars = MaybeUninit::<esp_adc_cal_characteristics_t>::zeroed();
+        let calibration = unsafe {
+            esp_adc_cal_characterize(
+                adc_unit_t_ADC_UNIT_1,
+                atten,
+                width,
+                DEFAULT_VREF,
+                chars.as_mut_ptr(),
+            )
+        };
+        let calibration = match calibration {
+            esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_TP => Calibration::TwoPoint,
+            esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_VREF => Calibration::Vref,
+            _ => Calibration::Default,
+        };
+
+        Ok(Adc {
+            channel,
+            chars: unsafe { chars.assume_init() },
+            calibration,
+            config: config.clone(),
+        })
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    /// One uncalibrated sample, 0 to 4095
+    pub fn read_raw(&self) -> EspResult<u16> {
+        let raw = unsafe { adc1_get_raw(self.channel) };
+        if raw < 0 {
+            return Err(EspError::new(ESP_ERR_INVALID_STATE, "adc1_get_raw"));
+        }
+        Ok(raw as u16)
+    }
+
+    /// Millivolts at the measured node, averaged and scaled back through the divider
+    pub fn read_mv(&self) -> EspResult<u32> {
+        let mut sum = 0;
+        for _ in 0..self.config.samples {
+            sum += self.read_raw()? as u32;
+        }
+        let raw = sum / self.config.samples;
+        let mv = unsafe { esp_adc_cal_raw_to_voltage(raw, &self.chars) };
+        let top = self.config.divider_top as u64;
+        let bottom = self.config.divider_bottom as u64;
+        Ok((mv as u64 * (top + bottom) / bottom) as u32)
+    }
+}
+
+/// How a voltage compares to the `Thresholds`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VoltageLevel {
+    Critical,
+    Low,
+    Normal,
+}
+
+/// Levels in mV below which a voltage counts as low or critical
+///
+/// A voltage has to rise `hysteresis` above a threshold to leave it again, so a reading
+/// hovering around it doesn't keep firing events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    pub low: u32,
+    pub critical: u32,
+    pub hysteresis: u32,
+}
+
+/// Tracks the level of a voltage and reports when it changes
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    thresholds: Thresholds,
+    level: Option<VoltageLevel>,
+}
+
+impl Monitor {
+    pub const fn new(thresholds: Thresholds) -> Monitor {
+        Monitor {
+            thresholds,
+            level: None,
+        }
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    /// `None` until the first reading
+    pub fn level(&self) -> Option<VoltageLevel> {
+        self.level
+    }
+
+    /// Feeds a reading, returns the new level if it changed
+    ///
+    /// The first reading always reports its level.
+    pub fn update(&mut self, mv: u32) -> Option<VoltageLevel> {
+        let t = self.thresholds;
+        // Leaving a level upwards takes the hysteresis on top of its threshold
+        let rising = |threshold: u32, level: VoltageLevel| match self.level {
+            Some(current) if current <= level => threshold + t.hysteresis,
+            _ => threshold,
+        };
+        let level = if mv < rising(t.critical, VoltageLevel::Critical) {
+            VoltageLevel::Critical
+        } else if mv < rising(t.low, VoltageLevel::Low) {
+            VoltageLevel::Low
+        } else {
+            VoltageLevel::Normal
+        };
+
+        if self.level == Some(level) {
+            return None;
+        }
+        self.level = Some(level);
+        Some(level)
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::mock;
+
+    const THRESHOLDS: Thresholds = Thresholds {
+        low: 3500,
+        critical: 3300,
+        hysteresis: 100,
+    };
+
+    /// Difference between two readings
+    fn off(a: u32, b: u32) -> u32 {
+        if a > b {
+            a - b
+        } else {
+            b - a
+        }
+    }
+
+    #[test]
+    fn read_through_divider() {
+        let pins = unsafe { Pins::steal() };
+        let config = AdcConfig::new().samples(4).divider(100, 10);
+        let adc = Adc::new(pins.gpio34, &config).unwrap();
+        let channel = adc1_channel_t_ADC1_CHANNEL_6;
+        assert_eq!(mock::adc::attenuation(channel), adc_atten_t_ADC_ATTEN_DB_11);
+        assert_eq!(adc.calibration(), Calibration::Default);
+
+        // 4070 mV at the battery is 370 mV at the pin
+        mock::adc::set_input(channel, 370);
+        let reads = mock::adc::reads();
+        let mv = adc.read_mv().unwrap();
+        assert_eq!(mock::adc::reads() - reads, 4);
+        // One step of the ADC is about 1 mV at the pin, 11 at the battery
+        assert!(off(mv, 4070) <= 11, "{} mV", mv);
+
+        mock::adc::set_input(channel, 0);
+        assert_eq!(adc.read_mv().unwrap(), 0);
+        mock::adc::set_input(channel, 5000);
+        assert_eq!(adc.read_raw().unwrap(), 4095);
+    }
+
+    #[test]
+    fn calibration_and_attenuation() {
+        let pins = unsafe { Pins::steal() };
+        mock::adc::set_calibration(esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_TP);
+        let config = AdcConfig::new().attenuation(Attenuation::Db0).samples(0);
+        let adc = Adc::new(pins.gpio35, &config).unwrap();
+        mock::adc::set_calibration(esp_adc_cal_value_t_ESP_ADC_CAL_VAL_DEFAULT_VREF);
+        let channel = adc1_channel_t_ADC1_CHANNEL_7;
+        assert_eq!(adc.calibration(), Calibration::TwoPoint);
+        assert_eq!(mock::adc::attenuation(channel), adc_atten_t_ADC_ATTEN_DB_0);
+
+        // Samples are at least one and the input clips at 1100 mV
+        mock::adc::set_input(channel, 800);
+        let reads = mock::adc::reads();
+        assert!(off(adc.read_mv().unwrap(), 800) <= 1);
+        assert_eq!(mock::adc::reads() - reads, 1);
+        mock::adc::set_input(channel, 2000);
+        assert_eq!(adc.read_mv().unwrap(), 1100);
+    }
+
+    #[test]
+    fn monitor_hysteresis() {
+        let mut monitor = Monitor::new(THRESHOLDS);
+        assert_eq!(monitor.level(), None);
+        assert_eq!(monitor.update(4000), Some(VoltageLevel::Normal));
+        assert_eq!(monitor.update(3500), None);
+        assert_eq!(monitor.update(3499), Some(VoltageLevel::Low));
+        // Back above the threshold but not above the hysteresis
+        assert_eq!(monitor.update(3599), None);
+        assert_eq!(monitor.update(3299), Some(VoltageLevel::Critical));
+        assert_eq!(monitor.update(3399), None);
+        assert_eq!(monitor.update(3400), Some(VoltageLevel::Low));
+        assert_eq!(monitor.update(3300), None);
+        assert_eq!(monitor.update(3600), Some(VoltageLevel::Normal));
+        assert_eq!(monitor.level(), Some(VoltageLevel::Normal));
+
+        // Straight from critical to normal takes the low threshold's hysteresis
+        assert_eq!(monitor.update(3000), Some(VoltageLevel::Critical));
+        assert_eq!(monitor.update(3599), Some(VoltageLevel::Low));
+        assert_eq!(monitor.update(3000), Some(VoltageLevel::Critical));
+        assert_eq!(monitor.update(3600), Some(VoltageLevel::Normal));
+    }
+
+    #[test]
+    fn monitor_without_hysteresis() {
+        let mut monitor = Monitor::new(Thresholds {
+            hysteresis: 0,
+            ..THRESHOLDS
+        });
+        assert_eq!(monitor.update(3299), Some(VoltageLevel::Critical));
+        assert_eq!(monitor.update(3300), Some(VoltageLevel::Low));
+        assert_eq!(monitor.update(3299), Some(VoltageLevel::Critical));
+        assert_eq!(monitor.update(3500), Some(VoltageLevel::Normal));
+        assert_eq!(monitor.update(3499), Some(VoltageLevel::Low));
+        assert_eq!(monitor.update(3500), Some(VoltageLevel::Normal));
+    }
+}
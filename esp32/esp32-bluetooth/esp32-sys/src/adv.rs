@@ -0,0 +1,491 @@
+//! Advertising and scan response payloads
+//!
+//! `AdvData` encodes the AD structures itself rather than going through
+//! `ble_hs_adv_fields`, so a payload can be checked on the host and one that doesn't fit
+//! in 31 bytes is refused before it reaches the controller. Only the name gives way, it
+//! goes last and is sent as a shortened name when it doesn't fit whole.
+//!
+//! ```ignore
+//! let data = AdvData::new()
+//!     .flags(FLAG_GENERAL_DISCOVERABLE | FLAG_BREDR_UNSUPPORTED)
+//!     .uuid(Uuid::from_u16(0x180D))
+//!     .name("Heart rate");
+//! Advertising::new(data)
+//!     .scan_response(AdvData::new().manufacturer_data(0x02E5, &[0x01]))
+//!     .interval_ms(100, 150)
+//!     .start(own_addr_type, &GAP_HANDLER)?;
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ffi::c_void;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use crate::error::{BleError, BleResult};
+use crate::gap::{gap_event_cb, GapHandler};
+use crate::uuid::Uuid;
+use crate::*;
+
+/// Largest legacy advertising or scan response payload
+pub const MAX_LEN: usize = BLE_HS_ADV_MAX_SZ as usize;
+
+/// Flags, combine with `|`
+pub const FLAG_LIMITED_DISCOVERABLE: u8 = BLE_HS_ADV_F_DISC_LTD as u8;
+pub const FLAG_GENERAL_DISCOVERABLE: u8 = BLE_HS_ADV_F_DISC_GEN as u8;
+pub const FLAG_BREDR_UNSUPPORTED: u8 = BLE_HS_ADV_F_BREDR_UNSUP as u8;
+
+/// Advertising interval limits in ms for connectable advertising
+pub const MIN_INTERVAL_MS: u32 = 20;
+pub const MAX_INTERVAL_MS: u32 = 10240;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvError {
+    /// The fields encode to `len` bytes, more than `MAX_LEN`
+    TooLong { len: usize },
+    /// Outside `MIN_INTERVAL_MS` to `MAX_INTERVAL_MS`, or the minimum above the maximum
+    InvalidInterval { min_ms: u32, max_ms: u32 },
+}
+
+impl fmt::Display for AdvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AdvError::TooLong { len } => {
+                write!(
+                    f,
+                    "advertising data is {} bytes, at most {} fit",
+                    len, MAX_LEN
+                )
+            }
+            AdvError::InvalidInterval { min_ms, max_ms } => {
+                write!(
+                    f,
+                    "invalid advertising interval {} to {} ms",
+                    min_ms, max_ms
+                )
+            }
+        }
+    }
+}
+
+impl From<AdvError> for BleError {
+    fn from(err: AdvError) -> BleError {
+        match err {
+            AdvError::TooLong { .. } => BleError::new(BLE_HS_EMSGSIZE, "AdvData::encode"),
+            AdvError::InvalidInterval { .. } => {
+                BleError::new(BLE_HS_EINVAL, "Advertising::interval_ms")
+            }
+        }
+    }
+}
+
+/// Fields of an advertising or scan response payload
+///
+/// Fields are encoded in the order NimBLE's `ble_hs_adv_set_fields` uses, the name last.
+/// UUID lists are sent as incomplete unless `complete_uuids` says the device has no other
+/// services.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdvData {
+    flags: Option<u8>,
+    uuids16: Vec<u16>,
+    uuids32: Vec<u32>,
+    uuids128: Vec<[u8; 16]>,
+    uuids_complete: bool,
+    tx_power: Option<i8>,
+    appearance: Option<u16>,
+    manufacturer_data: Option<(u16, Vec<u8>)>,
+    name: Option<String>,
+}
+
+impl AdvData {
+    pub fn new() -> AdvData {
+        AdvData::default()
+    }
+
+    pub fn flags(mut self, flags: u8) -> AdvData {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Adds a service UUID to the list of its width
+    pub fn uuid(mut self, uuid: Uuid) -> AdvData {
+        if let Some(uuid) = uuid.as_uuid16() {
+            self.uuids16.push(uuid.value);
+        } else if let Some(uuid) = uuid.as_uuid32() {
+            self.uuids32.push(uuid.value);
+        } else if let Some(uuid) = uuid.as_uuid128() {
+            self.uuids128.push(uuid.value);
+        }
+        self
+    }
+
+    /// Marks the UUID lists as complete
+    pub fn complete_uuids(mut self) -> AdvData {
+        self.uuids_complete = true;
+        self
+    }
+
+    /// Transmit power in dBm, which must match what the controller is set to
+    pub fn tx_power(mut self, dbm: i8) -> AdvData {
+        self.tx_power = Some(dbm);
+        self
+    }
+
+    /// One of the GAP appearance values from the assigned numbers
+    pub fn appearance(mut self, appearance: u16) -> AdvData {
+        self.appearance = Some(appearance);
+        self
+    }
+
+    /// `data` after a Bluetooth SIG company identifier
+    pub fn manufacturer_data(mut self, company: u16, data: &[u8]) -> AdvData {
+        self.manufacturer_data = Some((company, data.to_vec()));
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> AdvData {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    /// The AD structures, each a length byte, the type and the data
+    ///
+    /// A name that doesn't fit is cut at a character boundary and sent as a shortened
+    /// name, any other field that doesn't fit is an error.
+    pub fn encode(&self) -> Result<Vec<u8>, AdvError> {
+        let mut out = Vec::with_capacity(MAX_LEN);
+        if let Some(flags) = self.flags {
+            push_field(&mut out, BLE_HS_ADV_TYPE_FLAGS, &[flags])?;
+        }
+
+        let (comp16, comp32, comp128) = if self.uuids_complete {
+            (
+                BLE_HS_ADV_TYPE_COMP_UUIDS16,
+                BLE_HS_ADV_TYPE_COMP_UUIDS32,
+                BLE_HS_ADV_TYPE_COMP_UUIDS128,
+            )
+        } else {
+            (
+                BLE_HS_ADV_TYPE_INCOMP_UUIDS16,
+                BLE_HS_ADV_TYPE_INCOMP_UUIDS32,
+                BLE_HS_ADV_TYPE_INCOMP_UUIDS128,
+            )
+        };
+        if !self.uuids16.is_empty() {
+            let data: Vec<u8> = self
+                .uuids16
+                .iter()
+                .flat_map(|u| u.to_le_bytes().to_vec())
+                .collect();
+            push_field(&mut out, comp16, &data)?;
+        }
+        if !self.uuids32.is_empty() {
+            let data: Vec<u8> = self
+                .uuids32
+                .iter()
+                .flat_map(|u| u.to_le_bytes().to_vec())
+                .collect();
+            push_field(&mut out, comp32, &data)?;
+        }
+        if !self.uuids128.is_empty() {
+            let data: Vec<u8> = self.uuids128.iter().flat_map(|u| u.to_vec()).collect();
+            push_field(&mut out, comp128, &data)?;
+        }
+
+        if let Some(dbm) = self.tx_power {
+            push_field(&mut out, BLE_HS_ADV_TYPE_TX_PWR_LVL, &[dbm as u8])?;
+        }
+        if let Some(appearance) = self.appearance {
+            push_field(
+                &mut out,
+                BLE_HS_ADV_TYPE_APPEARANCE,
+                &appearance.to_le_bytes(),
+            )?;
+        }
+        if let Some((company, ref data)) = self.manufacturer_data {
+            let mut field = company.to_le_bytes().to_vec();
+            field.extend_from_slice(data);
+            push_field(&mut out, BLE_HS_ADV_TYPE_MFG_DATA, &field)?;
+        }
+        if out.len() > MAX_LEN {
+            return Err(AdvError::TooLong { len: out.len() });
+        }
+
+        if let Some(ref name) = self.name {
+            // The length and type bytes take 2 of what is left
+            let room = MAX_LEN.saturating_sub(out.len() + 2);
+            if name.len() <= room {
+                push_field(&mut out, BLE_HS_ADV_TYPE_COMP_NAME, name.as_bytes())?;
+            } else {
+                let mut end = room;
+                while !name.is_char_boundary(end) {
+                    end -= 1;
+                }
+                if end == 0 {
+                    return Err(AdvError::TooLong {
+                        len: out.len() + 2 + name.len(),
+                    });
+                }
+                push_field(
+                    &mut out,
+                    BLE_HS_ADV_TYPE_INCOMP_NAME,
+                    &name.as_bytes()[..end],
+                )?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Appends one AD structure, whose length byte also counts the type
+fn push_field(out: &mut Vec<u8>, type_: u32, data: &[u8]) -> Result<(), AdvError> {
+    let len = u8::try_from(data.len() + 1).map_err(|_| AdvError::TooLong {
+        len: out.len() + 2 + data.len(),
+    })?;
+    out.push(len);
+    out.push(type_ as u8);
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+/// Undirected connectable and general discoverable advertising until stopped
+#[derive(Debug, Clone)]
+pub struct Advertising {
+    data: AdvData,
+    scan_response: AdvData,
+    interval_ms: Option<(u32, u32)>,
+    duration_ms: i32,
+}
+
+impl Advertising {
+    pub fn new(data: AdvData) -> Advertising {
+        Advertising {
+            data,
+            scan_response: AdvData::new(),
+            interval_ms: None,
+            duration_ms: i32::MAX,
+        }
+    }
+
+    /// Sent to scanners asking for more, empty unless set
+    pub fn scan_response(mut self, data: AdvData) -> Advertising {
+        self.scan_response = data;
+        self
+    }
+
+    /// The controller picks an interval between `min_ms` and `max_ms`, NimBLE's defaults
+    /// of 30 to 60 ms apply unless set
+    pub fn interval_ms(mut self, min_ms: u32, max_ms: u32) -> Advertising {
+        self.interval_ms = Some((min_ms, max_ms));
+        self
+    }
+
+    /// Stops after `duration_ms` with `BLE_GAP_EVENT_ADV_COMPLETE`
+    pub fn duration_ms(mut self, duration_ms: i32) -> Advertising {
+        self.duration_ms = duration_ms;
+        self
+    }
+
+    /// Both payloads and the advertising parameters, checked without touching the host
+    pub fn check(&self) -> Result<(Vec<u8>, Vec<u8>, ble_gap_adv_params), AdvError> {
+        let data = self.data.encode()?;
+        let scan_response = self.scan_response.encode()?;
+        let mut params: ble_gap_adv_params = unsafe { MaybeUninit::zeroed().assume_init() };
+        params.conn_mode = BLE_GAP_CONN_MODE_UND as u8;
+        params.disc_mode = BLE_GAP_DISC_MODE_GEN as u8;
+        if let Some((min_ms, max_ms)) = self.interval_ms {
+            let valid = MIN_INTERVAL_MS..=MAX_INTERVAL_MS;
+            if !valid.contains(&min_ms) || !valid.contains(&max_ms) || min_ms > max_ms {
+                return Err(AdvError::InvalidInterval { min_ms, max_ms });
+            }
+            params.itvl_min = interval_units(min_ms);
+            params.itvl_max = interval_units(max_ms);
+        }
+        Ok((data, scan_response, params))
+    }
+
+    /// Sets both payloads and starts advertising, GAP events of the advertisement and of
+    /// the connection it leads to go to `handler`
+    pub fn start<H: GapHandler>(&self, own_addr_type: u8, handler: &'static H) -> BleResult<()> {
+        let (data, scan_response, params) = self.check()?;
+        unsafe {
+            ble!(ble_gap_adv_set_data(data.as_ptr(), data.len() as i32))?;
+            ble!(ble_gap_adv_rsp_set_data(
+                scan_response.as_ptr(),
+                scan_response.len() as i32
+            ))?;
+            ble!(ble_gap_adv_start(
+                own_addr_type,
+                ptr::null(),
+                self.duration_ms,
+                &params,
+                Some(gap_event_cb::<H>),
+                handler as *const H as *mut c_void
+            ))
+        }
+    }
+}
+
+/// In units of 0.625 ms
+fn interval_units(ms: u32) -> u16 {
+    (ms * 8 / 5) as u16
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::gap::GapEvent;
+    use crate::mock;
+    use alloc::vec;
+
+    /// A vendor UUID with `alias` at bytes 12 and 13
+    fn vendor_uuid(alias: u16) -> Uuid {
+        Uuid::from_base(0x0000_0000_8c1d_4f5c_9a4e_5b1e_7e3a_2b01, alias)
+    }
+
+    #[test]
+    fn flags_and_name() {
+        let data = AdvData::new()
+            .flags(FLAG_GENERAL_DISCOVERABLE | FLAG_BREDR_UNSUPPORTED)
+            .name("abc");
+        assert_eq!(
+            data.encode(),
+            Ok(vec![2, 0x01, 0x06, 4, 0x09, b'a', b'b', b'c'])
+        );
+        assert_eq!(AdvData::new().encode(), Ok(vec![]));
+    }
+
+    #[test]
+    fn name_shortened_to_fit() {
+        let uuid = vendor_uuid(1);
+        let data = AdvData::new().flags(0x06).uuid(uuid).name("polargraph");
+        let encoded = data.encode().unwrap();
+        assert_eq!(encoded.len(), MAX_LEN);
+        assert_eq!(&encoded[21..], b"\x09\x08polargra");
+
+        // Cut before a character that doesn't fit whole
+        let data = AdvData::new()
+            .uuid(uuid)
+            .manufacturer_data(1, &[0; 5])
+            .name("éé");
+        assert_eq!(&data.encode().unwrap()[27..], &[3, 0x08, 0xc3, 0xa9]);
+
+        let data = AdvData::new()
+            .uuid(uuid)
+            .manufacturer_data(1, &[0; 9])
+            .name("x");
+        assert_eq!(data.encode(), Err(AdvError::TooLong { len: 34 }));
+    }
+
+    #[test]
+    fn uuid_lists() {
+        let data = AdvData::new()
+            .uuid(Uuid::from_u16(0x180D))
+            .uuid(Uuid::from_u16(0x180F));
+        assert_eq!(data.encode(), Ok(vec![5, 0x02, 0x0d, 0x18, 0x0f, 0x18]));
+        let data = data.complete_uuids();
+        assert_eq!(data.encode(), Ok(vec![5, 0x03, 0x0d, 0x18, 0x0f, 0x18]));
+
+        let uuid = vendor_uuid(1);
+        let encoded = AdvData::new().uuid(uuid).encode().unwrap();
+        assert_eq!(encoded[..2], [17, 0x06]);
+        assert_eq!(encoded[2..], uuid.as_u128().unwrap().to_le_bytes());
+        let encoded = AdvData::new().uuid(uuid).complete_uuids().encode().unwrap();
+        assert_eq!(encoded[..2], [17, 0x07]);
+        assert_eq!(
+            AdvData::new().uuid(uuid).uuid(vendor_uuid(2)).encode(),
+            Err(AdvError::TooLong { len: 34 })
+        );
+    }
+
+    #[test]
+    fn other_fields() {
+        let data = AdvData::new()
+            .appearance(0x0341)
+            .tx_power(-4)
+            .manufacturer_data(0x02E5, &[9]);
+        assert_eq!(
+            data.encode(),
+            Ok(vec![
+                2, 0x0a, 0xfc, 3, 0x19, 0x41, 0x03, 4, 0xff, 0xe5, 0x02, 9
+            ])
+        );
+        let encoded = AdvData::new().manufacturer_data(1, &[0; 27]).encode();
+        assert_eq!(encoded.unwrap().len(), MAX_LEN);
+        assert_eq!(
+            AdvData::new().manufacturer_data(1, &[0; 28]).encode(),
+            Err(AdvError::TooLong { len: 32 })
+        );
+    }
+
+    #[test]
+    fn field_length_overflow() {
+        // One more byte with the type doesn't fit the length byte
+        assert_eq!(
+            AdvData::new().manufacturer_data(1, &[0; 253]).encode(),
+            Err(AdvError::TooLong { len: 257 })
+        );
+        let data = AdvData::new().flags(0x06).manufacturer_data(1, &[0; 300]);
+        assert_eq!(data.encode(), Err(AdvError::TooLong { len: 307 }));
+        let name: String = core::iter::repeat('x').take(300).collect();
+        assert_eq!(
+            AdvData::new().name(&name).encode().unwrap()[..2],
+            [30, 0x08]
+        );
+    }
+
+    #[test]
+    fn intervals() {
+        let advertising = Advertising::new(AdvData::new());
+        let (_, _, params) = advertising.clone().interval_ms(100, 150).check().unwrap();
+        assert_eq!((params.itvl_min, params.itvl_max), (160, 240));
+        let (_, _, params) = advertising.check().unwrap();
+        assert_eq!((params.itvl_min, params.itvl_max), (0, 0));
+        let (_, _, params) = advertising
+            .clone()
+            .interval_ms(MIN_INTERVAL_MS, MAX_INTERVAL_MS)
+            .check()
+            .unwrap();
+        assert_eq!((params.itvl_min, params.itvl_max), (32, 16384));
+
+        for &(min_ms, max_ms) in &[(10, 100), (100, 10241), (200, 100)] {
+            assert_eq!(
+                advertising
+                    .clone()
+                    .interval_ms(min_ms, max_ms)
+                    .check()
+                    .err(),
+                Some(AdvError::InvalidInterval { min_ms, max_ms })
+            );
+        }
+    }
+
+    #[test]
+    fn start_sets_payloads() {
+        struct Nop;
+
+        impl GapHandler for Nop {
+            fn event(&self, _event: GapEvent) -> i32 {
+                0
+            }
+        }
+
+        static NOP: Nop = Nop;
+
+        Advertising::new(AdvData::new().flags(0x06))
+            .scan_response(AdvData::new().name("n"))
+            .interval_ms(100, 150)
+            .start(0, &NOP)
+            .unwrap();
+        assert_eq!(mock::nimble::adv_data(), vec![2, 0x01, 0x06]);
+        assert_eq!(mock::nimble::scan_rsp_data(), vec![2, 0x09, b'n']);
+        assert_eq!(mock::nimble::adv_intervals(), Some((160, 240)));
+        unsafe { ble_gap_adv_stop() };
+
+        let too_long = AdvData::new().manufacturer_data(1, &[0; 28]);
+        let err = Advertising::new(too_long).start(0, &NOP).unwrap_err();
+        assert_eq!(err.code(), BLE_HS_EMSGSIZE as i32);
+    }
+}
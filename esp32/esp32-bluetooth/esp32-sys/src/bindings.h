@@ -12,6 +12,8 @@ typedef struct _reent *_data;
 
 #include "driver/uart.h"
 #include "driver/gpio.h"
+#include "driver/adc.h"
+#include "esp_adc_cal.h"
 #include "esp_log.h"
 
 // Bluetooth?
@@ -22639,6 +22639,161 @@ extern "C" {
     #[doc = " @param oen_inv True if the output enable needs to be inversed, otherwise False."]
     pub fn gpio_iomux_out(gpio_num: u8, func: ::std::os::raw::c_int, oen_inv: bool);
 }
+#[doc = "< SAR ADC 1"]
+pub const adc_unit_t_ADC_UNIT_1: adc_unit_t = 1;
+#[doc = "< SAR ADC 2, not supported yet"]
+pub const adc_unit_t_ADC_UNIT_2: adc_unit_t = 2;
+#[doc = "< SAR ADC 1+2, not supported yet"]
+pub const adc_unit_t_ADC_UNIT_BOTH: adc_unit_t = 3;
+#[doc = "< SAR ADC 1+2 alternative mode, not supported yet"]
+pub const adc_unit_t_ADC_UNIT_ALTER: adc_unit_t = 7;
+pub const adc_unit_t_ADC_UNIT_MAX: adc_unit_t = 8;
+pub type adc_unit_t = u32;
+#[doc = "<The input voltage of ADC will be reduced to about 1/1"]
+pub const adc_atten_t_ADC_ATTEN_DB_0: adc_atten_t = 0;
+#[doc = "<The input voltage of ADC will be reduced to about 1/1.34"]
+pub const adc_atten_t_ADC_ATTEN_DB_2_5: adc_atten_t = 1;
+#[doc = "<The input voltage of ADC will be reduced to about 1/2"]
+pub const adc_atten_t_ADC_ATTEN_DB_6: adc_atten_t = 2;
+#[doc = "<The input voltage of ADC will be reduced to about 1/3.6"]
+pub const adc_atten_t_ADC_ATTEN_DB_11: adc_atten_t = 3;
+pub const adc_atten_t_ADC_ATTEN_MAX: adc_atten_t = 4;
+pub type adc_atten_t = u32;
+#[doc = "< ADC capture width is 9Bit"]
+pub const adc_bits_width_t_ADC_WIDTH_BIT_9: adc_bits_width_t = 0;
+#[doc = "< ADC capture width is 10Bit"]
+pub const adc_bits_width_t_ADC_WIDTH_BIT_10: adc_bits_width_t = 1;
+#[doc = "< ADC capture width is 11Bit"]
+pub const adc_bits_width_t_ADC_WIDTH_BIT_11: adc_bits_width_t = 2;
+#[doc = "< ADC capture width is 12Bit"]
+pub const adc_bits_width_t_ADC_WIDTH_BIT_12: adc_bits_width_t = 3;
+pub const adc_bits_width_t_ADC_WIDTH_MAX: adc_bits_width_t = 4;
+pub type adc_bits_width_t = u32;
+#[doc = "< ADC1 channel 0 is GPIO36"]
+pub const adc1_channel_t_ADC1_CHANNEL_0: adc1_channel_t = 0;
+#[doc = "< ADC1 channel 1 is GPIO37"]
+pub const adc1_channel_t_ADC1_CHANNEL_1: adc1_channel_t = 1;
+#[doc = "< ADC1 channel 2 is GPIO38"]
+pub const adc1_channel_t_ADC1_CHANNEL_2: adc1_channel_t = 2;
+#[doc = "< ADC1 channel 3 is GPIO39"]
+pub const adc1_channel_t_ADC1_CHANNEL_3: adc1_channel_t = 3;
+#[doc = "< ADC1 channel 4 is GPIO32"]
+pub const adc1_channel_t_ADC1_CHANNEL_4: adc1_channel_t = 4;
+#[doc = "< ADC1 channel 5 is GPIO33"]
+pub const adc1_channel_t_ADC1_CHANNEL_5: adc1_channel_t = 5;
+#[doc = "< ADC1 channel 6 is GPIO34"]
+pub const adc1_channel_t_ADC1_CHANNEL_6: adc1_channel_t = 6;
+#[doc = "< ADC1 channel 7 is GPIO35"]
+pub const adc1_channel_t_ADC1_CHANNEL_7: adc1_channel_t = 7;
+pub const adc1_channel_t_ADC1_CHANNEL_MAX: adc1_channel_t = 8;
+pub type adc1_channel_t = u32;
+extern "C" {
+    #[doc = " @brief Configure ADC1 capture width, meanwhile enable output invert for ADC1."]
+    #[doc = " The configuration is for all channels of ADC1"]
+    #[doc = " @param width_bit Bit capture width for ADC1"]
+    #[doc = ""]
+    #[doc = " @return"]
+    #[doc = "     - ESP_OK success"]
+    #[doc = "     - ESP_ERR_INVALID_ARG Parameter error"]
+    pub fn adc1_config_width(width_bit: adc_bits_width_t) -> esp_err_t;
+}
+extern "C" {
+    #[doc = " @brief Set the attenuation of a particular channel on ADC1, and configure its"]
+    #[doc = " associated GPIO pin mux."]
+    #[doc = ""]
+    #[doc = " @param channel ADC1 channel to configure"]
+    #[doc = " @param atten  Attenuation level"]
+    #[doc = ""]
+    #[doc = " @return"]
+    #[doc = "     - ESP_OK success"]
+    #[doc = "     - ESP_ERR_INVALID_ARG Parameter error"]
+    pub fn adc1_config_channel_atten(channel: adc1_channel_t, atten: adc_atten_t) -> esp_err_t;
+}
+extern "C" {
+    #[doc = " @brief Take an ADC1 reading from a single channel."]
+    #[doc = ""]
+    #[doc = " @param  channel ADC1 channel to read"]
+    #[doc = ""]
+    #[doc = " @return"]
+    #[doc = "     - -1: Parameter error"]
+    #[doc = "     -  Other: ADC1 channel reading."]
+    pub fn adc1_get_raw(channel: adc1_channel_t) -> ::std::os::raw::c_int;
+}
+#[doc = "< Characterization based on reference voltage stored in eFuse"]
+pub const esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_VREF: esp_adc_cal_value_t = 0;
+#[doc = "< Characterization based on Two Point values stored in eFuse"]
+pub const esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_TP: esp_adc_cal_value_t = 1;
+#[doc = "< Characterization based on default reference voltage"]
+pub const esp_adc_cal_value_t_ESP_ADC_CAL_VAL_DEFAULT_VREF: esp_adc_cal_value_t = 2;
+#[doc = " @brief Type of calibration value used in characterization"]
+pub type esp_adc_cal_value_t = u32;
+#[doc = " @brief Structure storing characteristics of an ADC"]
+#[doc = ""]
+#[doc = " @note Call esp_adc_cal_characterize() to initialize the structure"]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct esp_adc_cal_characteristics_t {
+    #[doc = "< ADC number"]
+    pub adc_num: adc_unit_t,
+    #[doc = "< ADC attenuation"]
+    pub atten: adc_atten_t,
+    #[doc = "< ADC bit width"]
+    pub bit_width: adc_bits_width_t,
+    #[doc = "< Gradient of ADC-Voltage curve"]
+    pub coeff_a: u32,
+    #[doc = "< Offset of ADC-Voltage curve"]
+    pub coeff_b: u32,
+    #[doc = "< Vref used by lookup table"]
+    pub vref: u32,
+    #[doc = "< Pointer to low Vref curve of lookup table (NULL if unused)"]
+    pub low_curve: *const u32,
+    #[doc = "< Pointer to high Vref curve of lookup table (NULL if unused)"]
+    pub high_curve: *const u32,
+}
+extern "C" {
+    #[doc = " @brief Characterize an ADC at a particular attenuation"]
+    #[doc = ""]
+    #[doc = " This function will characterize the ADC at a particular attenuation and generate"]
+    #[doc = " the ADC-Voltage curve in the form of [y = coeff_a * x + coeff_b]."]
+    #[doc = " Characterization can be based on Two Point values, eFuse Vref, or default Vref"]
+    #[doc = " and the calibration values will be prioritized in that order."]
+    #[doc = ""]
+    #[doc = " @param[in]   adc_num         ADC to characterize (ADC_UNIT_1 or ADC_UNIT_2)"]
+    #[doc = " @param[in]   atten           Attenuation to characterize"]
+    #[doc = " @param[in]   bit_width       Bit width configuration of ADC"]
+    #[doc = " @param[in]   default_vref    Default ADC reference voltage in mV (used if eFuse values is not available)"]
+    #[doc = " @param[out]  chars           Pointer to empty structure used to store ADC characteristics"]
+    #[doc = ""]
+    #[doc = " @return"]
+    #[doc = "      - ESP_ADC_CAL_VAL_EFUSE_VREF: eFuse Vref used for characterization"]
+    #[doc = "      - ESP_ADC_CAL_VAL_EFUSE_TP: Two Point value used for characterization (only in Linear Mode)"]
+    #[doc = "      - ESP_ADC_CAL_VAL_DEFAULT_VREF: Default Vref used for characterization"]
+    pub fn esp_adc_cal_characterize(
+        adc_num: adc_unit_t,
+        atten: adc_atten_t,
+        bit_width: adc_bits_width_t,
+        default_vref: u32,
+        chars: *mut esp_adc_cal_characteristics_t,
+    ) -> esp_adc_cal_value_t;
+}
+extern "C" {
+    #[doc = " @brief   Convert an ADC reading to voltage in mV"]
+    #[doc = ""]
+    #[doc = " This function converts an ADC reading to a voltage in mV based on the ADC's"]
+    #[doc = " characteristics."]
+    #[doc = ""]
+    #[doc = " @note    Characteristics structure must be initialized before this function"]
+    #[doc = "          is called (call esp_adc_cal_characterize())"]
+    #[doc = ""]
+    #[doc = " @param[in]   adc_reading     ADC reading"]
+    #[doc = " @param[in]   chars           Pointer to initialized structure containing ADC characteristics"]
+    #[doc = ""]
+    #[doc = " @return      Voltage in mV"]
+    pub fn esp_adc_cal_raw_to_voltage(
+        adc_reading: u32,
+        chars: *const esp_adc_cal_characteristics_t,
+    ) -> u32;
+}
 #[doc = "< No log output"]
 pub const esp_log_level_t_ESP_LOG_NONE: esp_log_level_t = 0;
 #[doc = "< Critical errors, software module can not recover on its own"]
@@ -0,0 +1,638 @@
+//! Open connections, the characteristics each one subscribed to and the values waiting to
+//! be sent to them
+//!
+//! NimBLE tracks client configuration itself but only tells the application through GAP
+//! events. `gap::gap_event_cb` hands every event to `handle_event` before the application
+//! sees it, which keeps this registry current, so `notify` can reach every subscriber of a
+//! characteristic on every connection.
+//!
+//! `notify` only queues the value. Each connection keeps the latest unsent value per
+//! characteristic, so high-rate telemetry that outpaces the link replaces stale values
+//! instead of piling up. When the host runs out of mbufs sending pauses and is retried
+//! from a timer. Indications go out one at a time per connection as ATT requires, the
+//! next once the peer confirmed the previous one.
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+
+use crate::error::{BleError, BleResult};
+use crate::gatt::Handle;
+use crate::sync::Mutex;
+use crate::timer::{Timer, TimerMode};
+use crate::*;
+
+/// How long sending pauses after the host ran out of mbufs
+const RETRY_MS: u32 = 20;
+
+/// What a connection enabled in the client configuration descriptor of a characteristic
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Subscription {
+    pub notify: bool,
+    pub indicate: bool,
+}
+
+impl Subscription {
+    pub fn is_active(self) -> bool {
+        self.notify || self.indicate
+    }
+}
+
+/// Counts of what happened to queued values since boot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Notifications and indications the host accepted
+    pub sent: u32,
+    /// Indications the peer acknowledged
+    pub confirmed: u32,
+    /// Values replaced by a newer one before they went out
+    pub coalesced: u32,
+    /// Sends put off because the host was out of mbufs
+    pub deferred: u32,
+    /// Values that never reached the peer: refused by the host, indications that were
+    /// never confirmed, and values still queued when the peer unsubscribed or disconnected
+    pub dropped: u32,
+}
+
+struct Pending {
+    attr_handle: u16,
+    value: Vec<u8>,
+    indicate: bool,
+}
+
+struct Connection {
+    handle: u16,
+    /// Value handles with an active subscription
+    subscriptions: Vec<(u16, Subscription)>,
+    /// Latest unsent value per value handle, oldest first
+    pending: Vec<Pending>,
+    /// An indication waits for its confirmation
+    indicating: bool,
+}
+
+struct Registry {
+    connections: Vec<Connection>,
+    stats: Stats,
+    /// Sending pauses until the retry timer fires
+    backing_off: bool,
+}
+
+impl Registry {
+    fn connection(&mut self, conn_handle: u16) -> Option<&mut Connection> {
+        self.connections
+            .iter_mut()
+            .find(|c| c.handle == conn_handle)
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    connections: Vec::new(),
+    stats: Stats {
+        sent: 0,
+        confirmed: 0,
+        coalesced: 0,
+        deferred: 0,
+        dropped: 0,
+    },
+    backing_off: false,
+});
+
+static RETRY_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
+
+/// Updates the registry, the GAP callback passes every event here before handling it
+///
+/// Connections are added when established and dropped with their subscriptions and
+/// queued values on disconnect. Subscribe events also cover subscriptions restored for
+/// bonded peers.
+pub fn handle_event(event: &ble_gap_event) {
+    let mut guard = REGISTRY.lock();
+    let registry = &mut *guard;
+    let mut resume = false;
+    match event.type_ as u32 {
+        BLE_GAP_EVENT_CONNECT => {
+            let connect = unsafe { &event.__bindgen_anon_1.connect };
+            if connect.status == 0 {
+                registry
+                    .connections
+                    .retain(|c| c.handle != connect.conn_handle);
+                registry.connections.push(Connection {
+                    handle: connect.conn_handle,
+                    subscriptions: Vec::new(),
+                    pending: Vec::new(),
+                    indicating: false,
+                });
+            }
+        }
+
+        BLE_GAP_EVENT_DISCONNECT => {
+            let conn_handle = unsafe { event.__bindgen_anon_1.disconnect.conn.conn_handle };
+            let stats = &mut registry.stats;
+            registry.connections.retain(|c| {
+                if c.handle == conn_handle {
+                    stats.dropped += c.pending.len() as u32;
+                }
+                c.handle != conn_handle
+            });
+        }
+
+        BLE_GAP_EVENT_SUBSCRIBE => {
+            let subscribe = unsafe { &event.__bindgen_anon_1.subscribe };
+            let subscription = Subscription {
+                notify: subscribe.cur_notify() != 0,
+                indicate: subscribe.cur_indicate() != 0,
+            };
+            let stats = &mut registry.stats;
+            // Unsubscribing as the connection goes down comes after the disconnect
+            let conn = registry
+                .connections
+                .iter_mut()
+                .find(|c| c.handle == subscribe.conn_handle);
+            if let Some(conn) = conn {
+                conn.subscriptions.retain(|s| s.0 != subscribe.attr_handle);
+                if subscription.is_active() {
+                    conn.subscriptions
+                        .push((subscribe.attr_handle, subscription));
+                } else {
+                    let queued = conn.pending.len();
+                    conn.pending
+                        .retain(|p| p.attr_handle != subscribe.attr_handle);
+                    stats.dropped += (queued - conn.pending.len()) as u32;
+                }
+            }
+        }
+
+        BLE_GAP_EVENT_NOTIFY_TX => {
+            let tx = unsafe { &event.__bindgen_anon_1.notify_tx };
+            // Failed sends are counted where they are made, only the outcome of an
+            // indication arrives here alone
+            let status = tx.status as u32;
+            if tx.indication() != 0 && (status == BLE_HS_EDONE || status == BLE_HS_ETIMEOUT) {
+                if let Some(conn) = registry.connection(tx.conn_handle) {
+                    conn.indicating = false;
+                }
+                if status == BLE_HS_EDONE {
+                    registry.stats.confirmed += 1;
+                } else {
+                    registry.stats.dropped += 1;
+                }
+                resume = !registry.backing_off;
+            }
+        }
+
+        _ => {}
+    }
+
+    // Not locked while sending, NimBLE reports through the GAP callback
+    drop(guard);
+    if resume {
+        flush();
+    }
+}
+
+/// Handles of the open connections
+pub fn handles() -> Vec<u16> {
+    REGISTRY
+        .lock()
+        .connections
+        .iter()
+        .map(|c| c.handle)
+        .collect()
+}
+
+/// How `conn_handle` is subscribed to the characteristic with value handle `attr_handle`
+pub fn subscription(conn_handle: u16, attr_handle: u16) -> Subscription {
+    REGISTRY
+        .lock()
+        .connection(conn_handle)
+        .and_then(|c| c.subscriptions.iter().find(|s| s.0 == attr_handle))
+        .map_or(Subscription::default(), |s| s.1)
+}
+
+/// Connections subscribed to the characteristic with value handle `attr_handle`
+pub fn subscribers(attr_handle: u16) -> Vec<(u16, Subscription)> {
+    REGISTRY
+        .lock()
+        .connections
+        .iter()
+        .filter_map(|c| {
+            c.subscriptions
+                .iter()
+                .find(|s| s.0 == attr_handle)
+                .map(|s| (c.handle, s.1))
+        })
+        .collect()
+}
+
+pub fn has_subscribers(attr_handle: u16) -> bool {
+    REGISTRY
+        .lock()
+        .connections
+        .iter()
+        .any(|c| c.subscriptions.iter().any(|s| s.0 == attr_handle))
+}
+
+pub fn stats() -> Stats {
+    REGISTRY.lock().stats
+}
+
+/// Queues `value` for every connection subscribed to `chr` and sends what it can, returns
+/// how many connections it was queued for
+///
+/// Connections that enabled notifications get one, the others an indication. A value
+/// still queued for the same characteristic is replaced.
+pub fn notify(chr: &Handle, value: &[u8]) -> usize {
+    let attr_handle = chr.get();
+    let mut guard = REGISTRY.lock();
+    let registry = &mut *guard;
+    let mut queued = 0;
+    for conn in registry.connections.iter_mut() {
+        if queue(conn, &mut registry.stats, attr_handle, value) {
+            queued += 1;
+        }
+    }
+
+    let backing_off = registry.backing_off;
+    drop(guard);
+    if !backing_off {
+        flush();
+    }
+    queued
+}
+
+/// Like `notify` for the one connection `conn_handle`, for values that differ between
+/// connections, returns whether it was subscribed
+pub fn notify_conn(conn_handle: u16, chr: &Handle, value: &[u8]) -> bool {
+    let attr_handle = chr.get();
+    let mut guard = REGISTRY.lock();
+    let registry = &mut *guard;
+    let queued = match registry
+        .connections
+        .iter_mut()
+        .find(|c| c.handle == conn_handle)
+    {
+        Some(conn) => queue(conn, &mut registry.stats, attr_handle, value),
+        None => false,
+    };
+
+    let backing_off = registry.backing_off;
+    drop(guard);
+    if !backing_off {
+        flush();
+    }
+    queued
+}
+
+fn queue(conn: &mut Connection, stats: &mut Stats, attr_handle: u16, value: &[u8]) -> bool {
+    let subscription = match conn.subscriptions.iter().find(|s| s.0 == attr_handle) {
+        Some(&(_, subscription)) => subscription,
+        None => return false,
+    };
+    let indicate = !subscription.notify;
+    match conn
+        .pending
+        .iter_mut()
+        .find(|p| p.attr_handle == attr_handle)
+    {
+        Some(pending) => {
+            pending.value.clear();
+            pending.value.extend_from_slice(value);
+            pending.indicate = indicate;
+            stats.coalesced += 1;
+        }
+        None => conn.pending.push(Pending {
+            attr_handle,
+            value: value.to_vec(),
+            indicate,
+        }),
+    }
+    true
+}
+
+/// Sends queued values until nothing is left that may go out or the host runs out of
+/// mbufs
+pub fn flush() {
+    while let Some((conn_handle, pending)) = next_pending() {
+        let result = send(conn_handle, &pending);
+        let mut guard = REGISTRY.lock();
+        let registry = &mut *guard;
+        match result {
+            Ok(()) => registry.stats.sent += 1,
+
+            Err(err) if err.code() == BLE_HS_ENOMEM as i32 => {
+                registry.stats.deferred += 1;
+                registry.backing_off = true;
+                if let Some(conn) = registry.connection(conn_handle) {
+                    if pending.indicate {
+                        conn.indicating = false;
+                    }
+                    // Back to the front, unless a newer value was queued meanwhile
+                    if conn
+                        .pending
+                        .iter()
+                        .all(|p| p.attr_handle != pending.attr_handle)
+                    {
+                        conn.pending.insert(0, pending);
+                    } else {
+                        registry.stats.coalesced += 1;
+                    }
+                }
+                drop(guard);
+                schedule_retry();
+                return;
+            }
+
+            Err(_) => {
+                registry.stats.dropped += 1;
+                if pending.indicate {
+                    if let Some(conn) = registry.connection(conn_handle) {
+                        conn.indicating = false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Takes the oldest value that may be sent now, marking an indication as in flight
+fn next_pending() -> Option<(u16, Pending)> {
+    let mut registry = REGISTRY.lock();
+    for conn in registry.connections.iter_mut() {
+        let indicating = conn.indicating;
+        if let Some(i) = conn
+            .pending
+            .iter()
+            .position(|p| !(p.indicate && indicating))
+        {
+            let pending = conn.pending.remove(i);
+            conn.indicating |= pending.indicate;
+            return Some((conn.handle, pending));
+        }
+    }
+    None
+}
+
+fn send(conn_handle: u16, pending: &Pending) -> BleResult<()> {
+    let value = &pending.value;
+    unsafe {
+        // The host frees the mbuf, also when sending fails
+        let om = ble_hs_mbuf_from_flat(value.as_ptr() as *const c_void, value.len() as u16);
+        if om.is_null() {
+            return Err(BleError::new(BLE_HS_ENOMEM, "ble_hs_mbuf_from_flat"));
+        }
+        if pending.indicate {
+            ble!(ble_gattc_indicate_custom(
+                conn_handle,
+                pending.attr_handle,
+                om
+            ))
+        } else {
+            ble!(ble_gattc_notify_custom(
+                conn_handle,
+                pending.attr_handle,
+                om
+            ))
+        }
+    }
+}
+
+fn schedule_retry() {
+    let mut timer = RETRY_TIMER.lock();
+    if timer.is_none() {
+        *timer = Timer::new(
+            "notify_retry\0",
+            pdMS_TO_TICKS!(RETRY_MS),
+            TimerMode::OneShot,
+            |_| {
+                REGISTRY.lock().backing_off = false;
+                flush();
+            },
+        )
+        .ok();
+    }
+    let started = match *timer {
+        Some(ref timer) => timer.start(0).is_ok(),
+        None => false,
+    };
+    if !started {
+        // Without a timer the next `notify` retries
+        REGISTRY.lock().backing_off = false;
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::gap::{self, GapEvent, GapHandler};
+    use crate::mock;
+    use crate::mock::nimble::Notification;
+    use alloc::vec;
+    use core::ptr;
+
+    struct Central;
+
+    impl GapHandler for Central {
+        fn event(&self, _event: GapEvent) -> i32 {
+            0
+        }
+    }
+
+    static CENTRAL: Central = Central;
+
+    /// Connects `conn_handle` through a GAP callback that updates the registry
+    fn connect(conn_handle: u16) {
+        unsafe {
+            let params = core::mem::zeroed();
+            let arg = &CENTRAL as *const Central as *mut c_void;
+            let cb = Some(gap::gap_event_cb::<Central> as _);
+            assert_eq!(ble_gap_adv_start(0, ptr::null(), 0, &params, cb, arg), 0);
+        }
+        mock::nimble::connect(conn_handle);
+    }
+
+    /// A characteristic the GATT server registered as `attr_handle`
+    fn chr(handle: &'static Handle, attr_handle: u16) -> &'static Handle {
+        unsafe { *handle.as_ptr() = attr_handle };
+        handle
+    }
+
+    fn sent(notifications: &[Notification]) -> Vec<(u16, bool, Vec<u8>)> {
+        notifications
+            .iter()
+            .map(|n| (n.conn_handle, n.indication, n.data.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn two_centrals() {
+        static LEVEL: Handle = Handle::new();
+        let level = chr(&LEVEL, 0x101);
+        connect(11);
+        connect(12);
+        assert!(handles().ends_with(&[11, 12]));
+
+        mock::nimble::subscribe(11, 0x101, true, false);
+        mock::nimble::subscribe(12, 0x101, true, true);
+        assert_eq!(
+            subscribers(0x101),
+            vec![
+                (
+                    11,
+                    Subscription {
+                        notify: true,
+                        indicate: false
+                    }
+                ),
+                (
+                    12,
+                    Subscription {
+                        notify: true,
+                        indicate: true
+                    }
+                ),
+            ]
+        );
+
+        // Enabling both prefers notifications, indications only go where they are the
+        // sole choice
+        assert_eq!(notify(level, &[1]), 2);
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(11, false, vec![1]), (12, false, vec![1])]
+        );
+        mock::nimble::subscribe(12, 0x101, false, true);
+        assert_eq!(notify(level, &[2]), 2);
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(11, false, vec![2]), (12, true, vec![2])]
+        );
+        mock::nimble::confirm(12);
+
+        // Unsubscribing leaves the other central alone
+        mock::nimble::subscribe(11, 0x101, false, false);
+        assert!(!subscription(11, 0x101).is_active());
+        assert_eq!(subscribers(0x101).len(), 1);
+        assert!(!notify_conn(11, level, &[3]));
+        assert!(notify_conn(12, level, &[3]));
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(12, true, vec![3])]
+        );
+        mock::nimble::confirm(12);
+
+        mock::nimble::disconnect(11, 0x213);
+        mock::nimble::disconnect(12, 0x213);
+        assert!(!handles().contains(&11) && !handles().contains(&12));
+        assert!(!has_subscribers(0x101));
+        assert_eq!(notify(level, &[4]), 0);
+        assert!(mock::nimble::take_notifications().is_empty());
+        assert_eq!(mock::nimble::live_mbufs(), 0);
+    }
+
+    #[test]
+    fn queued_values_dropped_with_subscription() {
+        static STATE: Handle = Handle::new();
+        let state = chr(&STATE, 0x111);
+        connect(21);
+        mock::nimble::subscribe(21, 0x111, false, true);
+        let before = stats();
+
+        // The second value waits for the confirmation of the first
+        notify(state, &[1]);
+        notify(state, &[2]);
+        assert_eq!(mock::nimble::take_notifications().len(), 1);
+        mock::nimble::subscribe(21, 0x111, false, false);
+        assert_eq!(stats().dropped, before.dropped + 1);
+
+        // Queued values go with the connection too, the first is still unconfirmed
+        mock::nimble::subscribe(21, 0x111, false, true);
+        notify(state, &[3]);
+        assert!(mock::nimble::take_notifications().is_empty());
+        mock::nimble::disconnect(21, 0x213);
+        assert_eq!(stats().dropped, before.dropped + 2);
+        assert!(!handles().contains(&21));
+        assert_eq!(mock::nimble::live_mbufs(), 0);
+    }
+
+    #[test]
+    fn indications_wait_for_confirmation() {
+        static EVENT: Handle = Handle::new();
+        let event = chr(&EVENT, 0x121);
+        connect(31);
+        mock::nimble::subscribe(31, 0x121, false, true);
+        let before = stats();
+
+        notify(event, &[1]);
+        notify(event, &[2]);
+        notify(event, &[3]);
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(31, true, vec![1])]
+        );
+        assert_eq!(stats().coalesced, before.coalesced + 1);
+
+        // The confirmation releases the latest value
+        mock::nimble::confirm(31);
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(31, true, vec![3])]
+        );
+        assert_eq!(stats().confirmed, before.confirmed + 1);
+
+        // So does a timeout, which loses the unconfirmed one
+        notify(event, &[4]);
+        mock::nimble::indication_timeout(31);
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(31, true, vec![4])]
+        );
+        assert_eq!(stats().dropped, before.dropped + 1);
+        mock::nimble::confirm(31);
+        assert!(mock::nimble::take_notifications().is_empty());
+
+        let after = stats();
+        assert_eq!(after.sent, before.sent + 3);
+        assert_eq!(after.confirmed, before.confirmed + 2);
+        mock::nimble::disconnect(31, 0x213);
+        assert_eq!(mock::nimble::live_mbufs(), 0);
+    }
+
+    #[test]
+    fn backs_off_when_out_of_mbufs() {
+        static POSITION: Handle = Handle::new();
+        let position = chr(&POSITION, 0x131);
+        connect(41);
+        mock::nimble::subscribe(41, 0x131, true, false);
+        let before = stats();
+
+        mock::nimble::fail_next_sends(1, BLE_HS_ENOMEM as i32);
+        notify(position, &[1]);
+        assert!(mock::nimble::take_notifications().is_empty());
+        assert_eq!(stats().deferred, before.deferred + 1);
+
+        // Nothing goes out until the retry, newer values replace the deferred one
+        notify(position, &[2]);
+        notify(position, &[3]);
+        assert!(mock::nimble::take_notifications().is_empty());
+        assert_eq!(stats().coalesced, before.coalesced + 2);
+        mock::advance(pdMS_TO_TICKS!(RETRY_MS));
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(41, false, vec![3])]
+        );
+
+        // Other errors drop the value without pausing
+        mock::nimble::fail_next_sends(1, BLE_HS_EINVAL as i32);
+        notify(position, &[4]);
+        notify(position, &[5]);
+        assert_eq!(
+            sent(&mock::nimble::take_notifications()),
+            vec![(41, false, vec![5])]
+        );
+
+        let after = stats();
+        assert_eq!(after.sent, before.sent + 2);
+        assert_eq!(after.deferred, before.deferred + 1);
+        assert_eq!(after.dropped, before.dropped + 1);
+        mock::nimble::disconnect(41, 0x213);
+        assert_eq!(mock::nimble::live_mbufs(), 0);
+    }
+}
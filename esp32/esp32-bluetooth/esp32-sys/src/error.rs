@@ -0,0 +1,507 @@
+use core::fmt;
+
+use crate::*;
+
+pub type EspResult<T> = Result<T, EspError>;
+
+pub type BleResult<T> = Result<T, BleError>;
+
+/// A failed `esp_err_t`, optionally tagged with the call that returned it
+///
+/// Names and descriptions come from a table kept here instead of `esp_err_to_name`, so
+/// they are available when building for the host.
+#[derive(Clone, Copy)]
+pub struct EspError {
+    code: esp_err_t,
+    call: Option<&'static str>,
+}
+
+impl EspError {
+    /// Wraps a return code, `ESP_OK` is not an error and yields `None`
+    pub fn from(code: esp_err_t) -> Option<EspError> {
+        if code == ESP_OK as esp_err_t {
+            None
+        } else {
+            Some(EspError { code, call: None })
+        }
+    }
+
+    /// For errors raised by the Rust wrappers themselves, `code` must not be `ESP_OK`
+    pub(crate) fn new(code: u32, call: &'static str) -> EspError {
+        debug_assert!(code != ESP_OK);
+        EspError {
+            code: code as esp_err_t,
+            call: Some(call),
+        }
+    }
+
+    pub fn check(code: esp_err_t) -> EspResult<()> {
+        match EspError::from(code) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the call that failed, see the `esp!` macro
+    pub fn in_call(self, call: &'static str) -> EspError {
+        EspError {
+            call: Some(call),
+            ..self
+        }
+    }
+
+    pub fn code(&self) -> esp_err_t {
+        self.code
+    }
+
+    pub fn call(&self) -> Option<&'static str> {
+        self.call
+    }
+
+    /// The C constant name, e.g. `ESP_ERR_NO_MEM`
+    pub fn name(&self) -> &'static str {
+        lookup(self.code).map_or("UNKNOWN ERROR", |&(_, name, _)| name)
+    }
+
+    pub fn description(&self) -> Option<&'static str> {
+        lookup(self.code).map(|&(_, _, description)| description)
+    }
+}
+
+impl PartialEq for EspError {
+    fn eq(&self, other: &EspError) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for EspError {}
+
+impl fmt::Debug for EspError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EspError({} 0x{:x})", self.name(), self.code)
+    }
+}
+
+impl fmt::Display for EspError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(call) = self.call {
+            write!(f, "{} failed: ", call)?;
+        }
+        write!(f, "{} (0x{:x})", self.name(), self.code)?;
+        if let Some(description) = self.description() {
+            write!(f, ": {}", description)?;
+        }
+        Ok(())
+    }
+}
+
+/// A failed NimBLE host call, optionally tagged with the call that returned it
+///
+/// Codes below 0x100 are `BLE_HS_E*`, above that the host passes on ATT, HCI, L2CAP and
+/// security manager errors offset by their `BLE_HS_ERR_*_BASE`.
+#[derive(Clone, Copy)]
+pub struct BleError {
+    code: i32,
+    call: Option<&'static str>,
+}
+
+impl BleError {
+    /// Wraps a return code, 0 is not an error and yields `None`
+    pub fn from(code: i32) -> Option<BleError> {
+        if code == 0 {
+            None
+        } else {
+            Some(BleError { code, call: None })
+        }
+    }
+
+    /// For errors raised by the Rust wrappers themselves, `code` must not be 0
+    pub(crate) fn new(code: u32, call: &'static str) -> BleError {
+        debug_assert!(code != 0);
+        BleError {
+            code: code as i32,
+            call: Some(call),
+        }
+    }
+
+    pub fn check(code: i32) -> BleResult<()> {
+        match BleError::from(code) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the call that failed, see the `ble!` macro
+    pub fn in_call(self, call: &'static str) -> BleError {
+        BleError {
+            call: Some(call),
+            ..self
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    pub fn call(&self) -> Option<&'static str> {
+        self.call
+    }
+
+    /// The C constant name, e.g. `BLE_HS_ENOMEM`, or the name of the range base the
+    /// code is offset from
+    pub fn name(&self) -> &'static str {
+        if let Some(&(_, name)) = BLE_ERRORS.iter().find(|&&(c, _)| c as i32 == self.code) {
+            return name;
+        }
+        match self.range() {
+            Some((_, name)) => name,
+            None => "UNKNOWN ERROR",
+        }
+    }
+
+    /// The HCI error the controller reported, for codes offset by `BLE_HS_ERR_HCI_BASE`
+    pub fn hci(&self) -> Option<HciError> {
+        let base = BLE_HS_ERR_HCI_BASE as i32;
+        if self.code >= base && self.code < base + 0x100 {
+            Some(HciError::from_code((self.code - base) as u8))
+        } else {
+            None
+        }
+    }
+
+    fn range(&self) -> Option<(u32, &'static str)> {
+        BLE_ERROR_RANGES
+            .iter()
+            .find(|&&(base, _)| self.code >= base as i32 && self.code < base as i32 + 0x100)
+            .cloned()
+    }
+}
+
+impl PartialEq for BleError {
+    fn eq(&self, other: &BleError) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for BleError {}
+
+impl fmt::Debug for BleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BleError({} 0x{:x})", self.name(), self.code)
+    }
+}
+
+impl fmt::Display for BleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(call) = self.call {
+            write!(f, "{} failed: ", call)?;
+        }
+        match self.range() {
+            Some((base, name)) => write!(f, "{} + 0x{:02x}", name, self.code - base as i32)?,
+            None => write!(f, "{} ({})", self.name(), self.code)?,
+        }
+        if let Some(hci) = self.hci() {
+            write!(f, ": {}", hci.description())?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! hci_errors {
+    ($($code:ident => $name:ident, $description:expr;)*) => {
+        /// An HCI error code, as in Vol 2 Part D of the Core specification
+        ///
+        /// The controller reports them as disconnect reasons and as the status of failed
+        /// commands, the host passes them on offset by `BLE_HS_ERR_HCI_BASE`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum HciError {
+            $($name,)*
+            Unknown(u8),
+        }
+
+        impl HciError {
+            pub fn from_code(code: u8) -> HciError {
+                match code as ble_error_codes {
+                    $($code => HciError::$name,)*
+                    _ => HciError::Unknown(code),
+                }
+            }
+
+            pub fn code(self) -> u8 {
+                match self {
+                    $(HciError::$name => $code as u8,)*
+                    HciError::Unknown(code) => code,
+                }
+            }
+
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(HciError::$name => $description,)*
+                    HciError::Unknown(_) => "unknown HCI error",
+                }
+            }
+        }
+    };
+}
+
+hci_errors! {
+    ble_error_codes_BLE_ERR_UNKNOWN_HCI_CMD => UnknownCommand, "unknown HCI command";
+    ble_error_codes_BLE_ERR_UNK_CONN_ID => UnknownConnectionId, "unknown connection identifier";
+    ble_error_codes_BLE_ERR_HW_FAIL => HardwareFailure, "hardware failure";
+    ble_error_codes_BLE_ERR_PAGE_TMO => PageTimeout, "page timeout";
+    ble_error_codes_BLE_ERR_AUTH_FAIL => AuthenticationFailure, "authentication failure";
+    ble_error_codes_BLE_ERR_PINKEY_MISSING => PinOrKeyMissing, "PIN or key missing";
+    ble_error_codes_BLE_ERR_MEM_CAPACITY => MemoryCapacityExceeded, "memory capacity exceeded";
+    ble_error_codes_BLE_ERR_CONN_SPVN_TMO => ConnectionTimeout, "connection timeout";
+    ble_error_codes_BLE_ERR_CONN_LIMIT => ConnectionLimitExceeded, "connection limit exceeded";
+    ble_error_codes_BLE_ERR_SYNCH_CONN_LIMIT => SynchronousConnectionLimitExceeded, "synchronous connection limit to a device exceeded";
+    ble_error_codes_BLE_ERR_ACL_CONN_EXISTS => ConnectionAlreadyExists, "connection already exists";
+    ble_error_codes_BLE_ERR_CMD_DISALLOWED => CommandDisallowed, "command disallowed";
+    ble_error_codes_BLE_ERR_CONN_REJ_RESOURCES => RejectedLimitedResources, "connection rejected due to limited resources";
+    ble_error_codes_BLE_ERR_CONN_REJ_SECURITY => RejectedSecurityReasons, "connection rejected due to security reasons";
+    ble_error_codes_BLE_ERR_CONN_REJ_BD_ADDR => RejectedUnacceptableAddress, "connection rejected due to unacceptable BD_ADDR";
+    ble_error_codes_BLE_ERR_CONN_ACCEPT_TMO => ConnectionAcceptTimeout, "connection accept timeout exceeded";
+    ble_error_codes_BLE_ERR_UNSUPPORTED => UnsupportedFeature, "unsupported feature or parameter value";
+    ble_error_codes_BLE_ERR_INV_HCI_CMD_PARMS => InvalidParameters, "invalid HCI command parameters";
+    ble_error_codes_BLE_ERR_REM_USER_CONN_TERM => RemoteUserTerminated, "remote user terminated connection";
+    ble_error_codes_BLE_ERR_RD_CONN_TERM_RESRCS => RemoteLowResources, "remote device terminated connection due to low resources";
+    ble_error_codes_BLE_ERR_RD_CONN_TERM_PWROFF => RemotePowerOff, "remote device terminated connection due to power off";
+    ble_error_codes_BLE_ERR_CONN_TERM_LOCAL => LocalHostTerminated, "connection terminated by local host";
+    ble_error_codes_BLE_ERR_REPEATED_ATTEMPTS => RepeatedAttempts, "repeated attempts";
+    ble_error_codes_BLE_ERR_NO_PAIRING => PairingNotAllowed, "pairing not allowed";
+    ble_error_codes_BLE_ERR_UNK_LMP => UnknownLmpPdu, "unknown LMP PDU";
+    ble_error_codes_BLE_ERR_UNSUPP_REM_FEATURE => UnsupportedRemoteFeature, "unsupported remote feature";
+    ble_error_codes_BLE_ERR_SCO_OFFSET => ScoOffsetRejected, "SCO offset rejected";
+    ble_error_codes_BLE_ERR_SCO_ITVL => ScoIntervalRejected, "SCO interval rejected";
+    ble_error_codes_BLE_ERR_SCO_AIR_MODE => ScoAirModeRejected, "SCO air mode rejected";
+    ble_error_codes_BLE_ERR_INV_LMP_LL_PARM => InvalidLlParameters, "invalid LMP or LL parameters";
+    ble_error_codes_BLE_ERR_UNSPECIFIED => Unspecified, "unspecified error";
+    ble_error_codes_BLE_ERR_UNSUPP_LMP_LL_PARM => UnsupportedLlParameter, "unsupported LMP or LL parameter value";
+    ble_error_codes_BLE_ERR_NO_ROLE_CHANGE => RoleChangeNotAllowed, "role change not allowed";
+    ble_error_codes_BLE_ERR_LMP_LL_RSP_TMO => LlResponseTimeout, "LMP or LL response timeout";
+    ble_error_codes_BLE_ERR_LMP_COLLISION => LlProcedureCollision, "LMP error transaction collision or LL procedure collision";
+    ble_error_codes_BLE_ERR_LMP_PDU => LmpPduNotAllowed, "LMP PDU not allowed";
+    ble_error_codes_BLE_ERR_ENCRYPTION_MODE => EncryptionModeNotAcceptable, "encryption mode not acceptable";
+    ble_error_codes_BLE_ERR_LINK_KEY_CHANGE => LinkKeyCannotBeChanged, "link key cannot be changed";
+    ble_error_codes_BLE_ERR_UNSUPP_QOS => QosNotSupported, "requested QoS not supported";
+    ble_error_codes_BLE_ERR_INSTANT_PASSED => InstantPassed, "instant passed";
+    ble_error_codes_BLE_ERR_UNIT_KEY_PAIRING => UnitKeyPairingNotSupported, "pairing with unit key not supported";
+    ble_error_codes_BLE_ERR_DIFF_TRANS_COLL => DifferentTransactionCollision, "different transaction collision";
+    ble_error_codes_BLE_ERR_QOS_PARM => QosUnacceptableParameter, "QoS unacceptable parameter";
+    ble_error_codes_BLE_ERR_QOS_REJECTED => QosRejected, "QoS rejected";
+    ble_error_codes_BLE_ERR_CHAN_CLASS => ChannelClassificationNotSupported, "channel classification not supported";
+    ble_error_codes_BLE_ERR_INSUFFICIENT_SEC => InsufficientSecurity, "insufficient security";
+    ble_error_codes_BLE_ERR_PARM_OUT_OF_RANGE => ParameterOutOfRange, "parameter out of mandatory range";
+    ble_error_codes_BLE_ERR_PENDING_ROLE_SW => RoleSwitchPending, "role switch pending";
+    ble_error_codes_BLE_ERR_RESERVED_SLOT => ReservedSlotViolation, "reserved slot violation";
+    ble_error_codes_BLE_ERR_ROLE_SW_FAIL => RoleSwitchFailed, "role switch failed";
+    ble_error_codes_BLE_ERR_INQ_RSP_TOO_BIG => InquiryResponseTooLarge, "extended inquiry response too large";
+    ble_error_codes_BLE_ERR_SEC_SIMPLE_PAIR => SimplePairingNotSupported, "secure simple pairing not supported by host";
+    ble_error_codes_BLE_ERR_HOST_BUSY_PAIR => HostBusyPairing, "host busy - pairing";
+    ble_error_codes_BLE_ERR_CONN_REJ_CHANNEL => NoSuitableChannel, "connection rejected due to no suitable channel found";
+    ble_error_codes_BLE_ERR_CTLR_BUSY => ControllerBusy, "controller busy";
+    ble_error_codes_BLE_ERR_CONN_PARMS => UnacceptableConnectionParameters, "unacceptable connection parameters";
+    ble_error_codes_BLE_ERR_DIR_ADV_TMO => AdvertisingTimeout, "advertising timeout";
+    ble_error_codes_BLE_ERR_CONN_TERM_MIC => MicFailure, "connection terminated due to MIC failure";
+    ble_error_codes_BLE_ERR_CONN_ESTABLISHMENT => ConnectionFailedToEstablish, "connection failed to be established";
+    ble_error_codes_BLE_ERR_MAC_CONN_FAIL => MacConnectionFailed, "MAC connection failed";
+    ble_error_codes_BLE_ERR_COARSE_CLK_ADJ => CoarseClockAdjustmentRejected, "coarse clock adjustment rejected";
+    ble_error_codes_BLE_ERR_TYPE0_SUBMAP_NDEF => Type0SubmapNotDefined, "type0 submap not defined";
+    ble_error_codes_BLE_ERR_UNK_ADV_INDENT => UnknownAdvertisingIdentifier, "unknown advertising identifier";
+    ble_error_codes_BLE_RR_LIMIT_REACHED => LimitReached, "limit reached";
+}
+
+impl fmt::Display for HciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (0x{:02x})", self.description(), self.code())
+    }
+}
+
+fn lookup(code: esp_err_t) -> Option<&'static (esp_err_t, &'static str, &'static str)> {
+    ERRORS.iter().find(|&&(c, _, _)| c == code)
+}
+
+#[rustfmt::skip]
+static ERRORS: &[(esp_err_t, &str, &str)] = &[
+    (ESP_FAIL, "ESP_FAIL", "generic failure"),
+    (ESP_ERR_NO_MEM as esp_err_t, "ESP_ERR_NO_MEM", "out of memory"),
+    (ESP_ERR_INVALID_ARG as esp_err_t, "ESP_ERR_INVALID_ARG", "invalid argument"),
+    (ESP_ERR_INVALID_STATE as esp_err_t, "ESP_ERR_INVALID_STATE", "invalid state"),
+    (ESP_ERR_INVALID_SIZE as esp_err_t, "ESP_ERR_INVALID_SIZE", "invalid size"),
+    (ESP_ERR_NOT_FOUND as esp_err_t, "ESP_ERR_NOT_FOUND", "requested resource not found"),
+    (ESP_ERR_NOT_SUPPORTED as esp_err_t, "ESP_ERR_NOT_SUPPORTED", "operation or feature not supported"),
+    (ESP_ERR_TIMEOUT as esp_err_t, "ESP_ERR_TIMEOUT", "operation timed out"),
+    (ESP_ERR_INVALID_RESPONSE as esp_err_t, "ESP_ERR_INVALID_RESPONSE", "received response was invalid"),
+    (ESP_ERR_INVALID_CRC as esp_err_t, "ESP_ERR_INVALID_CRC", "CRC or checksum was invalid"),
+    (ESP_ERR_INVALID_VERSION as esp_err_t, "ESP_ERR_INVALID_VERSION", "version was invalid"),
+    (ESP_ERR_INVALID_MAC as esp_err_t, "ESP_ERR_INVALID_MAC", "MAC address was invalid"),
+
+    (ESP_ERR_NVS_NOT_INITIALIZED as esp_err_t, "ESP_ERR_NVS_NOT_INITIALIZED", "the storage driver is not initialized"),
+    (ESP_ERR_NVS_NOT_FOUND as esp_err_t, "ESP_ERR_NVS_NOT_FOUND", "requested key or namespace doesn't exist"),
+    (ESP_ERR_NVS_TYPE_MISMATCH as esp_err_t, "ESP_ERR_NVS_TYPE_MISMATCH", "value type doesn't match the type stored in NVS"),
+    (ESP_ERR_NVS_READ_ONLY as esp_err_t, "ESP_ERR_NVS_READ_ONLY", "storage handle was opened as read only"),
+    (ESP_ERR_NVS_NOT_ENOUGH_SPACE as esp_err_t, "ESP_ERR_NVS_NOT_ENOUGH_SPACE", "not enough space in the underlying storage to save the value"),
+    (ESP_ERR_NVS_INVALID_NAME as esp_err_t, "ESP_ERR_NVS_INVALID_NAME", "namespace name doesn't satisfy constraints"),
+    (ESP_ERR_NVS_INVALID_HANDLE as esp_err_t, "ESP_ERR_NVS_INVALID_HANDLE", "handle has been closed or is NULL"),
+    (ESP_ERR_NVS_REMOVE_FAILED as esp_err_t, "ESP_ERR_NVS_REMOVE_FAILED", "the value wasn't updated because the flash write failed"),
+    (ESP_ERR_NVS_KEY_TOO_LONG as esp_err_t, "ESP_ERR_NVS_KEY_TOO_LONG", "key name is too long"),
+    (ESP_ERR_NVS_PAGE_FULL as esp_err_t, "ESP_ERR_NVS_PAGE_FULL", "internal error, never returned by the NVS API"),
+    (ESP_ERR_NVS_INVALID_STATE as esp_err_t, "ESP_ERR_NVS_INVALID_STATE", "NVS is in an inconsistent state due to a previous error"),
+    (ESP_ERR_NVS_INVALID_LENGTH as esp_err_t, "ESP_ERR_NVS_INVALID_LENGTH", "string or blob length is not sufficient to store the data"),
+    (ESP_ERR_NVS_NO_FREE_PAGES as esp_err_t, "ESP_ERR_NVS_NO_FREE_PAGES", "NVS partition doesn't contain any empty pages"),
+    (ESP_ERR_NVS_VALUE_TOO_LONG as esp_err_t, "ESP_ERR_NVS_VALUE_TOO_LONG", "string or blob is longer than supported"),
+    (ESP_ERR_NVS_PART_NOT_FOUND as esp_err_t, "ESP_ERR_NVS_PART_NOT_FOUND", "partition not found in the partition table"),
+    (ESP_ERR_NVS_NEW_VERSION_FOUND as esp_err_t, "ESP_ERR_NVS_NEW_VERSION_FOUND", "NVS partition contains data in a newer format"),
+    (ESP_ERR_NVS_XTS_ENCR_FAILED as esp_err_t, "ESP_ERR_NVS_XTS_ENCR_FAILED", "XTS encryption failed while writing an NVS entry"),
+    (ESP_ERR_NVS_XTS_DECR_FAILED as esp_err_t, "ESP_ERR_NVS_XTS_DECR_FAILED", "XTS decryption failed while reading an NVS entry"),
+    (ESP_ERR_NVS_XTS_CFG_FAILED as esp_err_t, "ESP_ERR_NVS_XTS_CFG_FAILED", "XTS configuration setting failed"),
+    (ESP_ERR_NVS_XTS_CFG_NOT_FOUND as esp_err_t, "ESP_ERR_NVS_XTS_CFG_NOT_FOUND", "XTS configuration not found"),
+    (ESP_ERR_NVS_ENCR_NOT_SUPPORTED as esp_err_t, "ESP_ERR_NVS_ENCR_NOT_SUPPORTED", "NVS encryption is not supported"),
+    (ESP_ERR_NVS_KEYS_NOT_INITIALIZED as esp_err_t, "ESP_ERR_NVS_KEYS_NOT_INITIALIZED", "NVS key partition is uninitialized"),
+    (ESP_ERR_NVS_CORRUPT_KEY_PART as esp_err_t, "ESP_ERR_NVS_CORRUPT_KEY_PART", "NVS key partition is corrupt"),
+    (ESP_ERR_NVS_CONTENT_DIFFERS as esp_err_t, "ESP_ERR_NVS_CONTENT_DIFFERS", "internal error, never returned by the NVS API"),
+
+    (ESP_ERR_FLASH_OP_FAIL as esp_err_t, "ESP_ERR_FLASH_OP_FAIL", "flash operation failed"),
+    (ESP_ERR_FLASH_OP_TIMEOUT as esp_err_t, "ESP_ERR_FLASH_OP_TIMEOUT", "flash operation timed out"),
+    (ESP_ERR_FLASH_NOT_INITIALISED as esp_err_t, "ESP_ERR_FLASH_NOT_INITIALISED", "flash chip not initialised"),
+    (ESP_ERR_FLASH_UNSUPPORTED_HOST as esp_err_t, "ESP_ERR_FLASH_UNSUPPORTED_HOST", "flash host not supported"),
+    (ESP_ERR_FLASH_UNSUPPORTED_CHIP as esp_err_t, "ESP_ERR_FLASH_UNSUPPORTED_CHIP", "flash chip not supported"),
+    (ESP_ERR_FLASH_PROTECTED as esp_err_t, "ESP_ERR_FLASH_PROTECTED", "flash is write protected"),
+];
+
+static BLE_ERRORS: &[(u32, &str)] = &[
+    (BLE_HS_EAGAIN, "BLE_HS_EAGAIN"),
+    (BLE_HS_EALREADY, "BLE_HS_EALREADY"),
+    (BLE_HS_EINVAL, "BLE_HS_EINVAL"),
+    (BLE_HS_EMSGSIZE, "BLE_HS_EMSGSIZE"),
+    (BLE_HS_ENOENT, "BLE_HS_ENOENT"),
+    (BLE_HS_ENOMEM, "BLE_HS_ENOMEM"),
+    (BLE_HS_ENOTCONN, "BLE_HS_ENOTCONN"),
+    (BLE_HS_ENOTSUP, "BLE_HS_ENOTSUP"),
+    (BLE_HS_EAPP, "BLE_HS_EAPP"),
+    (BLE_HS_EBADDATA, "BLE_HS_EBADDATA"),
+    (BLE_HS_EOS, "BLE_HS_EOS"),
+    (BLE_HS_ECONTROLLER, "BLE_HS_ECONTROLLER"),
+    (BLE_HS_ETIMEOUT, "BLE_HS_ETIMEOUT"),
+    (BLE_HS_EDONE, "BLE_HS_EDONE"),
+    (BLE_HS_EBUSY, "BLE_HS_EBUSY"),
+    (BLE_HS_EREJECT, "BLE_HS_EREJECT"),
+    (BLE_HS_EUNKNOWN, "BLE_HS_EUNKNOWN"),
+    (BLE_HS_EROLE, "BLE_HS_EROLE"),
+    (BLE_HS_ETIMEOUT_HCI, "BLE_HS_ETIMEOUT_HCI"),
+    (BLE_HS_ENOMEM_EVT, "BLE_HS_ENOMEM_EVT"),
+    (BLE_HS_ENOADDR, "BLE_HS_ENOADDR"),
+    (BLE_HS_ENOTSYNCED, "BLE_HS_ENOTSYNCED"),
+    (BLE_HS_EAUTHEN, "BLE_HS_EAUTHEN"),
+    (BLE_HS_EAUTHOR, "BLE_HS_EAUTHOR"),
+    (BLE_HS_EENCRYPT, "BLE_HS_EENCRYPT"),
+    (BLE_HS_EENCRYPT_KEY_SZ, "BLE_HS_EENCRYPT_KEY_SZ"),
+    (BLE_HS_ESTORE_CAP, "BLE_HS_ESTORE_CAP"),
+    (BLE_HS_ESTORE_FAIL, "BLE_HS_ESTORE_FAIL"),
+    (BLE_HS_EPREEMPTED, "BLE_HS_EPREEMPTED"),
+    (BLE_HS_EDISABLED, "BLE_HS_EDISABLED"),
+];
+
+static BLE_ERROR_RANGES: &[(u32, &str)] = &[
+    (BLE_HS_ERR_ATT_BASE, "BLE_HS_ERR_ATT_BASE"),
+    (BLE_HS_ERR_HCI_BASE, "BLE_HS_ERR_HCI_BASE"),
+    (BLE_HS_ERR_L2C_BASE, "BLE_HS_ERR_L2C_BASE"),
+    (BLE_HS_ERR_SM_US_BASE, "BLE_HS_ERR_SM_US_BASE"),
+    (BLE_HS_ERR_SM_PEER_BASE, "BLE_HS_ERR_SM_PEER_BASE"),
+    (BLE_HS_ERR_HW_BASE, "BLE_HS_ERR_HW_BASE"),
+];
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn esp_error_names() {
+        let err = EspError::from(ESP_ERR_NO_MEM as esp_err_t).unwrap();
+        assert_eq!(err.name(), "ESP_ERR_NO_MEM");
+        assert_eq!(err.description(), Some("out of memory"));
+        assert_eq!(format!("{}", err), "ESP_ERR_NO_MEM (0x101): out of memory");
+        assert_eq!(format!("{:?}", err), "EspError(ESP_ERR_NO_MEM 0x101)");
+        assert!(EspError::from(ESP_OK as esp_err_t).is_none());
+        assert_eq!(EspError::check(ESP_OK as esp_err_t), Ok(()));
+
+        let unknown = EspError::from(0x7abc).unwrap();
+        assert_eq!(unknown.name(), "UNKNOWN ERROR");
+        assert_eq!(unknown.description(), None);
+        assert_eq!(format!("{}", unknown), "UNKNOWN ERROR (0x7abc)");
+    }
+
+    #[test]
+    fn esp_error_call() {
+        let err = EspError::from(ESP_ERR_NVS_NOT_FOUND as esp_err_t)
+            .unwrap()
+            .in_call("nvs_get_str(handle, key, ptr, len)");
+        assert_eq!(err.call(), Some("nvs_get_str(handle, key, ptr, len)"));
+        assert_eq!(
+            format!("{}", err),
+            "nvs_get_str(handle, key, ptr, len) failed: ESP_ERR_NVS_NOT_FOUND (0x1102): \
+             requested key or namespace doesn't exist"
+        );
+        /* The call is context, not part of the error */
+        assert_eq!(
+            err,
+            EspError::from(ESP_ERR_NVS_NOT_FOUND as esp_err_t).unwrap()
+        );
+
+        let err = esp!(ESP_ERR_NO_MEM as esp_err_t).unwrap_err();
+        assert_eq!(err.call(), Some("ESP_ERR_NO_MEM as esp_err_t"));
+    }
+
+    #[test]
+    fn ble_error_names() {
+        let err = BleError::from(BLE_HS_ENOMEM as i32).unwrap();
+        assert_eq!(err.name(), "BLE_HS_ENOMEM");
+        assert_eq!(format!("{}", err), "BLE_HS_ENOMEM (6)");
+        assert_eq!(format!("{:?}", err), "BleError(BLE_HS_ENOMEM 0x6)");
+        assert_eq!(err.hci(), None);
+        assert!(BleError::from(0).is_none());
+
+        let att = BleError::from((BLE_HS_ERR_ATT_BASE + BLE_ATT_ERR_INSUFFICIENT_AUTHEN) as i32);
+        let att = att.unwrap();
+        assert_eq!(att.name(), "BLE_HS_ERR_ATT_BASE");
+        assert_eq!(format!("{}", att), "BLE_HS_ERR_ATT_BASE + 0x05");
+
+        let unknown = BleError::from(0x7ff).unwrap();
+        assert_eq!(unknown.name(), "UNKNOWN ERROR");
+        assert_eq!(format!("{}", unknown), "UNKNOWN ERROR (2047)");
+
+        let err = ble!(BLE_HS_ETIMEOUT as i32).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "BLE_HS_ETIMEOUT as i32 failed: BLE_HS_ETIMEOUT (13)"
+        );
+    }
+
+    #[test]
+    fn hci_errors() {
+        let err = BleError::from(0x213).unwrap();
+        assert_eq!(err.hci(), Some(HciError::RemoteUserTerminated));
+        assert_eq!(
+            format!("{}", err),
+            "BLE_HS_ERR_HCI_BASE + 0x13: remote user terminated connection"
+        );
+        for code in 1..=0x43 {
+            assert_eq!(HciError::from_code(code).code(), code);
+        }
+        assert_eq!(
+            HciError::from_code(0x3e),
+            HciError::ConnectionFailedToEstablish
+        );
+        assert_eq!(HciError::from_code(0x16), HciError::LocalHostTerminated);
+        assert_eq!(HciError::from_code(0x90), HciError::Unknown(0x90));
+        assert_eq!(
+            format!("{}", HciError::from_code(0x90)),
+            "unknown HCI error (0x90)"
+        );
+        assert_eq!(
+            format!("{}", HciError::MicFailure),
+            "connection terminated due to MIC failure (0x3d)"
+        );
+    }
+}
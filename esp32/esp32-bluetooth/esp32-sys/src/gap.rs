@@ -0,0 +1,729 @@
+//! GAP events as a Rust enum
+//!
+//! NimBLE hands every GAP event to one callback as a `ble_gap_event`, a type code and a
+//! union only that code says how to read. `GapEvent::from_raw` reads it once and decodes
+//! what is in it: statuses become `BleResult`, disconnect reasons name the HCI error,
+//! connection descriptors, subscriptions and pairing properties get their own types.
+//!
+//! The application implements `GapHandler` and passes it to `Advertising::start`, which
+//! registers `gap_event_cb` for it. The callback feeds `connections::handle_event` before
+//! the handler sees the event.
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::fmt;
+use core::slice;
+
+use crate::connections::{self, Subscription};
+use crate::error::{BleError, BleResult, HciError};
+use crate::link::ConnParams;
+use crate::security::{PasskeyAction, SecState};
+use crate::*;
+
+/// A device address, `val` least significant byte first as NimBLE stores it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    /// `BLE_ADDR_PUBLIC`, `BLE_ADDR_RANDOM` or one of the RPA types
+    pub addr_type: u8,
+    pub val: [u8; 6],
+}
+
+impl Address {
+    pub fn from_raw(addr: &ble_addr_t) -> Address {
+        Address {
+            addr_type: addr.type_,
+            val: addr.val,
+        }
+    }
+}
+
+/// Most significant byte first, the way addresses are usually written
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let v = &self.val;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            v[5], v[4], v[3], v[2], v[1], v[0]
+        )
+    }
+}
+
+/// A connection as the host describes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnDesc {
+    pub conn_handle: u16,
+    /// In 1.25 ms
+    pub interval: u16,
+    pub latency: u16,
+    /// In 10 ms
+    pub supervision_timeout: u16,
+    /// `BLE_GAP_ROLE_SLAVE` or `BLE_GAP_ROLE_MASTER`
+    pub role: u8,
+    pub master_clock_accuracy: u8,
+    pub our_id_addr: Address,
+    pub peer_id_addr: Address,
+    pub our_ota_addr: Address,
+    pub peer_ota_addr: Address,
+    pub sec: SecState,
+}
+
+impl ConnDesc {
+    pub fn from_raw(desc: &ble_gap_conn_desc) -> ConnDesc {
+        ConnDesc {
+            conn_handle: desc.conn_handle,
+            interval: desc.conn_itvl,
+            latency: desc.conn_latency,
+            supervision_timeout: desc.supervision_timeout,
+            role: desc.role,
+            master_clock_accuracy: desc.master_clock_accuracy,
+            our_id_addr: Address::from_raw(&desc.our_id_addr),
+            peer_id_addr: Address::from_raw(&desc.peer_id_addr),
+            our_ota_addr: Address::from_raw(&desc.our_ota_addr),
+            peer_ota_addr: Address::from_raw(&desc.peer_ota_addr),
+            sec: SecState::from_raw(&desc.sec_state),
+        }
+    }
+}
+
+/// Why a connection went down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The controller's reason, e.g. `RemoteUserTerminated` when the central disconnected
+    Hci(HciError),
+    /// The host gave up on the link, e.g. `BLE_HS_ETIMEOUT_HCI`
+    Host(BleError),
+}
+
+impl DisconnectReason {
+    pub fn from_code(code: i32) -> DisconnectReason {
+        match BleError::from(code) {
+            Some(err) => err
+                .hci()
+                .map_or(DisconnectReason::Host(err), DisconnectReason::Hci),
+            /* The host always gives a reason */
+            None => DisconnectReason::Hci(HciError::Unspecified),
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::Hci(hci) => write!(f, "{}", hci),
+            DisconnectReason::Host(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// What changed a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeReason {
+    /// The peer wrote the client configuration descriptor
+    Write,
+    /// The connection went down, or the peer unsubscribed by the indication it never
+    /// confirmed
+    Term,
+    /// A bonded peer's subscriptions came back from the store as it encrypted again
+    Restore,
+    Unknown(u8),
+}
+
+impl SubscribeReason {
+    fn from_code(reason: u8) -> SubscribeReason {
+        match reason as u32 {
+            BLE_GAP_SUBSCRIBE_REASON_WRITE => SubscribeReason::Write,
+            BLE_GAP_SUBSCRIBE_REASON_TERM => SubscribeReason::Term,
+            BLE_GAP_SUBSCRIBE_REASON_RESTORE => SubscribeReason::Restore,
+            _ => SubscribeReason::Unknown(reason),
+        }
+    }
+}
+
+/// How far a notification or indication went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Handed to the controller, final for notifications
+    Sent,
+    /// The peer acknowledged the indication
+    Confirmed,
+    Failed(BleError),
+}
+
+impl TxStatus {
+    fn from_code(status: i32) -> TxStatus {
+        match BleError::from(status) {
+            None => TxStatus::Sent,
+            Some(_) if status == BLE_HS_EDONE as i32 => TxStatus::Confirmed,
+            Some(err) => TxStatus::Failed(err),
+        }
+    }
+}
+
+/// Properties of a bond, or of the link pairing would lead to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairingProps {
+    pub key_size: u8,
+    /// With MITM protection
+    pub authenticated: bool,
+    /// With LE Secure Connections
+    pub secure_connections: bool,
+}
+
+/// An advertising report of a discovery procedure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscReport<'a> {
+    /// One of `BLE_HCI_ADV_RPT_EVTYPE_*`
+    pub event_type: u8,
+    pub addr: Address,
+    pub rssi: i8,
+    /// The advertising or scan response payload
+    pub data: &'a [u8],
+    /// For directed advertising, `BLE_ADDR_ANY` otherwise
+    pub direct_addr: Address,
+}
+
+/// A GAP event with its fields decoded, see `GapEvent::from_raw`
+///
+/// Update requests lend the parameters the device answers with, a handler that accepts
+/// may change them in place.
+#[derive(Debug)]
+pub enum GapEvent<'a> {
+    /// A connection was established, or an attempt failed and advertising stopped
+    Connect {
+        conn_handle: u16,
+        status: BleResult<()>,
+    },
+    Disconnect {
+        reason: DisconnectReason,
+        /// The connection as it was before it went down
+        conn: ConnDesc,
+    },
+    ConnUpdate {
+        conn_handle: u16,
+        status: BleResult<()>,
+    },
+    /// The central asks for new parameters over the link layer
+    ConnUpdateReq {
+        conn_handle: u16,
+        peer: ConnParams,
+        own: &'a mut ble_gap_upd_params,
+    },
+    /// The central asks for new parameters over the L2CAP signalling channel
+    L2capUpdateReq {
+        conn_handle: u16,
+        peer: ConnParams,
+        own: &'a mut ble_gap_upd_params,
+    },
+    /// Terminating a connection failed
+    TermFailure {
+        conn_handle: u16,
+        status: BleResult<()>,
+    },
+    Disc(DiscReport<'a>),
+    /// The discovery procedure stopped, `Ok` when its duration ran out
+    DiscComplete {
+        reason: BleResult<()>,
+    },
+    /// Advertising stopped, `Ok` when a central connected
+    AdvComplete {
+        reason: BleResult<()>,
+    },
+    /// Pairing, or encryption with the keys of a bond, finished
+    EncChange {
+        conn_handle: u16,
+        status: BleResult<()>,
+    },
+    PasskeyAction {
+        conn_handle: u16,
+        action: PasskeyAction,
+    },
+    /// A notification or indication from the peer, the host frees the mbuf afterwards
+    NotifyRx {
+        conn_handle: u16,
+        attr_handle: u16,
+        indication: bool,
+        data: Vec<u8>,
+    },
+    NotifyTx {
+        conn_handle: u16,
+        attr_handle: u16,
+        indication: bool,
+        status: TxStatus,
+    },
+    Subscribe {
+        conn_handle: u16,
+        attr_handle: u16,
+        reason: SubscribeReason,
+        prev: Subscription,
+        cur: Subscription,
+    },
+    Mtu {
+        conn_handle: u16,
+        channel_id: u16,
+        mtu: u16,
+    },
+    /// The peer's resolvable private address was resolved to its identity
+    IdentityResolved {
+        conn_handle: u16,
+    },
+    /// A bonded peer pairs again
+    RepeatPairing {
+        conn_handle: u16,
+        current: PairingProps,
+        new: PairingProps,
+    },
+    PhyUpdateComplete {
+        conn_handle: u16,
+        status: BleResult<()>,
+        tx_phy: u8,
+        rx_phy: u8,
+    },
+    /// An event this crate doesn't decode
+    ///
+    /// `BLE_GAP_EVENT_EXT_DISC` is one: the host is built without `BLE_EXT_ADV`, so the
+    /// union has no `ext_disc` member to read and the host never reports the event.
+    Other(u8),
+}
+
+impl<'a> GapEvent<'a> {
+    /// Reads the union member `event.type_` selects
+    ///
+    /// `event` must be filled in the way the host fills it for its type: pointers in it
+    /// valid for `'a`, `disc.data` pointing at `disc.length_data` bytes.
+    pub unsafe fn from_raw(event: &'a mut ble_gap_event) -> GapEvent<'a> {
+        let type_ = event.type_;
+        let u = &mut event.__bindgen_anon_1;
+        match type_ as u32 {
+            BLE_GAP_EVENT_CONNECT => GapEvent::Connect {
+                conn_handle: u.connect.conn_handle,
+                status: BleError::check(u.connect.status),
+            },
+            BLE_GAP_EVENT_DISCONNECT => GapEvent::Disconnect {
+                reason: DisconnectReason::from_code(u.disconnect.reason),
+                conn: ConnDesc::from_raw(&u.disconnect.conn),
+            },
+            BLE_GAP_EVENT_CONN_UPDATE => GapEvent::ConnUpdate {
+                conn_handle: u.conn_update.conn_handle,
+                status: BleError::check(u.conn_update.status),
+            },
+            BLE_GAP_EVENT_CONN_UPDATE_REQ => GapEvent::ConnUpdateReq {
+                conn_handle: u.conn_update_req.conn_handle,
+                peer: ConnParams::from_upd_params(&*u.conn_update_req.peer_params),
+                own: &mut *u.conn_update_req.self_params,
+            },
+            BLE_GAP_EVENT_L2CAP_UPDATE_REQ => GapEvent::L2capUpdateReq {
+                conn_handle: u.conn_update_req.conn_handle,
+                peer: ConnParams::from_upd_params(&*u.conn_update_req.peer_params),
+                own: &mut *u.conn_update_req.self_params,
+            },
+            BLE_GAP_EVENT_TERM_FAILURE => GapEvent::TermFailure {
+                conn_handle: u.term_failure.conn_handle,
+                status: BleError::check(u.term_failure.status),
+            },
+            BLE_GAP_EVENT_DISC => {
+                let disc = &u.disc;
+                let data = if disc.data.is_null() {
+                    &[]
+                } else {
+                    slice::from_raw_parts(disc.data as *const u8, disc.length_data as usize)
+                };
+                GapEvent::Disc(DiscReport {
+                    event_type: disc.event_type,
+                    addr: Address::from_raw(&disc.addr),
+                    rssi: disc.rssi,
+                    data,
+                    direct_addr: Address::from_raw(&disc.direct_addr),
+                })
+            }
+            BLE_GAP_EVENT_DISC_COMPLETE => GapEvent::DiscComplete {
+                reason: BleError::check(u.disc_complete.reason),
+            },
+            BLE_GAP_EVENT_ADV_COMPLETE => GapEvent::AdvComplete {
+                reason: BleError::check(u.adv_complete.reason),
+            },
+            BLE_GAP_EVENT_ENC_CHANGE => GapEvent::EncChange {
+                conn_handle: u.enc_change.conn_handle,
+                status: BleError::check(u.enc_change.status),
+            },
+            BLE_GAP_EVENT_PASSKEY_ACTION => GapEvent::PasskeyAction {
+                conn_handle: u.passkey.conn_handle,
+                action: PasskeyAction::from_params(&u.passkey.params),
+            },
+            BLE_GAP_EVENT_NOTIFY_RX => {
+                let rx = &u.notify_rx;
+                GapEvent::NotifyRx {
+                    conn_handle: rx.conn_handle,
+                    attr_handle: rx.attr_handle,
+                    indication: rx.indication() != 0,
+                    data: mbuf_to_vec(rx.om),
+                }
+            }
+            BLE_GAP_EVENT_NOTIFY_TX => {
+                let tx = &u.notify_tx;
+                GapEvent::NotifyTx {
+                    conn_handle: tx.conn_handle,
+                    attr_handle: tx.attr_handle,
+                    indication: tx.indication() != 0,
+                    status: TxStatus::from_code(tx.status),
+                }
+            }
+            BLE_GAP_EVENT_SUBSCRIBE => {
+                let subscribe = &u.subscribe;
+                GapEvent::Subscribe {
+                    conn_handle: subscribe.conn_handle,
+                    attr_handle: subscribe.attr_handle,
+                    reason: SubscribeReason::from_code(subscribe.reason),
+                    prev: Subscription {
+                        notify: subscribe.prev_notify() != 0,
+                        indicate: subscribe.prev_indicate() != 0,
+                    },
+                    cur: Subscription {
+                        notify: subscribe.cur_notify() != 0,
+                        indicate: subscribe.cur_indicate() != 0,
+                    },
+                }
+            }
+            BLE_GAP_EVENT_MTU => GapEvent::Mtu {
+                conn_handle: u.mtu.conn_handle,
+                channel_id: u.mtu.channel_id,
+                mtu: u.mtu.value,
+            },
+            BLE_GAP_EVENT_IDENTITY_RESOLVED => GapEvent::IdentityResolved {
+                conn_handle: u.identity_resolved.conn_handle,
+            },
+            BLE_GAP_EVENT_REPEAT_PAIRING => {
+                let repeat = &u.repeat_pairing;
+                GapEvent::RepeatPairing {
+                    conn_handle: repeat.conn_handle,
+                    current: PairingProps {
+                        key_size: repeat.cur_key_size,
+                        authenticated: repeat.cur_authenticated() != 0,
+                        secure_connections: repeat.cur_sc() != 0,
+                    },
+                    new: PairingProps {
+                        key_size: repeat.new_key_size,
+                        authenticated: repeat.new_authenticated() != 0,
+                        secure_connections: repeat.new_sc() != 0,
+                    },
+                }
+            }
+            BLE_GAP_EVENT_PHY_UPDATE_COMPLETE => GapEvent::PhyUpdateComplete {
+                conn_handle: u.phy_updated.conn_handle,
+                status: BleError::check(u.phy_updated.status),
+                tx_phy: u.phy_updated.tx_phy,
+                rx_phy: u.phy_updated.rx_phy,
+            },
+            _ => GapEvent::Other(type_),
+        }
+    }
+}
+
+unsafe fn mbuf_to_vec(om: *mut os_mbuf) -> Vec<u8> {
+    if om.is_null() {
+        return Vec::new();
+    }
+    let mut data = Vec::with_capacity(OS_MBUF_PKTLEN(om) as usize);
+    let mut len = 0;
+    ble_hs_mbuf_to_flat(
+        om,
+        data.as_mut_ptr() as *mut c_void,
+        data.capacity() as u16,
+        &mut len,
+    );
+    data.set_len(len as usize);
+    data
+}
+
+/// Handles the GAP events of an advertisement and the connections it leads to
+///
+/// Runs on the NimBLE host task. The return value is the callback's: 0 for most events,
+/// for `ConnUpdateReq` and `L2capUpdateReq` 0 accepts and an HCI error code refuses, for
+/// `RepeatPairing` `BLE_GAP_REPEAT_PAIRING_RETRY` once the old bond is deleted or
+/// `BLE_GAP_REPEAT_PAIRING_IGNORE`.
+pub trait GapHandler: Sync {
+    fn event(&self, event: GapEvent) -> i32;
+}
+
+/// Dispatches a NimBLE GAP callback to the `GapHandler` `arg` points to
+///
+/// `arg` must be a `*const H`, `Advertising::start` sets it up that way.
+#[doc(hidden)]
+pub unsafe extern "C" fn gap_event_cb<H: GapHandler>(
+    event: *mut ble_gap_event,
+    arg: *mut c_void,
+) -> i32 {
+    connections::handle_event(&*event);
+    let handler = &*(arg as *const H);
+    handler.event(GapEvent::from_raw(&mut *event))
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock::nimble::new_event;
+    use alloc::format;
+
+    #[test]
+    fn connect() {
+        let mut event = new_event(BLE_GAP_EVENT_CONNECT);
+        event.__bindgen_anon_1.connect.conn_handle = 3;
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Connect {
+                conn_handle: 3,
+                status: Ok(()),
+            } => {}
+            other => panic!("{:?}", other),
+        }
+
+        event.__bindgen_anon_1.connect.conn_handle = BLE_HS_CONN_HANDLE_NONE as u16;
+        event.__bindgen_anon_1.connect.status = BLE_HS_ETIMEOUT as i32;
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Connect {
+                status: Err(err), ..
+            } => assert_eq!(err.code(), BLE_HS_ETIMEOUT as i32),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut event = new_event(BLE_GAP_EVENT_DISCONNECT);
+        unsafe {
+            let disconnect = &mut event.__bindgen_anon_1.disconnect;
+            disconnect.reason = 0x213;
+            disconnect.conn.conn_handle = 4;
+            disconnect.conn.conn_itvl = 24;
+            disconnect.conn.peer_id_addr.type_ = BLE_ADDR_RANDOM as u8;
+            disconnect.conn.peer_id_addr.val = [1, 2, 3, 4, 5, 0xc6];
+            disconnect.conn.sec_state.set_encrypted(1);
+            disconnect.conn.sec_state.set_key_size(16);
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Disconnect { reason, conn } => {
+                assert_eq!(
+                    reason,
+                    DisconnectReason::Hci(HciError::RemoteUserTerminated)
+                );
+                assert_eq!(conn.conn_handle, 4);
+                assert_eq!(conn.interval, 24);
+                assert_eq!(conn.peer_id_addr.addr_type, BLE_ADDR_RANDOM as u8);
+                assert_eq!(format!("{}", conn.peer_id_addr), "c6:05:04:03:02:01");
+                assert!(conn.sec.encrypted && !conn.sec.bonded);
+                assert_eq!(conn.sec.key_size, 16);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        /* The host's own reasons aren't HCI errors */
+        event.__bindgen_anon_1.disconnect.reason = BLE_HS_ETIMEOUT_HCI as i32;
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Disconnect {
+                reason: DisconnectReason::Host(err),
+                ..
+            } => assert_eq!(err.code(), BLE_HS_ETIMEOUT_HCI as i32),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut event = new_event(BLE_GAP_EVENT_SUBSCRIBE);
+        unsafe {
+            let subscribe = &mut event.__bindgen_anon_1.subscribe;
+            subscribe.conn_handle = 3;
+            subscribe.attr_handle = 17;
+            subscribe.reason = BLE_GAP_SUBSCRIBE_REASON_TERM as u8;
+            subscribe.set_prev_indicate(1);
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Subscribe {
+                conn_handle: 3,
+                attr_handle: 17,
+                reason,
+                prev,
+                cur,
+            } => {
+                assert_eq!(reason, SubscribeReason::Term);
+                assert_eq!(
+                    prev,
+                    Subscription {
+                        notify: false,
+                        indicate: true
+                    }
+                );
+                assert!(!cur.is_active());
+            }
+            other => panic!("{:?}", other),
+        }
+
+        unsafe {
+            let subscribe = &mut event.__bindgen_anon_1.subscribe;
+            subscribe.reason = BLE_GAP_SUBSCRIBE_REASON_RESTORE as u8;
+            subscribe.set_prev_indicate(0);
+            subscribe.set_cur_notify(1);
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Subscribe { reason, cur, .. } => {
+                assert_eq!(reason, SubscribeReason::Restore);
+                assert!(cur.notify && !cur.indicate);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn notify_tx() {
+        let mut event = new_event(BLE_GAP_EVENT_NOTIFY_TX);
+        unsafe {
+            let tx = &mut event.__bindgen_anon_1.notify_tx;
+            tx.conn_handle = 2;
+            tx.attr_handle = 9;
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::NotifyTx {
+                conn_handle: 2,
+                attr_handle: 9,
+                indication: false,
+                status: TxStatus::Sent,
+            } => {}
+            other => panic!("{:?}", other),
+        }
+
+        unsafe {
+            let tx = &mut event.__bindgen_anon_1.notify_tx;
+            tx.set_indication(1);
+            tx.status = BLE_HS_EDONE as i32;
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::NotifyTx {
+                indication: true,
+                status: TxStatus::Confirmed,
+                ..
+            } => {}
+            other => panic!("{:?}", other),
+        }
+
+        event.__bindgen_anon_1.notify_tx.status = BLE_HS_ETIMEOUT as i32;
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::NotifyTx {
+                status: TxStatus::Failed(err),
+                ..
+            } => assert_eq!(err.code(), BLE_HS_ETIMEOUT as i32),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn conn_update_req() {
+        let mut peer: ble_gap_upd_params = unsafe { core::mem::zeroed() };
+        peer.itvl_min = 6;
+        peer.itvl_max = 12;
+        peer.latency = 4;
+        peer.supervision_timeout = 500;
+        let mut own = peer;
+        let mut event = new_event(BLE_GAP_EVENT_CONN_UPDATE_REQ);
+        unsafe {
+            let request = &mut event.__bindgen_anon_1.conn_update_req;
+            request.conn_handle = 5;
+            request.peer_params = &peer;
+            request.self_params = &mut own;
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::ConnUpdateReq {
+                conn_handle: 5,
+                peer,
+                own,
+            } => {
+                assert_eq!(peer, ConnParams::new(6, 12, 4, 500));
+                /* The handler answers through `own` */
+                own.latency = 0;
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(own.latency, 0);
+
+        event.type_ = BLE_GAP_EVENT_L2CAP_UPDATE_REQ as u8;
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::L2capUpdateReq { conn_handle: 5, .. } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn passkey_action() {
+        let mut event = new_event(BLE_GAP_EVENT_PASSKEY_ACTION);
+        unsafe {
+            let passkey = &mut event.__bindgen_anon_1.passkey;
+            passkey.conn_handle = 1;
+            passkey.params.action = BLE_SM_IOACT_DISP as u8;
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::PasskeyAction {
+                conn_handle: 1,
+                action: PasskeyAction::Display,
+            } => {}
+            other => panic!("{:?}", other),
+        }
+
+        unsafe {
+            let params = &mut event.__bindgen_anon_1.passkey.params;
+            params.action = BLE_SM_IOACT_NUMCMP as u8;
+            params.numcmp = 123_456;
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::PasskeyAction {
+                action: PasskeyAction::NumericComparison(123_456),
+                ..
+            } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn repeat_pairing() {
+        let mut event = new_event(BLE_GAP_EVENT_REPEAT_PAIRING);
+        unsafe {
+            let repeat = &mut event.__bindgen_anon_1.repeat_pairing;
+            repeat.conn_handle = 6;
+            repeat.cur_key_size = 16;
+            repeat.set_cur_authenticated(1);
+            repeat.set_cur_sc(1);
+            repeat.new_key_size = 7;
+        }
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::RepeatPairing {
+                conn_handle: 6,
+                current,
+                new,
+            } => {
+                assert_eq!(
+                    current,
+                    PairingProps {
+                        key_size: 16,
+                        authenticated: true,
+                        secure_connections: true
+                    }
+                );
+                assert_eq!(
+                    new,
+                    PairingProps {
+                        key_size: 7,
+                        authenticated: false,
+                        secure_connections: false
+                    }
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn ext_disc_not_decoded() {
+        let mut event = new_event(BLE_GAP_EVENT_EXT_DISC);
+        match unsafe { GapEvent::from_raw(&mut event) } {
+            GapEvent::Other(type_) => assert_eq!(type_ as u32, BLE_GAP_EVENT_EXT_DISC),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,888 @@
+//! GATT server tables declared as statics
+//!
+//! `gatt_services!` lays out the terminated `ble_gatt_svc_def`, `ble_gatt_chr_def` and
+//! `ble_gatt_dsc_def` arrays NimBLE registers as constant data, so nothing is allocated or
+//! leaked. Each characteristic and descriptor is served by its own `Access` implementation,
+//! NimBLE calls one trampoline that finds it through the definition's `arg`.
+//!
+//! Written values are checked and converted with `Request::decode`, any type implementing
+//! `Decode` states how long its encoding may be and which values it refuses.
+//!
+//! ```ignore
+//! static HRM_HANDLE: Handle = Handle::new();
+//!
+//! gatt_services! {
+//!     pub static SERVICES = [
+//!         primary(Uuid::from_u16(0x180D)) {
+//!             characteristic(Uuid::from_u16(0x2A37), HEART_RATE_MEASUREMENT) {
+//!                 flags: NOTIFY,
+//!                 handle: HRM_HANDLE,
+//!             }
+//!             characteristic(Uuid::from_u16(0x2A38), ReadOnly(&[0x01])) {
+//!                 flags: READ,
+//!                 descriptor(USER_DESCRIPTION_UUID, UserDescription("Body sensor location")) {
+//!                     flags: DSC_READ,
+//!                 }
+//!             }
+//!         }
+//!     ];
+//! }
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ffi::c_void;
+use core::ptr;
+use core::slice;
+
+use crate::error::BleResult;
+use crate::uuid::Uuid;
+use crate::*;
+
+/// Characteristic property and permission flags, combine with `|`
+pub const READ: u16 = BLE_GATT_CHR_F_READ as u16;
+pub const WRITE: u16 = BLE_GATT_CHR_F_WRITE as u16;
+pub const WRITE_NO_RSP: u16 = BLE_GATT_CHR_F_WRITE_NO_RSP as u16;
+pub const NOTIFY: u16 = BLE_GATT_CHR_F_NOTIFY as u16;
+pub const INDICATE: u16 = BLE_GATT_CHR_F_INDICATE as u16;
+pub const READ_ENC: u16 = BLE_GATT_CHR_F_READ_ENC as u16;
+pub const READ_AUTHEN: u16 = BLE_GATT_CHR_F_READ_AUTHEN as u16;
+pub const WRITE_ENC: u16 = BLE_GATT_CHR_F_WRITE_ENC as u16;
+pub const WRITE_AUTHEN: u16 = BLE_GATT_CHR_F_WRITE_AUTHEN as u16;
+
+/// Descriptor permission flags
+pub const DSC_READ: u8 = BLE_ATT_F_READ as u8;
+pub const DSC_WRITE: u8 = BLE_ATT_F_WRITE as u8;
+pub const DSC_READ_ENC: u8 = BLE_ATT_F_READ_ENC as u8;
+pub const DSC_READ_AUTHEN: u8 = BLE_ATT_F_READ_AUTHEN as u8;
+pub const DSC_WRITE_ENC: u8 = BLE_ATT_F_WRITE_ENC as u8;
+pub const DSC_WRITE_AUTHEN: u8 = BLE_ATT_F_WRITE_AUTHEN as u8;
+
+/// ATT error codes an access handler can answer with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttError {
+    InvalidHandle,
+    ReadNotPermitted,
+    WriteNotPermitted,
+    InsufficientAuthen,
+    RequestNotSupported,
+    InvalidOffset,
+    InsufficientAuthor,
+    InvalidAttrValueLen,
+    Unlikely,
+    InsufficientEnc,
+    InsufficientRes,
+    /// From the Core Specification Supplement, newer than the NimBLE headers
+    ValueNotAllowed,
+    /// 0x80 to 0x9F, defined by the service
+    Application(u8),
+}
+
+impl AttError {
+    pub fn code(self) -> u8 {
+        match self {
+            AttError::InvalidHandle => BLE_ATT_ERR_INVALID_HANDLE as u8,
+            AttError::ReadNotPermitted => BLE_ATT_ERR_READ_NOT_PERMITTED as u8,
+            AttError::WriteNotPermitted => BLE_ATT_ERR_WRITE_NOT_PERMITTED as u8,
+            AttError::InsufficientAuthen => BLE_ATT_ERR_INSUFFICIENT_AUTHEN as u8,
+            AttError::RequestNotSupported => BLE_ATT_ERR_REQ_NOT_SUPPORTED as u8,
+            AttError::InvalidOffset => BLE_ATT_ERR_INVALID_OFFSET as u8,
+            AttError::InsufficientAuthor => BLE_ATT_ERR_INSUFFICIENT_AUTHOR as u8,
+            AttError::InvalidAttrValueLen => BLE_ATT_ERR_INVALID_ATTR_VALUE_LEN as u8,
+            AttError::Unlikely => BLE_ATT_ERR_UNLIKELY as u8,
+            AttError::InsufficientEnc => BLE_ATT_ERR_INSUFFICIENT_ENC as u8,
+            AttError::InsufficientRes => BLE_ATT_ERR_INSUFFICIENT_RES as u8,
+            AttError::ValueNotAllowed => 0x13,
+            AttError::Application(code) => code,
+        }
+    }
+}
+
+/// Longest attribute value ATT allows
+pub const MAX_VALUE_LEN: usize = BLE_ATT_ATTR_MAX_LEN as usize;
+
+/// Serves reads and writes of one characteristic value or descriptor
+///
+/// Runs on the NimBLE host task. Only the operations allowed by the definition's flags
+/// ever reach the handler, the defaults refuse them anyway.
+pub trait Access: Sync {
+    fn read(&self, _request: &mut Request) -> Result<(), AttError> {
+        Err(AttError::ReadNotPermitted)
+    }
+
+    fn write(&self, _request: &mut Request) -> Result<(), AttError> {
+        Err(AttError::WriteNotPermitted)
+    }
+}
+
+/// A value that never changes, e.g. a model number
+pub struct ReadOnly(pub &'static [u8]);
+
+impl Access for ReadOnly {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(self.0)
+    }
+}
+
+/// Characteristic User Description, a name generic explorers show instead of the UUID
+pub const USER_DESCRIPTION_UUID: Uuid = Uuid::from_u16(0x2901);
+/// Characteristic Presentation Format
+pub const PRESENTATION_FORMAT_UUID: Uuid = Uuid::from_u16(0x2904);
+/// Valid Range, the lowest and highest value a write may carry
+pub const VALID_RANGE_UUID: Uuid = Uuid::from_u16(0x2906);
+
+/// Text of a Characteristic User Description, sent without a terminator
+pub struct UserDescription(pub &'static str);
+
+impl Access for UserDescription {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(self.0.as_bytes())
+    }
+}
+
+/// Formats from the Characteristic Presentation Format assigned numbers
+pub const FORMAT_BOOLEAN: u8 = 0x01;
+pub const FORMAT_UINT8: u8 = 0x04;
+pub const FORMAT_UINT16: u8 = 0x06;
+pub const FORMAT_UINT32: u8 = 0x08;
+pub const FORMAT_SINT8: u8 = 0x0c;
+pub const FORMAT_SINT16: u8 = 0x0e;
+pub const FORMAT_SINT32: u8 = 0x10;
+pub const FORMAT_FLOAT32: u8 = 0x14;
+pub const FORMAT_UTF8: u8 = 0x19;
+/// Opaque structure, for values made of several fields
+pub const FORMAT_STRUCT: u8 = 0x1b;
+
+/// Units from the GATT unit assigned numbers
+pub const UNIT_UNITLESS: u16 = 0x2700;
+pub const UNIT_METRE: u16 = 0x2701;
+pub const UNIT_SECOND: u16 = 0x2703;
+pub const UNIT_HERTZ: u16 = 0x2722;
+pub const UNIT_VOLT: u16 = 0x2728;
+pub const UNIT_PERCENT: u16 = 0x27ad;
+pub const UNIT_BEATS_PER_MINUTE: u16 = 0x27a7;
+
+/// Characteristic Presentation Format, how to show the value: its format, the power of ten
+/// it is scaled by and its unit
+///
+/// ```ignore
+/// const MILLIVOLTS: PresentationFormat =
+///     PresentationFormat::new(FORMAT_UINT16, UNIT_VOLT).exponent(-3);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresentationFormat {
+    pub format: u8,
+    pub exponent: i8,
+    pub unit: u16,
+    pub namespace: u8,
+    pub description: u16,
+}
+
+impl PresentationFormat {
+    /// Unscaled, with an unknown description in the Bluetooth SIG namespace
+    pub const fn new(format: u8, unit: u16) -> PresentationFormat {
+        PresentationFormat {
+            format,
+            exponent: 0,
+            unit,
+            namespace: 1,
+            description: 0,
+        }
+    }
+
+    /// The shown value is the characteristic value times 10^`exponent`
+    pub const fn exponent(mut self, exponent: i8) -> PresentationFormat {
+        self.exponent = exponent;
+        self
+    }
+
+    /// Tells apart several characteristics of the same type, e.g. `0x0001` for "first"
+    pub const fn description(mut self, description: u16) -> PresentationFormat {
+        self.description = description;
+        self
+    }
+
+    pub fn to_bytes(self) -> [u8; 7] {
+        let unit = self.unit.to_le_bytes();
+        let description = self.description.to_le_bytes();
+        [
+            self.format,
+            self.exponent as u8,
+            unit[0],
+            unit[1],
+            self.namespace,
+            description[0],
+            description[1],
+        ]
+    }
+}
+
+impl Access for PresentationFormat {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(&self.to_bytes())
+    }
+}
+
+/// Valid Range, lower then upper bound encoded like the characteristic value
+pub struct ValidRange(pub &'static [u8], pub &'static [u8]);
+
+impl Access for ValidRange {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(self.0)?;
+        request.append(self.1)
+    }
+}
+
+/// One read or write of an attribute, wraps the `ble_gatt_access_ctxt` NimBLE passes in
+pub struct Request<'a> {
+    conn_handle: u16,
+    attr_handle: u16,
+    ctxt: &'a mut ble_gatt_access_ctxt,
+}
+
+impl<'a> Request<'a> {
+    /// Wraps a context the way the access trampoline does, e.g. to call a handler directly
+    ///
+    /// `ctxt.om` must point to a valid mbuf.
+    pub unsafe fn new(
+        conn_handle: u16,
+        attr_handle: u16,
+        ctxt: &'a mut ble_gatt_access_ctxt,
+    ) -> Request<'a> {
+        Request {
+            conn_handle,
+            attr_handle,
+            ctxt,
+        }
+    }
+
+    pub fn conn_handle(&self) -> u16 {
+        self.conn_handle
+    }
+
+    pub fn attr_handle(&self) -> u16 {
+        self.attr_handle
+    }
+
+    /// Whether a descriptor rather than a characteristic value is accessed
+    pub fn is_descriptor(&self) -> bool {
+        let op = self.ctxt.op as u32;
+        op == BLE_GATT_ACCESS_OP_READ_DSC || op == BLE_GATT_ACCESS_OP_WRITE_DSC
+    }
+
+    /// Adds bytes to the value sent back for a read
+    pub fn append(&mut self, bytes: &[u8]) -> Result<(), AttError> {
+        let rc = unsafe {
+            os_mbuf_append(
+                self.ctxt.om,
+                bytes.as_ptr() as *const c_void,
+                bytes.len() as u16,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(AttError::InsufficientRes)
+        }
+    }
+
+    /// Length of the written value
+    pub fn len(&self) -> usize {
+        unsafe { OS_MBUF_PKTLEN(self.ctxt.om) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Flattens the written value into `buf`, failing with `InvalidAttrValueLen` unless it
+    /// is at least `min_len` long and fits
+    pub fn value<'b>(&self, min_len: usize, buf: &'b mut [u8]) -> Result<&'b [u8], AttError> {
+        let len = self.len();
+        if len < min_len || len > buf.len() {
+            return Err(AttError::InvalidAttrValueLen);
+        }
+        let mut len = 0;
+        let rc = unsafe {
+            ble_hs_mbuf_to_flat(
+                self.ctxt.om,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u16,
+                &mut len,
+            )
+        };
+        if rc == 0 {
+            Ok(&buf[..len as usize])
+        } else {
+            Err(AttError::Unlikely)
+        }
+    }
+
+    /// Decodes the written value, checking its length against `T::MIN_LEN..=T::MAX_LEN`
+    pub fn decode<T: Decode>(&self) -> Result<T, AttError> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let value = self.value(T::MIN_LEN, &mut buf[..T::MAX_LEN])?;
+        T::decode(value)
+    }
+}
+
+/// A value that can be written to an attribute, in its wire format
+pub trait Decode: Sized {
+    /// Shortest and longest encoding, writes outside fail with `InvalidAttrValueLen`
+    const MIN_LEN: usize;
+    const MAX_LEN: usize;
+
+    /// Only called with lengths in range, should fail with `ValueNotAllowed` for values
+    /// the application can't take
+    fn decode(bytes: &[u8]) -> Result<Self, AttError>;
+}
+
+macro_rules! decode_le {
+    ($($ty:ty),*) => {$(
+        impl Decode for $ty {
+            const MIN_LEN: usize = core::mem::size_of::<$ty>();
+            const MAX_LEN: usize = core::mem::size_of::<$ty>();
+
+            fn decode(bytes: &[u8]) -> Result<$ty, AttError> {
+                let mut le = [0; core::mem::size_of::<$ty>()];
+                le.copy_from_slice(bytes);
+                Ok(<$ty>::from_le_bytes(le))
+            }
+        }
+    )*};
+}
+
+decode_le!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+/// A single byte that must be 0 or 1
+impl Decode for bool {
+    const MIN_LEN: usize = 1;
+    const MAX_LEN: usize = 1;
+
+    fn decode(bytes: &[u8]) -> Result<bool, AttError> {
+        match bytes[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(AttError::ValueNotAllowed),
+        }
+    }
+}
+
+/// UTF-8 text without a terminator
+impl Decode for String {
+    const MIN_LEN: usize = 0;
+    const MAX_LEN: usize = MAX_VALUE_LEN;
+
+    fn decode(bytes: &[u8]) -> Result<String, AttError> {
+        core::str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| AttError::ValueNotAllowed)
+    }
+}
+
+impl Decode for Vec<u8> {
+    const MIN_LEN: usize = 0;
+    const MAX_LEN: usize = MAX_VALUE_LEN;
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>, AttError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Dispatches a NimBLE access callback to the `Access` implementation `arg` points to
+///
+/// `arg` must be a `*const &'static dyn Access`, `gatt_services!` sets it up that way.
+#[doc(hidden)]
+pub unsafe extern "C" fn access_cb(
+    conn_handle: u16,
+    attr_handle: u16,
+    ctxt: *mut ble_gatt_access_ctxt,
+    arg: *mut c_void,
+) -> i32 {
+    let handler = *(arg as *const &'static dyn Access);
+    let mut request = Request::new(conn_handle, attr_handle, &mut *ctxt);
+    let result = match (*ctxt).op as u32 {
+        BLE_GATT_ACCESS_OP_READ_CHR | BLE_GATT_ACCESS_OP_READ_DSC => handler.read(&mut request),
+        BLE_GATT_ACCESS_OP_WRITE_CHR | BLE_GATT_ACCESS_OP_WRITE_DSC => handler.write(&mut request),
+        _ => Err(AttError::Unlikely),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => err.code() as i32,
+    }
+}
+
+/// Where NimBLE stores the value handle of a characteristic when it is registered
+pub struct Handle(UnsafeCell<u16>);
+
+unsafe impl Sync for Handle {}
+
+impl Handle {
+    pub const fn new() -> Handle {
+        Handle(UnsafeCell::new(0))
+    }
+
+    /// The value handle, 0 until the GATT server has started
+    pub fn get(&self) -> u16 {
+        // Written once by the host task while registering, before any connection exists
+        unsafe { ptr::read_volatile(self.0.get()) }
+    }
+
+    #[doc(hidden)]
+    pub const fn as_ptr(&self) -> *mut u16 {
+        self.0.get()
+    }
+}
+
+impl Default for Handle {
+    fn default() -> Handle {
+        Handle::new()
+    }
+}
+
+/// A terminated service table built by `gatt_services!`
+pub struct Services {
+    #[doc(hidden)]
+    pub defs: *const ble_gatt_svc_def,
+}
+
+// The tables are never written after the static is initialized
+unsafe impl Sync for Services {}
+
+impl Services {
+    pub fn as_ptr(&self) -> *const ble_gatt_svc_def {
+        self.defs
+    }
+
+    /// Service definitions without the terminator
+    pub fn defs(&self) -> &'static [ble_gatt_svc_def] {
+        unsafe {
+            let mut len = 0;
+            while (*self.defs.add(len)).type_ != BLE_GATT_SVC_TYPE_END as u8 {
+                len += 1;
+            }
+            slice::from_raw_parts(self.defs, len)
+        }
+    }
+
+    /// Adds the services to the GATT server, which assigns their handles once it starts
+    ///
+    /// The GAP and GATT services must be initialized first.
+    pub fn register(&'static self) -> BleResult<()> {
+        unsafe {
+            ble!(ble_gatts_count_cfg(self.defs))?;
+            ble!(ble_gatts_add_svcs(self.defs))
+        }
+    }
+}
+
+/// Characteristic definitions of a service without the terminator
+pub fn characteristics(svc: &ble_gatt_svc_def) -> &[ble_gatt_chr_def] {
+    if svc.characteristics.is_null() {
+        return &[];
+    }
+    unsafe {
+        let mut len = 0;
+        while !(*svc.characteristics.add(len)).uuid.is_null() {
+            len += 1;
+        }
+        slice::from_raw_parts(svc.characteristics, len)
+    }
+}
+
+/// Descriptor definitions of a characteristic without the terminator
+pub fn descriptors(chr: &ble_gatt_chr_def) -> &[ble_gatt_dsc_def] {
+    if chr.descriptors.is_null() {
+        return &[];
+    }
+    unsafe {
+        let mut len = 0;
+        while !(*chr.descriptors.add(len)).uuid.is_null() {
+            len += 1;
+        }
+        slice::from_raw_parts(chr.descriptors, len)
+    }
+}
+
+#[doc(hidden)]
+pub const SVC_END: ble_gatt_svc_def = ble_gatt_svc_def {
+    type_: BLE_GATT_SVC_TYPE_END as u8,
+    uuid: ptr::null(),
+    includes: ptr::null_mut(),
+    characteristics: ptr::null(),
+};
+
+#[doc(hidden)]
+pub const CHR_END: ble_gatt_chr_def = ble_gatt_chr_def {
+    uuid: ptr::null(),
+    access_cb: None,
+    arg: ptr::null_mut(),
+    descriptors: ptr::null_mut(),
+    flags: 0,
+    min_key_size: 0,
+    val_handle: ptr::null_mut(),
+};
+
+#[doc(hidden)]
+pub const DSC_END: ble_gatt_dsc_def = ble_gatt_dsc_def {
+    uuid: ptr::null(),
+    att_flags: 0,
+    min_key_size: 0,
+    access_cb: None,
+    arg: ptr::null_mut(),
+};
+
+/// Declares a static GATT service table, see the module docs for the syntax
+///
+/// Services are `primary` or `secondary`. A characteristic takes its UUID and an
+/// expression implementing `Access`, then `flags`, an optional `handle` slot, an optional
+/// `min_key_size` and any number of descriptors, each with their own UUID, handler, flags
+/// and optional `min_key_size`. UUIDs are `Uuid` values.
+#[macro_export]
+macro_rules! gatt_services {
+    (
+        $(#[$attr:meta])*
+        $vis:vis static $name:ident = [
+            $( $kind:ident ( $uuid:expr ) { $($chrs:tt)* } )*
+        ];
+    ) => {
+        $(#[$attr])*
+        $vis static $name: $crate::gatt::Services = $crate::gatt::Services {
+            defs: &[
+                $( $crate::gatt_services!(@svc $kind ($uuid) $($chrs)*), )*
+                $crate::gatt::SVC_END,
+            ] as *const _ as *const $crate::ble_gatt_svc_def,
+        };
+    };
+
+    (@svc primary ($uuid:expr) $($chrs:tt)*) => {
+        $crate::gatt_services!(@svc_def $crate::BLE_GATT_SVC_TYPE_PRIMARY, $uuid, $($chrs)*)
+    };
+    (@svc secondary ($uuid:expr) $($chrs:tt)*) => {
+        $crate::gatt_services!(@svc_def $crate::BLE_GATT_SVC_TYPE_SECONDARY, $uuid, $($chrs)*)
+    };
+    (@svc_def $type:expr, $uuid:expr,
+        $( characteristic ( $chr_uuid:expr, $handler:expr ) { $($chr:tt)* } )*
+    ) => {
+        $crate::ble_gatt_svc_def {
+            type_: $type as u8,
+            uuid: $crate::gatt_services!(@uuid $uuid),
+            includes: 0 as *mut _,
+            characteristics: &[
+                $( $crate::gatt_services!(@chr ($chr_uuid, $handler) $($chr)*), )*
+                $crate::gatt::CHR_END,
+            ] as *const _ as *const $crate::ble_gatt_chr_def,
+        }
+    };
+
+    (@chr ($uuid:expr, $handler:expr)
+        flags: $flags:expr,
+        $( handle: $handle:expr, )?
+        $( min_key_size: $min_key_size:expr, )?
+        $( descriptor ( $dsc_uuid:expr, $dsc_handler:expr ) { $($dsc:tt)* } )*
+    ) => {
+        $crate::ble_gatt_chr_def {
+            uuid: $crate::gatt_services!(@uuid $uuid),
+            access_cb: Some($crate::gatt::access_cb),
+            arg: $crate::gatt_services!(@arg $handler),
+            descriptors: $crate::gatt_services!(@dscs
+                $( ($dsc_uuid, $dsc_handler) { $($dsc)* } )*
+            ),
+            flags: $flags,
+            min_key_size: $crate::gatt_services!(@or 0, $($min_key_size)?),
+            val_handle: $crate::gatt_services!(@handle $($handle)?),
+        }
+    };
+
+    (@dscs) => { 0 as *mut _ };
+    (@dscs $( ($uuid:expr, $handler:expr) {
+        flags: $flags:expr,
+        $( min_key_size: $min_key_size:expr, )?
+    } )+ ) => {
+        &[
+            $( $crate::ble_gatt_dsc_def {
+                uuid: $crate::gatt_services!(@uuid $uuid),
+                att_flags: $flags,
+                min_key_size: $crate::gatt_services!(@or 0, $($min_key_size)?),
+                access_cb: Some($crate::gatt::access_cb),
+                arg: $crate::gatt_services!(@arg $handler),
+            }, )+
+            $crate::gatt::DSC_END,
+        ] as *const _ as *mut $crate::ble_gatt_dsc_def
+    };
+
+    (@uuid $uuid:expr) => {
+        &$uuid as *const $crate::uuid::Uuid as *const $crate::ble_uuid_t
+    };
+    (@arg $handler:expr) => {
+        &(&$handler as &'static dyn $crate::gatt::Access)
+            as *const &'static dyn $crate::gatt::Access as *mut _
+    };
+    (@handle) => { 0 as *mut _ };
+    (@handle $handle:expr) => { $handle.as_ptr() };
+    (@or $default:expr,) => { $default };
+    (@or $default:expr, $value:expr) => { $value };
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use alloc::vec;
+
+    struct Setting(u8);
+
+    impl Access for Setting {
+        fn write(&self, request: &mut Request) -> Result<(), AttError> {
+            let value: u8 = request.decode()?;
+            if value == self.0 {
+                Ok(())
+            } else {
+                Err(AttError::ValueNotAllowed)
+            }
+        }
+    }
+
+    /// A 1 to 3 byte counter that has to be odd
+    #[derive(Debug, PartialEq)]
+    struct Odd(u32);
+
+    impl Decode for Odd {
+        const MIN_LEN: usize = 1;
+        const MAX_LEN: usize = 3;
+
+        fn decode(bytes: &[u8]) -> Result<Odd, AttError> {
+            let mut le = [0; 4];
+            le[..bytes.len()].copy_from_slice(bytes);
+            let value = u32::from_le_bytes(le);
+            if value % 2 == 1 {
+                Ok(Odd(value))
+            } else {
+                Err(AttError::ValueNotAllowed)
+            }
+        }
+    }
+
+    static SETTING: Setting = Setting(7);
+    static SETTING_NAME: UserDescription = UserDescription("Setting");
+    static SETTING_HANDLE: Handle = Handle::new();
+
+    gatt_services! {
+        static SERVICES = [
+            primary(Uuid::from_u16(0x1234)) {
+                characteristic(Uuid::from_u16(0x1235), SETTING) {
+                    flags: WRITE | NOTIFY,
+                    handle: SETTING_HANDLE,
+                    min_key_size: 16,
+                    descriptor(USER_DESCRIPTION_UUID, SETTING_NAME) {
+                        flags: DSC_READ,
+                    }
+                    descriptor(VALID_RANGE_UUID, ReadOnly(&[7, 7])) {
+                        flags: DSC_READ | DSC_READ_ENC,
+                        min_key_size: 7,
+                    }
+                }
+                characteristic(Uuid::from_u16(0x1236), ReadOnly(b"x")) {
+                    flags: READ,
+                }
+                characteristic(Uuid::from_u16(0x1237), Setting(3)) {
+                    flags: WRITE_NO_RSP,
+                }
+            }
+            secondary(Uuid::from_u16(0x5678)) {}
+        ];
+    }
+
+    fn access(arg: *mut c_void) -> *const u8 {
+        unsafe { *(arg as *const &'static dyn Access) as *const dyn Access as *const u8 }
+    }
+
+    #[test]
+    fn table_layout() {
+        let defs = SERVICES.defs();
+        assert_eq!(defs.len(), 2);
+        assert_eq!(defs[0].type_, BLE_GATT_SVC_TYPE_PRIMARY as u8);
+        assert_eq!(defs[1].type_, BLE_GATT_SVC_TYPE_SECONDARY as u8);
+        assert_eq!(
+            unsafe { Uuid::from_ptr(defs[0].uuid) },
+            Uuid::from_u16(0x1234)
+        );
+        assert!(defs[0].includes.is_null());
+        let end = unsafe { &*SERVICES.as_ptr().add(2) };
+        assert_eq!(end.type_, 0);
+        assert!(end.uuid.is_null() && end.characteristics.is_null());
+
+        let chrs = characteristics(&defs[0]);
+        assert_eq!(chrs.len(), 3);
+        assert_eq!(chrs[2].flags, WRITE_NO_RSP);
+        assert!(characteristics(&defs[1]).is_empty());
+        let end = unsafe { &*defs[1].characteristics };
+        assert!(end.uuid.is_null() && end.access_cb.is_none() && end.arg.is_null());
+        assert!(end.descriptors.is_null() && end.val_handle.is_null());
+        assert_eq!((end.flags, end.min_key_size), (0, 0));
+
+        let setting = &chrs[0];
+        assert_eq!(setting.flags, WRITE | NOTIFY);
+        assert_eq!(setting.min_key_size, 16);
+        assert_eq!(setting.val_handle, SETTING_HANDLE.as_ptr());
+        assert_eq!(access(setting.arg), &SETTING as *const Setting as *const u8);
+        assert!(setting.access_cb.is_some());
+        assert_eq!(chrs[1].flags, READ);
+        assert_eq!(chrs[1].min_key_size, 0);
+        assert!(chrs[1].val_handle.is_null());
+        assert!(descriptors(&chrs[1]).is_empty());
+
+        let dscs = descriptors(setting);
+        assert_eq!(dscs.len(), 2);
+        assert_eq!(
+            unsafe { Uuid::from_ptr(dscs[0].uuid) },
+            USER_DESCRIPTION_UUID
+        );
+        assert_eq!(dscs[0].att_flags, DSC_READ);
+        assert_eq!(dscs[0].min_key_size, 0);
+        assert_eq!(
+            access(dscs[0].arg),
+            &SETTING_NAME as *const UserDescription as *const u8
+        );
+        assert_eq!(dscs[1].att_flags, DSC_READ | DSC_READ_ENC);
+        assert_eq!(dscs[1].min_key_size, 7);
+        let end = unsafe { &*setting.descriptors.add(2) };
+        assert!(end.uuid.is_null() && end.access_cb.is_none() && end.arg.is_null());
+        assert_eq!((end.att_flags, end.min_key_size), (0, 0));
+    }
+
+    #[test]
+    fn registered_table_dispatches() {
+        SERVICES.register().unwrap();
+        mock::nimble::sync();
+        let handle = mock::nimble::find_chr("0x1235").unwrap();
+        assert_eq!(SETTING_HANDLE.get(), handle);
+        let attrs = mock::nimble::attributes();
+        let dsc = |uuid: &str| attrs.iter().find(|a| a.1 == uuid).unwrap().0;
+
+        unsafe {
+            let params = core::mem::zeroed();
+            ble_gap_adv_start(0, ptr::null(), 0, &params, None, ptr::null_mut());
+        }
+        mock::nimble::connect(1);
+        assert_eq!(
+            mock::nimble::read(1, dsc("0x2901")),
+            Ok(b"Setting".to_vec())
+        );
+        // Unbonded, so the central is asked to pair
+        assert_eq!(
+            mock::nimble::read(1, dsc("0x2906")),
+            Err(AttError::InsufficientAuthen.code() as i32)
+        );
+        mock::nimble::pair(1, true);
+        assert_eq!(mock::nimble::read(1, dsc("0x2906")), Ok(vec![7, 7]));
+
+        assert_eq!(mock::nimble::write(1, handle, &[7]), Ok(()));
+        assert_eq!(
+            mock::nimble::write(1, handle, &[8]),
+            Err(AttError::ValueNotAllowed.code() as i32)
+        );
+        let other = mock::nimble::find_chr("0x1236").unwrap();
+        assert_eq!(mock::nimble::read(1, other), Ok(b"x".to_vec()));
+
+        // Writes without response reach the handler the same way, a plain write doesn't
+        let no_rsp = mock::nimble::find_chr("0x1237").unwrap();
+        assert_eq!(mock::nimble::write_no_rsp(1, no_rsp, &[3]), Ok(()));
+        assert_eq!(
+            mock::nimble::write_no_rsp(1, no_rsp, &[3, 0]),
+            Err(AttError::InvalidAttrValueLen.code() as i32)
+        );
+        assert_eq!(
+            mock::nimble::write(1, no_rsp, &[3]),
+            Err(AttError::WriteNotPermitted.code() as i32)
+        );
+        assert_eq!(
+            mock::nimble::write_no_rsp(1, handle, &[7]),
+            Err(AttError::WriteNotPermitted.code() as i32)
+        );
+        mock::nimble::disconnect(1, 0x213);
+    }
+
+    fn decode<T: Decode>(data: &[u8]) -> Result<T, AttError> {
+        let write = BLE_GATT_ACCESS_OP_WRITE_CHR;
+        mock::nimble::with_request(1, write, data, |request| request.decode::<T>()).0
+    }
+
+    #[test]
+    fn decode_checks_length() {
+        assert_eq!(decode::<u16>(&[0x34, 0x12]), Ok(0x1234));
+        assert_eq!(decode::<u16>(&[0x34]), Err(AttError::InvalidAttrValueLen));
+        assert_eq!(
+            decode::<u16>(&[1, 2, 3]),
+            Err(AttError::InvalidAttrValueLen)
+        );
+        assert_eq!(decode::<i32>(&[0xfe, 0xff, 0xff, 0xff]), Ok(-2));
+        assert_eq!(decode::<u8>(&[]), Err(AttError::InvalidAttrValueLen));
+
+        assert_eq!(decode::<Odd>(&[1]), Ok(Odd(1)));
+        assert_eq!(decode::<Odd>(&[1, 0, 1]), Ok(Odd(0x10001)));
+        assert_eq!(decode::<Odd>(&[]), Err(AttError::InvalidAttrValueLen));
+        assert_eq!(
+            decode::<Odd>(&[1, 0, 0, 0]),
+            Err(AttError::InvalidAttrValueLen)
+        );
+
+        assert_eq!(decode::<String>(b""), Ok(String::new()));
+        assert_eq!(
+            decode::<Vec<u8>>(&[0; MAX_VALUE_LEN]).map(|v| v.len()),
+            Ok(MAX_VALUE_LEN)
+        );
+        assert_eq!(
+            decode::<Vec<u8>>(&[0; MAX_VALUE_LEN + 1]),
+            Err(AttError::InvalidAttrValueLen)
+        );
+    }
+
+    #[test]
+    fn decode_rejects_values() {
+        assert_eq!(decode::<bool>(&[1]), Ok(true));
+        assert_eq!(decode::<bool>(&[0]), Ok(false));
+        assert_eq!(decode::<bool>(&[2]), Err(AttError::ValueNotAllowed));
+        assert_eq!(decode::<Odd>(&[2]), Err(AttError::ValueNotAllowed));
+        assert_eq!(decode::<String>(b"hi"), Ok(String::from("hi")));
+        assert_eq!(decode::<String>(&[0xff]), Err(AttError::ValueNotAllowed));
+    }
+
+    #[test]
+    fn value_bounds() {
+        let write = BLE_GATT_ACCESS_OP_WRITE_CHR;
+        let value = |data: &[u8], min_len| {
+            mock::nimble::with_request(1, write, data, |request| {
+                assert_eq!(request.len(), data.len());
+                let mut buf = [0; 4];
+                request.value(min_len, &mut buf).map(|value| value.to_vec())
+            })
+            .0
+        };
+        assert_eq!(value(&[1, 2, 3], 2), Ok(vec![1, 2, 3]));
+        assert_eq!(value(&[1, 2, 3, 4], 0), Ok(vec![1, 2, 3, 4]));
+        assert_eq!(value(&[1], 2), Err(AttError::InvalidAttrValueLen));
+        assert_eq!(
+            value(&[1, 2, 3, 4, 5], 2),
+            Err(AttError::InvalidAttrValueLen)
+        );
+    }
+
+    #[test]
+    fn reads_append() {
+        let (result, sent) =
+            mock::nimble::with_request(1, BLE_GATT_ACCESS_OP_READ_DSC, &[], |request| {
+                assert!(request.is_descriptor());
+                ValidRange(&[0x81], &[0x7f]).read(request)
+            });
+        assert_eq!((result, sent), (Ok(()), vec![0x81, 0x7f]));
+
+        let (result, sent) =
+            mock::nimble::with_request(1, BLE_GATT_ACCESS_OP_READ_CHR, &[], |request| {
+                assert!(!request.is_descriptor());
+                Setting(7).read(request)
+            });
+        assert_eq!((result, sent), (Err(AttError::ReadNotPermitted), vec![]));
+    }
+}
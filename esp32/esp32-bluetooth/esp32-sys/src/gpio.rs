@@ -0,0 +1,420 @@
+use alloc::boxed::Box;
+use core::ffi::c_void;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::{EspError, EspResult};
+use crate::*;
+
+/// A GPIO that exists on the ESP32 and isn't wired to the SPI flash
+pub trait Pin {
+    fn number(&self) -> gpio_num_t;
+}
+
+/// Pins that have an output driver, GPIO34-39 are input only
+pub trait OutputCapable: Pin {}
+
+/// Pins with internal pull resistors, GPIO34-39 have none
+pub trait PullCapable: Pin {}
+
+macro_rules! pin {
+    ($name:ident = $num:expr $(, $cap:ident)*) => {
+        pub struct $name {
+            _private: (),
+        }
+
+        impl Pin for $name {
+            fn number(&self) -> gpio_num_t {
+                $num
+            }
+        }
+
+        $(impl $cap for $name {})*
+    };
+}
+
+macro_rules! pins {
+    ($($field:ident: $name:ident = $num:expr $(, $cap:ident)*;)*) => {
+        $(pin!($name = $num $(, $cap)*);)*
+
+        /// Every GPIO once, so no two drivers own the same pin
+        ///
+        /// GPIO1 and GPIO3 carry the console and are only handed out through `console`.
+        pub struct Pins {
+            $(pub $field: $name,)*
+            pub console: ConsolePins,
+        }
+
+        impl Pins {
+            /// Conjures the pins regardless of who owns them, for tests and for code that
+            /// hands them out itself. Any pin used twice is driven twice.
+            pub unsafe fn steal() -> Pins {
+                Pins {
+                    $($field: $name { _private: () },)*
+                    console: ConsolePins { _private: () },
+                }
+            }
+        }
+    };
+}
+
+pins! {
+    gpio0: Gpio0 = 0, OutputCapable, PullCapable;
+    gpio2: Gpio2 = 2, OutputCapable, PullCapable;
+    gpio4: Gpio4 = 4, OutputCapable, PullCapable;
+    gpio5: Gpio5 = 5, OutputCapable, PullCapable;
+    gpio12: Gpio12 = 12, OutputCapable, PullCapable;
+    gpio13: Gpio13 = 13, OutputCapable, PullCapable;
+    gpio14: Gpio14 = 14, OutputCapable, PullCapable;
+    gpio15: Gpio15 = 15, OutputCapable, PullCapable;
+    gpio16: Gpio16 = 16, OutputCapable, PullCapable;
+    gpio17: Gpio17 = 17, OutputCapable, PullCapable;
+    gpio18: Gpio18 = 18, OutputCapable, PullCapable;
+    gpio19: Gpio19 = 19, OutputCapable, PullCapable;
+    gpio21: Gpio21 = 21, OutputCapable, PullCapable;
+    gpio22: Gpio22 = 22, OutputCapable, PullCapable;
+    gpio23: Gpio23 = 23, OutputCapable, PullCapable;
+    gpio25: Gpio25 = 25, OutputCapable, PullCapable;
+    gpio26: Gpio26 = 26, OutputCapable, PullCapable;
+    gpio27: Gpio27 = 27, OutputCapable, PullCapable;
+    gpio32: Gpio32 = 32, OutputCapable, PullCapable;
+    gpio33: Gpio33 = 33, OutputCapable, PullCapable;
+    gpio34: Gpio34 = 34;
+    gpio35: Gpio35 = 35;
+    gpio36: Gpio36 = 36;
+    gpio37: Gpio37 = 37;
+    gpio38: Gpio38 = 38;
+    gpio39: Gpio39 = 39;
+}
+
+pin!(Gpio1 = 1, OutputCapable, PullCapable);
+pin!(Gpio3 = 3, OutputCapable, PullCapable);
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl Pins {
+    /// The pins, the first time only
+    pub fn take() -> Option<Pins> {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            None
+        } else {
+            Some(unsafe { Pins::steal() })
+        }
+    }
+}
+
+/// GPIO1 and GPIO3, TX and RX of UART0, which the ROM and ESP-IDF print the boot messages
+/// and the log on
+pub struct ConsolePins {
+    _private: (),
+}
+
+impl ConsolePins {
+    /// Hands the console pins out for other uses
+    ///
+    /// Nothing may print to UART0 afterwards: the log has to be off or redirected to
+    /// another UART, or the pins end up driven by the console and their new owner alike.
+    pub unsafe fn release(self) -> (Gpio1, Gpio3) {
+        (Gpio1 { _private: () }, Gpio3 { _private: () })
+    }
+}
+
+/// Runtime equivalent of the `Pin` impls above
+pub fn is_usable(num: gpio_num_t) -> bool {
+    match num {
+        0..=5 | 12..=19 | 21..=23 | 25..=27 | 32..=39 => true,
+        _ => false,
+    }
+}
+
+/// Runtime equivalent of `OutputCapable`, also implies `PullCapable`
+pub fn is_output_capable(num: gpio_num_t) -> bool {
+    is_usable(num) && num < 34
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    Floating,
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
+impl Edge {
+    fn intr_type(self) -> gpio_int_type_t {
+        match self {
+            Edge::Rising => gpio_int_type_t_GPIO_INTR_POSEDGE,
+            Edge::Falling => gpio_int_type_t_GPIO_INTR_NEGEDGE,
+            Edge::Any => gpio_int_type_t_GPIO_INTR_ANYEDGE,
+        }
+    }
+}
+
+fn configure(num: gpio_num_t, mode: gpio_mode_t, pull: Pull) -> EspResult<()> {
+    let config = gpio_config_t {
+        pin_bit_mask: 1u64 << num,
+        mode,
+        pull_up_en: if pull == Pull::Up {
+            gpio_pullup_t_GPIO_PULLUP_ENABLE
+        } else {
+            gpio_pullup_t_GPIO_PULLUP_DISABLE
+        },
+        pull_down_en: if pull == Pull::Down {
+            gpio_pulldown_t_GPIO_PULLDOWN_ENABLE
+        } else {
+            gpio_pulldown_t_GPIO_PULLDOWN_DISABLE
+        },
+        intr_type: gpio_int_type_t_GPIO_INTR_DISABLE,
+    };
+    unsafe { esp!(gpio_config(&config)) }
+}
+
+/// A push/pull output, the pin is reset when this is dropped
+pub struct OutputPin {
+    num: gpio_num_t,
+    high: bool,
+}
+
+impl OutputPin {
+    pub fn new<P: OutputCapable>(pin: P) -> EspResult<OutputPin> {
+        OutputPin::configure(pin.number())
+    }
+
+    /// For pin numbers only known at runtime, rejects input-only and flash pins
+    ///
+    /// Unlike `new` this can't tell whether the pin already has an owner, the caller
+    /// makes sure it has none.
+    pub unsafe fn from_num(num: gpio_num_t) -> EspResult<OutputPin> {
+        if !is_output_capable(num) {
+            return Err(EspError::new(ESP_ERR_INVALID_ARG, "OutputPin::from_num"));
+        }
+        OutputPin::configure(num)
+    }
+
+    fn configure(num: gpio_num_t) -> EspResult<OutputPin> {
+        configure(num, gpio_mode_t_GPIO_MODE_OUTPUT, Pull::Floating)?;
+        let mut pin = OutputPin { num, high: false };
+        pin.set_low()?;
+        Ok(pin)
+    }
+
+    pub fn number(&self) -> gpio_num_t {
+        self.num
+    }
+
+    pub fn set_level(&mut self, high: bool) -> EspResult<()> {
+        unsafe { esp!(gpio_set_level(self.num, high as u32)) }?;
+        self.high = high;
+        Ok(())
+    }
+
+    pub fn set_high(&mut self) -> EspResult<()> {
+        self.set_level(true)
+    }
+
+    pub fn set_low(&mut self) -> EspResult<()> {
+        self.set_level(false)
+    }
+
+    pub fn toggle(&mut self) -> EspResult<()> {
+        let high = !self.high;
+        self.set_level(high)
+    }
+
+    /// The level last written, the output driver can't be read back in this mode
+    pub fn is_set_high(&self) -> bool {
+        self.high
+    }
+}
+
+impl Drop for OutputPin {
+    fn drop(&mut self) {
+        unsafe { gpio_reset_pin(self.num) };
+    }
+}
+
+type IsrCallback = Box<dyn FnMut() + Send + 'static>;
+
+/// An input, optionally with an edge interrupt running a Rust closure
+pub struct InputPin {
+    num: gpio_num_t,
+    isr: *mut IsrCallback,
+}
+
+unsafe impl Send for InputPin {}
+
+impl InputPin {
+    pub fn new<P: Pin>(pin: P) -> EspResult<InputPin> {
+        InputPin::configure(pin.number(), Pull::Floating)
+    }
+
+    pub fn with_pull<P: PullCapable>(pin: P, pull: Pull) -> EspResult<InputPin> {
+        InputPin::configure(pin.number(), pull)
+    }
+
+    /// For pin numbers only known at runtime, pulls are rejected on GPIO34-39
+    ///
+    /// Unlike `new` this can't tell whether the pin already has an owner, the caller
+    /// makes sure it has none.
+    pub unsafe fn from_num(num: gpio_num_t, pull: Pull) -> EspResult<InputPin> {
+        if !is_usable(num) || (pull != Pull::Floating && !is_output_capable(num)) {
+            return Err(EspError::new(ESP_ERR_INVALID_ARG, "InputPin::from_num"));
+        }
+        InputPin::configure(num, pull)
+    }
+
+    fn configure(num: gpio_num_t, pull: Pull) -> EspResult<InputPin> {
+        configure(num, gpio_mode_t_GPIO_MODE_INPUT, pull)?;
+        Ok(InputPin {
+            num,
+            isr: ptr::null_mut(),
+        })
+    }
+
+    pub fn number(&self) -> gpio_num_t {
+        self.num
+    }
+
+    pub fn is_high(&self) -> bool {
+        unsafe { gpio_get_level(self.num) != 0 }
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Runs `callback` in interrupt context on every matching edge
+    ///
+    /// The callback must not block, replaces any callback set before.
+    pub fn on_edge<F>(&mut self, edge: Edge, callback: F) -> EspResult<()>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.clear_interrupt()?;
+        install_isr_service()?;
+
+        let isr: *mut IsrCallback = Box::into_raw(Box::new(Box::new(callback)));
+        unsafe {
+            let rc = esp!(gpio_isr_handler_add(
+                self.num,
+                Some(isr_trampoline),
+                isr as *mut c_void
+            ));
+            if let Err(err) = rc {
+                drop(Box::from_raw(isr));
+                return Err(err);
+            }
+        }
+        self.isr = isr;
+
+        unsafe { esp!(gpio_set_intr_type(self.num, edge.intr_type())) }?;
+        unsafe { esp!(gpio_intr_enable(self.num)) }
+    }
+
+    pub fn clear_interrupt(&mut self) -> EspResult<()> {
+        if self.isr.is_null() {
+            return Ok(());
+        }
+
+        unsafe {
+            esp!(gpio_set_intr_type(
+                self.num,
+                gpio_int_type_t_GPIO_INTR_DISABLE
+            ))?;
+            esp!(gpio_isr_handler_remove(self.num))?;
+            drop(Box::from_raw(self.isr));
+        }
+        self.isr = ptr::null_mut();
+        Ok(())
+    }
+}
+
+impl Drop for InputPin {
+    fn drop(&mut self) {
+        // If the handler can't be removed the closure has to stay alive for it
+        if self.clear_interrupt().is_ok() {
+            unsafe { gpio_reset_pin(self.num) };
+        }
+    }
+}
+
+fn install_isr_service() -> EspResult<()> {
+    match unsafe { esp!(gpio_install_isr_service(0)) } {
+        // Already installed by an earlier pin
+        Err(ref err) if err.code() == ESP_ERR_INVALID_STATE as esp_err_t => Ok(()),
+        rc => rc,
+    }
+}
+
+unsafe extern "C" fn isr_trampoline(arg: *mut c_void) {
+    let callback = arg as *mut IsrCallback;
+    (*callback)();
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use core::sync::atomic::AtomicU32;
+
+    static EDGES: AtomicU32 = AtomicU32::new(0);
+
+    #[test]
+    fn pins_are_taken_once() {
+        let pins = Pins::take().unwrap();
+        assert!(Pins::take().is_none());
+        assert_eq!(pins.gpio5.number(), 5);
+        assert_eq!(pins.gpio39.number(), 39);
+        let (tx, rx) = unsafe { pins.console.release() };
+        assert_eq!((tx.number(), rx.number()), (1, 3));
+    }
+
+    #[test]
+    fn output_pin() {
+        let pins = unsafe { Pins::steal() };
+        let mut out = OutputPin::new(pins.gpio5).unwrap();
+        assert_eq!(mock::gpio::mode(5), gpio_mode_t_GPIO_MODE_OUTPUT);
+        assert!(!mock::gpio::output_level(5));
+        out.set_high().unwrap();
+        assert!(mock::gpio::output_level(5));
+        out.toggle().unwrap();
+        assert!(!mock::gpio::output_level(5));
+        drop(out);
+        assert_eq!(mock::gpio::mode(5), gpio_mode_t_GPIO_MODE_DISABLE);
+    }
+
+    #[test]
+    fn pin_numbers_checked_at_runtime() {
+        unsafe {
+            assert!(OutputPin::from_num(34).is_err());
+            assert!(OutputPin::from_num(7).is_err());
+            assert!(InputPin::from_num(34, Pull::Up).is_err());
+            assert!(InputPin::from_num(34, Pull::Floating).is_ok());
+        }
+    }
+
+    #[test]
+    fn input_pin_edges() {
+        let pins = unsafe { Pins::steal() };
+        let mut input = InputPin::with_pull(pins.gpio4, Pull::Up).unwrap();
+        assert_eq!(mock::gpio::pulls(4), (true, false));
+        input
+            .on_edge(Edge::Rising, || {
+                EDGES.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        mock::gpio::drive(4, true);
+        mock::gpio::drive(4, false);
+        mock::gpio::drive(4, true);
+        assert_eq!(EDGES.load(Ordering::SeqCst), 2);
+        assert!(input.is_high());
+        drop(input);
+        assert!(!mock::gpio::has_isr(4));
+    }
+}
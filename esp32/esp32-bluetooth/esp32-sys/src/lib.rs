@@ -1,8 +1,13 @@
 #![no_std]
+#![feature(const_if_match, const_loop, const_panic)]
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
+extern crate alloc;
+#[cfg(feature = "host-mock")]
+extern crate std as host_std;
+
 pub use core::cell::UnsafeCell;
 use core::ptr;
 
@@ -26,19 +31,6 @@ pub mod std {
     }
 }
 
-#[macro_export]
-macro_rules! xTimerStart {
-    ($xTimer:expr, $xTicksToWait:expr) => {
-        xTimerGenericCommand(
-            ($xTimer),
-            tmrCOMMAND_START,
-            (xTaskGetTickCount()),
-            ptr::null_ptr(),
-            ($xTicksToWait),
-        )
-    };
-}
-
 enum TimerCommand {
     tmrCOMMAND_EXECUTE_CALLBACK_FROM_ISR = -2,
     tmrCOMMAND_EXECUTE_CALLBACK = -1,
@@ -89,13 +81,42 @@ pub unsafe fn xTimerReset(handle: TimerHandle_t, ticksToWait: u32) -> i32 {
     )
 }
 
+/// Length of the whole chain starting at a packet header mbuf, the packet header follows
+/// the `os_mbuf` directly
+pub unsafe fn OS_MBUF_PKTLEN(om: *const os_mbuf) -> u16 {
+    (*(om.add(1) as *const os_mbuf_pkthdr)).omp_len
+}
+
+/// Aborts through `_esp_error_check_failed` like the C `ESP_ERROR_CHECK`
 #[macro_export]
 macro_rules! esp_error_check {
-    ($err:expr) => {
-        if ($err != ESP_OK as esp_err_t) {
-            //_esp_error_check_failed($err, file!(), line!(),
-            //                        __ASSERT_FUNC, #x);
+    ($err:expr) => {{
+        let rc: $crate::esp_err_t = $err;
+        if rc != $crate::ESP_OK as $crate::esp_err_t {
+            $crate::_esp_error_check_failed(
+                rc,
+                concat!(file!(), "\0").as_ptr() as *const _,
+                line!() as i32,
+                concat!(module_path!(), "\0").as_ptr() as *const _,
+                concat!(stringify!($err), "\0").as_ptr() as *const _,
+            );
         }
+    }};
+}
+
+/// Turns an `esp_err_t` returning call into an `EspResult` that remembers the call
+#[macro_export]
+macro_rules! esp {
+    ($call:expr) => {
+        $crate::error::EspError::check($call).map_err(|err| err.in_call(stringify!($call)))
+    };
+}
+
+/// Like `esp!`, for NimBLE calls that return a `BLE_HS_E*` code
+#[macro_export]
+macro_rules! ble {
+    ($call:expr) => {
+        $crate::error::BleError::check($call).map_err(|err| err.in_call(stringify!($call)))
     };
 }
 
@@ -106,7 +127,38 @@ macro_rules! pdMS_TO_TICKS {
     };
 }
 
+pub mod adc;
+pub mod adv;
+pub mod connections;
+pub mod error;
+pub mod gap;
+pub mod gatt;
+pub mod gpio;
+pub mod link;
+pub mod logging;
+#[cfg(feature = "host-mock")]
+pub mod mock;
+pub mod nvs;
+pub mod queue;
+pub mod security;
+pub mod sync;
+pub mod task;
+pub mod timer;
+pub mod uart;
+pub mod uuid;
+
 pub static pdTRUE: u32 = 1;
 pub static pdPASS: i32 = 1;
+pub const portMAX_DELAY: TickType_t = 0xffffffff;
+
+// Cast macros in queue.h that bindgen skips
+pub const queueSEND_TO_BACK: BaseType_t = 0;
+pub const queueSEND_TO_FRONT: BaseType_t = 1;
+pub const queueOVERWRITE: BaseType_t = 2;
+pub const queueQUEUE_TYPE_BASE: u8 = 0;
+pub const queueQUEUE_TYPE_MUTEX: u8 = 1;
+pub const queueQUEUE_TYPE_COUNTING_SEMAPHORE: u8 = 2;
+pub const queueQUEUE_TYPE_BINARY_SEMAPHORE: u8 = 3;
+pub const queueQUEUE_TYPE_RECURSIVE_MUTEX: u8 = 4;
 
 include!("bindings.rs");
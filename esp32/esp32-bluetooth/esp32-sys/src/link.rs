@@ -0,0 +1,197 @@
+//! Connection parameters and link quality of open connections
+//!
+//! A peripheral can only ask the central for parameters with `request`, the central
+//! decides and NimBLE reports the outcome with `BLE_GAP_EVENT_CONN_UPDATE`. A central
+//! asking to change them arrives as `BLE_GAP_EVENT_CONN_UPDATE_REQ` or
+//! `BLE_GAP_EVENT_L2CAP_UPDATE_REQ`, `ConnParams::from_upd_params` reads what it asks for.
+
+use core::mem::MaybeUninit;
+
+use crate::error::{BleError, BleResult};
+use crate::*;
+
+/// RSSI reported when the controller can't tell, as in HCI
+pub const RSSI_UNAVAILABLE: i8 = 127;
+
+/// Connection parameters in the units of the Core specification
+///
+/// Intervals count 1.25 ms, the supervision timeout 10 ms. The latency is how many
+/// connection events the peripheral may skip when it has nothing to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnParams {
+    pub itvl_min: u16,
+    pub itvl_max: u16,
+    pub latency: u16,
+    pub supervision_timeout: u16,
+}
+
+impl ConnParams {
+    pub const fn new(
+        itvl_min: u16,
+        itvl_max: u16,
+        latency: u16,
+        supervision_timeout: u16,
+    ) -> ConnParams {
+        ConnParams {
+            itvl_min,
+            itvl_max,
+            latency,
+            supervision_timeout,
+        }
+    }
+
+    /// What a central asks for in an update request
+    pub fn from_upd_params(params: &ble_gap_upd_params) -> ConnParams {
+        ConnParams::new(
+            params.itvl_min,
+            params.itvl_max,
+            params.latency,
+            params.supervision_timeout,
+        )
+    }
+
+    /// Within the ranges of the Core specification, with a supervision timeout long
+    /// enough to survive the skipped events
+    pub fn is_valid(&self) -> bool {
+        let ranges = (6..=3200).contains(&self.itvl_min)
+            && (self.itvl_min..=3200).contains(&self.itvl_max)
+            && self.latency <= 499
+            && (10..=3200).contains(&self.supervision_timeout);
+        // timeout * 10 ms > (1 + latency) * itvl_max * 1.25 ms * 2
+        ranges
+            && self.supervision_timeout as u32 * 4
+                > (1 + self.latency as u32) * self.itvl_max as u32
+    }
+
+    fn to_upd_params(self) -> ble_gap_upd_params {
+        ble_gap_upd_params {
+            itvl_min: self.itvl_min,
+            itvl_max: self.itvl_max,
+            latency: self.latency,
+            supervision_timeout: self.supervision_timeout,
+            min_ce_len: 0,
+            max_ce_len: 0,
+        }
+    }
+}
+
+/// Asks the central of `conn_handle` for `params`
+pub fn request(conn_handle: u16, params: &ConnParams) -> BleResult<()> {
+    if !params.is_valid() {
+        return Err(BleError::new(BLE_HS_EINVAL, "link::request"));
+    }
+    let params = params.to_upd_params();
+    unsafe { ble!(ble_gap_update_params(conn_handle, &params)) }
+}
+
+/// The parameters in use on a connection and how well it is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkInfo {
+    /// In 1.25 ms
+    pub interval: u16,
+    pub latency: u16,
+    /// In 10 ms
+    pub supervision_timeout: u16,
+    /// In dBm, `RSSI_UNAVAILABLE` if unknown
+    pub rssi: i8,
+    /// `BLE_GAP_LE_PHY_1M`, `BLE_GAP_LE_PHY_2M` or `BLE_GAP_LE_PHY_CODED`
+    pub tx_phy: u8,
+    pub rx_phy: u8,
+}
+
+impl LinkInfo {
+    /// Interval, latency and supervision timeout as little-endian `u16`, then RSSI, TX
+    /// PHY and RX PHY
+    pub fn to_bytes(&self) -> [u8; 9] {
+        let mut bytes = [0; 9];
+        bytes[0..2].copy_from_slice(&self.interval.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.latency.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.supervision_timeout.to_le_bytes());
+        bytes[6] = self.rssi as u8;
+        bytes[7] = self.tx_phy;
+        bytes[8] = self.rx_phy;
+        bytes
+    }
+}
+
+/// Current parameters, RSSI and PHY of `conn_handle`
+///
+/// The original ESP32 controller is Bluetooth 4.2, it has no PHY commands and is always
+/// on the LE 1M PHY, which is reported when the PHY can't be read.
+pub fn link_info(conn_handle: u16) -> BleResult<LinkInfo> {
+    let desc = unsafe {
+        let mut desc = MaybeUninit::<ble_gap_conn_desc>::zeroed();
+        ble!(ble_gap_conn_find(conn_handle, desc.as_mut_ptr()))?;
+        desc.assume_init()
+    };
+
+    let mut rssi = 0;
+    if unsafe { ble_gap_conn_rssi(conn_handle, &mut rssi) } != 0 {
+        rssi = RSSI_UNAVAILABLE;
+    }
+    let (mut tx_phy, mut rx_phy) = (0, 0);
+    if unsafe { ble_gap_read_le_phy(conn_handle, &mut tx_phy, &mut rx_phy) } != 0 {
+        tx_phy = BLE_GAP_LE_PHY_1M as u8;
+        rx_phy = BLE_GAP_LE_PHY_1M as u8;
+    }
+
+    Ok(LinkInfo {
+        interval: desc.conn_itvl,
+        latency: desc.conn_latency,
+        supervision_timeout: desc.supervision_timeout,
+        rssi,
+        tx_phy,
+        rx_phy,
+    })
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_params() {
+        assert!(ConnParams::new(6, 16, 0, 400).is_valid());
+        assert!(ConnParams::new(3200, 3200, 0, 3200).is_valid());
+        assert!(!ConnParams::new(5, 16, 0, 400).is_valid());
+        assert!(!ConnParams::new(16, 6, 0, 400).is_valid());
+        assert!(!ConnParams::new(6, 3201, 0, 3200).is_valid());
+        assert!(!ConnParams::new(6, 16, 500, 3200).is_valid());
+        assert!(!ConnParams::new(6, 6, 0, 9).is_valid());
+        assert!(!ConnParams::new(6, 16, 0, 3201).is_valid());
+    }
+
+    #[test]
+    fn supervision_timeout_covers_skipped_events() {
+        // 5 events of 200 ms are 1 s, the timeout has to be over twice that
+        assert!(!ConnParams::new(80, 160, 4, 200).is_valid());
+        assert!(ConnParams::new(80, 160, 4, 201).is_valid());
+        // Only the longest interval counts
+        assert!(ConnParams::new(6, 40, 0, 11).is_valid());
+        assert!(!ConnParams::new(6, 40, 1, 20).is_valid());
+    }
+
+    #[test]
+    fn link_info_bytes() {
+        let info = LinkInfo {
+            interval: 0x0118,
+            latency: 4,
+            supervision_timeout: 600,
+            rssi: -60,
+            tx_phy: BLE_GAP_LE_PHY_2M as u8,
+            rx_phy: BLE_GAP_LE_PHY_1M as u8,
+        };
+        assert_eq!(info.to_bytes(), [0x18, 0x01, 4, 0, 0x58, 0x02, 0xc4, 2, 1]);
+        let info = LinkInfo {
+            rssi: RSSI_UNAVAILABLE,
+            ..info
+        };
+        assert_eq!(info.to_bytes()[6], 0x7f);
+    }
+
+    #[test]
+    fn invalid_request_refused() {
+        let err = request(1, &ConnParams::new(6, 16, 0, 5)).unwrap_err();
+        assert_eq!(err.code(), BLE_HS_EINVAL as i32);
+    }
+}
@@ -0,0 +1,336 @@
+//! Leveled logging through `esp_log_write` with `core::fmt` formatting
+//!
+//! Messages are formatted into a fixed size stack buffer and printed in the same layout as
+//! the C `ESP_LOGx` macros, so Rust and C output interleave cleanly:
+//!
+//! ```ignore
+//! const TAG: &str = "plotter\0";
+//! info!(TAG, "velocity {} {}", left, right);
+//! ```
+//!
+//! Tags are NUL terminated `&'static str`s because ESP-IDF caches them by address.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+
+use crate::error::{EspError, EspResult};
+use crate::sync::CriticalSection;
+use crate::*;
+
+/// Longest message that is printed in full, longer ones are cut and end in `...`
+pub const BUFFER_SIZE: usize = 256;
+
+/// Messages above this level are compiled out, like `LOG_LOCAL_LEVEL` in C
+pub const MAX_LEVEL: Level = LEVELS[CONFIG_LOG_DEFAULT_LEVEL as usize];
+
+/// Tags with a level of their own, see `set_level`
+const MAX_TAGS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Nothing is logged, only meaningful as a filter
+    None = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Verbose = 5,
+}
+
+const LEVELS: [Level; 6] = [
+    Level::None,
+    Level::Error,
+    Level::Warn,
+    Level::Info,
+    Level::Debug,
+    Level::Verbose,
+];
+
+impl Level {
+    pub fn from_raw(level: esp_log_level_t) -> Level {
+        LEVELS[(level as usize).min(LEVELS.len() - 1)]
+    }
+
+    pub fn raw(self) -> esp_log_level_t {
+        self as esp_log_level_t
+    }
+
+    pub(crate) fn letter(self) -> u8 {
+        b"NEWIDV"[self as usize]
+    }
+
+    #[cfg(not(feature = "host-mock"))]
+    fn color(self) -> &'static str {
+        if CONFIG_LOG_COLORS == 0 {
+            return "\0";
+        }
+        match self {
+            Level::Error => "\x1b[0;31m\0",
+            Level::Warn => "\x1b[0;33m\0",
+            Level::Info => "\x1b[0;32m\0",
+            _ => "\0",
+        }
+    }
+}
+
+struct Filters {
+    default: Level,
+    tags: [Option<(&'static str, Level)>; MAX_TAGS],
+}
+
+struct FilterTable {
+    lock: CriticalSection,
+    filters: UnsafeCell<Filters>,
+}
+
+unsafe impl Sync for FilterTable {}
+
+impl FilterTable {
+    fn with<R>(&self, f: impl FnOnce(&mut Filters) -> R) -> R {
+        self.lock.with(|| f(unsafe { &mut *self.filters.get() }))
+    }
+}
+
+static FILTERS: FilterTable = FilterTable {
+    lock: CriticalSection::new(),
+    filters: UnsafeCell::new(Filters {
+        default: MAX_LEVEL,
+        tags: [None; MAX_TAGS],
+    }),
+};
+
+/// Limits `tag` to messages at `level` and below, `Level::None` silences it
+///
+/// Fails with `ESP_ERR_NO_MEM` once `MAX_TAGS` different tags have their own level.
+pub fn set_level(tag: &'static str, level: Level) -> EspResult<()> {
+    FILTERS.with(|filters| {
+        let existing = filters
+            .tags
+            .iter()
+            .position(|t| t.map_or(false, |(t, _)| t == tag));
+        let slot = match existing {
+            Some(index) => index,
+            None => match filters.tags.iter().position(Option::is_none) {
+                Some(index) => index,
+                None => return Err(EspError::new(ESP_ERR_NO_MEM, "logging::set_level")),
+            },
+        };
+        filters.tags[slot] = Some((tag, level));
+        Ok(())
+    })
+}
+
+/// Level for every tag without one of its own, capped at `MAX_LEVEL`
+pub fn set_default_level(level: Level) {
+    FILTERS.with(|filters| filters.default = level.min(MAX_LEVEL));
+}
+
+pub fn enabled(level: Level, tag: &str) -> bool {
+    if level == Level::None || level > MAX_LEVEL {
+        return false;
+    }
+    let max = FILTERS.with(|filters| {
+        filters
+            .tags
+            .iter()
+            .filter_map(|t| *t)
+            .find(|&(t, _)| t == tag)
+            .map_or(filters.default, |(_, level)| level)
+    });
+    level <= max
+}
+
+/// Formats `args` and hands the line to `esp_log_write`, use the macros instead
+pub fn write(level: Level, tag: &'static str, args: fmt::Arguments) {
+    debug_assert!(tag.ends_with('\0'), "log tag must be NUL terminated");
+    if !enabled(level, tag) {
+        return;
+    }
+
+    let mut line = LineBuffer::new();
+    let _ = line.write_fmt(args);
+    emit(level, tag, line.as_bytes());
+}
+
+#[cfg(not(feature = "host-mock"))]
+fn emit(level: Level, tag: &'static str, text: &[u8]) {
+    let reset = if level.color().len() > 1 {
+        "\x1b[0m\0"
+    } else {
+        "\0"
+    };
+
+    unsafe {
+        esp_log_write(
+            level.raw(),
+            tag.as_ptr() as *const _,
+            "%s%c (%u) %s: %.*s%s\n\0".as_ptr() as *const _,
+            level.color().as_ptr(),
+            level.letter() as i32,
+            esp_log_timestamp(),
+            tag.as_ptr(),
+            text.len() as i32,
+            text.as_ptr(),
+            reset.as_ptr(),
+        );
+    }
+}
+
+// `esp_log_write` is variadic and can't be faked in Rust, the line goes to the mock instead
+#[cfg(feature = "host-mock")]
+fn emit(level: Level, tag: &'static str, text: &[u8]) {
+    mock::logging::record(level, &tag[..tag.len() - 1], text);
+}
+
+/// A `fmt::Write` sink that keeps what fits and marks the cut
+struct LineBuffer {
+    buf: [u8; BUFFER_SIZE],
+    len: usize,
+    truncated: bool,
+}
+
+impl LineBuffer {
+    fn new() -> LineBuffer {
+        LineBuffer {
+            buf: [0; BUFFER_SIZE],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    fn as_bytes(&mut self) -> &[u8] {
+        if self.truncated {
+            self.buf[BUFFER_SIZE - 3..].copy_from_slice(b"...");
+        }
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = BUFFER_SIZE - self.len;
+        let n = s.len().min(free);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        // Keep formatting, the rest of the arguments are simply dropped
+        self.truncated |= n < s.len();
+        Ok(())
+    }
+}
+
+/// Logs at an explicit `logging::Level`
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $tag:expr, $($arg:tt)+) => {{
+        let level: $crate::logging::Level = $level;
+        if level <= $crate::logging::MAX_LEVEL {
+            $crate::logging::write(level, $tag, format_args!($($arg)+));
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($tag:expr, $($arg:tt)+) => ($crate::log!($crate::logging::Level::Error, $tag, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($tag:expr, $($arg:tt)+) => ($crate::log!($crate::logging::Level::Warn, $tag, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($tag:expr, $($arg:tt)+) => ($crate::log!($crate::logging::Level::Info, $tag, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($tag:expr, $($arg:tt)+) => ($crate::log!($crate::logging::Level::Debug, $tag, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! verbose {
+    ($tag:expr, $($arg:tt)+) => ($crate::log!($crate::logging::Level::Verbose, $tag, $($arg)+));
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    const TAG: &str = "filtered\0";
+
+    fn messages() -> Vec<String> {
+        mock::logging::take()
+            .into_iter()
+            .map(|record| record.message)
+            .collect()
+    }
+
+    /// Forgets every level set so other tests start from the defaults
+    fn reset() {
+        FILTERS.with(|filters| {
+            filters.tags = [None; MAX_TAGS];
+            filters.default = MAX_LEVEL;
+        });
+    }
+
+    #[test]
+    fn levels_per_tag() {
+        mock::logging::take();
+        info!(TAG, "shown {}", 1);
+        debug!(TAG, "above MAX_LEVEL");
+        set_level(TAG, Level::Warn).unwrap();
+        info!(TAG, "hidden");
+        warn!(TAG, "shown {}", 2);
+        info!("other\0", "shown {}", 3);
+        set_level(TAG, Level::None).unwrap();
+        error!(TAG, "hidden");
+        assert_eq!(messages(), ["shown 1", "shown 2", "shown 3"]);
+
+        set_default_level(Level::Error);
+        assert!(!enabled(Level::Warn, "other\0"));
+        // Levels above MAX_LEVEL aren't compiled in
+        set_default_level(Level::Verbose);
+        assert!(enabled(Level::Info, "other\0"));
+        assert!(!enabled(Level::Debug, "other\0"));
+        assert!(!enabled(Level::None, "other\0"));
+        reset();
+    }
+
+    #[test]
+    fn tag_slots_run_out() {
+        const TAGS: [&str; MAX_TAGS + 1] = [
+            "t0\0", "t1\0", "t2\0", "t3\0", "t4\0", "t5\0", "t6\0", "t7\0", "t8\0", "t9\0",
+            "t10\0", "t11\0", "t12\0", "t13\0", "t14\0", "t15\0", "t16\0",
+        ];
+        for tag in &TAGS[..MAX_TAGS] {
+            set_level(tag, Level::Error).unwrap();
+        }
+        let err = set_level(TAGS[MAX_TAGS], Level::Error).unwrap_err();
+        assert_eq!(err.code(), ESP_ERR_NO_MEM as esp_err_t);
+        // A tag that has a slot can still change its level
+        set_level(TAGS[0], Level::Info).unwrap();
+        assert!(enabled(Level::Info, TAGS[0]));
+        assert!(!enabled(Level::Info, TAGS[1]));
+        reset();
+    }
+
+    #[test]
+    fn long_lines_truncated() {
+        mock::logging::take();
+        let long = "x".repeat(300);
+        info!(TAG, "{}", long);
+        let fits = "y".repeat(BUFFER_SIZE);
+        info!(TAG, "{}", fits);
+        let messages = messages();
+
+        assert_eq!(messages[0].len(), BUFFER_SIZE);
+        assert!(messages[0].starts_with("xxx"));
+        assert!(messages[0].ends_with("x..."));
+        assert_eq!(messages[1], fits);
+    }
+}
@@ -0,0 +1,138 @@
+//! Fake ADC1 with an ideal linear response
+//!
+//! Tests set the voltage at a channel's pin with `set_input`. The raw reading is that
+//! voltage over the full scale of the configured attenuation and width, and the
+//! characteristics map it back the same way, so a reading returns the input give or take
+//! the quantization.
+
+use core::cell::RefCell;
+use host_std::thread_local;
+
+use crate::{
+    adc1_channel_t, adc1_channel_t_ADC1_CHANNEL_MAX, adc_atten_t, adc_atten_t_ADC_ATTEN_DB_0,
+    adc_atten_t_ADC_ATTEN_DB_11, adc_atten_t_ADC_ATTEN_DB_2_5, adc_atten_t_ADC_ATTEN_DB_6,
+    adc_atten_t_ADC_ATTEN_MAX, adc_bits_width_t, adc_bits_width_t_ADC_WIDTH_BIT_12,
+    adc_bits_width_t_ADC_WIDTH_MAX, adc_unit_t, esp_adc_cal_characteristics_t, esp_adc_cal_value_t,
+    esp_adc_cal_value_t_ESP_ADC_CAL_VAL_DEFAULT_VREF, esp_err_t, ESP_ERR_INVALID_ARG, ESP_OK,
+};
+
+const CHANNELS: usize = adc1_channel_t_ADC1_CHANNEL_MAX as usize;
+
+struct Adc1 {
+    width: adc_bits_width_t,
+    atten: [adc_atten_t; CHANNELS],
+    input_mv: [u32; CHANNELS],
+    calibration: esp_adc_cal_value_t,
+    reads: usize,
+}
+
+thread_local! {
+    static ADC1: RefCell<Adc1> = RefCell::new(Adc1 {
+        width: adc_bits_width_t_ADC_WIDTH_BIT_12,
+        atten: [adc_atten_t_ADC_ATTEN_DB_0; CHANNELS],
+        input_mv: [0; CHANNELS],
+        calibration: esp_adc_cal_value_t_ESP_ADC_CAL_VAL_DEFAULT_VREF,
+        reads: 0,
+    });
+}
+
+/// Nominal full scale in mV, the chip's reference of 1.1 V times the attenuation
+fn full_scale_mv(atten: adc_atten_t) -> u32 {
+    match atten {
+        adc_atten_t_ADC_ATTEN_DB_2_5 => 1467,
+        adc_atten_t_ADC_ATTEN_DB_6 => 2200,
+        adc_atten_t_ADC_ATTEN_DB_11 => 3960,
+        _ => 1100,
+    }
+}
+
+fn max_raw(width: adc_bits_width_t) -> u32 {
+    (1 << (9 + width)) - 1
+}
+
+/// Voltage at the pin of `channel`, clipped at the full scale when read
+pub fn set_input(channel: adc1_channel_t, mv: u32) {
+    ADC1.with(|adc| adc.borrow_mut().input_mv[channel as usize] = mv);
+}
+
+/// What `esp_adc_cal_characterize` reports the characterization was based on
+pub fn set_calibration(calibration: esp_adc_cal_value_t) {
+    ADC1.with(|adc| adc.borrow_mut().calibration = calibration);
+}
+
+/// Attenuation as last configured
+pub fn attenuation(channel: adc1_channel_t) -> adc_atten_t {
+    ADC1.with(|adc| adc.borrow().atten[channel as usize])
+}
+
+/// Samples taken since the thread started
+pub fn reads() -> usize {
+    ADC1.with(|adc| adc.borrow().reads)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn adc1_config_width(width_bit: adc_bits_width_t) -> esp_err_t {
+    if width_bit >= adc_bits_width_t_ADC_WIDTH_MAX {
+        return ESP_ERR_INVALID_ARG as esp_err_t;
+    }
+    ADC1.with(|adc| adc.borrow_mut().width = width_bit);
+    ESP_OK as esp_err_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn adc1_config_channel_atten(
+    channel: adc1_channel_t,
+    atten: adc_atten_t,
+) -> esp_err_t {
+    if channel >= adc1_channel_t_ADC1_CHANNEL_MAX || atten >= adc_atten_t_ADC_ATTEN_MAX {
+        return ESP_ERR_INVALID_ARG as esp_err_t;
+    }
+    ADC1.with(|adc| adc.borrow_mut().atten[channel as usize] = atten);
+    ESP_OK as esp_err_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn adc1_get_raw(channel: adc1_channel_t) -> i32 {
+    if channel >= adc1_channel_t_ADC1_CHANNEL_MAX {
+        return -1;
+    }
+    ADC1.with(|adc| {
+        let mut adc = adc.borrow_mut();
+        adc.reads += 1;
+        let full_scale = full_scale_mv(adc.atten[channel as usize]);
+        let mv = adc.input_mv[channel as usize].min(full_scale);
+        (mv * max_raw(adc.width) / full_scale) as i32
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn esp_adc_cal_characterize(
+    _adc_num: adc_unit_t,
+    atten: adc_atten_t,
+    bit_width: adc_bits_width_t,
+    default_vref: u32,
+    chars: *mut esp_adc_cal_characteristics_t,
+) -> esp_adc_cal_value_t {
+    /* coeff_a is the full scale and coeff_b the largest reading, unlike on the chip */
+    *chars = esp_adc_cal_characteristics_t {
+        adc_num: 1,
+        atten,
+        bit_width,
+        coeff_a: full_scale_mv(atten),
+        coeff_b: max_raw(bit_width),
+        vref: default_vref,
+        low_curve: core::ptr::null(),
+        high_curve: core::ptr::null(),
+    };
+    ADC1.with(|adc| adc.borrow().calibration)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn esp_adc_cal_raw_to_voltage(
+    adc_reading: u32,
+    chars: *const esp_adc_cal_characteristics_t,
+) -> u32 {
+    let chars = &*chars;
+    // Rounded to nearest, the truncation in `adc1_get_raw` would otherwise add up
+    (adc_reading * chars.coeff_a + chars.coeff_b / 2) / chars.coeff_b
+}
@@ -0,0 +1,208 @@
+//! Fake GPIO matrix
+//!
+//! Outputs remember the level last written. Inputs read whatever a test drives with
+//! `drive`, which also runs the installed ISR handler right away when the edge matches the
+//! configured interrupt type.
+
+use core::cell::RefCell;
+use core::ffi::c_void;
+use host_std::thread_local;
+
+use crate::{
+    esp_err_t, gpio_config_t, gpio_int_type_t, gpio_int_type_t_GPIO_INTR_ANYEDGE,
+    gpio_int_type_t_GPIO_INTR_DISABLE, gpio_int_type_t_GPIO_INTR_HIGH_LEVEL,
+    gpio_int_type_t_GPIO_INTR_LOW_LEVEL, gpio_int_type_t_GPIO_INTR_NEGEDGE,
+    gpio_int_type_t_GPIO_INTR_POSEDGE, gpio_isr_t, gpio_mode_t, gpio_mode_t_GPIO_MODE_DISABLE,
+    gpio_num_t, gpio_pulldown_t_GPIO_PULLDOWN_ENABLE, gpio_pullup_t_GPIO_PULLUP_ENABLE,
+    ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE, ESP_OK,
+};
+
+const PINS: usize = 40;
+
+#[derive(Clone, Copy)]
+struct MockPin {
+    mode: gpio_mode_t,
+    pull_up: bool,
+    pull_down: bool,
+    output: bool,
+    input: bool,
+    intr_type: gpio_int_type_t,
+    intr_enabled: bool,
+    handler: gpio_isr_t,
+    arg: *mut c_void,
+}
+
+impl Default for MockPin {
+    fn default() -> MockPin {
+        MockPin {
+            mode: gpio_mode_t_GPIO_MODE_DISABLE,
+            pull_up: false,
+            pull_down: false,
+            output: false,
+            input: false,
+            intr_type: gpio_int_type_t_GPIO_INTR_DISABLE,
+            intr_enabled: false,
+            handler: None,
+            arg: core::ptr::null_mut(),
+        }
+    }
+}
+
+struct Matrix {
+    pins: [MockPin; PINS],
+    isr_service: bool,
+}
+
+thread_local! {
+    static MATRIX: RefCell<Matrix> = RefCell::new(Matrix {
+        pins: [MockPin::default(); PINS],
+        isr_service: false,
+    });
+}
+
+fn with_pin<R>(num: gpio_num_t, f: impl FnOnce(&mut MockPin) -> R) -> Option<R> {
+    MATRIX.with(|matrix| matrix.borrow_mut().pins.get_mut(num as usize).map(f))
+}
+
+fn checked(num: gpio_num_t, f: impl FnOnce(&mut MockPin)) -> esp_err_t {
+    with_pin(num, f).map_or(ESP_ERR_INVALID_ARG as esp_err_t, |_| ESP_OK as esp_err_t)
+}
+
+/// Mode as last configured, `GPIO_MODE_DISABLE` after a reset
+pub fn mode(num: gpio_num_t) -> gpio_mode_t {
+    with_pin(num, |p| p.mode).unwrap_or(gpio_mode_t_GPIO_MODE_DISABLE)
+}
+
+/// Pull up and pull down enables
+pub fn pulls(num: gpio_num_t) -> (bool, bool) {
+    with_pin(num, |p| (p.pull_up, p.pull_down)).unwrap_or_default()
+}
+
+/// Level last written with `gpio_set_level`
+pub fn output_level(num: gpio_num_t) -> bool {
+    with_pin(num, |p| p.output).unwrap_or(false)
+}
+
+pub fn has_isr(num: gpio_num_t) -> bool {
+    with_pin(num, |p| p.handler.is_some()).unwrap_or(false)
+}
+
+pub fn is_isr_service_installed() -> bool {
+    MATRIX.with(|matrix| matrix.borrow().isr_service)
+}
+
+/// Drives an input from the outside, calling the ISR handler on a matching edge or level
+pub fn drive(num: gpio_num_t, high: bool) {
+    let isr = with_pin(num, |p| {
+        let rising = !p.input && high;
+        let falling = p.input && !high;
+        p.input = high;
+        let fires = match p.intr_type {
+            gpio_int_type_t_GPIO_INTR_POSEDGE => rising,
+            gpio_int_type_t_GPIO_INTR_NEGEDGE => falling,
+            gpio_int_type_t_GPIO_INTR_ANYEDGE => rising || falling,
+            gpio_int_type_t_GPIO_INTR_LOW_LEVEL => !high,
+            gpio_int_type_t_GPIO_INTR_HIGH_LEVEL => high,
+            _ => false,
+        };
+        if fires && p.intr_enabled {
+            p.handler.map(|handler| (handler, p.arg))
+        } else {
+            None
+        }
+    });
+    // The handler may touch the pin again, so it runs outside of the borrow
+    if let Some(Some((handler, arg))) = isr {
+        unsafe { handler(arg) };
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_config(pGPIOConfig: *const gpio_config_t) -> esp_err_t {
+    let config = &*pGPIOConfig;
+    if config.pin_bit_mask >> PINS != 0 {
+        return ESP_ERR_INVALID_ARG as esp_err_t;
+    }
+    for num in (0..PINS).filter(|n| config.pin_bit_mask & (1 << n) != 0) {
+        with_pin(num as gpio_num_t, |p| {
+            p.mode = config.mode;
+            p.pull_up = config.pull_up_en == gpio_pullup_t_GPIO_PULLUP_ENABLE;
+            p.pull_down = config.pull_down_en == gpio_pulldown_t_GPIO_PULLDOWN_ENABLE;
+            p.intr_type = config.intr_type;
+        });
+    }
+    ESP_OK as esp_err_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_reset_pin(gpio_num: gpio_num_t) -> esp_err_t {
+    checked(gpio_num, |p| {
+        // The ISR handler stays registered on real hardware as well
+        *p = MockPin {
+            handler: p.handler,
+            arg: p.arg,
+            ..MockPin::default()
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_set_level(gpio_num: gpio_num_t, level: u32) -> esp_err_t {
+    checked(gpio_num, |p| p.output = level != 0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_get_level(gpio_num: gpio_num_t) -> i32 {
+    with_pin(gpio_num, |p| p.input as i32).unwrap_or(0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_set_intr_type(
+    gpio_num: gpio_num_t,
+    intr_type: gpio_int_type_t,
+) -> esp_err_t {
+    checked(gpio_num, |p| p.intr_type = intr_type)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_intr_enable(gpio_num: gpio_num_t) -> esp_err_t {
+    checked(gpio_num, |p| p.intr_enabled = true)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_install_isr_service(_intr_alloc_flags: i32) -> esp_err_t {
+    MATRIX.with(|matrix| {
+        let mut matrix = matrix.borrow_mut();
+        if matrix.isr_service {
+            return ESP_ERR_INVALID_STATE as esp_err_t;
+        }
+        matrix.isr_service = true;
+        ESP_OK as esp_err_t
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_isr_handler_add(
+    gpio_num: gpio_num_t,
+    isr_handler: gpio_isr_t,
+    args: *mut c_void,
+) -> esp_err_t {
+    if !is_isr_service_installed() {
+        return ESP_ERR_INVALID_STATE as esp_err_t;
+    }
+    checked(gpio_num, |p| {
+        p.handler = isr_handler;
+        p.arg = args;
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gpio_isr_handler_remove(gpio_num: gpio_num_t) -> esp_err_t {
+    if !is_isr_service_installed() {
+        return ESP_ERR_INVALID_STATE as esp_err_t;
+    }
+    checked(gpio_num, |p| {
+        p.handler = None;
+        p.arg = core::ptr::null_mut();
+    })
+}
@@ -0,0 +1,59 @@
+//! Captured log output
+//!
+//! `logging::write` hands finished lines to `record` instead of `esp_log_write`. They are
+//! kept for inspection and echoed to stderr, which the test harness only shows for failing
+//! tests.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use host_std::{eprintln, thread_local};
+
+use super::ticks;
+use crate::logging::Level;
+use crate::portTICK_PERIOD_MS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub level: Level,
+    /// Without the NUL terminator
+    pub tag: String,
+    pub message: String,
+}
+
+thread_local! {
+    static RECORDS: RefCell<Vec<Record>> = RefCell::new(Vec::new());
+}
+
+pub(crate) fn record(level: Level, tag: &str, text: &[u8]) {
+    let message = String::from_utf8_lossy(text).into_owned();
+    eprintln!(
+        "{} ({}) {}: {}",
+        level.letter() as char,
+        unsafe { esp_log_timestamp() },
+        tag,
+        message
+    );
+    RECORDS.with(|records| {
+        records.borrow_mut().push(Record {
+            level,
+            tag: String::from(tag),
+            message,
+        })
+    });
+}
+
+/// Everything logged since the last call
+pub fn take() -> Vec<Record> {
+    RECORDS.with(|records| core::mem::replace(&mut *records.borrow_mut(), Vec::new()))
+}
+
+/// Whether any line logged so far, and not taken yet, contains `text`
+pub fn contains(text: &str) -> bool {
+    RECORDS.with(|records| records.borrow().iter().any(|r| r.message.contains(text)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn esp_log_timestamp() -> u32 {
+    ticks() * portTICK_PERIOD_MS
+}
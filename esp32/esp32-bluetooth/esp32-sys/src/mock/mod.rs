@@ -0,0 +1,165 @@
+//! Pure Rust stand-ins for the ESP-IDF functions used by the safe wrappers
+//!
+//! Enabled by the `host-mock` feature. The functions are exported under their C names so
+//! the `extern` declarations in the bindings resolve to them when linking on the host.
+//!
+//! Peripherals are thread local, every test thread gets its own fake device. Kernel objects,
+//! that is queues, timers, tasks and the tick count, are process wide like on the chip so that
+//! `static`s holding them keep working from every test thread. Tests that use those should
+//! run with `--test-threads=1`.
+
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use host_std::sync::Mutex as HostMutex;
+use host_std::thread_local;
+
+use crate::{portMUX_FREE_VAL, portMUX_TYPE, TickType_t};
+
+pub mod adc;
+pub mod gpio;
+pub mod logging;
+pub mod nimble;
+pub mod nvs;
+pub mod queue;
+pub mod task;
+pub mod timer;
+pub mod uart;
+
+/// Process wide fake state, allocated on first use
+pub(crate) struct Shared<T> {
+    state: AtomicUsize,
+    marker: PhantomData<T>,
+}
+
+// Only ever touched through the mutex, the raw pointers inside belong to the code under test
+unsafe impl<T> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    pub(crate) const fn new() -> Shared<T> {
+        Shared {
+            state: AtomicUsize::new(0),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Default> Shared<T> {
+    /// Runs `f` with the state locked, `f` must not call back into the same `Shared`
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        // A failed assertion in one test shouldn't poison the fake for the rest
+        let mut state = self.mutex().lock().unwrap_or_else(|err| err.into_inner());
+        f(&mut state)
+    }
+
+    fn mutex(&self) -> &HostMutex<T> {
+        let mut state = self.state.load(Ordering::Acquire);
+        if state == 0 {
+            let fresh = Box::into_raw(Box::new(HostMutex::new(T::default()))) as usize;
+            state = match self
+                .state
+                .compare_exchange(0, fresh, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => fresh,
+                Err(winner) => {
+                    drop(unsafe { Box::from_raw(fresh as *mut HostMutex<T>) });
+                    winner
+                }
+            };
+        }
+        unsafe { &*(state as *const HostMutex<T>) }
+    }
+}
+
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// Owner of the critical sections a thread holds, 0 is taken for never initialized muxes
+static NEXT_OWNER: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static CRITICAL_NESTING: Cell<u32> = Cell::new(0);
+    static OWNER: u32 = NEXT_OWNER.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Current fake tick count
+pub fn ticks() -> TickType_t {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Moves the fake clock forward, firing every timer that expires on the way
+pub fn advance(ticks: TickType_t) {
+    let target = self::ticks().wrapping_add(ticks);
+    while let Some(expiry) = timer::next_expiry(target) {
+        TICKS.store(expiry, Ordering::SeqCst);
+        timer::fire_expired(expiry);
+    }
+    TICKS.store(target, Ordering::SeqCst);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTaskGetTickCount() -> TickType_t {
+    ticks()
+}
+
+/// Returns straight away, timers due during the delay fire first
+#[no_mangle]
+pub unsafe extern "C" fn vTaskDelay(xTicksToDelay: TickType_t) {
+    advance(xTicksToDelay);
+}
+
+/// How many critical sections the current thread is inside, 0 outside of any
+pub fn critical_nesting() -> u32 {
+    CRITICAL_NESTING.with(|n| n.get())
+}
+
+/// Spins while another thread holds `mux` like the other core would, nests on this one
+#[no_mangle]
+pub unsafe extern "C" fn vTaskEnterCritical(mux: *mut portMUX_TYPE) {
+    let owner = &*(&mut (*mux).owner as *mut u32 as *const AtomicU32);
+    let me = OWNER.with(|owner| *owner);
+    while owner.load(Ordering::Acquire) != me {
+        if owner
+            .compare_exchange(portMUX_FREE_VAL, me, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            break;
+        }
+        host_std::thread::yield_now();
+    }
+    (*mux).count += 1;
+    CRITICAL_NESTING.with(|n| n.set(n.get() + 1));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vTaskExitCritical(mux: *mut portMUX_TYPE) {
+    assert!(
+        (*mux).count > 0,
+        "vTaskExitCritical without vTaskEnterCritical"
+    );
+    (*mux).count -= 1;
+    CRITICAL_NESTING.with(|n| n.set(n.get() - 1));
+    if (*mux).count == 0 {
+        let owner = &*(&mut (*mux).owner as *mut u32 as *const AtomicU32);
+        owner.store(portMUX_FREE_VAL, Ordering::Release);
+    }
+}
+
+static RANDOM: AtomicU32 = AtomicU32::new(0x2545_f491);
+
+/// Makes `esp_random` start over from `seed`, which must not be 0
+pub fn seed_random(seed: u32) {
+    assert!(seed != 0, "xorshift never leaves 0");
+    RANDOM.store(seed, Ordering::SeqCst);
+}
+
+/// xorshift32, repeatable from the seed unlike the hardware generator
+#[no_mangle]
+pub unsafe extern "C" fn esp_random() -> u32 {
+    let mut x = RANDOM.load(Ordering::SeqCst);
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    RANDOM.store(x, Ordering::SeqCst);
+    x
+}
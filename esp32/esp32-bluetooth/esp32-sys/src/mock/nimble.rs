@@ -0,0 +1,1593 @@
+//! Fake NimBLE host
+//!
+//! Covers the GAP and GATT server calls the firmware makes. Services passed to
+//! `ble_gatts_add_svcs` get their attribute handles in `ble_gatts_start`, numbered the way
+//! NimBLE does and reported through `ble_hs_cfg.gatts_register_cb`. `sync` starts the
+//! server and runs `ble_hs_cfg.sync_cb` like the host task does once the controller is up.
+//!
+//! The test plays the peer: it connects, subscribes, reads and writes attributes, and every
+//! GAP event goes to the callback passed to `ble_gap_adv_start`. Notifications are kept
+//! for inspection. mbufs are a single flat buffer each, with a packet header so
+//! `OS_MBUF_PKTLEN` works on them.
+//!
+//! The security manager is reduced to its outcome: `passkey_action` asks the application
+//! for its part of the key, `pair` finishes pairing and `encrypt` stands for a bonded
+//! central encrypting again. Attributes with `_ENC` or `_AUTHEN` flags refuse access over
+//! connections that aren't encrypted or authenticated like `ble_att_svr_check_perms` does.
+//!
+//! `ble_hs_cfg` is a real global, unlike the rest of the fake state which is thread local.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ffi::c_void;
+use core::fmt::Write;
+use core::mem::{self, size_of, MaybeUninit};
+use core::ptr;
+use core::slice;
+use host_std::thread_local;
+
+use crate::gap::PairingProps;
+use crate::gatt::Request;
+
+use crate::{
+    __BindgenBitfieldUnit, __IncompleteArrayField, ble_addr_t,
+    ble_error_codes_BLE_ERR_CONN_TERM_LOCAL, ble_error_codes_BLE_ERR_REM_USER_CONN_TERM,
+    ble_gap_adv_params, ble_gap_conn_desc, ble_gap_event, ble_gap_event_fn, ble_gap_upd_params,
+    ble_gatt_access_ctxt, ble_gatt_chr_def, ble_gatt_dsc_def, ble_gatt_register_ctxt,
+    ble_gatt_svc_def, ble_hs_adv_fields, ble_sm_io, ble_store_status_event, ble_uuid128_t,
+    ble_uuid16_t, ble_uuid32_t, ble_uuid_t, esp_err_t, os_mbuf, os_mbuf__bindgen_ty_1,
+    os_mbuf_pkthdr, os_mbuf_pkthdr__bindgen_ty_1, TaskFunction_t, BLE_ADDR_RANDOM,
+    BLE_ATT_ERR_INSUFFICIENT_AUTHEN, BLE_ATT_ERR_INSUFFICIENT_ENC, BLE_ATT_ERR_INVALID_HANDLE,
+    BLE_ATT_ERR_READ_NOT_PERMITTED, BLE_ATT_ERR_UNLIKELY, BLE_ATT_ERR_WRITE_NOT_PERMITTED,
+    BLE_ATT_F_READ, BLE_ATT_F_READ_AUTHEN, BLE_ATT_F_READ_ENC, BLE_ATT_F_WRITE,
+    BLE_ATT_F_WRITE_AUTHEN, BLE_ATT_F_WRITE_ENC, BLE_GAP_EVENT_ADV_COMPLETE, BLE_GAP_EVENT_CONNECT,
+    BLE_GAP_EVENT_CONN_UPDATE, BLE_GAP_EVENT_CONN_UPDATE_REQ, BLE_GAP_EVENT_DISCONNECT,
+    BLE_GAP_EVENT_ENC_CHANGE, BLE_GAP_EVENT_L2CAP_UPDATE_REQ, BLE_GAP_EVENT_MTU,
+    BLE_GAP_EVENT_NOTIFY_RX, BLE_GAP_EVENT_NOTIFY_TX, BLE_GAP_EVENT_PASSKEY_ACTION,
+    BLE_GAP_EVENT_PHY_UPDATE_COMPLETE, BLE_GAP_EVENT_REPEAT_PAIRING, BLE_GAP_EVENT_SUBSCRIBE,
+    BLE_GAP_LE_PHY_1M, BLE_GAP_ROLE_SLAVE, BLE_GAP_SUBSCRIBE_REASON_WRITE,
+    BLE_GATT_ACCESS_OP_READ_CHR, BLE_GATT_ACCESS_OP_READ_DSC, BLE_GATT_ACCESS_OP_WRITE_CHR,
+    BLE_GATT_ACCESS_OP_WRITE_DSC, BLE_GATT_CHR_F_INDICATE, BLE_GATT_CHR_F_NOTIFY,
+    BLE_GATT_CHR_F_READ, BLE_GATT_CHR_F_READ_AUTHEN, BLE_GATT_CHR_F_READ_ENC, BLE_GATT_CHR_F_WRITE,
+    BLE_GATT_CHR_F_WRITE_AUTHEN, BLE_GATT_CHR_F_WRITE_ENC, BLE_GATT_CHR_F_WRITE_NO_RSP,
+    BLE_GATT_REGISTER_OP_CHR, BLE_GATT_REGISTER_OP_DSC, BLE_GATT_REGISTER_OP_SVC,
+    BLE_GATT_SVC_TYPE_END, BLE_HS_ADV_MAX_SZ, BLE_HS_ADV_TX_PWR_LVL_AUTO,
+    BLE_HS_ADV_TYPE_APPEARANCE, BLE_HS_ADV_TYPE_COMP_NAME, BLE_HS_ADV_TYPE_COMP_UUIDS128,
+    BLE_HS_ADV_TYPE_COMP_UUIDS16, BLE_HS_ADV_TYPE_COMP_UUIDS32, BLE_HS_ADV_TYPE_FLAGS,
+    BLE_HS_ADV_TYPE_INCOMP_NAME, BLE_HS_ADV_TYPE_INCOMP_UUIDS128, BLE_HS_ADV_TYPE_INCOMP_UUIDS16,
+    BLE_HS_ADV_TYPE_INCOMP_UUIDS32, BLE_HS_ADV_TYPE_MFG_DATA, BLE_HS_ADV_TYPE_TX_PWR_LVL,
+    BLE_HS_CONN_HANDLE_NONE, BLE_HS_EALREADY, BLE_HS_EBUSY, BLE_HS_EDONE, BLE_HS_EINVAL,
+    BLE_HS_EMSGSIZE, BLE_HS_ENOENT, BLE_HS_ENOMEM, BLE_HS_ENOTCONN, BLE_HS_ERR_HCI_BASE,
+    BLE_HS_ETIMEOUT, BLE_L2CAP_CID_ATT, BLE_SM_IOACT_NUMCMP, BLE_UUID_TYPE_128, BLE_UUID_TYPE_16,
+    BLE_UUID_TYPE_32, CONFIG_BT_NIMBLE_MAX_BONDS, ESP_OK, MYNEWT_VAL_BLE_SVC_GAP_DEVICE_NAME,
+    MYNEWT_VAL_BLE_SVC_GAP_DEVICE_NAME_MAX_LENGTH,
+};
+
+#[no_mangle]
+pub static mut ble_hs_cfg: crate::ble_hs_cfg = crate::ble_hs_cfg {
+    gatts_register_cb: None,
+    gatts_register_arg: 0 as *mut c_void,
+    sm_io_cap: 0,
+    _bitfield_1: __BindgenBitfieldUnit::new([0; 1]),
+    sm_our_key_dist: 0,
+    sm_their_key_dist: 0,
+    reset_cb: None,
+    sync_cb: None,
+    store_read_cb: None,
+    store_write_cb: None,
+    store_delete_cb: None,
+    store_status_cb: None,
+    store_status_arg: 0 as *mut c_void,
+};
+
+/// A notification the server sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub conn_handle: u16,
+    pub attr_handle: u16,
+    pub data: Vec<u8>,
+    /// Sent as an indication, which the peer confirms
+    pub indication: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Attr {
+    handle: u16,
+    chr: *const ble_gatt_chr_def,
+    /// Null for characteristic values
+    dsc: *const ble_gatt_dsc_def,
+}
+
+struct Advertiser {
+    params: ble_gap_adv_params,
+    cb: ble_gap_event_fn,
+    arg: *mut c_void,
+}
+
+struct Connection {
+    handle: u16,
+    cb: ble_gap_event_fn,
+    arg: *mut c_void,
+    /// Value handle, notify and indicate as last subscribed
+    subscriptions: Vec<(u16, bool, bool)>,
+    /// Value handle of the indication awaiting confirmation
+    indicating: Option<u16>,
+    /// Interval, latency and supervision timeout in use
+    params: (u16, u16, u16),
+    /// Parameters last asked for with `ble_gap_update_params`
+    requested: Option<ble_gap_upd_params>,
+    rssi: i8,
+    phy: (u8, u8),
+    /// Identity address of the central
+    peer: [u8; 6],
+    encrypted: bool,
+    authenticated: bool,
+    /// Action and passkey, or numeric comparison answer, last passed to `ble_sm_inject_io`
+    injected: Option<(u8, u32)>,
+}
+
+/// Keys kept for a peer
+#[derive(Clone, Copy)]
+struct Bond {
+    peer: [u8; 6],
+    authenticated: bool,
+}
+
+/// What centrals commonly open a connection with: 30 ms, no latency, 720 ms timeout
+const DEFAULT_CONN_PARAMS: (u16, u16, u16) = (24, 0, 72);
+
+struct Host {
+    port_initialized: bool,
+    host_task: TaskFunction_t,
+    pending: Vec<*const ble_gatt_svc_def>,
+    started: bool,
+    next_handle: u16,
+    attrs: Vec<Attr>,
+    device_name: Vec<u8>,
+    address: [u8; 6],
+    adv_data: Vec<u8>,
+    rsp_data: Vec<u8>,
+    advertiser: Option<Advertiser>,
+    connections: Vec<Connection>,
+    notifications: Vec<Notification>,
+    /// Sends still to fail and the code they fail with
+    failing_sends: (usize, i32),
+    mbufs: usize,
+    store_configured: bool,
+    /// Oldest first
+    bonds: Vec<Bond>,
+}
+
+thread_local! {
+    static HOST: RefCell<Host> = RefCell::new(Host {
+        port_initialized: false,
+        host_task: None,
+        pending: Vec::new(),
+        started: false,
+        next_handle: 1,
+        attrs: Vec::new(),
+        device_name: MYNEWT_VAL_BLE_SVC_GAP_DEVICE_NAME.to_vec(),
+        // 24:0a:c4:00:00:01, stored least significant byte first
+        address: [0x01, 0x00, 0x00, 0xc4, 0x0a, 0x24],
+        adv_data: Vec::new(),
+        rsp_data: Vec::new(),
+        advertiser: None,
+        connections: Vec::new(),
+        notifications: Vec::new(),
+        failing_sends: (0, 0),
+        mbufs: 0,
+        store_configured: false,
+        bonds: Vec::new(),
+    });
+}
+
+fn with_host<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+    HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+/// Starts the GATT server and runs the sync callback, as the host task does on boot
+pub fn sync() {
+    unsafe {
+        let rc = ble_gatts_start();
+        assert!(rc == 0, "ble_gatts_start failed; rc={}", rc);
+        if let Some(sync_cb) = ble_hs_cfg.sync_cb {
+            sync_cb();
+        }
+    }
+}
+
+/// Runs the reset callback like the host does after a controller error
+pub fn reset(reason: i32) {
+    unsafe {
+        if let Some(reset_cb) = ble_hs_cfg.reset_cb {
+            reset_cb(reason);
+        }
+    }
+}
+
+pub fn is_port_initialized() -> bool {
+    with_host(|h| h.port_initialized)
+}
+
+/// The function passed to `nimble_port_freertos_init`, the fake never runs it
+pub fn host_task() -> TaskFunction_t {
+    with_host(|h| h.host_task)
+}
+
+pub fn is_started() -> bool {
+    with_host(|h| h.started)
+}
+
+/// Value handle of the first registered characteristic with the given UUID, in the
+/// `ble_uuid_to_str` form such as `0x2a37`
+pub fn find_chr(uuid: &str) -> Option<u16> {
+    with_host(|h| {
+        h.attrs
+            .iter()
+            .find(|a| a.dsc.is_null() && unsafe { uuid_string((*a.chr).uuid) } == uuid)
+            .map(|a| a.handle)
+    })
+}
+
+/// UUID of every registered characteristic value and descriptor, by handle
+pub fn attributes() -> Vec<(u16, String)> {
+    with_host(|h| {
+        h.attrs
+            .iter()
+            .map(|a| {
+                let uuid = unsafe {
+                    if a.dsc.is_null() {
+                        (*a.chr).uuid
+                    } else {
+                        (*a.dsc).uuid
+                    }
+                };
+                (a.handle, unsafe { uuid_string(uuid) })
+            })
+            .collect()
+    })
+}
+
+pub fn set_address(address: [u8; 6]) {
+    with_host(|h| h.address = address);
+}
+
+pub fn device_name() -> String {
+    with_host(|h| String::from_utf8_lossy(&h.device_name[..h.device_name.len() - 1]).into_owned())
+}
+
+pub fn is_advertising() -> bool {
+    with_host(|h| h.advertiser.is_some())
+}
+
+/// Connection and discovery mode of the running advertisement
+pub fn adv_modes() -> Option<(u8, u8)> {
+    with_host(|h| {
+        h.advertiser
+            .as_ref()
+            .map(|a| (a.params.conn_mode, a.params.disc_mode))
+    })
+}
+
+/// Minimum and maximum interval of the running advertisement, 0 for the defaults
+pub fn adv_intervals() -> Option<(u16, u16)> {
+    with_host(|h| {
+        h.advertiser
+            .as_ref()
+            .map(|a| (a.params.itvl_min, a.params.itvl_max))
+    })
+}
+
+/// Advertising payload as last set, raw or encoded from `ble_gap_adv_set_fields`
+pub fn adv_data() -> Vec<u8> {
+    with_host(|h| h.adv_data.clone())
+}
+
+/// Scan response payload as last set
+pub fn scan_rsp_data() -> Vec<u8> {
+    with_host(|h| h.rsp_data.clone())
+}
+
+/// Handles of open connections
+pub fn connections() -> Vec<u16> {
+    with_host(|h| h.connections.iter().map(|c| c.handle).collect())
+}
+
+/// Notifications sent since the last call
+pub fn take_notifications() -> Vec<Notification> {
+    with_host(|h| mem::replace(&mut h.notifications, Vec::new()))
+}
+
+/// mbufs that were allocated and not freed or handed to the host yet
+pub fn live_mbufs() -> usize {
+    with_host(|h| h.mbufs)
+}
+
+/// A central connects to the running advertisement, returns the callback's result
+pub fn connect(conn_handle: u16) -> i32 {
+    let advertiser = with_host(|h| h.advertiser.take()).expect("connect while not advertising");
+    with_host(|h| {
+        h.connections.push(Connection {
+            handle: conn_handle,
+            cb: advertiser.cb,
+            arg: advertiser.arg,
+            subscriptions: Vec::new(),
+            indicating: None,
+            params: DEFAULT_CONN_PARAMS,
+            requested: None,
+            rssi: -60,
+            phy: (BLE_GAP_LE_PHY_1M as u8, BLE_GAP_LE_PHY_1M as u8),
+            peer: peer_address(conn_handle),
+            encrypted: false,
+            authenticated: false,
+            injected: None,
+        })
+    });
+
+    let mut event = new_event(BLE_GAP_EVENT_CONNECT);
+    event.__bindgen_anon_1.connect.status = 0;
+    event.__bindgen_anon_1.connect.conn_handle = conn_handle;
+    deliver(advertiser.cb, advertiser.arg, &mut event)
+}
+
+/// A connection attempt fails with `status`, which ends the advertisement
+pub fn connect_failed(status: i32) -> i32 {
+    let advertiser = with_host(|h| h.advertiser.take()).expect("connect while not advertising");
+    let mut event = new_event(BLE_GAP_EVENT_CONNECT);
+    event.__bindgen_anon_1.connect.status = status;
+    event.__bindgen_anon_1.connect.conn_handle = BLE_HS_CONN_HANDLE_NONE as u16;
+    deliver(advertiser.cb, advertiser.arg, &mut event)
+}
+
+/// The link goes down with `reason`, e.g. `BLE_HS_ERR_HCI_BASE + BLE_ERR_REM_USER_CONN_TERM`
+pub fn disconnect(conn_handle: u16, reason: i32) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_DISCONNECT);
+    event.__bindgen_anon_1.disconnect.reason = reason;
+    unsafe { ble_gap_conn_find(conn_handle, &mut event.__bindgen_anon_1.disconnect.conn) };
+    let conn = with_host(|h| {
+        let index = h.connections.iter().position(|c| c.handle == conn_handle);
+        index.map(|i| h.connections.remove(i))
+    })
+    .expect("disconnect of unknown connection");
+    deliver(conn.cb, conn.arg, &mut event)
+}
+
+/// The peer writes the CCCD of the characteristic with value handle `attr_handle`
+pub fn subscribe(conn_handle: u16, attr_handle: u16, notify: bool, indicate: bool) -> i32 {
+    let (prev_notify, prev_indicate) = with_connection(conn_handle, |c| {
+        let prev = c
+            .subscriptions
+            .iter()
+            .find(|s| s.0 == attr_handle)
+            .map_or((false, false), |s| (s.1, s.2));
+        c.subscriptions.retain(|s| s.0 != attr_handle);
+        c.subscriptions.push((attr_handle, notify, indicate));
+        prev
+    });
+
+    let mut event = new_event(BLE_GAP_EVENT_SUBSCRIBE);
+    unsafe {
+        let subscribe = &mut event.__bindgen_anon_1.subscribe;
+        subscribe.conn_handle = conn_handle;
+        subscribe.attr_handle = attr_handle;
+        subscribe.reason = BLE_GAP_SUBSCRIBE_REASON_WRITE as u8;
+        subscribe.set_prev_notify(prev_notify as u8);
+        subscribe.set_cur_notify(notify as u8);
+        subscribe.set_prev_indicate(prev_indicate as u8);
+        subscribe.set_cur_indicate(indicate as u8);
+    }
+    send_event(conn_handle, &mut event)
+}
+
+/// ATT MTU exchange finished with `mtu`
+pub fn mtu(conn_handle: u16, mtu: u16) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_MTU);
+    event.__bindgen_anon_1.mtu.conn_handle = conn_handle;
+    event.__bindgen_anon_1.mtu.channel_id = BLE_L2CAP_CID_ATT as u16;
+    event.__bindgen_anon_1.mtu.value = mtu;
+    send_event(conn_handle, &mut event)
+}
+
+/// The peer notifies or indicates `data` on one of its own characteristics, the mbuf is
+/// freed after the callback like the host does
+pub fn notify_rx(conn_handle: u16, attr_handle: u16, data: &[u8], indication: bool) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_NOTIFY_RX);
+    let om = new_mbuf(data);
+    unsafe {
+        let rx = &mut event.__bindgen_anon_1.notify_rx;
+        rx.om = om;
+        rx.attr_handle = attr_handle;
+        rx.conn_handle = conn_handle;
+        rx.set_indication(indication as u8);
+    }
+    let rc = send_event(conn_handle, &mut event);
+    unsafe { free_mbuf(om) };
+    rc
+}
+
+/// Interval, latency and supervision timeout last asked for with `ble_gap_update_params`,
+/// cleared by the call
+pub fn take_requested_params(conn_handle: u16) -> Option<(u16, u16, u16, u16)> {
+    with_connection(conn_handle, |c| {
+        c.requested
+            .take()
+            .map(|p| (p.itvl_min, p.itvl_max, p.latency, p.supervision_timeout))
+    })
+}
+
+/// The central changed the connection parameters
+pub fn conn_update(conn_handle: u16, interval: u16, latency: u16, supervision_timeout: u16) -> i32 {
+    with_connection(conn_handle, |c| {
+        c.params = (interval, latency, supervision_timeout)
+    });
+    let mut event = new_event(BLE_GAP_EVENT_CONN_UPDATE);
+    event.__bindgen_anon_1.conn_update.status = 0;
+    event.__bindgen_anon_1.conn_update.conn_handle = conn_handle;
+    send_event(conn_handle, &mut event)
+}
+
+/// The central asks to change the connection parameters, returns the callback's result,
+/// 0 to accept
+///
+/// With `l2cap` the request came over the L2CAP signalling channel rather than the link
+/// layer. Nothing changes until the test follows up with `conn_update`.
+pub fn conn_update_req(conn_handle: u16, peer: (u16, u16, u16, u16), l2cap: bool) -> i32 {
+    let peer_params = ble_gap_upd_params {
+        itvl_min: peer.0,
+        itvl_max: peer.1,
+        latency: peer.2,
+        supervision_timeout: peer.3,
+        min_ce_len: 0,
+        max_ce_len: 0,
+    };
+    let mut self_params = peer_params;
+    let type_ = if l2cap {
+        BLE_GAP_EVENT_L2CAP_UPDATE_REQ
+    } else {
+        BLE_GAP_EVENT_CONN_UPDATE_REQ
+    };
+    let mut event = new_event(type_);
+    event.__bindgen_anon_1.conn_update_req.peer_params = &peer_params;
+    event.__bindgen_anon_1.conn_update_req.self_params = &mut self_params;
+    event.__bindgen_anon_1.conn_update_req.conn_handle = conn_handle;
+    send_event(conn_handle, &mut event)
+}
+
+pub fn set_rssi(conn_handle: u16, rssi: i8) {
+    with_connection(conn_handle, |c| c.rssi = rssi);
+}
+
+/// The PHYs of the connection changed
+pub fn phy_update(conn_handle: u16, tx_phy: u8, rx_phy: u8) -> i32 {
+    with_connection(conn_handle, |c| c.phy = (tx_phy, rx_phy));
+    let mut event = new_event(BLE_GAP_EVENT_PHY_UPDATE_COMPLETE);
+    event.__bindgen_anon_1.phy_updated.status = 0;
+    event.__bindgen_anon_1.phy_updated.conn_handle = conn_handle;
+    event.__bindgen_anon_1.phy_updated.tx_phy = tx_phy;
+    event.__bindgen_anon_1.phy_updated.rx_phy = rx_phy;
+    send_event(conn_handle, &mut event)
+}
+
+/// The advertisement runs out of time
+pub fn adv_timeout() -> i32 {
+    let advertiser = with_host(|h| h.advertiser.take()).expect("not advertising");
+    let mut event = new_event(BLE_GAP_EVENT_ADV_COMPLETE);
+    event.__bindgen_anon_1.adv_complete.reason = BLE_HS_ETIMEOUT as i32;
+    deliver(advertiser.cb, advertiser.arg, &mut event)
+}
+
+/// Identity address of the central that connects with `conn_handle`, a static random
+/// address made from the handle so that reconnecting with the same handle is the same peer
+pub fn peer_address(conn_handle: u16) -> [u8; 6] {
+    let [lo, hi] = conn_handle.to_le_bytes();
+    [lo, hi, 0x00, 0x5e, 0x11, 0xc0]
+}
+
+/// Whether `ble_store_config_init` set up the key store
+pub fn is_store_configured() -> bool {
+    with_host(|h| h.store_configured)
+}
+
+/// Identity addresses of the bonded peers, oldest first
+pub fn bonds() -> Vec<[u8; 6]> {
+    with_host(|h| h.bonds.iter().map(|b| b.peer).collect())
+}
+
+/// The security manager asks for the application's part of pairing, `action` is a
+/// `BLE_SM_IOACT_*` and `numcmp` the number to compare for `BLE_SM_IOACT_NUMCMP`
+pub fn passkey_action(conn_handle: u16, action: u32, numcmp: u32) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_PASSKEY_ACTION);
+    unsafe {
+        let passkey = &mut event.__bindgen_anon_1.passkey;
+        passkey.conn_handle = conn_handle;
+        passkey.params.action = action as u8;
+        passkey.params.numcmp = numcmp;
+    }
+    send_event(conn_handle, &mut event)
+}
+
+/// What the application passed to `ble_sm_inject_io` last: the action and the passkey, or
+/// for numeric comparison whether it accepted, cleared by the call
+pub fn take_injected_io(conn_handle: u16) -> Option<(u8, u32)> {
+    with_connection(conn_handle, |c| c.injected.take())
+}
+
+/// Pairing succeeds, authenticated if a passkey or numeric comparison protected it
+///
+/// The peer is bonded when `ble_hs_cfg` asks for bonding, the oldest bond makes room once
+/// `CONFIG_BT_NIMBLE_MAX_BONDS` are stored. Returns the callback's result for
+/// `BLE_GAP_EVENT_ENC_CHANGE`.
+pub fn pair(conn_handle: u16, authenticated: bool) -> i32 {
+    let bonding = unsafe { ble_hs_cfg.sm_bonding() != 0 };
+    let peer = with_connection(conn_handle, |c| {
+        c.encrypted = true;
+        c.authenticated = authenticated;
+        c.peer
+    });
+    if bonding {
+        with_host(|h| {
+            h.bonds.retain(|b| b.peer != peer);
+            if h.bonds.len() == CONFIG_BT_NIMBLE_MAX_BONDS as usize {
+                h.bonds.remove(0);
+            }
+            h.bonds.push(Bond {
+                peer,
+                authenticated,
+            });
+        });
+    }
+    enc_change(conn_handle, 0)
+}
+
+/// The bonded central encrypts the link with its stored keys
+pub fn encrypt(conn_handle: u16) -> i32 {
+    let peer = with_connection(conn_handle, |c| c.peer);
+    let bond = with_host(|h| h.bonds.iter().find(|b| b.peer == peer).cloned())
+        .expect("encrypt without a bond");
+    with_connection(conn_handle, |c| {
+        c.encrypted = true;
+        c.authenticated = bond.authenticated;
+    });
+    enc_change(conn_handle, 0)
+}
+
+/// Pairing or encryption fails with `status`, e.g. `BLE_HS_ERR_SM_US_BASE +
+/// BLE_SM_ERR_PASSKEY`
+pub fn pairing_failed(conn_handle: u16, status: i32) -> i32 {
+    enc_change(conn_handle, status)
+}
+
+/// A bonded central pairs again, `current` are the properties of its bond and `new` those
+/// the pairing would lead to
+///
+/// Returns the callback's result, `BLE_GAP_REPEAT_PAIRING_RETRY` or
+/// `BLE_GAP_REPEAT_PAIRING_IGNORE`.
+pub fn repeat_pairing(conn_handle: u16, current: PairingProps, new: PairingProps) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_REPEAT_PAIRING);
+    unsafe {
+        let repeat = &mut event.__bindgen_anon_1.repeat_pairing;
+        repeat.conn_handle = conn_handle;
+        repeat.cur_key_size = current.key_size;
+        repeat.set_cur_authenticated(current.authenticated as u8);
+        repeat.set_cur_sc(current.secure_connections as u8);
+        repeat.new_key_size = new.key_size;
+        repeat.set_new_authenticated(new.authenticated as u8);
+        repeat.set_new_sc(new.secure_connections as u8);
+    }
+    send_event(conn_handle, &mut event)
+}
+
+fn enc_change(conn_handle: u16, status: i32) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_ENC_CHANGE);
+    event.__bindgen_anon_1.enc_change.status = status;
+    event.__bindgen_anon_1.enc_change.conn_handle = conn_handle;
+    send_event(conn_handle, &mut event)
+}
+
+/// Hands any GAP event to the callback of a connection, for events without a helper
+pub fn send_event(conn_handle: u16, event: &mut ble_gap_event) -> i32 {
+    let (cb, arg) = with_connection(conn_handle, |c| (c.cb, c.arg));
+    deliver(cb, arg, event)
+}
+
+/// A zeroed event of the given `BLE_GAP_EVENT_*` type
+pub fn new_event(type_: u32) -> ble_gap_event {
+    let mut event: ble_gap_event = unsafe { MaybeUninit::zeroed().assume_init() };
+    event.type_ = type_ as u8;
+    event
+}
+
+/// The peer reads an attribute, `Err` carries the ATT error
+pub fn read(conn_handle: u16, attr_handle: u16) -> Result<Vec<u8>, i32> {
+    access(conn_handle, attr_handle, Op::Read)
+}
+
+/// The peer writes an attribute with a Write Request, `Err` carries the ATT error
+pub fn write(conn_handle: u16, attr_handle: u16, data: &[u8]) -> Result<(), i32> {
+    access(conn_handle, attr_handle, Op::Write(data)).map(|_| ())
+}
+
+/// The peer sends a Write Command
+///
+/// The result is what the access callback returned, a real peer never learns about it.
+pub fn write_no_rsp(conn_handle: u16, attr_handle: u16, data: &[u8]) -> Result<(), i32> {
+    access(conn_handle, attr_handle, Op::WriteNoRsp(data)).map(|_| ())
+}
+
+/// Runs `f` on a request for a synthetic access context, e.g. to call an `Access`
+/// implementation without registering it
+///
+/// `op` is a `BLE_GATT_ACCESS_OP_*` and `data` the written value. Returns the result of `f`
+/// and the bytes in the mbuf afterwards, which for reads are the ones appended.
+pub fn with_request<R>(
+    conn_handle: u16,
+    op: u32,
+    data: &[u8],
+    f: impl FnOnce(&mut Request) -> R,
+) -> (R, Vec<u8>) {
+    let mut ctxt: ble_gatt_access_ctxt = unsafe { MaybeUninit::zeroed().assume_init() };
+    ctxt.op = op as u8;
+    ctxt.om = new_mbuf(data);
+    let result = f(&mut unsafe { Request::new(conn_handle, 0, &mut ctxt) });
+    (result, unsafe { free_mbuf(ctxt.om) })
+}
+
+/// The peer confirms the indication it received last, returns the callback's result
+pub fn confirm(conn_handle: u16) -> i32 {
+    end_indication(conn_handle, BLE_HS_EDONE as i32)
+}
+
+/// The peer never confirms the indication it received last
+pub fn indication_timeout(conn_handle: u16) -> i32 {
+    end_indication(conn_handle, BLE_HS_ETIMEOUT as i32)
+}
+
+/// The next `count` notifications or indications fail with `rc`, e.g. `BLE_HS_ENOMEM`
+pub fn fail_next_sends(count: usize, rc: i32) {
+    with_host(|h| h.failing_sends = (count, rc));
+}
+
+fn end_indication(conn_handle: u16, status: i32) -> i32 {
+    let attr_handle =
+        with_connection(conn_handle, |c| c.indicating.take()).expect("no indication in flight");
+    notify_tx(conn_handle, attr_handle, status, true)
+}
+
+fn notify_tx(conn_handle: u16, attr_handle: u16, status: i32, indication: bool) -> i32 {
+    let mut event = new_event(BLE_GAP_EVENT_NOTIFY_TX);
+    unsafe {
+        let tx = &mut event.__bindgen_anon_1.notify_tx;
+        tx.status = status;
+        tx.conn_handle = conn_handle;
+        tx.attr_handle = attr_handle;
+        tx.set_indication(indication as u8);
+    }
+    send_event(conn_handle, &mut event)
+}
+
+fn with_connection<R>(conn_handle: u16, f: impl FnOnce(&mut Connection) -> R) -> R {
+    with_host(|h| {
+        h.connections
+            .iter_mut()
+            .find(|c| c.handle == conn_handle)
+            .map(f)
+    })
+    .expect("unknown connection")
+}
+
+fn deliver(cb: ble_gap_event_fn, arg: *mut c_void, event: &mut ble_gap_event) -> i32 {
+    // The callback usually calls back into the fake, nothing may be borrowed here
+    match cb {
+        Some(cb) => unsafe { cb(event, arg) },
+        None => 0,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op<'a> {
+    Read,
+    Write(&'a [u8]),
+    WriteNoRsp(&'a [u8]),
+}
+
+fn access(conn_handle: u16, attr_handle: u16, op: Op) -> Result<Vec<u8>, i32> {
+    let attr = with_host(|h| h.attrs.iter().find(|a| a.handle == attr_handle).cloned())
+        .ok_or(BLE_ATT_ERR_INVALID_HANDLE as i32)?;
+
+    let (write, data) = match op {
+        Op::Read => (false, &[][..]),
+        Op::Write(data) | Op::WriteNoRsp(data) => (true, data),
+    };
+    let mut ctxt: ble_gatt_access_ctxt = unsafe { MaybeUninit::zeroed().assume_init() };
+    let (cb, arg) = unsafe {
+        if attr.dsc.is_null() {
+            let chr = &*attr.chr;
+            let needed = match op {
+                Op::Read => BLE_GATT_CHR_F_READ,
+                Op::Write(_) => BLE_GATT_CHR_F_WRITE,
+                Op::WriteNoRsp(_) => BLE_GATT_CHR_F_WRITE_NO_RSP,
+            };
+            check_permission(chr.flags as u32 & needed != 0, write)?;
+            let (enc, authen) = if write {
+                (BLE_GATT_CHR_F_WRITE_ENC, BLE_GATT_CHR_F_WRITE_AUTHEN)
+            } else {
+                (BLE_GATT_CHR_F_READ_ENC, BLE_GATT_CHR_F_READ_AUTHEN)
+            };
+            check_security(
+                conn_handle,
+                chr.flags as u32 & enc != 0,
+                chr.flags as u32 & authen != 0,
+            )?;
+            ctxt.op = if write {
+                BLE_GATT_ACCESS_OP_WRITE_CHR
+            } else {
+                BLE_GATT_ACCESS_OP_READ_CHR
+            } as u8;
+            ctxt.__bindgen_anon_1.chr = attr.chr;
+            (chr.access_cb, chr.arg)
+        } else {
+            // Descriptors only take Write Requests
+            let needed = match op {
+                Op::Read => BLE_ATT_F_READ,
+                Op::Write(_) => BLE_ATT_F_WRITE,
+                Op::WriteNoRsp(_) => 0,
+            };
+            let dsc = &*attr.dsc;
+            check_permission(dsc.att_flags as u32 & needed != 0, write)?;
+            let (enc, authen) = if write {
+                (BLE_ATT_F_WRITE_ENC, BLE_ATT_F_WRITE_AUTHEN)
+            } else {
+                (BLE_ATT_F_READ_ENC, BLE_ATT_F_READ_AUTHEN)
+            };
+            check_security(
+                conn_handle,
+                dsc.att_flags as u32 & enc != 0,
+                dsc.att_flags as u32 & authen != 0,
+            )?;
+            ctxt.op = if write {
+                BLE_GATT_ACCESS_OP_WRITE_DSC
+            } else {
+                BLE_GATT_ACCESS_OP_READ_DSC
+            } as u8;
+            ctxt.__bindgen_anon_1.dsc = attr.dsc;
+            (dsc.access_cb, dsc.arg)
+        }
+    };
+
+    ctxt.om = new_mbuf(data);
+    let rc = match cb {
+        Some(cb) => unsafe { cb(conn_handle, attr_handle, &mut ctxt, arg) },
+        None => BLE_ATT_ERR_UNLIKELY as i32,
+    };
+    // On writes the application may keep the mbuf by clearing `om`
+    let data = if ctxt.om.is_null() {
+        Vec::new()
+    } else {
+        unsafe { free_mbuf(ctxt.om) }
+    };
+
+    if rc == 0 {
+        Ok(data)
+    } else {
+        Err(rc)
+    }
+}
+
+fn check_permission(allowed: bool, write: bool) -> Result<(), i32> {
+    match (allowed, write) {
+        (true, _) => Ok(()),
+        (false, false) => Err(BLE_ATT_ERR_READ_NOT_PERMITTED as i32),
+        (false, true) => Err(BLE_ATT_ERR_WRITE_NOT_PERMITTED as i32),
+    }
+}
+
+/// Local reads without a connection skip the check
+fn check_security(conn_handle: u16, enc: bool, authen: bool) -> Result<(), i32> {
+    if conn_handle == BLE_HS_CONN_HANDLE_NONE as u16 || !(enc || authen) {
+        return Ok(());
+    }
+    let (peer, encrypted, authenticated) =
+        with_connection(conn_handle, |c| (c.peer, c.encrypted, c.authenticated));
+    if !encrypted {
+        // With keys the central only has to encrypt, without it has to pair first
+        let bonded = with_host(|h| h.bonds.iter().any(|b| b.peer == peer));
+        return Err(if bonded {
+            BLE_ATT_ERR_INSUFFICIENT_ENC
+        } else {
+            BLE_ATT_ERR_INSUFFICIENT_AUTHEN
+        } as i32);
+    }
+    if authen && !authenticated {
+        return Err(BLE_ATT_ERR_INSUFFICIENT_AUTHEN as i32);
+    }
+    Ok(())
+}
+
+fn next_handle() -> u16 {
+    with_host(|h| {
+        let handle = h.next_handle;
+        h.next_handle += 1;
+        handle
+    })
+}
+
+unsafe fn register(ctxt: &mut ble_gatt_register_ctxt) {
+    if let Some(register_cb) = ble_hs_cfg.gatts_register_cb {
+        register_cb(ctxt, ble_hs_cfg.gatts_register_arg);
+    }
+}
+
+unsafe fn register_svcs(svcs: *const ble_gatt_svc_def) {
+    let mut svc = svcs;
+    while (*svc).type_ != BLE_GATT_SVC_TYPE_END as u8 {
+        let mut ctxt: ble_gatt_register_ctxt = MaybeUninit::zeroed().assume_init();
+        ctxt.op = BLE_GATT_REGISTER_OP_SVC as u8;
+        ctxt.__bindgen_anon_1.svc.handle = next_handle();
+        ctxt.__bindgen_anon_1.svc.svc_def = svc;
+        register(&mut ctxt);
+
+        let mut chr = (*svc).characteristics;
+        while !chr.is_null() && !(*chr).uuid.is_null() {
+            register_chr(svc, chr);
+            chr = chr.add(1);
+        }
+        svc = svc.add(1);
+    }
+}
+
+unsafe fn register_chr(svc: *const ble_gatt_svc_def, chr: *const ble_gatt_chr_def) {
+    let def_handle = next_handle();
+    let val_handle = next_handle();
+    if !(*chr).val_handle.is_null() {
+        *(*chr).val_handle = val_handle;
+    }
+    with_host(|h| {
+        h.attrs.push(Attr {
+            handle: val_handle,
+            chr,
+            dsc: ptr::null(),
+        })
+    });
+
+    let mut ctxt: ble_gatt_register_ctxt = MaybeUninit::zeroed().assume_init();
+    ctxt.op = BLE_GATT_REGISTER_OP_CHR as u8;
+    ctxt.__bindgen_anon_1.chr.def_handle = def_handle;
+    ctxt.__bindgen_anon_1.chr.val_handle = val_handle;
+    ctxt.__bindgen_anon_1.chr.chr_def = chr;
+    ctxt.__bindgen_anon_1.chr.svc_def = svc;
+    register(&mut ctxt);
+
+    // The CCCD comes right after the value, it isn't reported to the application
+    if (*chr).flags as u32 & (BLE_GATT_CHR_F_NOTIFY | BLE_GATT_CHR_F_INDICATE) != 0 {
+        next_handle();
+    }
+
+    let mut dsc = (*chr).descriptors as *const ble_gatt_dsc_def;
+    while !dsc.is_null() && !(*dsc).uuid.is_null() {
+        let handle = next_handle();
+        with_host(|h| h.attrs.push(Attr { handle, chr, dsc }));
+
+        let mut ctxt: ble_gatt_register_ctxt = MaybeUninit::zeroed().assume_init();
+        ctxt.op = BLE_GATT_REGISTER_OP_DSC as u8;
+        ctxt.__bindgen_anon_1.dsc.handle = handle;
+        ctxt.__bindgen_anon_1.dsc.dsc_def = dsc;
+        ctxt.__bindgen_anon_1.dsc.chr_def = chr;
+        ctxt.__bindgen_anon_1.dsc.svc_def = svc;
+        register(&mut ctxt);
+        dsc = dsc.add(1);
+    }
+}
+
+unsafe fn uuid_string(uuid: *const ble_uuid_t) -> String {
+    let mut buf = [0i8; 37];
+    ble_uuid_to_str(uuid, buf.as_mut_ptr());
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(slice::from_raw_parts(buf.as_ptr() as *const u8, len)).into_owned()
+}
+
+/// mbuf header, packet header and the data it points into
+#[repr(C)]
+struct MockMbuf {
+    om: os_mbuf,
+    pkthdr: os_mbuf_pkthdr,
+    data: Vec<u8>,
+}
+
+impl MockMbuf {
+    fn update(&mut self) {
+        self.om.om_data = self.data.as_mut_ptr();
+        self.om.om_len = self.data.len() as u16;
+        self.pkthdr.omp_len = self.data.len() as u16;
+    }
+}
+
+fn new_mbuf(data: &[u8]) -> *mut os_mbuf {
+    let mut mbuf = Box::new(MockMbuf {
+        om: os_mbuf {
+            om_data: ptr::null_mut(),
+            om_flags: 0,
+            om_pkthdr_len: size_of::<os_mbuf_pkthdr>() as u8,
+            om_len: 0,
+            om_omp: ptr::null_mut(),
+            om_next: os_mbuf__bindgen_ty_1 {
+                sle_next: ptr::null_mut(),
+            },
+            om_databuf: __IncompleteArrayField::new(),
+        },
+        pkthdr: os_mbuf_pkthdr {
+            omp_len: 0,
+            omp_flags: 0,
+            omp_next: os_mbuf_pkthdr__bindgen_ty_1 {
+                stqe_next: ptr::null_mut(),
+            },
+        },
+        data: data.to_vec(),
+    });
+    mbuf.update();
+    with_host(|h| h.mbufs += 1);
+    Box::into_raw(mbuf) as *mut os_mbuf
+}
+
+unsafe fn mbuf<'a>(om: *const os_mbuf) -> &'a mut MockMbuf {
+    &mut *(om as *mut MockMbuf)
+}
+
+unsafe fn free_mbuf(om: *mut os_mbuf) -> Vec<u8> {
+    with_host(|h| h.mbufs -= 1);
+    Box::from_raw(om as *mut MockMbuf).data
+}
+
+fn push_field(out: &mut Vec<u8>, type_: u32, data: &[u8]) {
+    out.push(data.len() as u8 + 1);
+    out.push(type_ as u8);
+    out.extend_from_slice(data);
+}
+
+unsafe fn raw<'a, T>(p: *const T, len: u8) -> &'a [u8] {
+    if p.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(p as *const u8, len as usize * size_of::<T>())
+    }
+}
+
+/// Same field order as `ble_hs_adv_set_fields`, for the fields the firmware uses
+unsafe fn encode_adv(fields: &ble_hs_adv_fields) -> Vec<u8> {
+    let mut out = Vec::new();
+    if fields.flags != 0 {
+        push_field(&mut out, BLE_HS_ADV_TYPE_FLAGS, &[fields.flags]);
+    }
+
+    if fields.num_uuids16 > 0 || fields.uuids16_is_complete() != 0 {
+        let mut data = Vec::new();
+        for uuid in slice::from_raw_parts(fields.uuids16, fields.num_uuids16 as usize) {
+            data.extend_from_slice(&uuid.value.to_le_bytes());
+        }
+        let type_ = if fields.uuids16_is_complete() != 0 {
+            BLE_HS_ADV_TYPE_COMP_UUIDS16
+        } else {
+            BLE_HS_ADV_TYPE_INCOMP_UUIDS16
+        };
+        push_field(&mut out, type_, &data);
+    }
+
+    if fields.num_uuids32 > 0 || fields.uuids32_is_complete() != 0 {
+        let mut data = Vec::new();
+        for uuid in slice::from_raw_parts(fields.uuids32, fields.num_uuids32 as usize) {
+            data.extend_from_slice(&uuid.value.to_le_bytes());
+        }
+        let type_ = if fields.uuids32_is_complete() != 0 {
+            BLE_HS_ADV_TYPE_COMP_UUIDS32
+        } else {
+            BLE_HS_ADV_TYPE_INCOMP_UUIDS32
+        };
+        push_field(&mut out, type_, &data);
+    }
+
+    if fields.num_uuids128 > 0 || fields.uuids128_is_complete() != 0 {
+        let mut data = Vec::new();
+        for uuid in slice::from_raw_parts(fields.uuids128, fields.num_uuids128 as usize) {
+            data.extend_from_slice(&uuid.value);
+        }
+        let type_ = if fields.uuids128_is_complete() != 0 {
+            BLE_HS_ADV_TYPE_COMP_UUIDS128
+        } else {
+            BLE_HS_ADV_TYPE_INCOMP_UUIDS128
+        };
+        push_field(&mut out, type_, &data);
+    }
+
+    if !fields.name.is_null() {
+        let type_ = if fields.name_is_complete() != 0 {
+            BLE_HS_ADV_TYPE_COMP_NAME
+        } else {
+            BLE_HS_ADV_TYPE_INCOMP_NAME
+        };
+        push_field(&mut out, type_, raw(fields.name, fields.name_len));
+    }
+
+    if fields.tx_pwr_lvl_is_present() != 0 {
+        // The controller fills in its real level for the auto value
+        let level = if fields.tx_pwr_lvl as i32 == BLE_HS_ADV_TX_PWR_LVL_AUTO {
+            0
+        } else {
+            fields.tx_pwr_lvl
+        };
+        push_field(&mut out, BLE_HS_ADV_TYPE_TX_PWR_LVL, &[level as u8]);
+    }
+
+    if fields.appearance_is_present() != 0 {
+        push_field(
+            &mut out,
+            BLE_HS_ADV_TYPE_APPEARANCE,
+            &fields.appearance.to_le_bytes(),
+        );
+    }
+
+    if !fields.mfg_data.is_null() {
+        push_field(
+            &mut out,
+            BLE_HS_ADV_TYPE_MFG_DATA,
+            raw(fields.mfg_data, fields.mfg_data_len),
+        );
+    }
+    out
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn esp_nimble_hci_and_controller_init() -> esp_err_t {
+    ESP_OK as esp_err_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nimble_port_init() {
+    with_host(|h| h.port_initialized = true);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nimble_port_run() {}
+
+#[no_mangle]
+pub unsafe extern "C" fn nimble_port_freertos_init(host_task_fn: TaskFunction_t) {
+    with_host(|h| h.host_task = host_task_fn);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nimble_port_freertos_deinit() {
+    with_host(|h| h.host_task = None);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_svc_gap_init() {}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_svc_gatt_init() {}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_svc_gap_device_name() -> *const i8 {
+    // Only valid until the name is set again, as in NimBLE
+    with_host(|h| h.device_name.as_ptr() as *const i8)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_svc_gap_device_name_set(name: *const i8) -> i32 {
+    let len = crate::strlen(name) as usize;
+    if len > MYNEWT_VAL_BLE_SVC_GAP_DEVICE_NAME_MAX_LENGTH as usize {
+        return BLE_HS_EINVAL as i32;
+    }
+    let name = slice::from_raw_parts(name as *const u8, len + 1);
+    with_host(|h| h.device_name = name.to_vec());
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gatts_count_cfg(_defs: *const ble_gatt_svc_def) -> i32 {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gatts_add_svcs(svcs: *const ble_gatt_svc_def) -> i32 {
+    with_host(|h| {
+        if h.started {
+            return BLE_HS_EBUSY as i32;
+        }
+        h.pending.push(svcs);
+        0
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gatts_start() -> i32 {
+    let pending = with_host(|h| {
+        h.started = true;
+        mem::replace(&mut h.pending, Vec::new())
+    });
+    for svcs in pending {
+        register_svcs(svcs);
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_uuid_u16(uuid: *const ble_uuid_t) -> u16 {
+    if (*uuid).type_ == BLE_UUID_TYPE_16 as u8 {
+        (*(uuid as *const ble_uuid16_t)).value
+    } else {
+        0
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_uuid_cmp(uuid1: *const ble_uuid_t, uuid2: *const ble_uuid_t) -> i32 {
+    if (*uuid1).type_ != (*uuid2).type_ {
+        return (*uuid1).type_ as i32 - (*uuid2).type_ as i32;
+    }
+    match (*uuid1).type_ as u32 {
+        BLE_UUID_TYPE_16 => {
+            (*(uuid1 as *const ble_uuid16_t)).value as i32
+                - (*(uuid2 as *const ble_uuid16_t)).value as i32
+        }
+        BLE_UUID_TYPE_32 => {
+            let (a, b) = (
+                (*(uuid1 as *const ble_uuid32_t)).value,
+                (*(uuid2 as *const ble_uuid32_t)).value,
+            );
+            a.cmp(&b) as i32
+        }
+        _ => {
+            let a = &(*(uuid1 as *const ble_uuid128_t)).value;
+            let b = &(*(uuid2 as *const ble_uuid128_t)).value;
+            a.cmp(b) as i32
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_uuid_to_str(uuid: *const ble_uuid_t, dst: *mut i8) -> *mut i8 {
+    let mut text = String::new();
+    match (*uuid).type_ as u32 {
+        BLE_UUID_TYPE_16 => {
+            let _ = write!(text, "0x{:04x}", (*(uuid as *const ble_uuid16_t)).value);
+        }
+        BLE_UUID_TYPE_32 => {
+            let _ = write!(text, "0x{:08x}", (*(uuid as *const ble_uuid32_t)).value);
+        }
+        BLE_UUID_TYPE_128 => {
+            // Stored little endian, printed most significant byte first
+            let value = &(*(uuid as *const ble_uuid128_t)).value;
+            for (i, byte) in value.iter().rev().enumerate() {
+                if i == 4 || i == 6 || i == 8 || i == 10 {
+                    text.push('-');
+                }
+                let _ = write!(text, "{:02x}", byte);
+            }
+        }
+        _ => text.push_str("unknown"),
+    }
+    text.push('\0');
+    ptr::copy_nonoverlapping(text.as_ptr() as *const i8, dst, text.len());
+    dst
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_hs_mbuf_from_flat(buf: *const c_void, len: u16) -> *mut os_mbuf {
+    if buf.is_null() {
+        return new_mbuf(&[]);
+    }
+    new_mbuf(slice::from_raw_parts(buf as *const u8, len as usize))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn os_mbuf_append(m: *mut os_mbuf, arg1: *const c_void, arg2: u16) -> i32 {
+    let mbuf = mbuf(m);
+    mbuf.data
+        .extend_from_slice(slice::from_raw_parts(arg1 as *const u8, arg2 as usize));
+    mbuf.update();
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn os_mbuf_copydata(
+    m: *const os_mbuf,
+    off: i32,
+    len: i32,
+    dst: *mut c_void,
+) -> i32 {
+    let data = &mbuf(m).data;
+    match data.get(off as usize..(off + len) as usize) {
+        Some(src) => {
+            ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+            0
+        }
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn os_mbuf_free_chain(om: *mut os_mbuf) -> i32 {
+    if !om.is_null() {
+        free_mbuf(om);
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_hs_mbuf_to_flat(
+    om: *const os_mbuf,
+    flat: *mut c_void,
+    max_len: u16,
+    out_copy_len: *mut u16,
+) -> i32 {
+    let data = &mbuf(om).data;
+    let len = data.len().min(max_len as usize);
+    ptr::copy_nonoverlapping(data.as_ptr(), flat as *mut u8, len);
+    if !out_copy_len.is_null() {
+        *out_copy_len = len as u16;
+    }
+    if len < data.len() {
+        BLE_HS_EMSGSIZE as i32
+    } else {
+        0
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gattc_notify_custom(
+    conn_handle: u16,
+    att_handle: u16,
+    om: *mut os_mbuf,
+) -> i32 {
+    send_custom(conn_handle, att_handle, om, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gattc_indicate_custom(
+    conn_handle: u16,
+    chr_val_handle: u16,
+    txom: *mut os_mbuf,
+) -> i32 {
+    send_custom(conn_handle, chr_val_handle, txom, true)
+}
+
+/// Like the host, the outcome of notifications and of indications that could not be sent
+/// is reported right away with `BLE_GAP_EVENT_NOTIFY_TX`, see `confirm` for the rest
+unsafe fn send_custom(
+    conn_handle: u16,
+    attr_handle: u16,
+    om: *mut os_mbuf,
+    indication: bool,
+) -> i32 {
+    // The host owns the mbuf even when sending fails
+    let data = free_mbuf(om);
+    let rc = with_host(|h| {
+        let conn = match h.connections.iter_mut().find(|c| c.handle == conn_handle) {
+            Some(conn) => conn,
+            None => return BLE_HS_ENOTCONN as i32,
+        };
+        if h.failing_sends.0 > 0 {
+            h.failing_sends.0 -= 1;
+            return h.failing_sends.1;
+        }
+        if indication {
+            conn.indicating = Some(attr_handle);
+        }
+        h.notifications.push(Notification {
+            conn_handle,
+            attr_handle,
+            data,
+            indication,
+        });
+        0
+    });
+    if rc != BLE_HS_ENOTCONN as i32 && (rc != 0 || !indication) {
+        notify_tx(conn_handle, attr_handle, rc, indication);
+    }
+    rc
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gatts_chr_updated(chr_val_handle: u16) {
+    // Indications only go to connections that did not enable notifications
+    let subscribers: Vec<(u16, bool)> = with_host(|h| {
+        h.connections
+            .iter()
+            .filter_map(|c| {
+                c.subscriptions
+                    .iter()
+                    .find(|s| s.0 == chr_val_handle && (s.1 || s.2))
+                    .map(|s| (c.handle, !s.1))
+            })
+            .collect()
+    });
+    for (conn_handle, indication) in subscribers {
+        // NimBLE reads the current value locally, without a connection
+        if let Ok(data) = access(BLE_HS_CONN_HANDLE_NONE as u16, chr_val_handle, Op::Read) {
+            with_host(|h| {
+                h.notifications.push(Notification {
+                    conn_handle,
+                    attr_handle: chr_val_handle,
+                    data,
+                    indication,
+                })
+            });
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_hs_id_infer_auto(_privacy: i32, out_addr_type: *mut u8) -> i32 {
+    *out_addr_type = crate::BLE_OWN_ADDR_PUBLIC as u8;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_hs_id_copy_addr(
+    _id_addr_type: u8,
+    out_id_addr: *mut u8,
+    out_is_nrpa: *mut i32,
+) -> i32 {
+    let address = with_host(|h| h.address);
+    if !out_id_addr.is_null() {
+        ptr::copy_nonoverlapping(address.as_ptr(), out_id_addr, address.len());
+    }
+    if !out_is_nrpa.is_null() {
+        *out_is_nrpa = 0;
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_adv_set_fields(rsp_fields: *const ble_hs_adv_fields) -> i32 {
+    let data = encode_adv(&*rsp_fields);
+    if data.len() > BLE_HS_ADV_MAX_SZ as usize {
+        return BLE_HS_EMSGSIZE as i32;
+    }
+    with_host(|h| h.adv_data = data);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_adv_set_data(data: *const u8, data_len: i32) -> i32 {
+    if data_len < 0 || data_len > BLE_HS_ADV_MAX_SZ as i32 {
+        return BLE_HS_EMSGSIZE as i32;
+    }
+    let data = raw(data, data_len as u8).to_vec();
+    with_host(|h| h.adv_data = data);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_adv_rsp_set_data(data: *const u8, data_len: i32) -> i32 {
+    if data_len < 0 || data_len > BLE_HS_ADV_MAX_SZ as i32 {
+        return BLE_HS_EMSGSIZE as i32;
+    }
+    let data = raw(data, data_len as u8).to_vec();
+    with_host(|h| h.rsp_data = data);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_adv_start(
+    _own_addr_type: u8,
+    _direct_addr: *const ble_addr_t,
+    _duration_ms: i32,
+    adv_params: *const ble_gap_adv_params,
+    cb: ble_gap_event_fn,
+    cb_arg: *mut c_void,
+) -> i32 {
+    with_host(|h| {
+        if h.advertiser.is_some() {
+            return BLE_HS_EALREADY as i32;
+        }
+        h.advertiser = Some(Advertiser {
+            params: *adv_params,
+            cb,
+            arg: cb_arg,
+        });
+        0
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_adv_stop() -> i32 {
+    match with_host(|h| h.advertiser.take()) {
+        Some(_) => 0,
+        None => BLE_HS_EALREADY as i32,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_adv_active() -> i32 {
+    is_advertising() as i32
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_conn_find(handle: u16, out_desc: *mut ble_gap_conn_desc) -> i32 {
+    let conn = with_host(|h| {
+        h.connections
+            .iter()
+            .find(|c| c.handle == handle)
+            .map(|c| (c.params, c.peer, c.encrypted, c.authenticated))
+    });
+    let ((interval, latency, supervision_timeout), peer, encrypted, authenticated) = match conn {
+        Some(conn) => conn,
+        None => return BLE_HS_ENOTCONN as i32,
+    };
+    let bonded = encrypted && bonds().contains(&peer);
+    if !out_desc.is_null() {
+        let mut desc = MaybeUninit::<ble_gap_conn_desc>::zeroed().assume_init();
+        desc.conn_handle = handle;
+        desc.conn_itvl = interval;
+        desc.conn_latency = latency;
+        desc.supervision_timeout = supervision_timeout;
+        desc.role = BLE_GAP_ROLE_SLAVE as u8;
+        desc.peer_id_addr.type_ = BLE_ADDR_RANDOM as u8;
+        desc.peer_id_addr.val = peer;
+        desc.peer_ota_addr = desc.peer_id_addr;
+        desc.sec_state.set_encrypted(encrypted as u32);
+        desc.sec_state.set_authenticated(authenticated as u32);
+        desc.sec_state.set_bonded(bonded as u32);
+        desc.sec_state.set_key_size(if encrypted { 16 } else { 0 });
+        *out_desc = desc;
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_update_params(
+    conn_handle: u16,
+    params: *const ble_gap_upd_params,
+) -> i32 {
+    with_host(
+        |h| match h.connections.iter_mut().find(|c| c.handle == conn_handle) {
+            Some(c) => {
+                c.requested = Some(*params);
+                0
+            }
+            None => BLE_HS_ENOTCONN as i32,
+        },
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_conn_rssi(conn_handle: u16, out_rssi: *mut i8) -> i32 {
+    with_host(
+        |h| match h.connections.iter().find(|c| c.handle == conn_handle) {
+            Some(c) => {
+                *out_rssi = c.rssi;
+                0
+            }
+            None => BLE_HS_ENOTCONN as i32,
+        },
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_read_le_phy(
+    conn_handle: u16,
+    tx_phy: *mut u8,
+    rx_phy: *mut u8,
+) -> i32 {
+    with_host(
+        |h| match h.connections.iter().find(|c| c.handle == conn_handle) {
+            Some(c) => {
+                *tx_phy = c.phy.0;
+                *rx_phy = c.phy.1;
+                0
+            }
+            None => BLE_HS_ENOTCONN as i32,
+        },
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_terminate(conn_handle: u16, _hci_reason: u8) -> i32 {
+    if !connections().contains(&conn_handle) {
+        return BLE_HS_ENOTCONN as i32;
+    }
+    disconnect(
+        conn_handle,
+        (BLE_HS_ERR_HCI_BASE + ble_error_codes_BLE_ERR_CONN_TERM_LOCAL) as i32,
+    );
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_sm_inject_io(conn_handle: u16, pkey: *mut ble_sm_io) -> i32 {
+    let io = &*pkey;
+    let value = if io.action as u32 == BLE_SM_IOACT_NUMCMP {
+        io.__bindgen_anon_1.numcmp_accept as u32
+    } else {
+        io.__bindgen_anon_1.passkey
+    };
+    with_host(
+        |h| match h.connections.iter_mut().find(|c| c.handle == conn_handle) {
+            Some(c) => {
+                c.injected = Some((io.action, value));
+                0
+            }
+            None => BLE_HS_ENOTCONN as i32,
+        },
+    )
+}
+
+/// The test decides whether the central pairs, see `pair`
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_security_initiate(conn_handle: u16) -> i32 {
+    if !connections().contains(&conn_handle) {
+        return BLE_HS_ENOTCONN as i32;
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_store_config_init() {
+    with_host(|h| h.store_configured = true);
+}
+
+/// Never called, the fake store makes room itself
+#[no_mangle]
+pub unsafe extern "C" fn ble_store_util_status_rr(
+    _event: *mut ble_store_status_event,
+    _arg: *mut c_void,
+) -> i32 {
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_store_util_bonded_peers(
+    out_peer_id_addrs: *mut ble_addr_t,
+    out_num_peers: *mut i32,
+    max_peers: i32,
+) -> i32 {
+    let bonds = bonds();
+    if bonds.len() > max_peers as usize {
+        return BLE_HS_ENOMEM as i32;
+    }
+    for (i, peer) in bonds.iter().enumerate() {
+        *out_peer_id_addrs.add(i) = ble_addr_t {
+            type_: BLE_ADDR_RANDOM as u8,
+            val: *peer,
+        };
+    }
+    *out_num_peers = bonds.len() as i32;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ble_store_util_delete_peer(peer_id_addr: *const ble_addr_t) -> i32 {
+    let peer = (*peer_id_addr).val;
+    with_host(|h| {
+        let before = h.bonds.len();
+        h.bonds.retain(|b| b.peer != peer);
+        if h.bonds.len() == before {
+            BLE_HS_ENOENT as i32
+        } else {
+            0
+        }
+    })
+}
+
+/// Like the host, a connection to the peer is terminated
+#[no_mangle]
+pub unsafe extern "C" fn ble_gap_unpair(peer_addr: *const ble_addr_t) -> i32 {
+    let peer = (*peer_addr).val;
+    let conn_handle = with_host(|h| {
+        h.connections
+            .iter()
+            .find(|c| c.peer == peer)
+            .map(|c| c.handle)
+    });
+    if let Some(conn_handle) = conn_handle {
+        ble_gap_terminate(
+            conn_handle,
+            ble_error_codes_BLE_ERR_REM_USER_CONN_TERM as u8,
+        );
+    }
+    match ble_store_util_delete_peer(peer_addr) {
+        rc if rc == BLE_HS_ENOENT as i32 => 0,
+        rc => rc,
+    }
+}
@@ -0,0 +1,425 @@
+//! In-memory NVS partition
+//!
+//! Values are kept per namespace and key together with their type, the way the flash
+//! format stores them. Like the real driver every write lands immediately, `nvs_commit`
+//! is only counted so tests can check it was called. The partition survives handles being
+//! closed, `erase` wipes it like `nvs_flash_erase`.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ffi::c_void;
+use core::ptr;
+use core::slice;
+use host_std::thread_local;
+use std::os::raw::c_char;
+
+use crate::{
+    esp_err_t, nvs_entry_info_t, nvs_handle_t, nvs_iterator_t, nvs_open_mode_t,
+    nvs_open_mode_t_NVS_READONLY, nvs_type_t, nvs_type_t_NVS_TYPE_ANY, nvs_type_t_NVS_TYPE_BLOB,
+    nvs_type_t_NVS_TYPE_I16, nvs_type_t_NVS_TYPE_I32, nvs_type_t_NVS_TYPE_I64,
+    nvs_type_t_NVS_TYPE_I8, nvs_type_t_NVS_TYPE_STR, nvs_type_t_NVS_TYPE_U16,
+    nvs_type_t_NVS_TYPE_U32, nvs_type_t_NVS_TYPE_U64, nvs_type_t_NVS_TYPE_U8, size_t,
+    ESP_ERR_NVS_INVALID_HANDLE, ESP_ERR_NVS_INVALID_LENGTH, ESP_ERR_NVS_INVALID_NAME,
+    ESP_ERR_NVS_KEY_TOO_LONG, ESP_ERR_NVS_NOT_FOUND, ESP_ERR_NVS_NOT_INITIALIZED,
+    ESP_ERR_NVS_READ_ONLY, ESP_OK,
+};
+
+const MAX_NAME_LEN: usize = 15;
+
+type Key = (String, String);
+
+#[derive(Default)]
+struct Partition {
+    initialized: bool,
+    init_error: Option<esp_err_t>,
+    values: BTreeMap<Key, (nvs_type_t, Vec<u8>)>,
+    handles: Vec<Option<(String, bool)>>,
+    commits: usize,
+}
+
+thread_local! {
+    static PARTITION: RefCell<Partition> = RefCell::new(Default::default());
+}
+
+struct Iter {
+    entries: Vec<(Key, nvs_type_t)>,
+    pos: usize,
+}
+
+/// Makes the next `nvs_flash_init` fail with `code`, e.g. `ESP_ERR_NVS_NO_FREE_PAGES`
+pub fn fail_next_init(code: esp_err_t) {
+    PARTITION.with(|p| p.borrow_mut().init_error = Some(code));
+}
+
+pub fn is_initialized() -> bool {
+    PARTITION.with(|p| p.borrow().initialized)
+}
+
+/// The raw bytes stored under `key`, strings include their NUL
+pub fn stored(namespace: &str, key: &str) -> Option<Vec<u8>> {
+    PARTITION.with(|p| {
+        p.borrow()
+            .values
+            .get(&(namespace.into(), key.into()))
+            .map(|(_, value)| value.clone())
+    })
+}
+
+/// Number of successful `nvs_commit` calls
+pub fn commits() -> usize {
+    PARTITION.with(|p| p.borrow().commits)
+}
+
+/// Handles that were opened and not closed yet
+pub fn open_handles() -> usize {
+    PARTITION.with(|p| p.borrow().handles.iter().filter(|h| h.is_some()).count())
+}
+
+/// Wipes every namespace, open handles stay valid
+pub fn erase() {
+    PARTITION.with(|p| p.borrow_mut().values.clear());
+}
+
+unsafe fn c_name(name: *const c_char) -> Result<String, esp_err_t> {
+    if name.is_null() {
+        return Err(ESP_ERR_NVS_INVALID_NAME as esp_err_t);
+    }
+    let mut len = 0;
+    while *name.add(len) != 0 {
+        len += 1;
+    }
+    if len > MAX_NAME_LEN {
+        return Err(ESP_ERR_NVS_KEY_TOO_LONG as esp_err_t);
+    }
+    let bytes = slice::from_raw_parts(name as *const u8, len);
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Resolves `handle` to its namespace, `write` additionally rejects read only handles
+fn with_handle<R>(
+    handle: nvs_handle_t,
+    write: bool,
+    f: impl FnOnce(&mut Partition, String) -> Result<R, esp_err_t>,
+) -> Result<R, esp_err_t> {
+    PARTITION.with(|p| {
+        let p = &mut *p.borrow_mut();
+        let (namespace, read_only) = p
+            .handles
+            .get((handle as usize).wrapping_sub(1))
+            .and_then(Option::as_ref)
+            .cloned()
+            .ok_or(ESP_ERR_NVS_INVALID_HANDLE as esp_err_t)?;
+        if write && read_only {
+            return Err(ESP_ERR_NVS_READ_ONLY as esp_err_t);
+        }
+        f(p, namespace)
+    })
+}
+
+unsafe fn with_key<R>(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    write: bool,
+    f: impl FnOnce(&mut BTreeMap<Key, (nvs_type_t, Vec<u8>)>, Key) -> Result<R, esp_err_t>,
+) -> Result<R, esp_err_t> {
+    let key = c_name(key)?;
+    with_handle(handle, write, |p, namespace| {
+        f(&mut p.values, (namespace, key))
+    })
+}
+
+fn rc(result: Result<(), esp_err_t>) -> esp_err_t {
+    result.err().unwrap_or(ESP_OK as esp_err_t)
+}
+
+unsafe fn set(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    kind: nvs_type_t,
+    value: &[u8],
+) -> esp_err_t {
+    rc(with_key(handle, key, true, |values, key| {
+        values.insert(key, (kind, value.to_vec()));
+        Ok(())
+    }))
+}
+
+unsafe fn get(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    kind: nvs_type_t,
+) -> Result<Vec<u8>, esp_err_t> {
+    with_key(handle, key, false, |values, key| match values.get(&key) {
+        Some((stored, value)) if *stored == kind => Ok(value.clone()),
+        _ => Err(ESP_ERR_NVS_NOT_FOUND as esp_err_t),
+    })
+}
+
+/// `nvs_get_str` and `nvs_get_blob`, a NULL `out` only asks for the length
+unsafe fn get_var(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    kind: nvs_type_t,
+    out: *mut c_void,
+    length: *mut size_t,
+) -> esp_err_t {
+    rc(get(handle, key, kind).and_then(|value| {
+        if !out.is_null() {
+            if (*length as usize) < value.len() {
+                return Err(ESP_ERR_NVS_INVALID_LENGTH as esp_err_t);
+            }
+            ptr::copy_nonoverlapping(value.as_ptr(), out as *mut u8, value.len());
+        }
+        *length = value.len() as size_t;
+        Ok(())
+    }))
+}
+
+macro_rules! int_values {
+    ($($ty:ty, $kind:ident => $get:ident, $set:ident;)*) => {
+        $(
+            #[no_mangle]
+            pub unsafe extern "C" fn $set(
+                handle: nvs_handle_t,
+                key: *const c_char,
+                value: $ty,
+            ) -> esp_err_t {
+                set(handle, key, $kind, &value.to_le_bytes())
+            }
+
+            #[no_mangle]
+            pub unsafe extern "C" fn $get(
+                handle: nvs_handle_t,
+                key: *const c_char,
+                out_value: *mut $ty,
+            ) -> esp_err_t {
+                rc(get(handle, key, $kind).map(|value| {
+                    let mut bytes = [0; core::mem::size_of::<$ty>()];
+                    bytes.copy_from_slice(&value);
+                    *out_value = <$ty>::from_le_bytes(bytes);
+                }))
+            }
+        )*
+    };
+}
+
+int_values! {
+    u8, nvs_type_t_NVS_TYPE_U8 => nvs_get_u8, nvs_set_u8;
+    i8, nvs_type_t_NVS_TYPE_I8 => nvs_get_i8, nvs_set_i8;
+    u16, nvs_type_t_NVS_TYPE_U16 => nvs_get_u16, nvs_set_u16;
+    i16, nvs_type_t_NVS_TYPE_I16 => nvs_get_i16, nvs_set_i16;
+    u32, nvs_type_t_NVS_TYPE_U32 => nvs_get_u32, nvs_set_u32;
+    i32, nvs_type_t_NVS_TYPE_I32 => nvs_get_i32, nvs_set_i32;
+    u64, nvs_type_t_NVS_TYPE_U64 => nvs_get_u64, nvs_set_u64;
+    i64, nvs_type_t_NVS_TYPE_I64 => nvs_get_i64, nvs_set_i64;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_flash_init() -> esp_err_t {
+    PARTITION.with(|p| {
+        let mut p = p.borrow_mut();
+        match p.init_error.take() {
+            Some(code) => code,
+            None => {
+                p.initialized = true;
+                ESP_OK as esp_err_t
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_flash_erase() -> esp_err_t {
+    PARTITION.with(|p| {
+        let mut p = p.borrow_mut();
+        p.values.clear();
+        p.initialized = false;
+    });
+    ESP_OK as esp_err_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_open(
+    name: *const c_char,
+    open_mode: nvs_open_mode_t,
+    out_handle: *mut nvs_handle_t,
+) -> esp_err_t {
+    let namespace = match c_name(name) {
+        Ok(namespace) => namespace,
+        Err(code) => return code,
+    };
+    PARTITION.with(|p| {
+        let mut p = p.borrow_mut();
+        if !p.initialized {
+            return ESP_ERR_NVS_NOT_INITIALIZED as esp_err_t;
+        }
+        let read_only = open_mode == nvs_open_mode_t_NVS_READONLY;
+        let exists = p.values.keys().any(|(ns, _)| *ns == namespace);
+        if read_only && !exists {
+            return ESP_ERR_NVS_NOT_FOUND as esp_err_t;
+        }
+        p.handles.push(Some((namespace, read_only)));
+        *out_handle = p.handles.len() as nvs_handle_t;
+        ESP_OK as esp_err_t
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_close(handle: nvs_handle_t) {
+    PARTITION.with(|p| {
+        if let Some(h) = p
+            .borrow_mut()
+            .handles
+            .get_mut((handle as usize).wrapping_sub(1))
+        {
+            *h = None;
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_set_str(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    value: *const c_char,
+) -> esp_err_t {
+    let mut len = 0;
+    while *value.add(len) != 0 {
+        len += 1;
+    }
+    let value = slice::from_raw_parts(value as *const u8, len + 1);
+    set(handle, key, nvs_type_t_NVS_TYPE_STR, value)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_get_str(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    out_value: *mut c_char,
+    length: *mut size_t,
+) -> esp_err_t {
+    get_var(
+        handle,
+        key,
+        nvs_type_t_NVS_TYPE_STR,
+        out_value as *mut c_void,
+        length,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_set_blob(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    value: *const c_void,
+    length: size_t,
+) -> esp_err_t {
+    let value = if length == 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(value as *const u8, length as usize)
+    };
+    set(handle, key, nvs_type_t_NVS_TYPE_BLOB, value)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_get_blob(
+    handle: nvs_handle_t,
+    key: *const c_char,
+    out_value: *mut c_void,
+    length: *mut size_t,
+) -> esp_err_t {
+    get_var(handle, key, nvs_type_t_NVS_TYPE_BLOB, out_value, length)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_erase_key(handle: nvs_handle_t, key: *const c_char) -> esp_err_t {
+    rc(with_key(handle, key, true, |values, key| {
+        values
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(ESP_ERR_NVS_NOT_FOUND as esp_err_t)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_erase_all(handle: nvs_handle_t) -> esp_err_t {
+    rc(with_handle(handle, true, |p, namespace| {
+        p.values.retain(|(ns, _), _| *ns != namespace);
+        Ok(())
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_commit(handle: nvs_handle_t) -> esp_err_t {
+    rc(with_handle(handle, false, |p, _| {
+        p.commits += 1;
+        Ok(())
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_entry_find(
+    _part_name: *const c_char,
+    namespace_name: *const c_char,
+    type_: nvs_type_t,
+) -> nvs_iterator_t {
+    let namespace = if namespace_name.is_null() {
+        None
+    } else {
+        c_name(namespace_name).ok()
+    };
+    let entries: Vec<_> = PARTITION.with(|p| {
+        p.borrow()
+            .values
+            .iter()
+            .filter(|((ns, _), (kind, _))| {
+                namespace.as_ref().map_or(true, |n| n == ns)
+                    && (type_ == nvs_type_t_NVS_TYPE_ANY || type_ == *kind)
+            })
+            .map(|(key, (kind, _))| (key.clone(), *kind))
+            .collect()
+    });
+    if entries.is_empty() {
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(Iter { entries, pos: 0 })) as nvs_iterator_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_entry_next(iterator: nvs_iterator_t) -> nvs_iterator_t {
+    let iter = &mut *(iterator as *mut Iter);
+    iter.pos += 1;
+    if iter.pos < iter.entries.len() {
+        iterator
+    } else {
+        nvs_release_iterator(iterator);
+        ptr::null_mut()
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_entry_info(iterator: nvs_iterator_t, out_info: *mut nvs_entry_info_t) {
+    let iter = &*(iterator as *const Iter);
+    let ((namespace, key), kind) = &iter.entries[iter.pos];
+    let info = &mut *out_info;
+    info.namespace_name = [0; 16];
+    info.key = [0; 16];
+    for (dst, src) in info.namespace_name.iter_mut().zip(namespace.bytes()) {
+        *dst = src as c_char;
+    }
+    for (dst, src) in info.key.iter_mut().zip(key.bytes()) {
+        *dst = src as c_char;
+    }
+    info.type_ = *kind;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn nvs_release_iterator(iterator: nvs_iterator_t) {
+    if !iterator.is_null() {
+        drop(Box::from_raw(iterator as *mut Iter));
+    }
+}
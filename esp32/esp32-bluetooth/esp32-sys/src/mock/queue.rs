@@ -0,0 +1,255 @@
+//! Fake FreeRTOS queues
+//!
+//! Items are stored as byte copies like the real kernel does. Nothing ever blocks, a send
+//! to a full queue or a receive from an empty one fails straight away as if the timeout
+//! had passed. Mutexes and semaphores are queues of empty items here just like in the
+//! kernel, a recursive mutex additionally counts how often it was taken. With a single
+//! thread per fake device the holder is always the caller.
+//!
+//! Queues are process wide like on the chip, see `mock::Shared`.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::ptr;
+use core::slice;
+
+use super::Shared;
+use crate::{
+    queueOVERWRITE, queueQUEUE_TYPE_COUNTING_SEMAPHORE, queueSEND_TO_FRONT, BaseType_t,
+    QueueHandle_t, TickType_t, UBaseType_t,
+};
+
+const PASS: BaseType_t = 1;
+const FAIL: BaseType_t = 0;
+
+struct MockQueue {
+    capacity: usize,
+    item_size: usize,
+    kind: u8,
+    items: VecDeque<Vec<u8>>,
+    recursion: u32,
+}
+
+static QUEUES: Shared<Vec<Option<MockQueue>>> = Shared::new();
+
+fn with_queue<R>(handle: QueueHandle_t, f: impl FnOnce(&mut MockQueue) -> R) -> Option<R> {
+    let index = (handle as usize).wrapping_sub(1);
+    QUEUES.with(|queues| queues.get_mut(index).and_then(Option::as_mut).map(f))
+}
+
+/// Creates a queue from Rust, e.g. for the event queue of a fake driver
+pub(crate) fn create(capacity: usize, item_size: usize, kind: u8) -> QueueHandle_t {
+    QUEUES.with(|queues| {
+        queues.push(Some(MockQueue {
+            capacity,
+            item_size,
+            kind,
+            items: VecDeque::new(),
+            recursion: 0,
+        }));
+        queues.len() as QueueHandle_t
+    })
+}
+
+/// Appends a raw item from Rust, fails like `xQueueSend` when the queue is full
+pub(crate) fn push(handle: QueueHandle_t, item: &[u8]) -> bool {
+    with_queue(handle, |q| {
+        if q.items.len() >= q.capacity {
+            return false;
+        }
+        q.items.push_back(item[..q.item_size].to_vec());
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Items waiting in the queue, 0 for deleted ones
+pub fn len(handle: QueueHandle_t) -> usize {
+    with_queue(handle, |q| q.items.len()).unwrap_or(0)
+}
+
+/// The `ucQueueType` the queue was created with
+pub fn kind(handle: QueueHandle_t) -> Option<u8> {
+    with_queue(handle, |q| q.kind)
+}
+
+pub fn is_deleted(handle: QueueHandle_t) -> bool {
+    with_queue(handle, |_| ()).is_none()
+}
+
+/// Queues that were created and not deleted yet
+pub fn live_count() -> usize {
+    QUEUES.with(|queues| queues.iter().filter(|q| q.is_some()).count())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueGenericCreate(
+    uxQueueLength: UBaseType_t,
+    uxItemSize: UBaseType_t,
+    ucQueueType: u8,
+) -> QueueHandle_t {
+    if uxQueueLength == 0 {
+        return ptr::null_mut();
+    }
+    create(uxQueueLength as usize, uxItemSize as usize, ucQueueType)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueCreateMutex(ucQueueType: u8) -> QueueHandle_t {
+    let handle = create(1, 0, ucQueueType);
+    push(handle, &[]);
+    handle
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueCreateCountingSemaphore(
+    uxMaxCount: UBaseType_t,
+    uxInitialCount: UBaseType_t,
+) -> QueueHandle_t {
+    let handle = create(uxMaxCount as usize, 0, queueQUEUE_TYPE_COUNTING_SEMAPHORE);
+    for _ in 0..uxInitialCount {
+        push(handle, &[]);
+    }
+    handle
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueTakeMutexRecursive(
+    xMutex: QueueHandle_t,
+    _xTicksToWait: TickType_t,
+) -> BaseType_t {
+    with_queue(xMutex, |q| {
+        if q.recursion > 0 {
+            q.recursion += 1;
+            PASS
+        } else if q.items.pop_front().is_some() {
+            q.recursion = 1;
+            PASS
+        } else {
+            FAIL
+        }
+    })
+    .unwrap_or(FAIL)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueGiveMutexRecursive(pxMutex: QueueHandle_t) -> BaseType_t {
+    with_queue(pxMutex, |q| {
+        if q.recursion == 0 {
+            return FAIL;
+        }
+        q.recursion -= 1;
+        if q.recursion == 0 {
+            q.items.push_back(Vec::new());
+        }
+        PASS
+    })
+    .unwrap_or(FAIL)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueGiveFromISR(
+    xQueue: QueueHandle_t,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    xQueueGenericSendFromISR(xQueue, ptr::null(), pxHigherPriorityTaskWoken, 0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueGenericSend(
+    xQueue: QueueHandle_t,
+    pvItemToQueue: *const c_void,
+    _xTicksToWait: TickType_t,
+    xCopyPosition: BaseType_t,
+) -> BaseType_t {
+    with_queue(xQueue, |q| {
+        let item = if q.item_size == 0 {
+            Vec::new()
+        } else {
+            slice::from_raw_parts(pvItemToQueue as *const u8, q.item_size).to_vec()
+        };
+
+        if xCopyPosition == queueOVERWRITE {
+            // Only meant for queues of length 1
+            q.items.clear();
+        } else if q.items.len() >= q.capacity {
+            return FAIL;
+        }
+
+        if xCopyPosition == queueSEND_TO_FRONT {
+            q.items.push_front(item);
+        } else {
+            q.items.push_back(item);
+        }
+        PASS
+    })
+    .unwrap_or(FAIL)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueGenericSendFromISR(
+    xQueue: QueueHandle_t,
+    pvItemToQueue: *const c_void,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+    xCopyPosition: BaseType_t,
+) -> BaseType_t {
+    if !pxHigherPriorityTaskWoken.is_null() {
+        *pxHigherPriorityTaskWoken = 0;
+    }
+    xQueueGenericSend(xQueue, pvItemToQueue, 0, xCopyPosition)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueGenericReceive(
+    xQueue: QueueHandle_t,
+    pvBuffer: *mut c_void,
+    _xTicksToWait: TickType_t,
+    xJustPeek: BaseType_t,
+) -> BaseType_t {
+    with_queue(xQueue, |q| {
+        let item = if xJustPeek != 0 {
+            q.items.front().cloned()
+        } else {
+            q.items.pop_front()
+        };
+        match item {
+            Some(item) => {
+                // Semaphore takes pass no buffer at all
+                if !item.is_empty() {
+                    ptr::copy_nonoverlapping(item.as_ptr(), pvBuffer as *mut u8, item.len());
+                }
+                PASS
+            }
+            None => FAIL,
+        }
+    })
+    .unwrap_or(FAIL)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xQueueReceiveFromISR(
+    xQueue: QueueHandle_t,
+    pvBuffer: *mut c_void,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    if !pxHigherPriorityTaskWoken.is_null() {
+        *pxHigherPriorityTaskWoken = 0;
+    }
+    xQueueGenericReceive(xQueue, pvBuffer, 0, 0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uxQueueMessagesWaiting(xQueue: QueueHandle_t) -> UBaseType_t {
+    len(xQueue) as UBaseType_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vQueueDelete(xQueue: QueueHandle_t) {
+    let index = (xQueue as usize).wrapping_sub(1);
+    QUEUES.with(|queues| {
+        if let Some(q) = queues.get_mut(index) {
+            *q = None;
+        }
+    });
+}
@@ -0,0 +1,349 @@
+//! Fake FreeRTOS tasks, each one a host thread
+//!
+//! `xTaskCreatePinnedToCore` starts a thread that runs the task function right away.
+//! Priorities and cores are recorded, not enforced. A task that suspends itself blocks
+//! until it is resumed or deleted, suspending another task is only recorded. Host threads
+//! can't be killed, so a deleted task's thread blocks for good the next time it suspends,
+//! waits for a notification or deletes itself.
+//!
+//! Threads that weren't created as tasks, like the test's own, get a handle the first time
+//! they ask for one. Unlike the kernel time doesn't stop while a task waits for a
+//! notification: a finite timeout waits as long on the host clock.
+//!
+//! Tasks are process wide like on the chip, see `mock::Shared`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::ffi::CStr;
+use host_std::thread::{self, sleep};
+use host_std::thread_local;
+use host_std::time::{Duration, Instant};
+
+use super::Shared;
+use crate::{
+    configTICK_RATE_HZ, eNotifyAction, eNotifyAction_eIncrement, eNotifyAction_eNoAction,
+    eNotifyAction_eSetBits, eNotifyAction_eSetValueWithOverwrite,
+    eNotifyAction_eSetValueWithoutOverwrite, errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY, pdPASS,
+    portMAX_DELAY, size_t, BaseType_t, TaskFunction_t, TaskHandle_t, TickType_t, UBaseType_t,
+};
+
+const FAIL: BaseType_t = 0;
+
+/// Largest free block of internal RAM unless a test sets another
+const LARGEST_FREE_BLOCK: size_t = 160 * 1024;
+
+/// How long `wait_for` waits for a task before failing the test
+const WAIT_LIMIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Suspended,
+    Deleted,
+}
+
+/// What a task was created with and where it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub name: String,
+    pub stack_size: u32,
+    pub priority: UBaseType_t,
+    pub core: BaseType_t,
+    pub state: State,
+}
+
+struct MockTask {
+    info: TaskInfo,
+    notification: u32,
+    notified: bool,
+}
+
+#[derive(Default)]
+struct Tasks {
+    tasks: Vec<MockTask>,
+    largest_free_block: Option<size_t>,
+    failing_creates: usize,
+}
+
+static TASKS: Shared<Tasks> = Shared::new();
+
+thread_local! {
+    static CURRENT: Cell<usize> = Cell::new(0);
+}
+
+fn with_task<R>(handle: TaskHandle_t, f: impl FnOnce(&mut MockTask) -> R) -> R {
+    let index = (handle as usize).wrapping_sub(1);
+    TASKS.with(|tasks| f(tasks.tasks.get_mut(index).expect("unknown task handle")))
+}
+
+fn add(info: TaskInfo) -> TaskHandle_t {
+    TASKS.with(|tasks| {
+        tasks.tasks.push(MockTask {
+            info,
+            notification: 0,
+            notified: false,
+        });
+        tasks.tasks.len() as TaskHandle_t
+    })
+}
+
+/// The calling thread's task, created for threads that didn't start as one
+fn current() -> TaskHandle_t {
+    let handle = CURRENT.with(|c| c.get());
+    if handle != 0 {
+        return handle as TaskHandle_t;
+    }
+    let handle = add(TaskInfo {
+        name: String::from(thread::current().name().unwrap_or("thread")),
+        stack_size: 0,
+        priority: 1,
+        core: 0,
+        state: State::Running,
+    });
+    CURRENT.with(|c| c.set(handle as usize));
+    handle
+}
+
+fn or_current(handle: TaskHandle_t) -> TaskHandle_t {
+    if handle.is_null() {
+        current()
+    } else {
+        handle
+    }
+}
+
+fn state_of(handle: TaskHandle_t) -> State {
+    with_task(handle, |t| t.info.state)
+}
+
+/// What becomes of the calling thread once its task is deleted
+fn park_forever() -> ! {
+    loop {
+        thread::park();
+    }
+}
+
+/// Blocks the calling task while `blocked` holds, for good once the task is deleted
+fn block_while(mut blocked: impl FnMut(&mut MockTask) -> bool, timeout: Option<Duration>) {
+    let handle = current();
+    let start = Instant::now();
+    loop {
+        let (state, blocked) = with_task(handle, |t| (t.info.state, blocked(t)));
+        if state == State::Deleted {
+            park_forever();
+        }
+        if !blocked || timeout.map_or(false, |timeout| start.elapsed() >= timeout) {
+            return;
+        }
+        sleep(Duration::from_millis(1));
+    }
+}
+
+pub fn info(handle: TaskHandle_t) -> TaskInfo {
+    with_task(handle, |t| t.info.clone())
+}
+
+pub fn state(handle: TaskHandle_t) -> State {
+    state_of(handle)
+}
+
+/// Blocks the test until the task is in `state`, fails it after a few seconds
+pub fn wait_for(handle: TaskHandle_t, state: State) {
+    let start = Instant::now();
+    while state_of(handle) != state {
+        assert!(
+            start.elapsed() < WAIT_LIMIT,
+            "task {:?} never got {:?}",
+            info(handle).name,
+            state
+        );
+        sleep(Duration::from_millis(1));
+    }
+}
+
+/// Tasks created with `xTaskCreatePinnedToCore` and not yet deleted
+pub fn live_count() -> usize {
+    TASKS.with(|tasks| {
+        tasks
+            .tasks
+            .iter()
+            .filter(|t| t.info.stack_size != 0 && t.info.state != State::Deleted)
+            .count()
+    })
+}
+
+/// What `heap_caps_get_largest_free_block` returns, `None` for the default
+pub fn set_largest_free_block(size: Option<size_t>) {
+    TASKS.with(|tasks| tasks.largest_free_block = size);
+}
+
+/// The next `count` task creations fail as if the stack couldn't be allocated
+pub fn fail_next_creates(count: usize) {
+    TASKS.with(|tasks| tasks.failing_creates = count);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn heap_caps_get_largest_free_block(_caps: u32) -> size_t {
+    TASKS.with(|tasks| tasks.largest_free_block.unwrap_or(LARGEST_FREE_BLOCK))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTaskCreatePinnedToCore(
+    pvTaskCode: TaskFunction_t,
+    pcName: *const ::std::os::raw::c_char,
+    usStackDepth: u32,
+    pvParameters: *mut c_void,
+    uxPriority: UBaseType_t,
+    pvCreatedTask: *mut TaskHandle_t,
+    xCoreID: BaseType_t,
+) -> BaseType_t {
+    let failing = TASKS.with(|tasks| {
+        let failing = tasks.failing_creates > 0;
+        if failing {
+            tasks.failing_creates -= 1;
+        }
+        failing
+    });
+    if failing {
+        return errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY;
+    }
+
+    let name = String::from(CStr::from_ptr(pcName).to_str().expect("task name"));
+    let handle = add(TaskInfo {
+        name: name.clone(),
+        stack_size: usStackDepth,
+        priority: uxPriority,
+        core: xCoreID,
+        state: State::Running,
+    });
+    if !pvCreatedTask.is_null() {
+        *pvCreatedTask = handle;
+    }
+
+    let code = pvTaskCode.expect("task function");
+    // Raw pointers aren't Send, the task owns what they point to
+    let (task, parameters) = (handle as usize, pvParameters as usize);
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            CURRENT.with(|c| c.set(task));
+            code(parameters as *mut c_void);
+            panic!("FreeRTOS task function returned");
+        })
+        .expect("task thread");
+    pdPASS as BaseType_t
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTaskGetCurrentTaskHandle() -> TaskHandle_t {
+    current()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vTaskDelete(xTaskToDelete: TaskHandle_t) {
+    let handle = or_current(xTaskToDelete);
+    with_task(handle, |t| t.info.state = State::Deleted);
+    if handle == current() {
+        park_forever();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vTaskSuspend(xTaskToSuspend: TaskHandle_t) {
+    let handle = or_current(xTaskToSuspend);
+    with_task(handle, |t| {
+        if t.info.state == State::Running {
+            t.info.state = State::Suspended;
+        }
+    });
+    if handle == current() {
+        block_while(|t| t.info.state == State::Suspended, None);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vTaskResume(xTaskToResume: TaskHandle_t) {
+    with_task(xTaskToResume, |t| {
+        if t.info.state == State::Suspended {
+            t.info.state = State::Running;
+        }
+    });
+}
+
+/// The whole stack, the fake never uses any of it
+#[no_mangle]
+pub unsafe extern "C" fn uxTaskGetStackHighWaterMark(xTask: TaskHandle_t) -> UBaseType_t {
+    info(or_current(xTask)).stack_size
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTaskNotify(
+    xTaskToNotify: TaskHandle_t,
+    ulValue: u32,
+    eAction: eNotifyAction,
+) -> BaseType_t {
+    with_task(xTaskToNotify, |t| {
+        match eAction {
+            eNotifyAction_eNoAction => {}
+            eNotifyAction_eSetBits => t.notification |= ulValue,
+            eNotifyAction_eIncrement => t.notification = t.notification.wrapping_add(1),
+            eNotifyAction_eSetValueWithOverwrite => t.notification = ulValue,
+            eNotifyAction_eSetValueWithoutOverwrite if t.notified => return FAIL,
+            eNotifyAction_eSetValueWithoutOverwrite => t.notification = ulValue,
+            _ => panic!("unknown eNotifyAction {}", eAction),
+        }
+        t.notified = true;
+        pdPASS as BaseType_t
+    })
+}
+
+/// Never wakes a higher priority task, there are no priorities here
+#[no_mangle]
+pub unsafe extern "C" fn xTaskNotifyFromISR(
+    xTaskToNotify: TaskHandle_t,
+    ulValue: u32,
+    eAction: eNotifyAction,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    if !pxHigherPriorityTaskWoken.is_null() {
+        *pxHigherPriorityTaskWoken = 0;
+    }
+    xTaskNotify(xTaskToNotify, ulValue, eAction)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTaskNotifyWait(
+    ulBitsToClearOnEntry: u32,
+    ulBitsToClearOnExit: u32,
+    pulNotificationValue: *mut u32,
+    xTicksToWait: TickType_t,
+) -> BaseType_t {
+    let handle = current();
+    with_task(handle, |t| {
+        if !t.notified {
+            t.notification &= !ulBitsToClearOnEntry;
+        }
+    });
+    let timeout = if xTicksToWait == portMAX_DELAY {
+        None
+    } else {
+        Some(Duration::from_millis(
+            xTicksToWait as u64 * 1000 / configTICK_RATE_HZ as u64,
+        ))
+    };
+    block_while(|t| !t.notified, timeout);
+
+    with_task(handle, |t| {
+        if !pulNotificationValue.is_null() {
+            *pulNotificationValue = t.notification;
+        }
+        if !t.notified {
+            return FAIL;
+        }
+        t.notification &= !ulBitsToClearOnExit;
+        t.notified = false;
+        pdPASS as BaseType_t
+    })
+}
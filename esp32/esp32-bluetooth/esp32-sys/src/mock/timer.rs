@@ -0,0 +1,200 @@
+//! Fake FreeRTOS software timers
+//!
+//! Commands take effect immediately instead of going through the timer service queue and
+//! expiries only happen when a test moves the clock with `mock::advance`. Timers are process
+//! wide like on the chip, see `mock::Shared`.
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{ticks, Shared};
+use crate::timer::Timer;
+use crate::{
+    pdPASS, BaseType_t, PendedFunction_t, TickType_t, TimerCallbackFunction_t, TimerCommand,
+    TimerHandle_t, UBaseType_t,
+};
+
+struct MockTimer {
+    period: TickType_t,
+    auto_reload: bool,
+    active: bool,
+    deleted: bool,
+    expiry: TickType_t,
+    id: *mut c_void,
+    callback: TimerCallbackFunction_t,
+}
+
+static TIMERS: Shared<Vec<MockTimer>> = Shared::new();
+
+/// Commands still to refuse, see `fail_next_commands`
+static FAILING: AtomicUsize = AtomicUsize::new(0);
+
+fn handle_of(index: usize) -> TimerHandle_t {
+    (index + 1) as TimerHandle_t
+}
+
+fn with_timer<R>(handle: TimerHandle_t, f: impl FnOnce(&mut MockTimer) -> R) -> R {
+    TIMERS.with(|timers| {
+        let timer = &mut timers[handle as usize - 1];
+        assert!(!timer.deleted, "use of deleted timer {:?}", handle);
+        f(timer)
+    })
+}
+
+/// Number of timers created and not yet deleted
+pub fn live_count() -> usize {
+    TIMERS.with(|timers| timers.iter().filter(|t| !t.deleted).count())
+}
+
+pub fn is_deleted(handle: TimerHandle_t) -> bool {
+    TIMERS.with(|timers| timers[handle as usize - 1].deleted)
+}
+
+/// Tick at which an active timer will fire next
+pub fn expiry(handle: TimerHandle_t) -> Option<TickType_t> {
+    with_timer(handle, |t| if t.active { Some(t.expiry) } else { None })
+}
+
+/// Refuses the next `count` commands as if the timer service queue stayed full
+pub fn fail_next_commands(count: usize) {
+    FAILING.store(count, Ordering::SeqCst);
+}
+
+/// Runs the callback of `timer` right away, as if it had just expired
+pub fn fire(timer: &Timer) {
+    run(timer.handle());
+}
+
+fn run(handle: TimerHandle_t) {
+    let callback = with_timer(handle, |t| {
+        if t.auto_reload {
+            t.expiry = ticks().wrapping_add(t.period);
+        } else {
+            t.active = false;
+        }
+        t.callback
+    });
+    if let Some(callback) = callback {
+        unsafe { callback(handle) };
+    }
+}
+
+pub(super) fn next_expiry(limit: TickType_t) -> Option<TickType_t> {
+    let now = ticks();
+    TIMERS.with(|timers| {
+        timers
+            .iter()
+            .filter(|t| t.active && !t.deleted)
+            .map(|t| t.expiry)
+            .filter(|&e| e.wrapping_sub(now) <= limit.wrapping_sub(now))
+            .min_by_key(|&e| e.wrapping_sub(now))
+    })
+}
+
+pub(super) fn fire_expired(now: TickType_t) {
+    // Callbacks may command timers, so the table is not borrowed while they run
+    let due: Vec<TimerHandle_t> = TIMERS.with(|timers| {
+        timers
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.active && !t.deleted && t.expiry == now)
+            .map(|(i, _)| handle_of(i))
+            .collect()
+    });
+    for handle in due {
+        if !is_deleted(handle) && expiry(handle) == Some(now) {
+            run(handle);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTimerCreate(
+    _pcTimerName: *const ::std::os::raw::c_char,
+    xTimerPeriodInTicks: TickType_t,
+    uxAutoReload: UBaseType_t,
+    pvTimerID: *mut c_void,
+    pxCallbackFunction: TimerCallbackFunction_t,
+) -> TimerHandle_t {
+    TIMERS.with(|timers| {
+        timers.push(MockTimer {
+            period: xTimerPeriodInTicks,
+            auto_reload: uxAutoReload != 0,
+            active: false,
+            deleted: false,
+            expiry: 0,
+            id: pvTimerID,
+            callback: pxCallbackFunction,
+        });
+        handle_of(timers.len() - 1)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTimerGenericCommand(
+    xTimer: TimerHandle_t,
+    xCommandID: BaseType_t,
+    xOptionalValue: TickType_t,
+    _pxHigherPriorityTaskWoken: *mut BaseType_t,
+    _xTicksToWait: TickType_t,
+) -> BaseType_t {
+    const START: BaseType_t = TimerCommand::tmrCOMMAND_START as BaseType_t;
+    const RESET: BaseType_t = TimerCommand::tmrCOMMAND_RESET as BaseType_t;
+    const STOP: BaseType_t = TimerCommand::tmrCOMMAND_STOP as BaseType_t;
+    const CHANGE_PERIOD: BaseType_t = TimerCommand::tmrCOMMAND_CHANGE_PERIOD as BaseType_t;
+    const DELETE: BaseType_t = TimerCommand::tmrCOMMAND_DELETE as BaseType_t;
+
+    let failing = FAILING.load(Ordering::SeqCst);
+    if failing > 0 {
+        FAILING.store(failing - 1, Ordering::SeqCst);
+        return 0;
+    }
+    with_timer(xTimer, |t| match xCommandID {
+        START | RESET => {
+            t.active = true;
+            t.expiry = ticks().wrapping_add(t.period);
+        }
+        STOP => t.active = false,
+        CHANGE_PERIOD => {
+            t.period = xOptionalValue;
+            t.active = true;
+            t.expiry = ticks().wrapping_add(t.period);
+        }
+        DELETE => {
+            t.active = false;
+            t.deleted = true;
+        }
+        _ => panic!("unsupported timer command {}", xCommandID),
+    });
+    pdPASS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pvTimerGetTimerID(xTimer: TimerHandle_t) -> *mut c_void {
+    with_timer(xTimer, |t| t.id)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTimerGetPeriod(xTimer: TimerHandle_t) -> TickType_t {
+    with_timer(xTimer, |t| t.period)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTimerIsTimerActive(xTimer: TimerHandle_t) -> BaseType_t {
+    with_timer(xTimer, |t| t.active as BaseType_t)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn xTimerPendFunctionCall(
+    xFunctionToPend: PendedFunction_t,
+    pvParameter1: *mut c_void,
+    ulParameter2: u32,
+    _xTicksToWait: TickType_t,
+) -> BaseType_t {
+    // There is no service task, the queue is always drained by the time this returns
+    if let Some(function) = xFunctionToPend {
+        function(pvParameter1, ulParameter2);
+    }
+    pdPASS
+}
@@ -0,0 +1,246 @@
+//! Fake UART driver
+//!
+//! Every port loops its transmitted bytes back into its own receive buffer unless
+//! loopback is switched off. Tests can also feed the receiver and inspect what was sent.
+//! When an event queue was requested every chunk of received bytes posts a `UART_DATA`
+//! event to it, see `mock::queue`.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::mem::size_of;
+use core::ptr;
+use core::slice;
+use host_std::thread_local;
+
+use super::queue;
+use crate::{
+    esp_err_t, queueQUEUE_TYPE_BASE, size_t, uart_config_t, uart_event_t,
+    uart_event_type_t_UART_DATA, uart_port_t, vQueueDelete, QueueHandle_t, TickType_t,
+    ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE, ESP_OK,
+};
+
+const PORTS: usize = 3;
+
+struct MockPort {
+    installed: bool,
+    no_loopback: bool,
+    baud_rate: u32,
+    pins: [i32; 4],
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    events: QueueHandle_t,
+}
+
+impl Default for MockPort {
+    fn default() -> MockPort {
+        MockPort {
+            installed: false,
+            no_loopback: false,
+            baud_rate: 0,
+            pins: [0; 4],
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            events: ptr::null_mut(),
+        }
+    }
+}
+
+impl MockPort {
+    fn receive(&mut self, data: &[u8]) {
+        self.rx.extend(data);
+        if !self.events.is_null() && !data.is_empty() {
+            let event = uart_event_t {
+                type_: uart_event_type_t_UART_DATA,
+                size: data.len() as size_t,
+            };
+            let bytes = unsafe {
+                slice::from_raw_parts(&event as *const _ as *const u8, size_of::<uart_event_t>())
+            };
+            // A full event queue drops the event like the real driver does
+            queue::push(self.events, bytes);
+        }
+    }
+}
+
+thread_local! {
+    static PORT_STATE: RefCell<[MockPort; PORTS]> = RefCell::new(Default::default());
+}
+
+fn with_port<R>(port: uart_port_t, f: impl FnOnce(&mut MockPort) -> R) -> Option<R> {
+    PORT_STATE.with(|ports| ports.borrow_mut().get_mut(port as usize).map(f))
+}
+
+/// Bytes arriving from the other end of the wire
+pub fn inject(port: uart_port_t, data: &[u8]) {
+    with_port(port, |p| p.receive(data));
+}
+
+/// Everything written to the port since the last call
+pub fn take_written(port: uart_port_t) -> Vec<u8> {
+    with_port(port, |p| core::mem::replace(&mut p.tx, Vec::new())).unwrap_or_default()
+}
+
+pub fn set_loopback(port: uart_port_t, enabled: bool) {
+    with_port(port, |p| p.no_loopback = !enabled);
+}
+
+pub fn is_installed(port: uart_port_t) -> bool {
+    with_port(port, |p| p.installed).unwrap_or(false)
+}
+
+pub fn baud_rate(port: uart_port_t) -> u32 {
+    with_port(port, |p| p.baud_rate).unwrap_or(0)
+}
+
+/// TX, RX, RTS and CTS as last passed to `uart_set_pin`
+pub fn pins(port: uart_port_t) -> [i32; 4] {
+    with_port(port, |p| p.pins).unwrap_or_default()
+}
+
+fn installed_or(port: uart_port_t, f: impl FnOnce(&mut MockPort) -> esp_err_t) -> esp_err_t {
+    with_port(port, |p| {
+        if p.installed {
+            f(p)
+        } else {
+            ESP_ERR_INVALID_STATE as esp_err_t
+        }
+    })
+    .unwrap_or(ESP_ERR_INVALID_ARG as esp_err_t)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_param_config(
+    uart_num: uart_port_t,
+    uart_config: *const uart_config_t,
+) -> esp_err_t {
+    with_port(uart_num, |p| {
+        p.baud_rate = (*uart_config).baud_rate as u32;
+        ESP_OK as esp_err_t
+    })
+    .unwrap_or(ESP_ERR_INVALID_ARG as esp_err_t)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_set_pin(
+    uart_num: uart_port_t,
+    tx_io_num: i32,
+    rx_io_num: i32,
+    rts_io_num: i32,
+    cts_io_num: i32,
+) -> esp_err_t {
+    with_port(uart_num, |p| {
+        p.pins = [tx_io_num, rx_io_num, rts_io_num, cts_io_num];
+        ESP_OK as esp_err_t
+    })
+    .unwrap_or(ESP_ERR_INVALID_ARG as esp_err_t)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_driver_install(
+    uart_num: uart_port_t,
+    _rx_buffer_size: i32,
+    _tx_buffer_size: i32,
+    queue_size: i32,
+    uart_queue: *mut QueueHandle_t,
+    _intr_alloc_flags: i32,
+) -> esp_err_t {
+    with_port(uart_num, |p| {
+        if p.installed {
+            return ESP_ERR_INVALID_STATE as esp_err_t;
+        }
+        p.installed = true;
+        p.rx.clear();
+        if !uart_queue.is_null() {
+            p.events = queue::create(
+                queue_size as usize,
+                size_of::<uart_event_t>(),
+                queueQUEUE_TYPE_BASE,
+            );
+            *uart_queue = p.events;
+        }
+        ESP_OK as esp_err_t
+    })
+    .unwrap_or(ESP_ERR_INVALID_ARG as esp_err_t)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_driver_delete(uart_num: uart_port_t) -> esp_err_t {
+    installed_or(uart_num, |p| {
+        p.installed = false;
+        if !p.events.is_null() {
+            vQueueDelete(p.events);
+            p.events = ptr::null_mut();
+        }
+        ESP_OK as esp_err_t
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_write_bytes(
+    uart_num: uart_port_t,
+    src: *const ::std::os::raw::c_char,
+    size: size_t,
+) -> i32 {
+    let data = slice::from_raw_parts(src as *const u8, size as usize);
+    with_port(uart_num, |p| {
+        if !p.installed {
+            return -1;
+        }
+        p.tx.extend_from_slice(data);
+        if !p.no_loopback {
+            p.receive(data);
+        }
+        data.len() as i32
+    })
+    .unwrap_or(-1)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_read_bytes(
+    uart_num: uart_port_t,
+    buf: *mut u8,
+    length: u32,
+    _ticks_to_wait: TickType_t,
+) -> i32 {
+    // Never blocks, a timeout is the same as the data not being there yet
+    let buf = slice::from_raw_parts_mut(buf, length as usize);
+    with_port(uart_num, |p| {
+        if !p.installed {
+            return -1;
+        }
+        let n = buf.len().min(p.rx.len());
+        for (dst, src) in buf.iter_mut().zip(p.rx.drain(..n)) {
+            *dst = src;
+        }
+        n as i32
+    })
+    .unwrap_or(-1)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_wait_tx_done(
+    uart_num: uart_port_t,
+    _ticks_to_wait: TickType_t,
+) -> esp_err_t {
+    installed_or(uart_num, |_| ESP_OK as esp_err_t)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_flush_input(uart_num: uart_port_t) -> esp_err_t {
+    installed_or(uart_num, |p| {
+        p.rx.clear();
+        ESP_OK as esp_err_t
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn uart_get_buffered_data_len(
+    uart_num: uart_port_t,
+    size: *mut size_t,
+) -> esp_err_t {
+    installed_or(uart_num, |p| {
+        *size = p.rx.len() as size_t;
+        ESP_OK as esp_err_t
+    })
+}
@@ -0,0 +1,534 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::ptr;
+use core::str;
+use std::os::raw::c_char;
+
+use crate::error::{EspError, EspResult};
+use crate::*;
+
+/// Longest key or namespace name NVS accepts, the C limit of 16 includes the NUL
+pub const MAX_KEY_LEN: usize = 15;
+
+/// Initializes the default NVS partition, erasing it first if it is full or was written
+/// by a newer ESP-IDF
+///
+/// Needed before opening a namespace and by the BT controller for its PHY calibration.
+pub fn init() -> EspResult<()> {
+    if let Err(err) = unsafe { esp!(nvs_flash_init()) } {
+        if err.code() != ESP_ERR_NVS_NO_FREE_PAGES as esp_err_t
+            && err.code() != ESP_ERR_NVS_NEW_VERSION_FOUND as esp_err_t
+        {
+            return Err(err);
+        }
+        unsafe {
+            esp!(nvs_flash_erase())?;
+            esp!(nvs_flash_init())?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    Str,
+    Blob,
+}
+
+impl ValueType {
+    fn from_raw(kind: nvs_type_t) -> Option<ValueType> {
+        Some(match kind {
+            nvs_type_t_NVS_TYPE_U8 => ValueType::U8,
+            nvs_type_t_NVS_TYPE_I8 => ValueType::I8,
+            nvs_type_t_NVS_TYPE_U16 => ValueType::U16,
+            nvs_type_t_NVS_TYPE_I16 => ValueType::I16,
+            nvs_type_t_NVS_TYPE_U32 => ValueType::U32,
+            nvs_type_t_NVS_TYPE_I32 => ValueType::I32,
+            nvs_type_t_NVS_TYPE_U64 => ValueType::U64,
+            nvs_type_t_NVS_TYPE_I64 => ValueType::I64,
+            nvs_type_t_NVS_TYPE_STR => ValueType::Str,
+            nvs_type_t_NVS_TYPE_BLOB => ValueType::Blob,
+            _ => return None,
+        })
+    }
+}
+
+/// Integer types NVS stores natively, see `Nvs::get` and `Nvs::set`
+pub trait Value: Copy + Default {
+    #[doc(hidden)]
+    unsafe fn nvs_get(handle: nvs_handle_t, key: *const c_char, out: *mut Self) -> esp_err_t;
+    #[doc(hidden)]
+    unsafe fn nvs_set(handle: nvs_handle_t, key: *const c_char, value: Self) -> esp_err_t;
+}
+
+macro_rules! values {
+    ($($ty:ty => $get:ident, $set:ident;)*) => {
+        $(
+            impl Value for $ty {
+                unsafe fn nvs_get(
+                    handle: nvs_handle_t,
+                    key: *const c_char,
+                    out: *mut $ty,
+                ) -> esp_err_t {
+                    $get(handle, key, out)
+                }
+
+                unsafe fn nvs_set(
+                    handle: nvs_handle_t,
+                    key: *const c_char,
+                    value: $ty,
+                ) -> esp_err_t {
+                    $set(handle, key, value)
+                }
+            }
+        )*
+    };
+}
+
+values! {
+    u8 => nvs_get_u8, nvs_set_u8;
+    i8 => nvs_get_i8, nvs_set_i8;
+    u16 => nvs_get_u16, nvs_set_u16;
+    i16 => nvs_get_i16, nvs_set_i16;
+    u32 => nvs_get_u32, nvs_set_u32;
+    i32 => nvs_get_i32, nvs_set_i32;
+    u64 => nvs_get_u64, nvs_set_u64;
+    i64 => nvs_get_i64, nvs_set_i64;
+}
+
+/// A key or namespace name copied into a NUL terminated buffer
+struct Name([u8; MAX_KEY_LEN + 1]);
+
+impl Name {
+    fn new(name: &str, call: &'static str) -> EspResult<Name> {
+        if name.len() > MAX_KEY_LEN {
+            return Err(EspError::new(ESP_ERR_NVS_KEY_TOO_LONG, call));
+        }
+        if name.is_empty() || name.contains('\0') {
+            return Err(EspError::new(ESP_ERR_NVS_INVALID_NAME, call));
+        }
+        let mut buf = [0; MAX_KEY_LEN + 1];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Name(buf))
+    }
+
+    fn as_ptr(&self) -> *const c_char {
+        self.0.as_ptr() as *const c_char
+    }
+}
+
+/// Turns `ESP_ERR_NVS_NOT_FOUND` into `None`
+fn found<T>(result: EspResult<T>) -> EspResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ref err) if err.code() == ESP_ERR_NVS_NOT_FOUND as esp_err_t => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// An open namespace on the default NVS partition, closed on drop
+///
+/// Writes only become durable once `commit` returned, dropping the handle does not commit.
+/// Keys are at most `MAX_KEY_LEN` bytes and each key holds a single type, reading it as
+/// another type reports it as missing.
+pub struct Nvs {
+    handle: nvs_handle_t,
+    namespace: Name,
+}
+
+unsafe impl Send for Nvs {}
+
+impl Nvs {
+    /// Read only opens fail with `ESP_ERR_NVS_NOT_FOUND` until the namespace was written once
+    pub fn open(namespace: &str, mode: Mode) -> EspResult<Nvs> {
+        let namespace = Name::new(namespace, "Nvs::open")?;
+        let mode = match mode {
+            Mode::ReadOnly => nvs_open_mode_t_NVS_READONLY,
+            Mode::ReadWrite => nvs_open_mode_t_NVS_READWRITE,
+        };
+        let mut handle: nvs_handle_t = 0;
+        unsafe { esp!(nvs_open(namespace.as_ptr(), mode, &mut handle))? };
+        Ok(Nvs { handle, namespace })
+    }
+
+    pub fn handle(&self) -> nvs_handle_t {
+        self.handle
+    }
+
+    /// `None` if the key doesn't exist
+    pub fn get<T: Value>(&self, key: &str) -> EspResult<Option<T>> {
+        let key = Name::new(key, "Nvs::get")?;
+        let mut value = T::default();
+        found(unsafe { esp!(T::nvs_get(self.handle, key.as_ptr(), &mut value)) }.map(|_| value))
+    }
+
+    pub fn set<T: Value>(&mut self, key: &str, value: T) -> EspResult<()> {
+        let key = Name::new(key, "Nvs::set")?;
+        unsafe { esp!(T::nvs_set(self.handle, key.as_ptr(), value)) }
+    }
+
+    /// Reads a string into `buf`, which needs room for the value plus a NUL
+    ///
+    /// Fails with `ESP_ERR_NVS_INVALID_LENGTH` if it is too small and with
+    /// `ESP_ERR_INVALID_RESPONSE` if the stored value isn't UTF-8.
+    pub fn get_str<'b>(&self, key: &str, buf: &'b mut [u8]) -> EspResult<Option<&'b str>> {
+        let key = Name::new(key, "Nvs::get_str")?;
+        let mut len = buf.len() as size_t;
+        let read = unsafe {
+            esp!(nvs_get_str(
+                self.handle,
+                key.as_ptr(),
+                buf.as_mut_ptr() as *mut c_char,
+                &mut len
+            ))
+        };
+        match found(read)? {
+            Some(()) => {
+                // len includes the NUL
+                let bytes = &buf[..(len as usize).saturating_sub(1)];
+                str::from_utf8(bytes)
+                    .map(Some)
+                    .map_err(|_| EspError::new(ESP_ERR_INVALID_RESPONSE, "Nvs::get_str"))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Like `get_str` but sized to fit
+    pub fn get_string(&self, key: &str) -> EspResult<Option<String>> {
+        let key_name = Name::new(key, "Nvs::get_string")?;
+        let mut len: size_t = 0;
+        let probe = unsafe {
+            esp!(nvs_get_str(
+                self.handle,
+                key_name.as_ptr(),
+                ptr::null_mut(),
+                &mut len
+            ))
+        };
+        if found(probe)?.is_none() {
+            return Ok(None);
+        }
+
+        let mut buf = Vec::new();
+        buf.resize(len as usize, 0);
+        let value = self.get_str(key, &mut buf)?;
+        Ok(value.map(String::from))
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) -> EspResult<()> {
+        let key = Name::new(key, "Nvs::set_str")?;
+        if value.contains('\0') {
+            return Err(EspError::new(ESP_ERR_INVALID_ARG, "Nvs::set_str"));
+        }
+        let mut c_value = Vec::with_capacity(value.len() + 1);
+        c_value.extend_from_slice(value.as_bytes());
+        c_value.push(0);
+        unsafe {
+            esp!(nvs_set_str(
+                self.handle,
+                key.as_ptr(),
+                c_value.as_ptr() as *const c_char
+            ))
+        }
+    }
+
+    /// Reads a blob into `buf` and returns the part that was filled
+    ///
+    /// Fails with `ESP_ERR_NVS_INVALID_LENGTH` if `buf` is too small.
+    pub fn get_blob<'b>(&self, key: &str, buf: &'b mut [u8]) -> EspResult<Option<&'b [u8]>> {
+        let key = Name::new(key, "Nvs::get_blob")?;
+        let mut len = buf.len() as size_t;
+        let read = unsafe {
+            esp!(nvs_get_blob(
+                self.handle,
+                key.as_ptr(),
+                buf.as_mut_ptr() as *mut c_void,
+                &mut len
+            ))
+        };
+        Ok(found(read)?.map(move |_| &buf[..len as usize]))
+    }
+
+    /// Like `get_blob` but sized to fit
+    pub fn get_blob_vec(&self, key: &str) -> EspResult<Option<Vec<u8>>> {
+        let key_name = Name::new(key, "Nvs::get_blob_vec")?;
+        let mut len: size_t = 0;
+        let probe = unsafe {
+            esp!(nvs_get_blob(
+                self.handle,
+                key_name.as_ptr(),
+                ptr::null_mut(),
+                &mut len
+            ))
+        };
+        if found(probe)?.is_none() {
+            return Ok(None);
+        }
+
+        let mut buf = Vec::new();
+        buf.resize(len as usize, 0);
+        let filled = self.get_blob(key, &mut buf)?.map(|blob| blob.len());
+        Ok(filled.map(|len| {
+            buf.truncate(len);
+            buf
+        }))
+    }
+
+    pub fn set_blob(&mut self, key: &str, value: &[u8]) -> EspResult<()> {
+        let key = Name::new(key, "Nvs::set_blob")?;
+        unsafe {
+            esp!(nvs_set_blob(
+                self.handle,
+                key.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len() as size_t
+            ))
+        }
+    }
+
+    /// Returns whether the key existed
+    pub fn remove(&mut self, key: &str) -> EspResult<bool> {
+        let key = Name::new(key, "Nvs::remove")?;
+        found(unsafe { esp!(nvs_erase_key(self.handle, key.as_ptr())) }).map(|r| r.is_some())
+    }
+
+    /// Removes every key in the namespace
+    pub fn clear(&mut self) -> EspResult<()> {
+        unsafe { esp!(nvs_erase_all(self.handle)) }
+    }
+
+    pub fn commit(&mut self) -> EspResult<()> {
+        unsafe { esp!(nvs_commit(self.handle)) }
+    }
+
+    /// All keys in the namespace with the type stored under them
+    pub fn keys(&self) -> Keys {
+        let iterator = unsafe {
+            nvs_entry_find(
+                NVS_DEFAULT_PART_NAME.as_ptr() as *const c_char,
+                self.namespace.as_ptr(),
+                nvs_type_t_NVS_TYPE_ANY,
+            )
+        };
+        Keys { iterator }
+    }
+}
+
+impl Drop for Nvs {
+    fn drop(&mut self) {
+        unsafe { nvs_close(self.handle) };
+    }
+}
+
+/// A key found by `Nvs::keys`
+#[derive(Clone, Copy)]
+pub struct Entry {
+    key: [u8; MAX_KEY_LEN + 1],
+    value_type: Option<ValueType>,
+}
+
+impl Entry {
+    pub fn key(&self) -> &str {
+        let len = self.key.iter().position(|&b| b == 0).unwrap_or(MAX_KEY_LEN);
+        str::from_utf8(&self.key[..len]).unwrap_or("")
+    }
+
+    /// `None` for types newer than this wrapper
+    pub fn value_type(&self) -> Option<ValueType> {
+        self.value_type
+    }
+}
+
+pub struct Keys {
+    iterator: nvs_iterator_t,
+}
+
+impl Iterator for Keys {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        if self.iterator.is_null() {
+            return None;
+        }
+
+        let mut info = nvs_entry_info_t {
+            namespace_name: [0; 16],
+            key: [0; 16],
+            type_: 0,
+        };
+        unsafe { nvs_entry_info(self.iterator, &mut info) };
+        // Releases the iterator itself once the end is reached
+        self.iterator = unsafe { nvs_entry_next(self.iterator) };
+
+        let mut key = [0; MAX_KEY_LEN + 1];
+        for (dst, &src) in key.iter_mut().zip(info.key.iter()) {
+            *dst = src as u8;
+        }
+        Some(Entry {
+            key,
+            value_type: ValueType::from_raw(info.type_),
+        })
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        if !self.iterator.is_null() {
+            unsafe { nvs_release_iterator(self.iterator) };
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use alloc::string::ToString;
+
+    fn open(namespace: &str) -> Nvs {
+        init().unwrap();
+        Nvs::open(namespace, Mode::ReadWrite).unwrap()
+    }
+
+    #[test]
+    fn init_erases_full_partition() {
+        mock::nvs::fail_next_init(ESP_ERR_NVS_NO_FREE_PAGES as esp_err_t);
+        init().unwrap();
+        assert!(mock::nvs::is_initialized());
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        let mut nvs = open("ints");
+        nvs.set("u8", u8::max_value()).unwrap();
+        nvs.set("i8", i8::min_value()).unwrap();
+        nvs.set("u16", 0xbeefu16).unwrap();
+        nvs.set("i16", -5i16).unwrap();
+        nvs.set("u32", 1200u32).unwrap();
+        nvs.set("i32", -70_000i32).unwrap();
+        nvs.set("u64", u64::max_value()).unwrap();
+        nvs.set("i64", i64::min_value()).unwrap();
+        nvs.commit().unwrap();
+
+        assert_eq!(nvs.get::<u8>("u8").unwrap(), Some(u8::max_value()));
+        assert_eq!(nvs.get::<i8>("i8").unwrap(), Some(i8::min_value()));
+        assert_eq!(nvs.get::<u16>("u16").unwrap(), Some(0xbeef));
+        assert_eq!(nvs.get::<i16>("i16").unwrap(), Some(-5));
+        assert_eq!(nvs.get::<u32>("u32").unwrap(), Some(1200));
+        assert_eq!(nvs.get::<i32>("i32").unwrap(), Some(-70_000));
+        assert_eq!(nvs.get::<u64>("u64").unwrap(), Some(u64::max_value()));
+        assert_eq!(nvs.get::<i64>("i64").unwrap(), Some(i64::min_value()));
+        assert_eq!(
+            mock::nvs::stored("ints", "u32"),
+            Some(1200u32.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn missing_keys() {
+        let mut nvs = open("missing");
+        nvs.set("width", 1200u32).unwrap();
+        assert_eq!(nvs.get::<u32>("height").unwrap(), None);
+        // Stored under another type
+        assert_eq!(nvs.get::<u8>("width").unwrap(), None);
+        assert_eq!(nvs.get_string("name").unwrap(), None);
+        assert_eq!(nvs.get_blob_vec("cal").unwrap(), None);
+        let err = nvs.get::<u8>("waytoolongkeyname").unwrap_err();
+        assert_eq!(err.code(), ESP_ERR_NVS_KEY_TOO_LONG as esp_err_t);
+    }
+
+    #[test]
+    fn strings_and_blobs() {
+        let mut nvs = open("strings");
+        nvs.set_str("name", "plotter-1").unwrap();
+        assert!(nvs.set_str("nul", "a\0b").is_err());
+        assert_eq!(
+            nvs.get_string("name").unwrap().as_deref(),
+            Some("plotter-1")
+        );
+
+        let mut buf = [0u8; 10];
+        assert_eq!(nvs.get_str("name", &mut buf).unwrap(), Some("plotter-1"));
+        let mut small = [0u8; 9];
+        let err = nvs.get_str("name", &mut small).unwrap_err();
+        assert_eq!(err.code(), ESP_ERR_NVS_INVALID_LENGTH as esp_err_t);
+
+        nvs.set_blob("cal", &[1, 2, 3]).unwrap();
+        assert_eq!(
+            nvs.get_blob_vec("cal").unwrap().as_deref(),
+            Some(&[1, 2, 3][..])
+        );
+        let mut buf = [0u8; 8];
+        assert_eq!(nvs.get_blob("cal", &mut buf).unwrap(), Some(&[1, 2, 3][..]));
+        let mut small = [0u8; 2];
+        assert!(nvs.get_blob("cal", &mut small).is_err());
+    }
+
+    #[test]
+    fn remove_and_clear() {
+        let mut nvs = open("remove");
+        nvs.set("width", 1200u32).unwrap();
+        nvs.set("height", 800u32).unwrap();
+        assert!(nvs.remove("width").unwrap());
+        assert!(!nvs.remove("width").unwrap());
+        assert_eq!(nvs.get::<u32>("width").unwrap(), None);
+        assert_eq!(nvs.get::<u32>("height").unwrap(), Some(800));
+        nvs.clear().unwrap();
+        assert_eq!(nvs.get::<u32>("height").unwrap(), None);
+    }
+
+    #[test]
+    fn read_only() {
+        open("readonly").set("width", 1200u32).unwrap();
+        let mut nvs = Nvs::open("readonly", Mode::ReadOnly).unwrap();
+        assert_eq!(nvs.get::<u32>("width").unwrap(), Some(1200));
+        assert!(nvs.set("width", 1u32).is_err());
+        assert!(nvs.remove("width").is_err());
+        assert!(Nvs::open("nothing here", Mode::ReadOnly).is_err());
+    }
+
+    #[test]
+    fn keys_with_types() {
+        let handles = mock::nvs::open_handles();
+        {
+            let mut nvs = open("keys");
+            nvs.set("offset", -5i16).unwrap();
+            nvs.set("width", 1200u32).unwrap();
+            nvs.set_str("name", "plotter-1").unwrap();
+            nvs.set_blob("cal", &[1, 2, 3]).unwrap();
+            open("other").set("width", 1u8).unwrap();
+
+            let mut keys: Vec<_> = nvs
+                .keys()
+                .map(|entry| (entry.key().to_string(), entry.value_type()))
+                .collect();
+            keys.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                keys,
+                [
+                    ("cal".to_string(), Some(ValueType::Blob)),
+                    ("name".to_string(), Some(ValueType::Str)),
+                    ("offset".to_string(), Some(ValueType::I16)),
+                    ("width".to_string(), Some(ValueType::U32)),
+                ]
+            );
+        }
+        assert_eq!(mock::nvs::open_handles(), handles);
+    }
+}
@@ -0,0 +1,291 @@
+use core::ffi::c_void;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+
+use crate::error::{EspError, EspResult};
+use crate::*;
+
+/// A FreeRTOS queue carrying `T` by value, deleted on drop
+///
+/// Items are copied in and out byte for byte, hence the `Copy` bound. Use `split` or
+/// `sender`/`receiver` to hand out the two ends, a queue that has to outlive every task
+/// using it can be leaked with `Box::leak`.
+pub struct Queue<T: Copy + Send> {
+    handle: QueueHandle_t,
+    _item: PhantomData<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for Queue<T> {}
+unsafe impl<T: Copy + Send> Sync for Queue<T> {}
+
+/// The sending end of a `Queue`
+pub struct Sender<'a, T: Copy + Send + 'a> {
+    queue: &'a Queue<T>,
+}
+
+/// The receiving end of a `Queue`
+pub struct Receiver<'a, T: Copy + Send + 'a> {
+    queue: &'a Queue<T>,
+}
+
+impl<T: Copy + Send> Queue<T> {
+    pub fn new(len: usize) -> EspResult<Queue<T>> {
+        let handle = unsafe {
+            xQueueGenericCreate(
+                len as UBaseType_t,
+                size_of::<T>() as UBaseType_t,
+                queueQUEUE_TYPE_BASE,
+            )
+        };
+        if handle.is_null() {
+            return Err(EspError::new(ESP_ERR_NO_MEM, "xQueueGenericCreate"));
+        }
+
+        Ok(Queue {
+            handle,
+            _item: PhantomData,
+        })
+    }
+
+    pub fn split(&self) -> (Sender<'_, T>, Receiver<'_, T>) {
+        (self.sender(), self.receiver())
+    }
+
+    pub fn sender(&self) -> Sender<'_, T> {
+        Sender { queue: self }
+    }
+
+    pub fn receiver(&self) -> Receiver<'_, T> {
+        Receiver { queue: self }
+    }
+
+    pub fn handle(&self) -> QueueHandle_t {
+        self.handle
+    }
+
+    /// Number of items waiting
+    pub fn len(&self) -> usize {
+        unsafe { uxQueueMessagesWaiting(self.handle) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn send(&self, item: T, timeout: TickType_t, position: BaseType_t) -> Result<(), T> {
+        let rc = unsafe {
+            xQueueGenericSend(
+                self.handle,
+                &item as *const T as *const c_void,
+                timeout,
+                position,
+            )
+        };
+        if rc == pdPASS {
+            Ok(())
+        } else {
+            Err(item)
+        }
+    }
+
+    fn receive(&self, timeout: TickType_t, peek: bool) -> Option<T> {
+        let mut item = MaybeUninit::<T>::uninit();
+        let rc = unsafe {
+            xQueueGenericReceive(
+                self.handle,
+                item.as_mut_ptr() as *mut c_void,
+                timeout,
+                peek as BaseType_t,
+            )
+        };
+        if rc == pdPASS {
+            Some(unsafe { item.assume_init() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Copy + Send> Drop for Queue<T> {
+    fn drop(&mut self) {
+        unsafe { vQueueDelete(self.handle) };
+    }
+}
+
+impl<'a, T: Copy + Send> Sender<'a, T> {
+    /// Appends `item`, waiting up to `timeout` for space. Hands the item back if the
+    /// queue stayed full.
+    pub fn send(&self, item: T, timeout: TickType_t) -> Result<(), T> {
+        self.queue.send(item, timeout, queueSEND_TO_BACK)
+    }
+
+    /// Puts `item` at the head so it is received next
+    pub fn send_to_front(&self, item: T, timeout: TickType_t) -> Result<(), T> {
+        self.queue.send(item, timeout, queueSEND_TO_FRONT)
+    }
+
+    /// Replaces whatever is queued with `item`, never blocks
+    ///
+    /// Only for queues of length 1, which then hold the latest value like a mailbox.
+    pub fn overwrite(&self, item: T) {
+        // xQueueOverwrite can't fail
+        let _ = self.queue.send(item, 0, queueOVERWRITE);
+    }
+
+    /// Interrupt safe `send`, never blocks
+    ///
+    /// `Ok(true)` means a higher priority task was woken and the ISR should yield.
+    pub fn send_from_isr(&self, item: T) -> Result<bool, T> {
+        let mut woken: BaseType_t = 0;
+        let rc = unsafe {
+            xQueueGenericSendFromISR(
+                self.queue.handle,
+                &item as *const T as *const c_void,
+                &mut woken,
+                queueSEND_TO_BACK,
+            )
+        };
+        if rc == pdPASS {
+            Ok(woken != 0)
+        } else {
+            Err(item)
+        }
+    }
+}
+
+impl<'a, T: Copy + Send> Receiver<'a, T> {
+    /// Takes the oldest item, waiting up to `timeout` for one to arrive
+    pub fn receive(&self, timeout: TickType_t) -> Option<T> {
+        self.queue.receive(timeout, false)
+    }
+
+    /// Like `receive` but leaves the item in the queue
+    pub fn peek(&self, timeout: TickType_t) -> Option<T> {
+        self.queue.receive(timeout, true)
+    }
+
+    /// Interrupt safe `receive`, never blocks
+    ///
+    /// The flag is set when a higher priority task was woken and the ISR should yield.
+    pub fn receive_from_isr(&self) -> Option<(T, bool)> {
+        let mut item = MaybeUninit::<T>::uninit();
+        let mut woken: BaseType_t = 0;
+        let rc = unsafe {
+            xQueueReceiveFromISR(
+                self.queue.handle,
+                item.as_mut_ptr() as *mut c_void,
+                &mut woken,
+            )
+        };
+        if rc == pdPASS {
+            Some((unsafe { item.assume_init() }, woken != 0))
+        } else {
+            None
+        }
+    }
+
+    /// Takes everything that is waiting without blocking
+    pub fn try_iter(&self) -> TryIter<'_, 'a, T> {
+        TryIter { receiver: self }
+    }
+}
+
+impl<'a, T: Copy + Send> Clone for Sender<'a, T> {
+    fn clone(&self) -> Self {
+        Sender { queue: self.queue }
+    }
+}
+
+impl<'a, T: Copy + Send> Clone for Receiver<'a, T> {
+    fn clone(&self) -> Self {
+        Receiver { queue: self.queue }
+    }
+}
+
+pub struct TryIter<'r, 'a: 'r, T: Copy + Send + 'a> {
+    receiver: &'r Receiver<'a, T>,
+}
+
+impl<'r, 'a, T: Copy + Send> Iterator for TryIter<'r, 'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive(0)
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use alloc::vec::Vec;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Command {
+        stepper: u8,
+        steps: i16,
+    }
+
+    fn command(steps: i16) -> Command {
+        Command { stepper: 1, steps }
+    }
+
+    #[test]
+    fn send_receive_order() {
+        let queue = Queue::<Command>::new(4).unwrap();
+        assert_eq!(
+            mock::queue::kind(queue.handle()),
+            Some(queueQUEUE_TYPE_BASE)
+        );
+        let (tx, rx) = queue.split();
+        tx.send(command(1), 0).unwrap();
+        tx.send(command(2), 0).unwrap();
+        tx.send_to_front(command(3), 0).unwrap();
+        assert_eq!(tx.send_from_isr(command(4)), Ok(false));
+        assert_eq!(queue.len(), 4);
+
+        assert_eq!(rx.peek(0), Some(command(3)));
+        assert_eq!(rx.receive(0), Some(command(3)));
+        assert_eq!(rx.receive_from_isr(), Some((command(1), false)));
+        let rest: Vec<_> = rx.try_iter().collect();
+        assert_eq!(rest, [command(2), command(4)]);
+        assert!(queue.is_empty());
+        assert_eq!(rx.receive(10), None);
+    }
+
+    #[test]
+    fn full_queue_times_out() {
+        let queue = Queue::<Command>::new(2).unwrap();
+        let tx = queue.sender();
+        tx.send(command(1), 0).unwrap();
+        tx.send(command(2), 0).unwrap();
+        assert_eq!(tx.send(command(3), 10), Err(command(3)));
+        assert_eq!(tx.send_to_front(command(4), 10), Err(command(4)));
+        assert_eq!(tx.send_from_isr(command(5)), Err(command(5)));
+        assert_eq!(queue.receiver().receive(0), Some(command(1)));
+        assert_eq!(tx.send(command(6), 0), Ok(()));
+    }
+
+    #[test]
+    fn overwrite_keeps_latest() {
+        let queue = Queue::<Command>::new(1).unwrap();
+        let (tx, rx) = queue.split();
+        tx.overwrite(command(1));
+        tx.overwrite(command(2));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(tx.send(command(3), 0), Err(command(3)));
+        assert_eq!(rx.receive(0), Some(command(2)));
+        assert_eq!(rx.receive(0), None);
+    }
+
+    #[test]
+    fn deleted_on_drop() {
+        let live = mock::queue::live_count();
+        let queue = Queue::<u32>::new(3).unwrap();
+        let handle = queue.handle();
+        assert_eq!(mock::queue::live_count(), live + 1);
+        drop(queue);
+        assert!(mock::queue::is_deleted(handle));
+        assert!(Queue::<u32>::new(0).is_err());
+    }
+}
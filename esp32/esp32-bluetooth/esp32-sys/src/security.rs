@@ -0,0 +1,238 @@
+//! Pairing and bonding through NimBLE's security manager
+//!
+//! `Security` says what the device offers when a central pairs with it and points the host
+//! at NimBLE's own key store, which keeps bonds in NVS with
+//! `CONFIG_BT_NIMBLE_NVS_PERSIST`. Once `CONFIG_BT_NIMBLE_MAX_BONDS` peers are bonded the
+//! oldest one makes room for the next.
+//!
+//! Pairing itself is driven by GAP events: `BLE_GAP_EVENT_PASSKEY_ACTION` asks the
+//! application for its part of the key, `PasskeyAction::from_params` reads which part and
+//! `display_passkey`, `enter_passkey` or `confirm_numcmp` answers.
+//! `BLE_GAP_EVENT_ENC_CHANGE` reports the outcome, `sec_state` tells how far it went.
+
+use alloc::vec::Vec;
+use core::mem::MaybeUninit;
+
+use crate::error::{BleError, BleResult};
+use crate::gap::Address;
+use crate::*;
+
+extern "C" {
+    /// Sets the `ble_hs_cfg` store callbacks to the NVS backed store, declared in no header
+    fn ble_store_config_init();
+}
+
+/// Largest passkey, they are shown and entered as six decimal digits
+pub const MAX_PASSKEY: u32 = 999_999;
+
+/// What the device has to show or enter a passkey with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoCapability {
+    DisplayOnly,
+    DisplayYesNo,
+    KeyboardOnly,
+    NoInputOutput,
+    KeyboardDisplay,
+}
+
+impl IoCapability {
+    fn raw(self) -> u8 {
+        (match self {
+            IoCapability::DisplayOnly => BLE_HS_IO_DISPLAY_ONLY,
+            IoCapability::DisplayYesNo => BLE_HS_IO_DISPLAY_YESNO,
+            IoCapability::KeyboardOnly => BLE_HS_IO_KEYBOARD_ONLY,
+            IoCapability::NoInputOutput => BLE_HS_IO_NO_INPUT_OUTPUT,
+            IoCapability::KeyboardDisplay => BLE_HS_IO_KEYBOARD_DISPLAY,
+        }) as u8
+    }
+}
+
+/// Pairing features, bonding, MITM protection and LE Secure Connections unless turned off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Security {
+    io_capability: IoCapability,
+    bonding: bool,
+    mitm: bool,
+    secure_connections: bool,
+}
+
+impl Security {
+    pub fn new(io_capability: IoCapability) -> Security {
+        Security {
+            io_capability,
+            bonding: true,
+            mitm: true,
+            secure_connections: true,
+        }
+    }
+
+    /// Keeps the keys so the peer can encrypt again without pairing
+    pub fn bonding(mut self, bonding: bool) -> Security {
+        self.bonding = bonding;
+        self
+    }
+
+    /// Asks for a passkey or numeric comparison rather than Just Works
+    pub fn mitm(mut self, mitm: bool) -> Security {
+        self.mitm = mitm;
+        self
+    }
+
+    /// LE Secure Connections, with legacy pairing for centrals that lack it
+    pub fn secure_connections(mut self, secure_connections: bool) -> Security {
+        self.secure_connections = secure_connections;
+        self
+    }
+
+    /// Sets up the host, before the host task starts
+    ///
+    /// Both sides distribute their long term key and identity key, the identity key lets
+    /// a bonded central that uses private addresses be recognized.
+    pub unsafe fn configure(&self) {
+        ble_hs_cfg.sm_io_cap = self.io_capability.raw();
+        ble_hs_cfg.set_sm_bonding(self.bonding as u32);
+        ble_hs_cfg.set_sm_mitm(self.mitm as u32);
+        ble_hs_cfg.set_sm_sc(self.secure_connections as u32);
+        let key_dist = if self.bonding {
+            (BLE_SM_PAIR_KEY_DIST_ENC | BLE_SM_PAIR_KEY_DIST_ID) as u8
+        } else {
+            0
+        };
+        ble_hs_cfg.sm_our_key_dist = key_dist;
+        ble_hs_cfg.sm_their_key_dist = key_dist;
+        ble_hs_cfg.store_status_cb = Some(ble_store_util_status_rr);
+        ble_store_config_init();
+    }
+}
+
+/// What `BLE_GAP_EVENT_PASSKEY_ACTION` asks of the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasskeyAction {
+    /// Show a passkey for the user to enter on the peer
+    Display,
+    /// Take the passkey the peer shows
+    Input,
+    /// Ask whether the peer shows the same number
+    NumericComparison(u32),
+    /// Out of band data, which this crate doesn't offer
+    Oob,
+    Unknown(u8),
+}
+
+impl PasskeyAction {
+    pub fn from_params(params: &ble_gap_passkey_params) -> PasskeyAction {
+        match params.action as u32 {
+            BLE_SM_IOACT_DISP => PasskeyAction::Display,
+            BLE_SM_IOACT_INPUT => PasskeyAction::Input,
+            BLE_SM_IOACT_NUMCMP => PasskeyAction::NumericComparison(params.numcmp),
+            BLE_SM_IOACT_OOB => PasskeyAction::Oob,
+            action => PasskeyAction::Unknown(action as u8),
+        }
+    }
+}
+
+/// Answers `PasskeyAction::Display` with the passkey that is shown
+pub fn display_passkey(conn_handle: u16, passkey: u32) -> BleResult<()> {
+    inject_passkey(conn_handle, BLE_SM_IOACT_DISP, passkey)
+}
+
+/// Answers `PasskeyAction::Input` with the passkey the user entered
+pub fn enter_passkey(conn_handle: u16, passkey: u32) -> BleResult<()> {
+    inject_passkey(conn_handle, BLE_SM_IOACT_INPUT, passkey)
+}
+
+fn inject_passkey(conn_handle: u16, action: u32, passkey: u32) -> BleResult<()> {
+    if passkey > MAX_PASSKEY {
+        return Err(BleError::new(BLE_HS_EINVAL, "security::inject_passkey"));
+    }
+    unsafe {
+        let mut io: ble_sm_io = MaybeUninit::zeroed().assume_init();
+        io.action = action as u8;
+        io.__bindgen_anon_1.passkey = passkey;
+        ble!(ble_sm_inject_io(conn_handle, &mut io))
+    }
+}
+
+/// Answers `PasskeyAction::NumericComparison`
+pub fn confirm_numcmp(conn_handle: u16, accept: bool) -> BleResult<()> {
+    unsafe {
+        let mut io: ble_sm_io = MaybeUninit::zeroed().assume_init();
+        io.action = BLE_SM_IOACT_NUMCMP as u8;
+        io.__bindgen_anon_1.numcmp_accept = accept as u8;
+        ble!(ble_sm_inject_io(conn_handle, &mut io))
+    }
+}
+
+/// Security state of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SecState {
+    pub encrypted: bool,
+    /// Paired with MITM protection
+    pub authenticated: bool,
+    pub bonded: bool,
+    /// Of the encryption key in bytes, 0 while not encrypted
+    pub key_size: u8,
+}
+
+impl SecState {
+    pub fn from_raw(state: &ble_gap_sec_state) -> SecState {
+        SecState {
+            encrypted: state.encrypted() != 0,
+            authenticated: state.authenticated() != 0,
+            bonded: state.bonded() != 0,
+            key_size: state.key_size() as u8,
+        }
+    }
+}
+
+fn conn_desc(conn_handle: u16) -> BleResult<ble_gap_conn_desc> {
+    unsafe {
+        let mut desc = MaybeUninit::<ble_gap_conn_desc>::zeroed();
+        ble!(ble_gap_conn_find(conn_handle, desc.as_mut_ptr()))?;
+        Ok(desc.assume_init())
+    }
+}
+
+pub fn sec_state(conn_handle: u16) -> BleResult<SecState> {
+    conn_desc(conn_handle).map(|desc| SecState::from_raw(&desc.sec_state))
+}
+
+/// Asks the central of `conn_handle` to pair, or to encrypt with the keys of its bond
+pub fn initiate(conn_handle: u16) -> BleResult<()> {
+    unsafe { ble!(ble_gap_security_initiate(conn_handle)) }
+}
+
+/// Deletes the bond with the peer of `conn_handle`, which stays connected, returns the
+/// peer's identity address
+///
+/// For `BLE_GAP_EVENT_REPEAT_PAIRING`, a bonded central that lost its keys pairs again
+/// once the old ones are gone and the handler returns `BLE_GAP_REPEAT_PAIRING_RETRY`.
+pub fn forget(conn_handle: u16) -> BleResult<Address> {
+    let desc = conn_desc(conn_handle)?;
+    unsafe { ble!(ble_store_util_delete_peer(&desc.peer_id_addr))? };
+    Ok(Address::from_raw(&desc.peer_id_addr))
+}
+
+/// Identity addresses of the bonded peers
+pub fn bonded_peers() -> BleResult<Vec<ble_addr_t>> {
+    let max = CONFIG_BT_NIMBLE_MAX_BONDS as usize;
+    let mut peers = Vec::with_capacity(max);
+    let mut count = 0;
+    unsafe {
+        ble!(ble_store_util_bonded_peers(
+            peers.as_mut_ptr(),
+            &mut count,
+            max as i32
+        ))?;
+        peers.set_len(count as usize);
+    }
+    Ok(peers)
+}
+
+/// Deletes every bond, connections to bonded peers are terminated
+pub fn clear_bonds() -> BleResult<()> {
+    for peer in bonded_peers()? {
+        unsafe { ble!(ble_gap_unpair(&peer))? };
+    }
+    Ok(())
+}
@@ -0,0 +1,384 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+
+use crate::error::{EspError, EspResult};
+use crate::*;
+
+/// A `portMUX` spinlock, the Rust side of `portENTER_CRITICAL`/`portEXIT_CRITICAL`
+///
+/// Entering disables interrupts on the current core and spins until the other core leaves,
+/// so it can be shared with ISRs and lives in a `static`. Keep the guarded code short and
+/// never block while holding it.
+pub struct CriticalSection {
+    mux: UnsafeCell<portMUX_TYPE>,
+}
+
+unsafe impl Send for CriticalSection {}
+unsafe impl Sync for CriticalSection {}
+
+/// Leaves the critical section when dropped
+pub struct CriticalGuard<'a> {
+    section: &'a CriticalSection,
+}
+
+impl CriticalSection {
+    pub const fn new() -> CriticalSection {
+        CriticalSection {
+            mux: UnsafeCell::new(portMUX_TYPE {
+                owner: portMUX_FREE_VAL,
+                count: 0,
+            }),
+        }
+    }
+
+    /// Works in tasks and ISRs alike, the guard must be dropped on the same core
+    pub fn enter(&self) -> CriticalGuard<'_> {
+        unsafe { vTaskEnterCritical(self.mux.get()) };
+        CriticalGuard { section: self }
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.enter();
+        f()
+    }
+}
+
+impl Default for CriticalSection {
+    fn default() -> CriticalSection {
+        CriticalSection::new()
+    }
+}
+
+impl<'a> Drop for CriticalGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { vTaskExitCritical(self.section.mux.get()) };
+    }
+}
+
+/// A FreeRTOS mutex protecting `T`, usable as a `static`
+///
+/// The kernel object is created on first use, which makes `new` a `const fn`. Mutexes have
+/// priority inheritance and may only be locked from tasks, use a `CriticalSection` for
+/// state shared with ISRs.
+pub struct Mutex<T> {
+    handle: UnsafeCell<SemaphoreHandle_t>,
+    init: CriticalSection,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// Unlocks the `Mutex` when dropped
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            handle: UnsafeCell::new(ptr::null_mut()),
+            init: CriticalSection::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Blocks until the mutex is free, panics if the kernel object can't be allocated
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let handle = self.handle();
+        assert!(!handle.is_null(), "Mutex::lock failed to create the mutex");
+        self.take(handle, portMAX_DELAY)
+            .expect("Mutex::lock failed to take the mutex")
+    }
+
+    /// `None` if the mutex stayed locked for `timeout` or couldn't be created
+    pub fn try_lock(&self, timeout: TickType_t) -> Option<MutexGuard<'_, T>> {
+        let handle = self.handle();
+        if handle.is_null() {
+            return None;
+        }
+        self.take(handle, timeout)
+    }
+
+    fn take(&self, handle: SemaphoreHandle_t, timeout: TickType_t) -> Option<MutexGuard<'_, T>> {
+        let rc = unsafe { xQueueGenericReceive(handle, ptr::null_mut(), timeout, 0) };
+        if rc == pdPASS {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// No locking needed while the mutex is borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn handle(&self) -> SemaphoreHandle_t {
+        // Creating the mutex may not happen inside the critical section, two tasks racing
+        // here both create one and the loser deletes its own
+        let handle = self.init.with(|| unsafe { *self.handle.get() });
+        if !handle.is_null() {
+            return handle;
+        }
+
+        let created = unsafe { xQueueCreateMutex(queueQUEUE_TYPE_MUTEX) };
+        if created.is_null() {
+            return created;
+        }
+        let winner = self.init.with(|| unsafe {
+            let handle = self.handle.get();
+            if (*handle).is_null() {
+                *handle = created;
+            }
+            *handle
+        });
+        if winner != created {
+            unsafe { vQueueDelete(created) };
+        }
+        winner
+    }
+}
+
+impl<T> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        let handle = *self.handle.get_mut();
+        if !handle.is_null() {
+            unsafe { vQueueDelete(handle) };
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let handle = unsafe { *self.mutex.handle.get() };
+        unsafe { xQueueGenericSend(handle, ptr::null(), 0, queueSEND_TO_BACK) };
+    }
+}
+
+/// A FreeRTOS recursive mutex, the owning task may lock it again while holding it
+///
+/// It guards no data because nested guards would alias, wrap the shared state in a
+/// `Mutex` or `CriticalSection` of its own.
+pub struct RecursiveMutex {
+    handle: SemaphoreHandle_t,
+}
+
+unsafe impl Send for RecursiveMutex {}
+unsafe impl Sync for RecursiveMutex {}
+
+/// Releases one level of a `RecursiveMutex` when dropped
+pub struct RecursiveGuard<'a> {
+    mutex: &'a RecursiveMutex,
+}
+
+impl RecursiveMutex {
+    pub fn new() -> EspResult<RecursiveMutex> {
+        let handle = unsafe { xQueueCreateMutex(queueQUEUE_TYPE_RECURSIVE_MUTEX) };
+        if handle.is_null() {
+            return Err(EspError::new(ESP_ERR_NO_MEM, "xQueueCreateMutex"));
+        }
+        Ok(RecursiveMutex { handle })
+    }
+
+    /// `None` if another task held the mutex for all of `timeout`
+    pub fn lock(&self, timeout: TickType_t) -> Option<RecursiveGuard<'_>> {
+        if unsafe { xQueueTakeMutexRecursive(self.handle, timeout) } == pdPASS {
+            Some(RecursiveGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for RecursiveMutex {
+    fn drop(&mut self) {
+        unsafe { vQueueDelete(self.handle) };
+    }
+}
+
+impl<'a> Drop for RecursiveGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { xQueueGiveMutexRecursive(self.mutex.handle) };
+    }
+}
+
+/// A binary or counting FreeRTOS semaphore
+///
+/// Unlike mutexes semaphores can be given from ISRs, which makes them the usual way for an
+/// interrupt to wake a task.
+pub struct Semaphore {
+    handle: SemaphoreHandle_t,
+}
+
+unsafe impl Send for Semaphore {}
+unsafe impl Sync for Semaphore {}
+
+/// Gives the `Semaphore` back when dropped
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// A semaphore that is either available or not, starts out taken
+    pub fn binary() -> EspResult<Semaphore> {
+        let handle = unsafe { xQueueGenericCreate(1, 0, queueQUEUE_TYPE_BINARY_SEMAPHORE) };
+        if handle.is_null() {
+            return Err(EspError::new(ESP_ERR_NO_MEM, "xQueueGenericCreate"));
+        }
+        Ok(Semaphore { handle })
+    }
+
+    pub fn counting(max: u32, initial: u32) -> EspResult<Semaphore> {
+        if max == 0 || initial > max {
+            return Err(EspError::new(ESP_ERR_INVALID_ARG, "Semaphore::counting"));
+        }
+        let handle = unsafe { xQueueCreateCountingSemaphore(max, initial) };
+        if handle.is_null() {
+            return Err(EspError::new(
+                ESP_ERR_NO_MEM,
+                "xQueueCreateCountingSemaphore",
+            ));
+        }
+        Ok(Semaphore { handle })
+    }
+
+    /// Waits up to `timeout` for the semaphore, `false` if it stayed unavailable
+    pub fn take(&self, timeout: TickType_t) -> bool {
+        unsafe { xQueueGenericReceive(self.handle, ptr::null_mut(), timeout, 0) == pdPASS }
+    }
+
+    /// Like `take` but gives the semaphore back once the guard is dropped
+    pub fn acquire(&self, timeout: TickType_t) -> Option<SemaphoreGuard<'_>> {
+        if self.take(timeout) {
+            Some(SemaphoreGuard { semaphore: self })
+        } else {
+            None
+        }
+    }
+
+    /// `false` if the semaphore was already at its maximum count
+    pub fn give(&self) -> bool {
+        unsafe { xQueueGenericSend(self.handle, ptr::null(), 0, queueSEND_TO_BACK) == pdPASS }
+    }
+
+    /// Interrupt safe `give`
+    ///
+    /// `None` if the semaphore was full, otherwise whether a higher priority task was
+    /// woken and the ISR should yield.
+    pub fn give_from_isr(&self) -> Option<bool> {
+        let mut woken: BaseType_t = 0;
+        if unsafe { xQueueGiveFromISR(self.handle, &mut woken) } == pdPASS {
+            Some(woken != 0)
+        } else {
+            None
+        }
+    }
+
+    /// Current count, 0 or 1 for binary semaphores
+    pub fn count(&self) -> u32 {
+        unsafe { uxQueueMessagesWaiting(self.handle) }
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        unsafe { vQueueDelete(self.handle) };
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        self.semaphore.give();
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn mutex_created_on_first_lock() {
+        let live = mock::queue::live_count();
+        let mutex = Mutex::new(1);
+        assert_eq!(mock::queue::live_count(), live);
+
+        *mutex.lock() += 1;
+        assert_eq!(mock::queue::live_count(), live + 1);
+        let handle = unsafe { *mutex.handle.get() };
+        assert_eq!(mock::queue::kind(handle), Some(queueQUEUE_TYPE_MUTEX as u8));
+        assert_eq!(*mutex.lock(), 2);
+        drop(mutex);
+        assert!(mock::queue::is_deleted(handle));
+    }
+
+    #[test]
+    fn guard_unlocks_on_drop() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock(0).unwrap();
+        assert!(mutex.try_lock(10).is_none());
+        drop(guard);
+        assert!(mutex.try_lock(0).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Mutex::lock failed to take the mutex")]
+    fn lock_while_held() {
+        // The fake never blocks, so the second lock fails instead of deadlocking
+        let mutex = Mutex::new(());
+        let _guard = mutex.lock();
+        let _ = mutex.lock();
+    }
+
+    #[test]
+    fn recursive_mutex_nests() {
+        let mutex = RecursiveMutex::new().unwrap();
+        let outer = mutex.lock(0).unwrap();
+        let inner = mutex.lock(0).unwrap();
+        assert_eq!(mock::queue::len(mutex.handle), 0);
+        drop(inner);
+        assert_eq!(mock::queue::len(mutex.handle), 0);
+        drop(outer);
+        assert_eq!(mock::queue::len(mutex.handle), 1);
+    }
+
+    #[test]
+    fn semaphores() {
+        let binary = Semaphore::binary().unwrap();
+        assert!(!binary.take(0));
+        assert!(binary.give());
+        assert!(!binary.give());
+        assert_eq!(binary.count(), 1);
+        assert!(binary.take(0));
+        assert_eq!(binary.give_from_isr(), Some(false));
+        assert_eq!(binary.give_from_isr(), None);
+
+        let counting = Semaphore::counting(3, 2).unwrap();
+        {
+            let _first = counting.acquire(0).unwrap();
+            let _second = counting.acquire(0).unwrap();
+            assert!(counting.acquire(0).is_none());
+        }
+        assert_eq!(counting.count(), 2);
+        for &(max, initial) in &[(0, 0), (2, 3)] {
+            let err = Semaphore::counting(max, initial).err().unwrap();
+            assert_eq!(err.code(), ESP_ERR_INVALID_ARG as esp_err_t);
+        }
+    }
+}
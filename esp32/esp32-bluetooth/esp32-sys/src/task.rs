@@ -0,0 +1,389 @@
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::mem;
+use core::ops::Deref;
+use core::ptr;
+
+use crate::error::{EspError, EspResult};
+use crate::sync::CriticalSection;
+use crate::*;
+
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+/// Shared by a spawned task and its `Task`, freed by whichever of the two lets go last
+struct Exit {
+    section: CriticalSection,
+    /// The closure returned, the task is suspended waiting to be deleted
+    exited: Cell<bool>,
+    /// The `Task` was dropped or deleted
+    released: Cell<bool>,
+}
+
+struct Start {
+    entry: Entry,
+    exit: *mut Exit,
+}
+
+/// Smallest stack accepted by `spawn`, in bytes like everything stack related in ESP-IDF
+///
+/// The FreeRTOS minimum is only enough for the idle task, anything running Rust code with
+/// formatting or BLE calls needs several times that.
+pub const MIN_STACK_SIZE: u32 = configMINIMAL_STACK_SIZE * 2;
+
+/// xTaskCreatePinnedToCore's tskNO_AFFINITY, a macro in the headers
+const NO_AFFINITY: BaseType_t = 0x7FFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Core {
+    /// Core 0, runs the WiFi/BT controller
+    Pro = 0,
+    /// Core 1, runs `app_main`
+    App = 1,
+}
+
+/// A task spawned from Rust, owning its FreeRTOS task
+///
+/// A task whose closure returned stays suspended until this is dropped or deleted, so the
+/// handle never outlives the task. Dropping it lets the task run on and clean up after
+/// itself.
+pub struct Task {
+    task: TaskRef,
+    exit: *mut Exit,
+}
+
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+/// A task that isn't owned, as returned by `Task::current`
+///
+/// It can be notified and inspected but not deleted.
+pub struct TaskRef {
+    handle: TaskHandle_t,
+}
+
+unsafe impl Send for TaskRef {}
+unsafe impl Sync for TaskRef {}
+
+/// Runs `f` in a new task
+///
+/// `name` must be NUL terminated. `core` pins the task like the C firmware's
+/// `xTaskCreatePinnedToCore`, `None` lets the scheduler pick. Stacks below
+/// `MIN_STACK_SIZE` or larger than the largest free block of internal RAM are rejected
+/// with `ESP_ERR_INVALID_SIZE`, priorities past `configMAX_PRIORITIES` with
+/// `ESP_ERR_INVALID_ARG`.
+pub fn spawn<F>(
+    name: &'static str,
+    stack_size: u32,
+    priority: u32,
+    core: Option<Core>,
+    f: F,
+) -> EspResult<Task>
+where
+    F: FnOnce() + Send + 'static,
+{
+    assert!(name.ends_with('\0'), "task name must be NUL terminated");
+    let largest_block =
+        unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_INTERNAL | MALLOC_CAP_8BIT) };
+    if stack_size < MIN_STACK_SIZE || stack_size as size_t > largest_block {
+        return Err(EspError::new(ESP_ERR_INVALID_SIZE, "task::spawn"));
+    }
+    if priority >= configMAX_PRIORITIES {
+        return Err(EspError::new(ESP_ERR_INVALID_ARG, "task::spawn"));
+    }
+
+    let exit = Box::into_raw(Box::new(Exit {
+        section: CriticalSection::new(),
+        exited: Cell::new(false),
+        released: Cell::new(false),
+    }));
+    let start = Box::into_raw(Box::new(Start {
+        entry: Box::new(f),
+        exit,
+    }));
+    let mut handle: TaskHandle_t = ptr::null_mut();
+    let rc = unsafe {
+        xTaskCreatePinnedToCore(
+            Some(task_trampoline),
+            name.as_ptr() as *const _,
+            stack_size,
+            start as *mut c_void,
+            priority,
+            &mut handle,
+            core.map_or(NO_AFFINITY, |core| core as BaseType_t),
+        )
+    };
+
+    if rc != pdPASS {
+        unsafe {
+            drop(Box::from_raw(start));
+            drop(Box::from_raw(exit));
+        }
+        // errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY is the only way this fails
+        return Err(EspError::new(ESP_ERR_NO_MEM, "xTaskCreatePinnedToCore"));
+    }
+
+    Ok(Task {
+        task: TaskRef { handle },
+        exit,
+    })
+}
+
+impl Task {
+    /// The task this is called from, valid for as long as that task runs
+    pub fn current() -> TaskRef {
+        TaskRef {
+            handle: unsafe { xTaskGetCurrentTaskHandle() },
+        }
+    }
+
+    /// Whether the closure returned
+    pub fn has_exited(&self) -> bool {
+        let exit = unsafe { &*self.exit };
+        exit.section.with(|| exit.exited.get())
+    }
+
+    /// Kills the task unless it already exited, its closure and everything it owns is
+    /// leaked then
+    pub fn delete(self) {
+        // Without `released` set the task never deletes itself, the handle is valid
+        unsafe {
+            vTaskDelete(self.task.handle);
+            drop(Box::from_raw(self.exit));
+        }
+        mem::forget(self);
+    }
+}
+
+impl Deref for Task {
+    type Target = TaskRef;
+
+    fn deref(&self) -> &TaskRef {
+        &self.task
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let exited = unsafe {
+            let exit = &*self.exit;
+            exit.section.with(|| {
+                exit.released.set(true);
+                exit.exited.get()
+            })
+        };
+        // A task still running frees `Exit` and deletes itself once it returns
+        if exited {
+            unsafe {
+                vTaskDelete(self.task.handle);
+                drop(Box::from_raw(self.exit));
+            }
+        }
+    }
+}
+
+impl TaskRef {
+    pub fn handle(&self) -> TaskHandle_t {
+        self.handle
+    }
+
+    /// Sets `bits` in the task's notification value, see `wait_notification`
+    pub fn notify(&self, bits: u32) {
+        unsafe { xTaskNotify(self.handle, bits, eNotifyAction_eSetBits) };
+    }
+
+    /// Interrupt safe `notify`, returns whether a higher priority task was woken
+    pub fn notify_from_isr(&self, bits: u32) -> bool {
+        let mut woken: BaseType_t = 0;
+        unsafe { xTaskNotifyFromISR(self.handle, bits, eNotifyAction_eSetBits, &mut woken) };
+        woken != 0
+    }
+
+    pub fn suspend(&self) {
+        unsafe { vTaskSuspend(self.handle) };
+    }
+
+    pub fn resume(&self) {
+        unsafe { vTaskResume(self.handle) };
+    }
+
+    /// Smallest amount of stack, in bytes, that has been free since the task started
+    ///
+    /// Values close to 0 mean the stack size passed to `spawn` is too small.
+    pub fn stack_headroom(&self) -> u32 {
+        unsafe { uxTaskGetStackHighWaterMark(self.handle) }
+    }
+}
+
+/// Blocks the calling task until it is notified, returns the bits that were set
+///
+/// All bits are cleared once received, `None` on timeout.
+pub fn wait_notification(timeout: TickType_t) -> Option<u32> {
+    let mut bits = 0;
+    let rc = unsafe { xTaskNotifyWait(0, u32::max_value(), &mut bits, timeout) };
+    if rc == pdPASS {
+        Some(bits)
+    } else {
+        None
+    }
+}
+
+pub fn delay(ticks: TickType_t) {
+    unsafe { vTaskDelay(ticks) };
+}
+
+unsafe extern "C" fn task_trampoline(arg: *mut c_void) {
+    let start = Box::from_raw(arg as *mut Start);
+    let exit = start.exit;
+    (start.entry)();
+
+    let released = {
+        let exit = &*exit;
+        exit.section.with(|| {
+            exit.exited.set(true);
+            exit.released.get()
+        })
+    };
+
+    // FreeRTOS tasks must never return. With its `Task` still around the task waits for
+    // it to delete the task, so the handle stays valid until then.
+    if released {
+        drop(Box::from_raw(exit));
+        vTaskDelete(ptr::null_mut());
+    }
+    loop {
+        vTaskSuspend(ptr::null_mut());
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use crate::mock::task::State;
+    use host_std::sync::mpsc;
+
+    const STACK_SIZE: u32 = MIN_STACK_SIZE * 2;
+
+    /// Waits for a task's closure to return
+    fn wait_exited(task: &Task) {
+        mock::task::wait_for(task.handle(), State::Suspended);
+        assert!(task.has_exited());
+    }
+
+    #[test]
+    fn spawn_checks() {
+        let live = mock::task::live_count();
+        let spawn_with = |stack_size, priority| {
+            spawn("checked\0", stack_size, priority, None, || {})
+                .err()
+                .map(|err| err.code())
+        };
+        assert_eq!(
+            spawn_with(MIN_STACK_SIZE - 1, 1),
+            Some(ESP_ERR_INVALID_SIZE as esp_err_t)
+        );
+        mock::task::set_largest_free_block(Some(STACK_SIZE as size_t - 1));
+        assert_eq!(
+            spawn_with(STACK_SIZE, 1),
+            Some(ESP_ERR_INVALID_SIZE as esp_err_t)
+        );
+        mock::task::set_largest_free_block(None);
+        assert_eq!(
+            spawn_with(STACK_SIZE, configMAX_PRIORITIES),
+            Some(ESP_ERR_INVALID_ARG as esp_err_t)
+        );
+        mock::task::fail_next_creates(1);
+        assert_eq!(spawn_with(STACK_SIZE, 1), Some(ESP_ERR_NO_MEM as esp_err_t));
+        assert_eq!(mock::task::live_count(), live);
+
+        let task = spawn("pinned\0", STACK_SIZE, 5, Some(Core::App), || {}).unwrap();
+        let info = mock::task::info(task.handle());
+        assert_eq!(info.name, "pinned");
+        assert_eq!(
+            (info.stack_size, info.priority, info.core),
+            (STACK_SIZE, 5, 1)
+        );
+        assert_eq!(task.stack_headroom(), STACK_SIZE);
+        let task = spawn("anywhere\0", STACK_SIZE, 1, None, || {}).unwrap();
+        assert_eq!(mock::task::info(task.handle()).core, NO_AFFINITY);
+    }
+
+    #[test]
+    fn exits_before_drop() {
+        let (tx, rx) = mpsc::channel();
+        let task = spawn("exits\0", STACK_SIZE, 1, None, move || {
+            tx.send(Task::current().handle() as usize).unwrap();
+        })
+        .unwrap();
+        assert_eq!(rx.recv().unwrap(), task.handle() as usize);
+
+        // The task waits to be deleted, so its handle stays valid
+        wait_exited(&task);
+        let handle = task.handle();
+        task.notify(1);
+        drop(task);
+        assert_eq!(mock::task::state(handle), State::Deleted);
+    }
+
+    #[test]
+    fn dropped_before_exit() {
+        let (tx, rx) = mpsc::channel::<()>();
+        let task = spawn("outlives\0", STACK_SIZE, 1, None, move || {
+            let _ = rx.recv();
+        })
+        .unwrap();
+        let handle = task.handle();
+        drop(task);
+        assert_eq!(mock::task::state(handle), State::Running);
+
+        // The task frees what it shares with the `Task` and deletes itself once it returns
+        drop(tx);
+        mock::task::wait_for(handle, State::Deleted);
+    }
+
+    #[test]
+    fn delete() {
+        let task = spawn("deleted\0", STACK_SIZE, 1, None, || {
+            wait_notification(portMAX_DELAY);
+            unreachable!("deleted while waiting");
+        })
+        .unwrap();
+        let handle = task.handle();
+        task.delete();
+        assert_eq!(mock::task::state(handle), State::Deleted);
+        unsafe { xTaskNotify(handle, 1, eNotifyAction_eSetBits) };
+
+        let task = spawn("deleted\0", STACK_SIZE, 1, None, || {}).unwrap();
+        let handle = task.handle();
+        wait_exited(&task);
+        task.delete();
+        assert_eq!(mock::task::state(handle), State::Deleted);
+    }
+
+    #[test]
+    fn notifications() {
+        let (tx, rx) = mpsc::channel();
+        let task = spawn("notified\0", STACK_SIZE, 1, None, move || {
+            tx.send(wait_notification(portMAX_DELAY)).unwrap();
+        })
+        .unwrap();
+        task.notify(0b101);
+        assert_eq!(rx.recv().unwrap(), Some(0b101));
+        wait_exited(&task);
+    }
+
+    #[test]
+    fn current() {
+        let current = Task::current();
+        assert!(!current.handle().is_null());
+        assert_eq!(Task::current().handle(), current.handle());
+
+        current.notify(0b10);
+        current.notify(0b01);
+        assert_eq!(wait_notification(0), Some(0b11));
+        assert_eq!(wait_notification(0), None);
+        assert!(!current.notify_from_isr(4));
+        assert_eq!(wait_notification(1), Some(4));
+    }
+}
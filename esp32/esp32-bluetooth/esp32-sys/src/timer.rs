@@ -0,0 +1,166 @@
+use alloc::boxed::Box;
+use core::ffi::c_void;
+use core::mem::ManuallyDrop;
+use core::ptr;
+
+use crate::{
+    pdPASS, portMAX_DELAY, pvTimerGetTimerID, xTimerCreate, xTimerGenericCommand, xTimerGetPeriod,
+    xTimerIsTimerActive, xTimerPendFunctionCall, xTimerReset, xTimerStart, xTimerStop, BaseType_t,
+    TickType_t, TimerCommand, TimerHandle_t, UBaseType_t,
+};
+
+type Callback = Box<dyn FnMut(&Timer) + Send + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// xTimerCreate returned a null handle, the FreeRTOS heap is exhausted
+    CreateFailed,
+    /// The command could not be posted to the timer service queue in time
+    CommandFailed,
+}
+
+/// Whether a timer fires once or re-arms itself after every expiry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    AutoReload,
+}
+
+/// An owned FreeRTOS software timer running a Rust closure
+///
+/// The callback runs on the timer service task and receives the timer it was
+/// fired from, so it can stop, reset or re-period itself. Dropping the timer
+/// deletes it and frees the callback.
+pub struct Timer {
+    handle: TimerHandle_t,
+    callback: *mut Callback,
+}
+
+unsafe impl Send for Timer {}
+
+impl Timer {
+    /// Creates a dormant timer, call `start` to arm it
+    ///
+    /// `name` must be NUL terminated, FreeRTOS keeps the pointer around.
+    pub fn new<F>(
+        name: &'static str,
+        period: TickType_t,
+        mode: TimerMode,
+        callback: F,
+    ) -> Result<Timer, TimerError>
+    where
+        F: FnMut(&Timer) + Send + 'static,
+    {
+        assert!(name.ends_with('\0'), "timer name must be NUL terminated");
+
+        let callback: *mut Callback = Box::into_raw(Box::new(Box::new(callback)));
+        let handle = unsafe {
+            xTimerCreate(
+                name.as_ptr() as *const _,
+                period,
+                (mode == TimerMode::AutoReload) as UBaseType_t,
+                callback as *mut c_void,
+                Some(timer_trampoline),
+            )
+        };
+
+        if handle.is_null() {
+            unsafe { drop(Box::from_raw(callback)) };
+            return Err(TimerError::CreateFailed);
+        }
+
+        Ok(Timer { handle, callback })
+    }
+
+    pub fn start(&self, ticks_to_wait: TickType_t) -> Result<(), TimerError> {
+        check(unsafe { xTimerStart(self.handle, ticks_to_wait) })
+    }
+
+    pub fn stop(&self, ticks_to_wait: TickType_t) -> Result<(), TimerError> {
+        check(unsafe { xTimerStop(self.handle, ticks_to_wait) })
+    }
+
+    /// Restarts the timer so it expires one full period from now
+    pub fn reset(&self, ticks_to_wait: TickType_t) -> Result<(), TimerError> {
+        check(unsafe { xTimerReset(self.handle, ticks_to_wait) })
+    }
+
+    /// Changes the period, this also starts a dormant timer
+    pub fn set_period(
+        &self,
+        period: TickType_t,
+        ticks_to_wait: TickType_t,
+    ) -> Result<(), TimerError> {
+        check(unsafe {
+            xTimerGenericCommand(
+                self.handle,
+                TimerCommand::tmrCOMMAND_CHANGE_PERIOD as BaseType_t,
+                period,
+                ptr::null_mut(),
+                ticks_to_wait,
+            )
+        })
+    }
+
+    pub fn period(&self) -> TickType_t {
+        unsafe { xTimerGetPeriod(self.handle) }
+    }
+
+    pub fn is_active(&self) -> bool {
+        unsafe { xTimerIsTimerActive(self.handle) != 0 }
+    }
+
+    pub fn handle(&self) -> TimerHandle_t {
+        self.handle
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe {
+            let rc = xTimerGenericCommand(
+                self.handle,
+                TimerCommand::tmrCOMMAND_DELETE as BaseType_t,
+                0,
+                ptr::null_mut(),
+                portMAX_DELAY,
+            );
+            if rc != pdPASS {
+                return;
+            }
+
+            // Commands are processed in order by the service task, so the callback is freed
+            // there once the delete went through and no expiry can reference it anymore.
+            // If that can't be queued the callback is leaked rather than freed too early.
+            xTimerPendFunctionCall(
+                Some(free_callback),
+                self.callback as *mut c_void,
+                0,
+                portMAX_DELAY,
+            );
+        }
+    }
+}
+
+fn check(rc: BaseType_t) -> Result<(), TimerError> {
+    if rc == pdPASS {
+        Ok(())
+    } else {
+        Err(TimerError::CommandFailed)
+    }
+}
+
+unsafe extern "C" fn timer_trampoline(handle: TimerHandle_t) {
+    let callback = pvTimerGetTimerID(handle) as *mut Callback;
+    if callback.is_null() {
+        return;
+    }
+
+    // Borrowed view of the owning Timer, it must not delete the handle when it goes away
+    let timer = ManuallyDrop::new(Timer { handle, callback });
+    (*callback)(&timer);
+}
+
+unsafe extern "C" fn free_callback(callback: *mut c_void, _: u32) {
+    drop(Box::from_raw(callback as *mut Callback));
+}
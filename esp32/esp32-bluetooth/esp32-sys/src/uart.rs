@@ -0,0 +1,432 @@
+use core::ffi::c_void;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use crate::error::{EspError, EspResult};
+use crate::gpio::{OutputCapable, Pin};
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+/// Driver settings, defaults to 8N1 without flow control and a 2k receive buffer
+#[derive(Debug, Clone)]
+pub struct UartConfig {
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    tx_pin: i32,
+    rx_pin: i32,
+    rts_pin: i32,
+    cts_pin: i32,
+    rx_buffer_size: usize,
+    tx_buffer_size: usize,
+    event_queue_size: usize,
+}
+
+impl UartConfig {
+    pub fn new(baud_rate: u32) -> UartConfig {
+        UartConfig {
+            baud_rate,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            tx_pin: UART_PIN_NO_CHANGE,
+            rx_pin: UART_PIN_NO_CHANGE,
+            rts_pin: UART_PIN_NO_CHANGE,
+            cts_pin: UART_PIN_NO_CHANGE,
+            rx_buffer_size: 2048,
+            tx_buffer_size: 0,
+            event_queue_size: 0,
+        }
+    }
+
+    pub fn data_bits(mut self, data_bits: DataBits) -> UartConfig {
+        self.data_bits = data_bits;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> UartConfig {
+        self.parity = parity;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> UartConfig {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    pub fn pins<T: OutputCapable, R: Pin>(mut self, tx: T, rx: R) -> UartConfig {
+        self.tx_pin = tx.number();
+        self.rx_pin = rx.number();
+        self
+    }
+
+    /// Enables RTS/CTS hardware flow control on the given pins
+    pub fn flow_control<RTS: OutputCapable, CTS: Pin>(mut self, rts: RTS, cts: CTS) -> UartConfig {
+        self.rts_pin = rts.number();
+        self.cts_pin = cts.number();
+        self
+    }
+
+    /// Must be larger than the 128 byte hardware FIFO
+    pub fn rx_buffer_size(mut self, size: usize) -> UartConfig {
+        self.rx_buffer_size = size;
+        self
+    }
+
+    /// 0 makes writes block until everything went into the hardware FIFO
+    pub fn tx_buffer_size(mut self, size: usize) -> UartConfig {
+        self.tx_buffer_size = size;
+        self
+    }
+
+    /// Number of driver events buffered for `Uart::next_event`, 0 disables them
+    pub fn event_queue_size(mut self, size: usize) -> UartConfig {
+        self.event_queue_size = size;
+        self
+    }
+
+    fn raw(&self) -> uart_config_t {
+        let hw_flow = self.rts_pin != UART_PIN_NO_CHANGE;
+        uart_config_t {
+            baud_rate: self.baud_rate as i32,
+            data_bits: match self.data_bits {
+                DataBits::Five => uart_word_length_t_UART_DATA_5_BITS,
+                DataBits::Six => uart_word_length_t_UART_DATA_6_BITS,
+                DataBits::Seven => uart_word_length_t_UART_DATA_7_BITS,
+                DataBits::Eight => uart_word_length_t_UART_DATA_8_BITS,
+            },
+            parity: match self.parity {
+                Parity::None => uart_parity_t_UART_PARITY_DISABLE,
+                Parity::Even => uart_parity_t_UART_PARITY_EVEN,
+                Parity::Odd => uart_parity_t_UART_PARITY_ODD,
+            },
+            stop_bits: match self.stop_bits {
+                StopBits::One => uart_stop_bits_t_UART_STOP_BITS_1,
+                StopBits::OneAndHalf => uart_stop_bits_t_UART_STOP_BITS_1_5,
+                StopBits::Two => uart_stop_bits_t_UART_STOP_BITS_2,
+            },
+            flow_ctrl: if hw_flow {
+                uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_CTS_RTS
+            } else {
+                uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE
+            },
+            rx_flow_ctrl_thresh: if hw_flow { 122 } else { 0 },
+            use_ref_tick: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartEvent {
+    /// This many bytes are waiting in the receive buffer
+    Data(usize),
+    Break,
+    BufferFull,
+    FifoOverflow,
+    FrameError,
+    ParityError,
+    DataBreak,
+    PatternDetected,
+    Unknown(uart_event_type_t),
+}
+
+impl UartEvent {
+    fn from_raw(event: &uart_event_t) -> UartEvent {
+        match event.type_ {
+            uart_event_type_t_UART_DATA => UartEvent::Data(event.size as usize),
+            uart_event_type_t_UART_BREAK => UartEvent::Break,
+            uart_event_type_t_UART_BUFFER_FULL => UartEvent::BufferFull,
+            uart_event_type_t_UART_FIFO_OVF => UartEvent::FifoOverflow,
+            uart_event_type_t_UART_FRAME_ERR => UartEvent::FrameError,
+            uart_event_type_t_UART_PARITY_ERR => UartEvent::ParityError,
+            uart_event_type_t_UART_DATA_BREAK => UartEvent::DataBreak,
+            uart_event_type_t_UART_PATTERN_DET => UartEvent::PatternDetected,
+            other => UartEvent::Unknown(other),
+        }
+    }
+}
+
+/// An installed UART driver, uninstalled on drop
+pub struct Uart {
+    port: uart_port_t,
+    events: QueueHandle_t,
+}
+
+unsafe impl Send for Uart {}
+
+impl Uart {
+    pub fn new(port: uart_port_t, config: &UartConfig) -> EspResult<Uart> {
+        let mut events: QueueHandle_t = ptr::null_mut();
+        unsafe {
+            esp!(uart_param_config(port, &config.raw()))?;
+            esp!(uart_set_pin(
+                port,
+                config.tx_pin,
+                config.rx_pin,
+                config.rts_pin,
+                config.cts_pin
+            ))?;
+            esp!(uart_driver_install(
+                port,
+                config.rx_buffer_size as i32,
+                config.tx_buffer_size as i32,
+                config.event_queue_size as i32,
+                if config.event_queue_size > 0 {
+                    &mut events
+                } else {
+                    ptr::null_mut()
+                },
+                0
+            ))?;
+        }
+
+        Ok(Uart { port, events })
+    }
+
+    pub fn port(&self) -> uart_port_t {
+        self.port
+    }
+
+    /// Queues all of `data` for sending, blocks while the transmit buffer is full
+    pub fn write(&mut self, data: &[u8]) -> EspResult<usize> {
+        let written =
+            unsafe { uart_write_bytes(self.port, data.as_ptr() as *const _, data.len() as size_t) };
+        if written < 0 {
+            return Err(EspError::new(ESP_FAIL as u32, "uart_write_bytes"));
+        }
+        Ok(written as usize)
+    }
+
+    /// Waits until everything written so far left the transmitter
+    pub fn flush(&mut self, timeout: TickType_t) -> EspResult<()> {
+        unsafe { esp!(uart_wait_tx_done(self.port, timeout)) }
+    }
+
+    /// Reads up to `buf.len()` bytes, returns early with what arrived once `timeout` passes
+    pub fn read(&mut self, buf: &mut [u8], timeout: TickType_t) -> EspResult<usize> {
+        let read =
+            unsafe { uart_read_bytes(self.port, buf.as_mut_ptr(), buf.len() as u32, timeout) };
+        if read < 0 {
+            return Err(EspError::new(ESP_FAIL as u32, "uart_read_bytes"));
+        }
+        Ok(read as usize)
+    }
+
+    /// Blocks until `buf` is completely filled
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> EspResult<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            filled += self.read(&mut buf[filled..], portMAX_DELAY)?;
+        }
+        Ok(())
+    }
+
+    /// Reads one `\n` terminated line into `buf` and returns its length
+    ///
+    /// The terminator and a preceding `\r` are not stored. `timeout` applies to each byte,
+    /// fails with `ESP_ERR_TIMEOUT` if the line stalls, the partial line is dropped. A line
+    /// that doesn't fit is read up to its end and dropped, then fails with
+    /// `ESP_ERR_INVALID_SIZE`.
+    pub fn read_line(&mut self, buf: &mut [u8], timeout: TickType_t) -> EspResult<usize> {
+        let mut len = 0;
+        // A `\r` is only stored once something other than `\n` follows it
+        let mut cr = false;
+        loop {
+            let byte = self.read_byte(timeout)?;
+            if byte == b'\n' {
+                return Ok(len);
+            }
+
+            let pending: &[u8] = match (cr, byte) {
+                (true, b'\r') => b"\r",
+                (true, _) => &[b'\r', byte],
+                (false, b'\r') => b"",
+                (false, _) => core::slice::from_ref(&byte),
+            };
+            cr = byte == b'\r';
+            if pending.len() > buf.len() - len {
+                while self.read_byte(timeout)? != b'\n' {}
+                return Err(EspError::new(ESP_ERR_INVALID_SIZE, "Uart::read_line"));
+            }
+            buf[len..len + pending.len()].copy_from_slice(pending);
+            len += pending.len();
+        }
+    }
+
+    fn read_byte(&mut self, timeout: TickType_t) -> EspResult<u8> {
+        let mut byte = 0u8;
+        if self.read(core::slice::from_mut(&mut byte), timeout)? == 0 {
+            return Err(EspError::new(ESP_ERR_TIMEOUT, "Uart::read_line"));
+        }
+        Ok(byte)
+    }
+
+    /// Bytes waiting in the receive buffer
+    pub fn available(&self) -> EspResult<usize> {
+        let mut size: size_t = 0;
+        unsafe { esp!(uart_get_buffered_data_len(self.port, &mut size))? };
+        Ok(size as usize)
+    }
+
+    /// Drops everything in the receive buffer
+    pub fn clear_input(&mut self) -> EspResult<()> {
+        unsafe { esp!(uart_flush_input(self.port)) }
+    }
+
+    /// Waits for the next driver event, `None` on timeout or without an event queue
+    pub fn next_event(&mut self, timeout: TickType_t) -> Option<UartEvent> {
+        if self.events.is_null() {
+            return None;
+        }
+
+        let mut event = MaybeUninit::<uart_event_t>::uninit();
+        let received = unsafe {
+            xQueueGenericReceive(self.events, event.as_mut_ptr() as *mut c_void, timeout, 0)
+        };
+        if received == pdPASS {
+            Some(UartEvent::from_raw(unsafe { &*event.as_ptr() }))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes()).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
+
+impl Drop for Uart {
+    fn drop(&mut self) {
+        unsafe { uart_driver_delete(self.port) };
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use crate::gpio::Pins;
+    use crate::mock;
+    use core::fmt::Write;
+
+    fn loopback(port: uart_port_t) -> Uart {
+        let pins = unsafe { Pins::steal() };
+        Uart::new(
+            port,
+            &UartConfig::new(115200).pins(pins.gpio17, pins.gpio16),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn install_and_uninstall() {
+        let uart = loopback(1);
+        assert!(mock::uart::is_installed(1));
+        assert_eq!(mock::uart::baud_rate(1), 115200);
+        assert_eq!(mock::uart::pins(1), [17, 16, -1, -1]);
+        assert!(Uart::new(1, &UartConfig::new(9600)).is_err());
+        drop(uart);
+        assert!(!mock::uart::is_installed(1));
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut uart = loopback(1);
+        assert_eq!(uart.write(b"hello").unwrap(), 5);
+        write!(uart, " {}", 42).unwrap();
+        assert_eq!(mock::uart::take_written(1), b"hello 42");
+        assert_eq!(uart.available().unwrap(), 8);
+
+        let mut buf = [0u8; 5];
+        uart.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        let mut buf = [0u8; 16];
+        assert_eq!(uart.read(&mut buf, 10).unwrap(), 3);
+        assert_eq!(&buf[..3], b" 42");
+    }
+
+    #[test]
+    fn read_times_out_empty() {
+        let mut uart = loopback(1);
+        mock::uart::set_loopback(1, false);
+        uart.write(b"lost").unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(uart.read(&mut buf, 10).unwrap(), 0);
+
+        mock::uart::inject(1, b"par");
+        let err = uart.read_line(&mut buf, 10).unwrap_err();
+        assert_eq!(err.code(), ESP_ERR_TIMEOUT as esp_err_t);
+
+        mock::uart::inject(1, b"junk");
+        uart.clear_input().unwrap();
+        assert_eq!(uart.available().unwrap(), 0);
+        mock::uart::set_loopback(1, true);
+    }
+
+    #[test]
+    fn read_lines() {
+        let mut uart = loopback(1);
+        mock::uart::inject(1, b"G1 X10\r\nM2\n\nG1 X1000\r\na\rb\r\r\n");
+        let mut buf = [0u8; 8];
+        let len = uart.read_line(&mut buf, 10).unwrap();
+        assert_eq!(&buf[..len], b"G1 X10");
+        let len = uart.read_line(&mut buf, 10).unwrap();
+        assert_eq!(&buf[..len], b"M2");
+        assert_eq!(uart.read_line(&mut buf, 10).unwrap(), 0);
+        // CRLF after a line that fills the buffer doesn't overflow it
+        let len = uart.read_line(&mut buf, 10).unwrap();
+        assert_eq!(&buf[..len], b"G1 X1000");
+        let len = uart.read_line(&mut buf, 10).unwrap();
+        assert_eq!(&buf[..len], b"a\rb\r");
+
+        // The rest of a line that doesn't fit is dropped with it
+        mock::uart::inject(1, b"too long for this bonds clear\nok\n");
+        let err = uart.read_line(&mut buf, 10).unwrap_err();
+        assert_eq!(err.code(), ESP_ERR_INVALID_SIZE as esp_err_t);
+        let len = uart.read_line(&mut buf, 10).unwrap();
+        assert_eq!(&buf[..len], b"ok");
+
+        mock::uart::inject(1, b"12345678\r\r\n");
+        let err = uart.read_line(&mut buf, 10).unwrap_err();
+        assert_eq!(err.code(), ESP_ERR_INVALID_SIZE as esp_err_t);
+        assert_eq!(uart.available().unwrap(), 0);
+    }
+
+    #[test]
+    fn data_events() {
+        let live = mock::queue::live_count();
+        let mut uart = Uart::new(2, &UartConfig::new(9600).event_queue_size(4)).unwrap();
+        mock::uart::inject(2, b"abc");
+        uart.write(b"xy").unwrap();
+        assert_eq!(uart.next_event(0), Some(UartEvent::Data(3)));
+        assert_eq!(uart.next_event(0), Some(UartEvent::Data(2)));
+        assert_eq!(uart.next_event(0), None);
+        drop(uart);
+        assert_eq!(mock::queue::live_count(), live);
+        assert_eq!(loopback(1).next_event(0), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Bluetooth UUIDs laid out the way NimBLE takes them
+//!
+//! 128-bit UUIDs can be written out in their textual form and are parsed at compile time
+//! when used in a `const`:
+//!
+//! ```ignore
+//! const VENDOR: Uuid = Uuid::parse("0000ff01-0000-1000-8000-00805f9b34fb");
+//! ```
+
+use core::fmt;
+
+use crate::{
+    ble_uuid128_t, ble_uuid16_t, ble_uuid32_t, ble_uuid_any_t, ble_uuid_t, BLE_UUID_TYPE_128,
+    BLE_UUID_TYPE_16, BLE_UUID_TYPE_32,
+};
+
+/// The Bluetooth Base UUID `00000000-0000-1000-8000-00805f9b34fb`, 16 and 32-bit UUIDs
+/// are short for this with the top 32 bits replaced
+pub const BLUETOOTH_BASE: u128 = 0x00000000_0000_1000_8000_00805f9b34fb;
+
+/// A 16, 32 or 128-bit UUID that can be handed to NimBLE wherever it expects a `ble_uuid_t`
+/// pointer
+///
+/// Equality follows `ble_uuid_cmp`, a 16-bit UUID never equals its 128-bit spelling.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Uuid(ble_uuid_any_t);
+
+impl Uuid {
+    /// A 16-bit UUID from the Bluetooth SIG assigned numbers
+    pub const fn from_u16(value: u16) -> Uuid {
+        Uuid(ble_uuid_any_t {
+            u16: ble_uuid16_t {
+                u: ble_uuid_t {
+                    type_: BLE_UUID_TYPE_16 as u8,
+                },
+                value,
+            },
+        })
+    }
+
+    pub const fn from_u32(value: u32) -> Uuid {
+        Uuid(ble_uuid_any_t {
+            u32: ble_uuid32_t {
+                u: ble_uuid_t {
+                    type_: BLE_UUID_TYPE_32 as u8,
+                },
+                value,
+            },
+        })
+    }
+
+    /// A 128-bit UUID, the most significant bits are the first digits of the textual form
+    pub const fn from_u128(value: u128) -> Uuid {
+        Uuid(ble_uuid_any_t {
+            u128: ble_uuid128_t {
+                u: ble_uuid_t {
+                    type_: BLE_UUID_TYPE_128 as u8,
+                },
+                // NimBLE keeps them least significant byte first
+                value: value.to_le_bytes(),
+            },
+        })
+    }
+
+    /// A 128-bit UUID made from a vendor base with `alias` in place of the `xxxx` in
+    /// `0000xxxx-...`, the same way 16-bit UUIDs extend to the Bluetooth Base UUID
+    pub const fn from_base(base: u128, alias: u16) -> Uuid {
+        Uuid::from_u128(base & !(0xffff << 96) | (alias as u128) << 96)
+    }
+
+    /// Parses `2a37`, `0000fe59` or `0000ff01-0000-1000-8000-00805f9b34fb` into a 16, 32 or
+    /// 128-bit UUID, hex digits in either case
+    ///
+    /// Panics on anything else, which fails the build when evaluated in a `const`.
+    pub const fn parse(text: &str) -> Uuid {
+        let text = text.as_bytes();
+        match text.len() {
+            4 => Uuid::from_u16(parse_hex(text, 0, 4) as u16),
+            8 => Uuid::from_u32(parse_hex(text, 0, 8) as u32),
+            36 => {
+                if text[8] != b'-' || text[13] != b'-' || text[18] != b'-' || text[23] != b'-' {
+                    panic!("UUID groups must be separated by dashes");
+                }
+                Uuid::from_u128(
+                    parse_hex(text, 0, 8) << 96
+                        | parse_hex(text, 9, 13) << 80
+                        | parse_hex(text, 14, 18) << 64
+                        | parse_hex(text, 19, 23) << 48
+                        | parse_hex(text, 24, 36),
+                )
+            }
+            _ => panic!("UUID must have 4, 8 or 32 hex digits"),
+        }
+    }
+
+    /// Copies a UUID NimBLE handed out, reading only as much as its type says is there
+    pub unsafe fn from_ptr(uuid: *const ble_uuid_t) -> Uuid {
+        let uuid = uuid as *const ble_uuid_any_t;
+        match (*uuid).u.type_ as u32 {
+            BLE_UUID_TYPE_16 => Uuid(ble_uuid_any_t { u16: (*uuid).u16 }),
+            BLE_UUID_TYPE_32 => Uuid(ble_uuid_any_t { u32: (*uuid).u32 }),
+            _ => Uuid(ble_uuid_any_t { u128: (*uuid).u128 }),
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const ble_uuid_t {
+        &self.0 as *const ble_uuid_any_t as *const ble_uuid_t
+    }
+
+    /// The 16-bit value, `None` for longer UUIDs
+    pub fn as_u16(&self) -> Option<u16> {
+        self.as_uuid16().map(|uuid| uuid.value)
+    }
+
+    /// The 128-bit value, `None` for shorter UUIDs
+    pub fn as_u128(&self) -> Option<u128> {
+        self.as_uuid128()
+            .map(|uuid| u128::from_le_bytes(uuid.value))
+    }
+
+    /// For `ble_hs_adv_fields::uuids16` and the like
+    pub fn as_uuid16(&self) -> Option<&ble_uuid16_t> {
+        match self.bits() {
+            16 => Some(unsafe { &self.0.u16 }),
+            _ => None,
+        }
+    }
+
+    pub fn as_uuid32(&self) -> Option<&ble_uuid32_t> {
+        match self.bits() {
+            32 => Some(unsafe { &self.0.u32 }),
+            _ => None,
+        }
+    }
+
+    pub fn as_uuid128(&self) -> Option<&ble_uuid128_t> {
+        match self.bits() {
+            128 => Some(unsafe { &self.0.u128 }),
+            _ => None,
+        }
+    }
+
+    /// 16, 32 or 128
+    pub fn bits(&self) -> u32 {
+        unsafe { self.0.u.type_ as u32 }
+    }
+}
+
+impl PartialEq for Uuid {
+    fn eq(&self, other: &Uuid) -> bool {
+        match (self.bits(), other.bits()) {
+            (16, 16) => self.as_u16() == other.as_u16(),
+            (32, 32) => unsafe { self.0.u32.value == other.0.u32.value },
+            (128, 128) => self.as_u128() == other.as_u128(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Uuid {}
+
+/// Formats like `ble_uuid_to_str`, `0x2a37` for 16 and 32-bit UUIDs and the dashed form for
+/// 128-bit ones
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bits() {
+            16 => write!(f, "0x{:04x}", unsafe { self.0.u16.value }),
+            32 => write!(f, "0x{:08x}", unsafe { self.0.u32.value }),
+            _ => {
+                let v = u128::from_le_bytes(unsafe { self.0.u128.value });
+                write!(
+                    f,
+                    "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+                    v >> 96,
+                    v >> 80 & 0xffff,
+                    v >> 64 & 0xffff,
+                    v >> 48 & 0xffff,
+                    v & 0xffff_ffff_ffff
+                )
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Uuid({})", self)
+    }
+}
+
+/// Value of the hex digits in `text[start..end]`
+const fn parse_hex(text: &[u8], start: usize, end: usize) -> u128 {
+    let mut value = 0;
+    let mut i = start;
+    while i < end {
+        let digit = match text[i] {
+            b'0'..=b'9' => text[i] - b'0',
+            b'a'..=b'f' => text[i] - b'a' + 10,
+            b'A'..=b'F' => text[i] - b'A' + 10,
+            _ => panic!("invalid hex digit in UUID"),
+        };
+        value = value << 4 | digit as u128;
+        i += 1;
+    }
+    value
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    const VENDOR: Uuid = Uuid::parse("0000ff01-0000-1000-8000-00805f9b34fb");
+
+    #[test]
+    fn parse() {
+        assert_eq!(VENDOR, Uuid::from_base(BLUETOOTH_BASE, 0xff01));
+        assert_eq!(VENDOR.bits(), 128);
+        assert_eq!(
+            Uuid::parse("6E400001-B5A3-f393-e0a9-E50E24DCCA9E").as_u128(),
+            Some(0x6e400001_b5a3_f393_e0a9_e50e24dcca9e)
+        );
+
+        let heart_rate = Uuid::parse("2A37");
+        assert_eq!(heart_rate.bits(), 16);
+        assert_eq!(heart_rate.as_u16(), Some(0x2a37));
+        assert_eq!(heart_rate, Uuid::parse("2a37"));
+
+        let long = Uuid::parse("0000fE59");
+        assert_eq!(long.bits(), 32);
+        assert_eq!(long.as_uuid32().map(|uuid| uuid.value), Some(0xfe59));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid hex digit")]
+    fn parse_bad_digit() {
+        Uuid::parse("2a3g");
+    }
+
+    #[test]
+    #[should_panic(expected = "dashes")]
+    fn parse_missing_dash() {
+        Uuid::parse("0000ff01 0000-1000-8000-00805f9b34fb");
+    }
+
+    #[test]
+    fn widths_never_equal() {
+        let short = Uuid::from_u16(0x180d);
+        assert_ne!(short, Uuid::from_u32(0x180d));
+        assert_ne!(short, Uuid::from_base(BLUETOOTH_BASE, 0x180d));
+        assert_ne!(short, Uuid::from_u16(0x180f));
+        assert_eq!(Uuid::from_u32(0x180d), Uuid::from_u32(0x180d));
+    }
+
+    #[test]
+    fn from_ptr_round_trip() {
+        for uuid in &[Uuid::from_u16(0x2a19), Uuid::from_u32(0x1234_5678), VENDOR] {
+            let copy = unsafe { Uuid::from_ptr(uuid.as_ptr()) };
+            assert_eq!(copy, *uuid);
+            assert_eq!(copy.bits(), uuid.bits());
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", Uuid::from_u16(0x2a37)), "0x2a37");
+        assert_eq!(format!("{}", Uuid::from_u32(0xfe59)), "0x0000fe59");
+        assert_eq!(
+            format!("{}", VENDOR),
+            "0000ff01-0000-1000-8000-00805f9b34fb"
+        );
+        assert_eq!(format!("{:?}", Uuid::from_u16(0x180d)), "Uuid(0x180d)");
+    }
+}
@@ -0,0 +1,351 @@
+//! Battery Service and the motor supply voltage
+//!
+//! The battery pack is measured on GPIO34 and the motor supply, behind the motor switch,
+//! on GPIO35, both through a 100k/10k divider. The battery level is the pack voltage
+//! looked up on a `Curve` for its chemistry. The motor supply voltage is served in mV on
+//! a vendor characteristic of the polargraph service, and the motors are held while it is
+//! critical since the steppers lose steps when it sags.
+//!
+//! The curve is one of `PRESETS`, picked with the `BATTERY_CURVE` environment variable at
+//! build time. A unit can store its own points under `curve` in the `battery` NVS
+//! namespace, see `Curve::from_bytes`.
+
+use alloc::vec::Vec;
+use esp32_sys::adc::{Adc, AdcConfig, Monitor, Thresholds, VoltageLevel};
+use esp32_sys::connections;
+use esp32_sys::error::EspResult;
+use esp32_sys::gatt::{
+    Access, AttError, Handle, PresentationFormat, Request, FORMAT_UINT16, FORMAT_UINT8,
+    UNIT_PERCENT, UNIT_VOLT,
+};
+use esp32_sys::gpio::{Gpio34, Gpio35};
+use esp32_sys::nvs::{Mode, Nvs};
+use esp32_sys::sync::Mutex;
+use esp32_sys::timer::{Timer, TimerMode};
+use esp32_sys::uuid::Uuid;
+use esp32_sys::*;
+
+const TAG: &str = "battery\0";
+
+pub const GATT_BATTERY_UUID: Uuid = Uuid::from_u16(0x180F);
+pub const BATTERY_LEVEL_UUID: Uuid = Uuid::from_u16(0x2A19);
+pub const SUPPLY_VOLTAGE_UUID: Uuid = Uuid::from_u16(0xFF04);
+
+pub static BATTERY_LEVEL_HANDLE: Handle = Handle::new();
+pub static SUPPLY_VOLTAGE_HANDLE: Handle = Handle::new();
+
+pub const LEVEL_FORMAT: PresentationFormat = PresentationFormat::new(FORMAT_UINT8, UNIT_PERCENT);
+/// Little-endian `u16` in mV
+pub const SUPPLY_FORMAT: PresentationFormat =
+    PresentationFormat::new(FORMAT_UINT16, UNIT_VOLT).exponent(-3);
+
+const SAMPLE_PERIOD_MS: u32 = 1000;
+
+/// Smaller changes of the motor supply voltage aren't notified
+const SUPPLY_NOTIFY_STEP_MV: u16 = 100;
+
+const NAMESPACE: &str = "battery";
+
+/// Resting voltage in mV and state of charge in percent of common packs, the first is
+/// the default
+pub const PRESETS: &[(&str, &[(u32, u8)])] = &[
+    (
+        "li-ion-3s",
+        &[
+            (9000, 0),
+            (9900, 5),
+            (10500, 10),
+            (10950, 30),
+            (11250, 50),
+            (11550, 70),
+            (12000, 85),
+            (12450, 97),
+            (12600, 100),
+        ],
+    ),
+    (
+        "lead-acid-12v",
+        &[
+            (11600, 0),
+            (11800, 10),
+            (12000, 25),
+            (12200, 50),
+            (12400, 75),
+            (12500, 90),
+            (12700, 100),
+        ],
+    ),
+];
+
+/// Battery pack voltage in mV to state of charge in percent
+///
+/// Points are `(mV, percent)` with both rising, voltages in between are interpolated and
+/// voltages outside clamp to the first or last point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Curve {
+    points: Vec<(u32, u8)>,
+}
+
+impl Curve {
+    /// `None` unless there is a point and voltages and percentages rise up to 100
+    pub fn new(points: &[(u32, u8)]) -> Option<Curve> {
+        let rising = points
+            .windows(2)
+            .all(|pair| pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1);
+        let last = points.last()?;
+        if !rising || last.1 > 100 {
+            return None;
+        }
+        Some(Curve {
+            points: points.to_vec(),
+        })
+    }
+
+    pub fn preset(name: &str) -> Option<Curve> {
+        let &(_, points) = PRESETS.iter().find(|preset| preset.0 == name)?;
+        Curve::new(points)
+    }
+
+    /// Three bytes per point, mV as little-endian `u16` then percent
+    pub fn from_bytes(bytes: &[u8]) -> Option<Curve> {
+        if bytes.len() % 3 != 0 {
+            return None;
+        }
+        let points: Vec<(u32, u8)> = bytes
+            .chunks(3)
+            .map(|point| (u16::from_le_bytes([point[0], point[1]]) as u32, point[2]))
+            .collect();
+        Curve::new(&points)
+    }
+
+    pub fn percent(&self, mv: u32) -> u8 {
+        let first = self.points[0];
+        if mv <= first.0 {
+            return first.1;
+        }
+        for pair in self.points.windows(2) {
+            let ((mv0, p0), (mv1, p1)) = (pair[0], pair[1]);
+            if mv <= mv1 {
+                let span = (p1 - p0) as u32;
+                return p0 + ((mv - mv0) * span / (mv1 - mv0)) as u8;
+            }
+        }
+        self.points[self.points.len() - 1].1
+    }
+}
+
+/// The curve stored for this unit, else the one picked at build time
+fn load_curve() -> Curve {
+    let stored = Nvs::open(NAMESPACE, Mode::ReadOnly)
+        .and_then(|nvs| nvs.get_blob_vec("curve"))
+        .ok()
+        .and_then(|bytes| bytes);
+    if let Some(bytes) = stored {
+        match Curve::from_bytes(&bytes) {
+            Some(curve) => return curve,
+            None => warn!(TAG, "ignoring the stored curve, points must rise"),
+        }
+    }
+
+    let name = option_env!("BATTERY_CURVE").unwrap_or(PRESETS[0].0);
+    Curve::preset(name).unwrap_or_else(|| {
+        warn!(TAG, "unknown curve {}, using {}", name, PRESETS[0].0);
+        Curve::new(PRESETS[0].1).unwrap()
+    })
+}
+
+struct State {
+    battery: Adc,
+    supply: Adc,
+    curve: Curve,
+    monitor: Monitor,
+    level: u8,
+    supply_mv: u16,
+    /// Last supply voltage subscribers were told about
+    notified_supply_mv: u16,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+static SAMPLE_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
+
+/// Battery level in percent
+pub struct BatteryLevel;
+
+impl Access for BatteryLevel {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        let state = STATE.lock();
+        let state = state.as_ref().ok_or(AttError::Unlikely)?;
+        request.append(&[state.level])
+    }
+}
+
+/// Motor supply voltage in mV
+pub struct SupplyVoltage;
+
+impl Access for SupplyVoltage {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        let state = STATE.lock();
+        let state = state.as_ref().ok_or(AttError::Unlikely)?;
+        request.append(&state.supply_mv.to_le_bytes())
+    }
+}
+
+/// Sets up both ADC channels, takes a first sample and keeps sampling from a timer
+///
+/// The battery divider is on GPIO34, the motor supply's on GPIO35. The motor supply is
+/// `Low` or `Critical` below `supply_thresholds`. NVS must be initialized.
+pub fn init(
+    battery_pin: Gpio34,
+    supply_pin: Gpio35,
+    supply_thresholds: Thresholds,
+) -> EspResult<()> {
+    let curve = load_curve();
+    let config = AdcConfig::new().divider(100, 10);
+    let battery = Adc::new(battery_pin, &config)?;
+    let supply = Adc::new(supply_pin, &config)?;
+    info!(TAG, "ADC calibration: {:?}", battery.calibration());
+
+    *STATE.lock() = Some(State {
+        battery,
+        supply,
+        curve,
+        monitor: Monitor::new(supply_thresholds),
+        level: 0,
+        supply_mv: 0,
+        notified_supply_mv: 0,
+    });
+    sample();
+
+    let timer = Timer::new(
+        "battery_timer\0",
+        pdMS_TO_TICKS!(SAMPLE_PERIOD_MS),
+        TimerMode::AutoReload,
+        |_| sample(),
+    )
+    .expect("battery_timer");
+    timer.start(0).expect("battery_timer");
+    *SAMPLE_TIMER.lock() = Some(timer);
+    Ok(())
+}
+
+/// Level of the motor supply as of the last sample, `None` before `init`
+pub fn supply_level() -> Option<VoltageLevel> {
+    STATE
+        .lock()
+        .as_ref()
+        .and_then(|state| state.monitor.level())
+}
+
+/// Reads both voltages and notifies subscribers of changes
+fn sample() {
+    let mut guard = STATE.lock();
+    let state = match *guard {
+        Some(ref mut state) => state,
+        None => return,
+    };
+    let (battery_mv, supply_mv) = match (state.battery.read_mv(), state.supply.read_mv()) {
+        (Ok(battery_mv), Ok(supply_mv)) => (battery_mv, supply_mv),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!(TAG, "{}", err);
+            return;
+        }
+    };
+
+    let level = state.curve.percent(battery_mv);
+    let level_changed = level != state.level;
+    state.level = level;
+
+    let event = state.monitor.update(supply_mv);
+    state.supply_mv = supply_mv.min(u16::MAX as u32) as u16;
+    let step = (state.supply_mv as i32 - state.notified_supply_mv as i32).abs();
+    let supply_changed = event.is_some() || step >= SUPPLY_NOTIFY_STEP_MV as i32;
+    if supply_changed {
+        state.notified_supply_mv = state.supply_mv;
+    }
+    let supply_value = state.supply_mv.to_le_bytes();
+    drop(guard);
+
+    match event {
+        Some(VoltageLevel::Normal) => info!(TAG, "motor supply normal at {} mV", supply_mv),
+        Some(VoltageLevel::Low) => warn!(TAG, "motor supply low at {} mV", supply_mv),
+        Some(VoltageLevel::Critical) => {
+            error!(
+                TAG,
+                "motor supply critical at {} mV, holding the motors", supply_mv
+            )
+        }
+        None => {}
+    }
+
+    if level_changed {
+        connections::notify(&BATTERY_LEVEL_HANDLE, &[level]);
+    }
+    if supply_changed {
+        connections::notify(&SUPPLY_VOLTAGE_HANDLE, &supply_value);
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use esp32_sys::nvs;
+
+    #[test]
+    fn interpolates_between_points() {
+        let curve = Curve::preset("li-ion-3s").unwrap();
+        assert_eq!(curve.percent(9000), 0);
+        assert_eq!(curve.percent(9450), 2);
+        assert_eq!(curve.percent(9900), 5);
+        assert_eq!(curve.percent(12600), 100);
+        let curve = Curve::preset("lead-acid-12v").unwrap();
+        assert_eq!(curve.percent(12300), 62);
+        assert!(Curve::preset("nimh").is_none());
+    }
+
+    #[test]
+    fn clamps_outside_the_curve() {
+        let curve = Curve::new(&[(10000, 20), (20000, 80)]).unwrap();
+        assert_eq!(curve.percent(0), 20);
+        assert_eq!(curve.percent(9999), 20);
+        assert_eq!(curve.percent(20001), 80);
+        assert_eq!(curve.percent(u32::MAX), 80);
+    }
+
+    #[test]
+    fn rejects_invalid_curves() {
+        assert!(Curve::new(&[]).is_none());
+        assert!(Curve::new(&[(10000, 50), (10000, 60)]).is_none());
+        assert!(Curve::new(&[(10000, 50), (9000, 60)]).is_none());
+        assert!(Curve::new(&[(10000, 50), (11000, 40)]).is_none());
+        assert!(Curve::new(&[(10000, 50), (11000, 101)]).is_none());
+        assert!(Curve::new(&[(10000, 101)]).is_none());
+        assert!(Curve::new(&[(10000, 50), (11000, 50)]).is_some());
+    }
+
+    #[test]
+    fn curve_stored_in_nvs() {
+        nvs::init().unwrap();
+        let mut nvs = Nvs::open(NAMESPACE, Mode::ReadWrite).unwrap();
+        // 10000 mV at 20%, 20000 mV at 100%
+        nvs.set_blob("curve", &[0x10, 0x27, 20, 0x20, 0x4e, 100])
+            .unwrap();
+        nvs.commit().unwrap();
+        let curve = load_curve();
+        assert_eq!(curve, Curve::new(&[(10000, 20), (20000, 100)]).unwrap());
+        assert_eq!(curve.percent(15000), 60);
+
+        // Points that don't rise or a partial point fall back to the preset
+        nvs.set_blob("curve", &[0x20, 0x4e, 20, 0x10, 0x27, 100])
+            .unwrap();
+        nvs.commit().unwrap();
+        assert_eq!(load_curve(), Curve::new(PRESETS[0].1).unwrap());
+        nvs.set_blob("curve", &[0x10, 0x27, 20, 0x20]).unwrap();
+        nvs.commit().unwrap();
+        assert_eq!(load_curve(), Curve::new(PRESETS[0].1).unwrap());
+        assert!(Curve::from_bytes(&[1, 2]).is_none());
+
+        nvs.remove("curve").unwrap();
+        nvs.commit().unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+//! Connection parameters to suit what the plotter is doing
+//!
+//! Jogging with the app's joystick wants short intervals, the 7.5 to 20 ms the C firmware
+//! asks for. Once the motors stood still for `IDLE_AFTER_MS` the plotter asks for long
+//! intervals with some latency to save power, and for short ones again as soon as they
+//! move. A new connection is asked for the parameters of the current profile. While
+//! jogging, a central asking for intervals slower than jogging allows is refused.
+//!
+//! The link characteristic of the polargraph service serves each connection its own
+//! interval, latency, supervision timeout, RSSI and PHY, and notifies it when the
+//! parameters or PHY change. `Policy` has no BLE in it and runs the same on the host.
+
+use esp32_sys::connections;
+use esp32_sys::gatt::{
+    Access, AttError, Handle, PresentationFormat, Request, FORMAT_STRUCT, UNIT_UNITLESS,
+};
+use esp32_sys::link::{self, ConnParams};
+use esp32_sys::sync::Mutex;
+use esp32_sys::uuid::Uuid;
+use esp32_sys::*;
+
+const TAG: &str = "conn_policy\0";
+
+pub const LINK_UUID: Uuid = Uuid::from_u16(0xFF05);
+
+pub static LINK_HANDLE: Handle = Handle::new();
+
+/// Three little-endian `u16` and three bytes, see `LinkInfo::to_bytes`
+pub const LINK_FORMAT: PresentationFormat = PresentationFormat::new(FORMAT_STRUCT, UNIT_UNITLESS);
+
+/// 7.5 to 20 ms, no latency, 4 s supervision timeout
+pub const JOGGING: ConnParams = ConnParams::new(6, 16, 0, 400);
+/// 100 to 200 ms, 4 events latency, 6 s supervision timeout
+pub const IDLE: ConnParams = ConnParams::new(80, 160, 4, 600);
+
+/// Stand-still before the plotter asks for `IDLE`
+pub const IDLE_AFTER_MS: u32 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Jogging,
+    Idle,
+}
+
+impl Profile {
+    pub fn params(self) -> ConnParams {
+        match self {
+            Profile::Jogging => JOGGING,
+            Profile::Idle => IDLE,
+        }
+    }
+
+    /// Whether to go along with parameters a central asks for
+    pub fn accepts(self, peer: &ConnParams) -> bool {
+        peer.is_valid()
+            && match self {
+                Profile::Jogging => peer.itvl_max <= JOGGING.itvl_max && peer.latency == 0,
+                Profile::Idle => true,
+            }
+    }
+}
+
+/// Picks the profile from whether the motors move
+pub struct Policy {
+    profile: Profile,
+    still_ms: u32,
+}
+
+impl Policy {
+    /// Idle, nothing moves at boot
+    pub const fn new() -> Policy {
+        Policy {
+            profile: Profile::Idle,
+            still_ms: IDLE_AFTER_MS,
+        }
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Feeds whether the motors moved during the last `elapsed_ms`, returns the new
+    /// profile if it changed
+    pub fn update(&mut self, moving: bool, elapsed_ms: u32) -> Option<Profile> {
+        self.still_ms = if moving {
+            0
+        } else {
+            self.still_ms.saturating_add(elapsed_ms)
+        };
+        let profile = if self.still_ms >= IDLE_AFTER_MS {
+            Profile::Idle
+        } else {
+            Profile::Jogging
+        };
+        if profile == self.profile {
+            return None;
+        }
+        self.profile = profile;
+        Some(profile)
+    }
+}
+
+static POLICY: Mutex<Policy> = Mutex::new(Policy::new());
+
+fn request(conn_handle: u16, profile: Profile) {
+    match link::request(conn_handle, &profile.params()) {
+        Ok(()) => debug!(TAG, "asked connection {} for {:?}", conn_handle, profile),
+        Err(err) => warn!(TAG, "connection {}: {}", conn_handle, err),
+    }
+}
+
+/// From the motion timer, asks every connection for new parameters when the profile
+/// changes
+pub fn motion(moving: bool, elapsed_ms: u32) {
+    let changed = POLICY.lock().update(moving, elapsed_ms);
+    if let Some(profile) = changed {
+        info!(TAG, "{:?}", profile);
+        for conn_handle in connections::handles() {
+            request(conn_handle, profile);
+        }
+    }
+}
+
+/// A connection was established
+pub fn connected(conn_handle: u16) {
+    let profile = POLICY.lock().profile();
+    request(conn_handle, profile);
+}
+
+/// The parameters or PHY of a connection changed
+pub fn link_changed(conn_handle: u16) {
+    match link::link_info(conn_handle) {
+        Ok(info) => {
+            info!(
+                TAG,
+                "connection {}: interval={} latency={} timeout={} phy={}/{}",
+                conn_handle,
+                info.interval,
+                info.latency,
+                info.supervision_timeout,
+                info.tx_phy,
+                info.rx_phy
+            );
+            connections::notify_conn(conn_handle, &LINK_HANDLE, &info.to_bytes());
+        }
+        Err(err) => warn!(TAG, "connection {}: {}", conn_handle, err),
+    }
+}
+
+/// A central asks for `peer`, returns 0 to accept or the HCI reason to refuse with
+pub fn update_requested(conn_handle: u16, peer: &ConnParams) -> i32 {
+    let profile = POLICY.lock().profile();
+    if profile.accepts(peer) {
+        return 0;
+    }
+    info!(
+        TAG,
+        "connection {}: refusing {:?} while {:?}", conn_handle, peer, profile
+    );
+    ble_error_codes_BLE_ERR_CONN_PARMS as i32
+}
+
+/// The reading connection's own link
+pub struct Link;
+
+impl Access for Link {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        let info = link::link_info(request.conn_handle()).map_err(|_| AttError::Unlikely)?;
+        request.append(&info.to_bytes())
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_after_standing_still() {
+        let mut policy = Policy::new();
+        assert_eq!(policy.profile(), Profile::Idle);
+        assert_eq!(policy.update(false, 100), None);
+        assert_eq!(policy.update(true, 100), Some(Profile::Jogging));
+        assert_eq!(policy.update(true, 100), None);
+        assert_eq!(policy.update(false, IDLE_AFTER_MS - 1), None);
+        assert_eq!(policy.update(false, 1), Some(Profile::Idle));
+        assert_eq!(policy.update(false, u32::MAX), None);
+        assert_eq!(policy.update(true, 100), Some(Profile::Jogging));
+        assert_eq!(policy.profile(), Profile::Jogging);
+    }
+
+    #[test]
+    fn motion_restarts_the_wait() {
+        let mut policy = Policy::new();
+        policy.update(true, 100);
+        assert_eq!(policy.update(false, IDLE_AFTER_MS - 100), None);
+        assert_eq!(policy.update(true, 100), None);
+        assert_eq!(policy.update(false, IDLE_AFTER_MS - 100), None);
+        assert_eq!(policy.update(false, 100), Some(Profile::Idle));
+    }
+
+    #[test]
+    fn accepts_while_jogging() {
+        let jogging = Profile::Jogging;
+        assert!(jogging.accepts(&JOGGING));
+        assert!(jogging.accepts(&ConnParams::new(12, 12, 0, 100)));
+        // Slower than jogging allows, at least some of the time
+        assert!(!jogging.accepts(&ConnParams::new(6, 24, 0, 400)));
+        assert!(!jogging.accepts(&ConnParams::new(24, 40, 0, 400)));
+        assert!(!jogging.accepts(&ConnParams::new(6, 16, 1, 400)));
+        assert!(!jogging.accepts(&ConnParams::new(6, 16, 0, 8)));
+    }
+
+    #[test]
+    fn accepts_valid_while_idle() {
+        let idle = Profile::Idle;
+        assert!(idle.accepts(&IDLE));
+        assert!(idle.accepts(&JOGGING));
+        assert!(idle.accepts(&ConnParams::new(800, 800, 10, 3200)));
+        // 11 events of 1 s outlast a 20 s supervision timeout
+        assert!(!idle.accepts(&ConnParams::new(800, 800, 10, 2000)));
+        assert!(!idle.accepts(&ConnParams::new(3, 6, 0, 400)));
+    }
+}
@@ -1,6 +1,10 @@
-use core::{ffi::c_void, mem::size_of, ptr};
+use core::{fmt, mem::size_of};
+use esp32_sys::gatt::{self, Services};
+use esp32_sys::uuid::Uuid;
 use esp32_sys::*;
 
+const TAG: &str = "debug\0";
+
 #[macro_export]
 macro_rules! cstr {
     ($src:expr) => {
@@ -8,123 +12,111 @@ macro_rules! cstr {
     };
 }
 
-#[macro_export]
-macro_rules! esp_log {
-    ($tag:expr, $format:expr) => (esp_log_write(esp_log_level_t_ESP_LOG_INFO, $tag, $format));
-    ($tag:expr, $format:expr, $($arg:tt)*) => (esp_log_write(esp_log_level_t_ESP_LOG_INFO, $tag, $format, $($arg)*));
-}
-
 #[macro_export]
 macro_rules! emit_line {
     () => {
-        unsafe {
-            printf(cstr!("line %d\n"), core::line!() as u32);
-        }
+        esp32_sys::debug!(concat!(module_path!(), "\0"), "line {}", core::line!());
     };
 }
 
 #[macro_export]
 macro_rules! esp_assert {
     ($check:expr) => {
-        esp_assert!($check, cstr!("Assertion error"));
+        esp_assert!($check, "Assertion error");
     };
     ($check:expr, $msg:expr) => {
         if !$check {
-            let file = core::file!();
-            printf(
-                cstr!("%.*s:%d - %s"),
-                file.len(),
-                file.as_ptr(),
+            esp32_sys::error!(
+                concat!(module_path!(), "\0"),
+                "{}:{} - {}",
+                core::file!(),
                 core::line!(),
-                $msg,
+                $msg
             );
             panic!();
         }
     };
 }
 
-pub unsafe fn print_bytes(bytes: *const u8, len: usize) {
-    let u8p: &[u8];
+/// Hex dump, a space every two bytes and a line break every sixteen
+pub struct Hex<'a>(pub &'a [u8]);
 
-    u8p = core::slice::from_raw_parts(bytes, len);
-
-    for i in 0..len {
-        if (i & 0b1111) == 0 && i > 0 {
-            printf(cstr!("\n"));
-        } else if (i & 0b1) == 0 {
-            printf(cstr!(" "));
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if (i & 0b1111) == 0 && i > 0 {
+                f.write_str("\n")?;
+            } else if (i & 0b1) == 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", byte)?;
         }
-        printf(cstr!("%02x"), u8p[i] as u32);
+        Ok(())
     }
 }
 
-pub unsafe fn print_ptr<T>(name: *const u8, p: *const T) {
-    printf(cstr!("%p - %s:\n"), p, name);
-    print_bytes(p as *const _, size_of::<T>());
-    printf(cstr!("\n"));
-}
-
-pub unsafe fn print_addr(addr: *const c_void) {
-    let u8p: &[u8];
+/// A BLE address as NimBLE stores it, least significant byte first
+pub struct Addr<'a>(pub &'a [u8; 6]);
 
-    u8p = core::slice::from_raw_parts(addr as *const u8, 6);
-    printf(
-        cstr!("%02x:%02x:%02x:%02x:%02x:%02x"),
-        u8p[5] as u32,
-        u8p[4] as u32,
-        u8p[3] as u32,
-        u8p[2] as u32,
-        u8p[1] as u32,
-        u8p[0] as u32,
-    );
+impl<'a> fmt::Display for Addr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a[5], a[4], a[3], a[2], a[1], a[0]
+        )
+    }
 }
 
-pub unsafe fn print_svcs(svcs: *const ble_gatt_svc_def) {
-    let mut svc_p = svcs;
-    let mut svc = *svc_p;
-    loop {
-        print_ptr(cstr!("svc"), &svc);
-        print_ptr(cstr!(" -type"), &svc.type_);
-        print_ptr(cstr!(" -uuid"), &svc.uuid);
-        print_ptr(cstr!(" -includes"), &svc.includes);
-        print_ptr(cstr!(" -characteristics"), &svc.characteristics);
-        print_chrs(svc.characteristics);
-        svc = *svc_p;
-        svc_p = svc_p.add(1);
+pub unsafe fn print_ptr<T>(name: &str, p: *const T) {
+    let bytes = core::slice::from_raw_parts(p as *const u8, size_of::<T>());
+    debug!(TAG, "{:p} - {}:\n{}", p, name, Hex(bytes));
+}
 
-        if svc.type_ == 0 {
-            printf(cstr!("end\n"));
-            return;
-        }
+pub unsafe fn print_svcs(services: &Services) {
+    for svc in services.defs() {
+        print_ptr("svc", svc);
         if svc.type_ != BLE_GATT_SVC_TYPE_PRIMARY as u8
             && svc.type_ != BLE_GATT_SVC_TYPE_SECONDARY as u8
         {
-            printf(cstr!("insanity detected\n"));
-            break;
+            error!(TAG, "insanity detected");
+            return;
         }
+        debug!(
+            TAG,
+            " -type={} uuid={}",
+            svc.type_,
+            Uuid::from_ptr(svc.uuid)
+        );
+        print_chrs(svc);
     }
+    debug!(TAG, "end");
 }
 
-pub unsafe fn print_chrs(svcs: *const ble_gatt_chr_def) {
-    let mut chr_p = svcs;
-    let mut chr = *chr_p;
-    while chr.uuid != ptr::null() {
-        print_ptr(cstr!("  chr"), &chr);
-        print_ptr(cstr!("   -uuid"), &chr.uuid);
-        print_ptr(cstr!("   -access_cb"), &chr.access_cb);
-        print_ptr(cstr!("   -val_handle"), &chr.val_handle);
-        print_ptr(cstr!("   -flags"), &chr.flags);
-
-        chr = *chr_p;
-        chr_p = chr_p.add(1);
+pub unsafe fn print_chrs(svc: &ble_gatt_svc_def) {
+    for chr in gatt::characteristics(svc) {
+        print_ptr("  chr", chr);
+        debug!(
+            TAG,
+            "   -uuid={} flags=0x{:04x} val_handle={:p}",
+            Uuid::from_ptr(chr.uuid),
+            chr.flags,
+            chr.val_handle
+        );
+        print_dscs(chr);
+    }
+}
 
-        if chr.uuid == ptr::null() {
-            printf(cstr!("end"));
-            return;
-        }
-        if (*chr.uuid).type_ != BLE_UUID_TYPE_16 as u8 {
-            printf(cstr!("insanity detected"));
-            return;
-        }
+pub unsafe fn print_dscs(chr: &ble_gatt_chr_def) {
+    for dsc in gatt::descriptors(chr) {
+        print_ptr("   dsc", dsc);
+        debug!(
+            TAG,
+            "    -uuid={} att_flags=0x{:02x} min_key_size={}",
+            Uuid::from_ptr(dsc.uuid),
+            dsc.att_flags,
+            dsc.min_key_size
+        );
     }
 }
@@ -0,0 +1,147 @@
+//! Device Information Service, tells support which unit they are looking at
+//!
+//! Manufacturer, model and hardware revision are set at build time with the
+//! `DIS_MANUFACTURER`, `DIS_MODEL` and `DIS_HARDWARE_REVISION` environment variables. A
+//! unit can override them, and the serial number, in the `devinfo` NVS namespace under
+//! `manufacturer`, `model`, `hw_rev` and `serial`. Without an override the serial number
+//! is the Bluetooth MAC address. The firmware revision is the crate version and the commit
+//! it was built from.
+
+use alloc::string::String;
+use core::fmt::Write;
+use esp32_sys::gatt::{Access, AttError, Request};
+use esp32_sys::nvs::{Mode, Nvs};
+use esp32_sys::sync::Mutex;
+use esp32_sys::uuid::Uuid;
+use esp32_sys::*;
+
+const TAG: &str = "device_info\0";
+
+pub const GATT_DEVICE_INFO_UUID: Uuid = Uuid::from_u16(0x180A);
+pub const MANUFACTURER_NAME_UUID: Uuid = Uuid::from_u16(0x2A29);
+pub const MODEL_NUMBER_UUID: Uuid = Uuid::from_u16(0x2A24);
+pub const SERIAL_NUMBER_UUID: Uuid = Uuid::from_u16(0x2A25);
+pub const HARDWARE_REVISION_UUID: Uuid = Uuid::from_u16(0x2A27);
+pub const FIRMWARE_REVISION_UUID: Uuid = Uuid::from_u16(0x2A26);
+pub const PNP_ID_UUID: Uuid = Uuid::from_u16(0x2A50);
+
+pub const FIRMWARE_REVISION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+
+/* PnP ID, Espressif's Bluetooth SIG company identifier */
+const VENDOR_ID_SOURCE_SIG: u8 = 0x01;
+const VENDOR_ID: u16 = 0x02E5;
+const PRODUCT_ID: u16 = 0x0001;
+
+const NAMESPACE: &str = "devinfo";
+
+struct Settings {
+    manufacturer: String,
+    model: String,
+    hardware_revision: String,
+    serial_number: Option<String>,
+}
+
+static SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+
+/// One of the values served by the Device Information Service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Info {
+    Manufacturer,
+    Model,
+    SerialNumber,
+    HardwareRevision,
+    FirmwareRevision,
+    PnpId,
+}
+
+impl Access for Info {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        let settings = SETTINGS.lock();
+        let settings = settings.as_ref().ok_or(AttError::Unlikely)?;
+        match self {
+            Info::Manufacturer => request.append(settings.manufacturer.as_bytes()),
+            Info::Model => request.append(settings.model.as_bytes()),
+            Info::SerialNumber => match settings.serial_number {
+                Some(ref serial_number) => request.append(serial_number.as_bytes()),
+                None => request.append(mac_serial_number().as_bytes()),
+            },
+            Info::HardwareRevision => request.append(settings.hardware_revision.as_bytes()),
+            Info::FirmwareRevision => request.append(FIRMWARE_REVISION.as_bytes()),
+            Info::PnpId => request.append(&pnp_id()),
+        }
+    }
+}
+
+/// Reads the settings, NVS must be initialized
+pub fn load() {
+    /* Fails when nothing was ever stored for this unit */
+    let overrides = Nvs::open(NAMESPACE, Mode::ReadOnly).ok();
+    let stored = |key: &str| -> Option<String> {
+        match overrides.as_ref()?.get_string(key) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!(TAG, "{}: {}", key, err);
+                None
+            }
+        }
+    };
+    let built = |value: Option<&str>, default: &str| String::from(value.unwrap_or(default));
+
+    let settings = Settings {
+        manufacturer: stored("manufacturer")
+            .unwrap_or_else(|| built(option_env!("DIS_MANUFACTURER"), "Hanging Plotter")),
+        model: stored("model")
+            .unwrap_or_else(|| built(option_env!("DIS_MODEL"), "Polargraph ESP32")),
+        hardware_revision: stored("hw_rev")
+            .unwrap_or_else(|| built(option_env!("DIS_HARDWARE_REVISION"), "ESP32-DevKitC")),
+        serial_number: stored("serial"),
+    };
+    info!(
+        TAG,
+        "{} {} hardware {} firmware {}",
+        settings.manufacturer,
+        settings.model,
+        settings.hardware_revision,
+        FIRMWARE_REVISION
+    );
+    *SETTINGS.lock() = Some(settings);
+}
+
+/// The public address as 12 hex digits, most significant first
+fn mac_serial_number() -> String {
+    let mut addr = [0u8; 6];
+    let rc = unsafe {
+        ble_hs_id_copy_addr(
+            BLE_ADDR_PUBLIC as u8,
+            addr.as_mut_ptr(),
+            core::ptr::null_mut(),
+        )
+    };
+    let mut serial_number = String::new();
+    if rc == 0 {
+        for byte in addr.iter().rev() {
+            let _ = write!(serial_number, "{:02X}", byte);
+        }
+    }
+    serial_number
+}
+
+/// Vendor ID source, vendor ID, product ID and version `0xJJMN` for version `JJ.M.N`
+fn pnp_id() -> [u8; 7] {
+    let part = |part: &str| part.parse::<u16>().unwrap_or(0);
+    let version = part(env!("CARGO_PKG_VERSION_MAJOR")) << 8
+        | (part(env!("CARGO_PKG_VERSION_MINOR")) & 0xf) << 4
+        | part(env!("CARGO_PKG_VERSION_PATCH")) & 0xf;
+    let vendor = VENDOR_ID.to_le_bytes();
+    let product = PRODUCT_ID.to_le_bytes();
+    let version = version.to_le_bytes();
+    [
+        VENDOR_ID_SOURCE_SIG,
+        vendor[0],
+        vendor[1],
+        product[0],
+        product[1],
+        version[0],
+        version[1],
+    ]
+}
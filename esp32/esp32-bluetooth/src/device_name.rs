@@ -0,0 +1,257 @@
+//! The device name, advertised and served as the GAP Device Name
+//!
+//! A unit's name is stored under `name` in the `gap` NVS namespace. Until one is stored it
+//! is the `DEVICE_NAME_PREFIX` environment variable at build time, "Polargraph" if unset,
+//! and the last two bytes of the Bluetooth address, so plotters in one room tell apart
+//! out of the box.
+//!
+//! NimBLE's own GAP service can't be written to without rebuilding it, so `gatt_svr`
+//! declares the GAP service itself with `DeviceName` behind the Device Name
+//! characteristic. A central renames the unit by writing it, the host controller with the
+//! `name` UART command. Either way advertising restarts with the new name.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use esp32_sys::error::EspError;
+use esp32_sys::gatt::{Access, AttError, Request};
+use esp32_sys::nvs::{Mode, Nvs};
+use esp32_sys::sync::Mutex;
+use esp32_sys::uuid::Uuid;
+use esp32_sys::*;
+
+const TAG: &str = "device_name\0";
+
+pub const GATT_GAP_UUID: Uuid = Uuid::from_u16(0x1800);
+pub const DEVICE_NAME_UUID: Uuid = Uuid::from_u16(0x2A00);
+pub const APPEARANCE_UUID: Uuid = Uuid::from_u16(0x2A01);
+
+/// Generic unknown, no appearance category fits a plotter
+pub const APPEARANCE: [u8; 2] = [0x00, 0x00];
+
+/// Longest name that is advertised whole next to the flags and the polargraph UUID
+pub const MAX_NAME_LEN: usize = 22;
+
+const NAMESPACE: &str = "gap";
+
+/// The stored name, `None` for the default
+static NAME: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    /// Over `MAX_NAME_LEN` bytes of UTF-8
+    TooLong,
+    ControlCharacter,
+    Storage(EspError),
+}
+
+impl From<EspError> for NameError {
+    fn from(err: EspError) -> NameError {
+        NameError::Storage(err)
+    }
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "name is empty"),
+            NameError::TooLong => write!(f, "name is over {} bytes", MAX_NAME_LEN),
+            NameError::ControlCharacter => write!(f, "name contains a control character"),
+            NameError::Storage(err) => write!(f, "storing the name: {}", err),
+        }
+    }
+}
+
+/// Whether `name` can be stored
+pub fn check(name: &str) -> Result<(), NameError> {
+    if name.is_empty() {
+        Err(NameError::Empty)
+    } else if name.len() > MAX_NAME_LEN {
+        Err(NameError::TooLong)
+    } else if name.chars().any(char::is_control) {
+        Err(NameError::ControlCharacter)
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads the stored name, NVS must be initialized
+pub fn load() {
+    /* Fails when nothing was ever stored for this unit */
+    let stored = Nvs::open(NAMESPACE, Mode::ReadOnly)
+        .and_then(|nvs| nvs.get_string("name"))
+        .ok()
+        .and_then(|name| name);
+    let stored = stored.filter(|name| match check(name) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!(TAG, "ignoring the stored name: {}", err);
+            false
+        }
+    });
+    *NAME.lock() = stored;
+    info!(TAG, "device name {}", get());
+}
+
+/// The stored name, else the default
+pub fn get() -> String {
+    match *NAME.lock() {
+        Some(ref name) => name.clone(),
+        None => default_name(),
+    }
+}
+
+/// The prefix and the last two bytes of the public address, most significant first
+pub fn default_name() -> String {
+    let mut name = String::from(option_env!("DEVICE_NAME_PREFIX").unwrap_or("Polargraph"));
+    let mut addr = [0u8; 6];
+    let rc = unsafe {
+        ble_hs_id_copy_addr(
+            BLE_ADDR_PUBLIC as u8,
+            addr.as_mut_ptr(),
+            core::ptr::null_mut(),
+        )
+    };
+    if rc == 0 {
+        let _ = write!(name, "-{:02X}{:02X}", addr[1], addr[0]);
+    }
+    name
+}
+
+/// Stores `name` and advertises with it
+pub fn set(name: &str) -> Result<(), NameError> {
+    check(name)?;
+    let mut nvs = Nvs::open(NAMESPACE, Mode::ReadWrite)?;
+    nvs.set_str("name", name)?;
+    nvs.commit()?;
+    *NAME.lock() = Some(String::from(name));
+    renamed();
+    Ok(())
+}
+
+/// Forgets the stored name and advertises with the default
+pub fn reset() -> Result<(), NameError> {
+    let mut nvs = Nvs::open(NAMESPACE, Mode::ReadWrite)?;
+    nvs.remove("name")?;
+    nvs.commit()?;
+    *NAME.lock() = None;
+    renamed();
+    Ok(())
+}
+
+fn renamed() {
+    info!(TAG, "renamed to {}", get());
+    crate::restart_advertising();
+}
+
+/// UTF-8 without a terminator, writing an empty value restores the default
+pub struct DeviceName;
+
+impl Access for DeviceName {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(get().as_bytes())
+    }
+
+    fn write(&self, request: &mut Request) -> Result<(), AttError> {
+        let mut buf = [0; MAX_NAME_LEN];
+        let value = request.value(0, &mut buf)?;
+        let result = match core::str::from_utf8(value) {
+            Ok("") => reset(),
+            Ok(name) => set(name),
+            Err(_) => return Err(AttError::ValueNotAllowed),
+        };
+        result.map_err(|err| {
+            warn!(TAG, "{}", err);
+            match err {
+                NameError::Storage(_) => AttError::Unlikely,
+                _ => AttError::ValueNotAllowed,
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use esp32_sys::mock;
+    use esp32_sys::nvs;
+
+    fn prefix() -> &'static str {
+        option_env!("DEVICE_NAME_PREFIX").unwrap_or("Polargraph")
+    }
+
+    /// Writes `value` to `DeviceName` the way a central would
+    fn write(value: &[u8]) -> Result<(), AttError> {
+        mock::nimble::with_request(1, BLE_GATT_ACCESS_OP_WRITE_CHR, value, |request| {
+            DeviceName.write(request)
+        })
+        .0
+    }
+
+    fn stored() -> Option<Vec<u8>> {
+        mock::nvs::stored(NAMESPACE, "name")
+    }
+
+    #[test]
+    fn checks_names() {
+        assert_eq!(check(""), Err(NameError::Empty));
+        assert_eq!(check("Plotter in the hallway"), Ok(()));
+        assert_eq!(check("Plotter in the hallway!"), Err(NameError::TooLong));
+        // Bytes count, not characters
+        assert_eq!(check("Plötter in the hallway"), Err(NameError::TooLong));
+        assert_eq!(check("Plotter\n"), Err(NameError::ControlCharacter));
+        assert_eq!(check("Plotter\u{7f}"), Err(NameError::ControlCharacter));
+    }
+
+    #[test]
+    fn default_name_ends_in_address() {
+        mock::nimble::set_address([0x3c, 0xa2, 0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(default_name(), format!("{}-A23C", prefix()));
+    }
+
+    #[test]
+    fn written_by_central() {
+        nvs::init().unwrap();
+        mock::nimble::set_address([0x01, 0x02, 0, 0, 0, 0]);
+        write(b"Hallway").unwrap();
+        assert_eq!(get(), "Hallway");
+        assert_eq!(stored().as_deref(), Some(&b"Hallway\0"[..]));
+        let (result, value) =
+            mock::nimble::with_request(1, BLE_GATT_ACCESS_OP_READ_CHR, &[], |request| {
+                DeviceName.read(request)
+            });
+        result.unwrap();
+        assert_eq!(value, b"Hallway");
+
+        assert_eq!(write(b"Hall\xffway"), Err(AttError::ValueNotAllowed));
+        assert_eq!(write(b"Hall\tway"), Err(AttError::ValueNotAllowed));
+        assert_eq!(write(&[b'a'; 23]), Err(AttError::InvalidAttrValueLen));
+        assert_eq!(get(), "Hallway");
+
+        // Empty goes back to the default
+        write(b"").unwrap();
+        assert_eq!(get(), format!("{}-0201", prefix()));
+        assert_eq!(stored(), None);
+    }
+
+    #[test]
+    fn loaded_from_nvs() {
+        nvs::init().unwrap();
+        set("Workshop").unwrap();
+        *NAME.lock() = None;
+        load();
+        assert_eq!(get(), "Workshop");
+
+        // A name that can't be stored any more is ignored
+        let mut nvs = Nvs::open(NAMESPACE, Mode::ReadWrite).unwrap();
+        nvs.set_str("name", "Work\nshop").unwrap();
+        nvs.commit().unwrap();
+        load();
+        assert_eq!(*NAME.lock(), None);
+        assert!(mock::logging::contains("ignoring the stored name"));
+
+        reset().unwrap();
+        load();
+        assert_eq!(*NAME.lock(), None);
+    }
+}
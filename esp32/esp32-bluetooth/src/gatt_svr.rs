@@ -1,253 +1,386 @@
-use alloc::boxed::Box;
-use core::ffi::c_void;
-use core::mem::size_of;
-use core::ptr;
+use esp32_sys::connections;
+use esp32_sys::error::BleResult;
+use esp32_sys::gatt::{
+    Access, AttError, Decode, Handle, PresentationFormat, ReadOnly, Request, UserDescription,
+    ValidRange, DSC_READ, FORMAT_SINT32, FORMAT_STRUCT, NOTIFY, PRESENTATION_FORMAT_UUID, READ,
+    UNIT_UNITLESS, USER_DESCRIPTION_UUID, VALID_RANGE_UUID, WRITE, WRITE_AUTHEN, WRITE_ENC,
+    WRITE_NO_RSP,
+};
+use esp32_sys::sync::Mutex;
+use esp32_sys::uuid::Uuid;
 use esp32_sys::*;
 
-use crate::{cstr, debug, esp_assert};
+use crate::battery::{
+    BatteryLevel, SupplyVoltage, BATTERY_LEVEL_HANDLE, BATTERY_LEVEL_UUID, GATT_BATTERY_UUID,
+    LEVEL_FORMAT, SUPPLY_FORMAT, SUPPLY_VOLTAGE_HANDLE, SUPPLY_VOLTAGE_UUID,
+};
+use crate::conn_policy::{Link, LINK_FORMAT, LINK_HANDLE, LINK_UUID};
+use crate::debug;
+use crate::device_info::{
+    self, Info, FIRMWARE_REVISION_UUID, GATT_DEVICE_INFO_UUID, HARDWARE_REVISION_UUID,
+    MANUFACTURER_NAME_UUID, MODEL_NUMBER_UUID, PNP_ID_UUID, SERIAL_NUMBER_UUID,
+};
+use crate::device_name::{
+    self, DeviceName, APPEARANCE, APPEARANCE_UUID, DEVICE_NAME_UUID, GATT_GAP_UUID,
+};
+use crate::job::{
+    JobControl, JobData, JobStatus, GATT_JOB_UUID, JOB_CONTROL_UUID, JOB_DATA_UUID,
+    JOB_STATUS_HANDLE, JOB_STATUS_UUID,
+};
 use debug::print_svcs;
 
-extern crate alloc;
+const TAG: &str = "gatt_svr\0";
 
-const MANUF_NAME: &str = "Apache Mynewt ESP32 devkitC\0";
-const MODEL_NUM: &str = "Mynewt HR Sensor demo\0";
-pub static mut HRS_HRM_HANDLE: u16 = 0;
+pub static HRS_HRM_HANDLE: Handle = Handle::new();
 
-macro_rules! ble_uuid16_declare {
-    ($value:expr) => {
-        &(ble_uuid16_t {
-            u: ble_uuid_t {
-                type_: BLE_UUID_TYPE_16 as u8,
-            },
-            value: $value,
+/* Heart-rate configuration */
+const GATT_HRS_UUID: Uuid = Uuid::from_u16(0x180D);
+const GATT_HRS_MEASUREMENT_UUID: Uuid = Uuid::from_u16(0x2A37);
+const GATT_HRS_BODY_SENSOR_LOC_UUID: Uuid = Uuid::from_u16(0x2A38);
+
+/* Sensor location, set to "Chest" */
+const BODY_SENS_LOC: u8 = 0x01;
+
+/* Polargraph motor control, the same attributes as bluetooth.c of the C firmware */
+pub const POLARGRAPH_UUID: Uuid = Uuid::from_u16(0x00FF);
+const MOTOR_VEL_SET_UUID: Uuid = Uuid::from_u16(0xFF01);
+const MOTOR_VEL_REAL_UUID: Uuid = Uuid::from_u16(0xFF02);
+const MOTOR_POS_UUID: Uuid = Uuid::from_u16(0xFF03);
+/* Not in the C firmware, its FF03 only carries a single position */
+const MOTOR_POSITIONS_UUID: Uuid = Uuid::from_u16(0xFF06);
+
+pub static MOTOR_VEL_REAL_HANDLE: Handle = Handle::new();
+pub static MOTOR_POS_HANDLE: Handle = Handle::new();
+pub static MOTOR_POSITIONS_HANDLE: Handle = Handle::new();
+
+static VELOCITY_SETPOINT: Mutex<Velocity> = Mutex::new(Velocity { a: 0, b: 0 });
+static VELOCITY: Mutex<Velocity> = Mutex::new(Velocity { a: 0, b: 0 });
+static POSITION: Mutex<Position> = Mutex::new(Position { a: 0, b: 0 });
+
+/// Speed of both motors, -127 to 127 each as `Polargraph.sendVelocity` scales it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Velocity {
+    pub a: i8,
+    pub b: i8,
+}
+
+impl Velocity {
+    /// Wire format, `a` then `b` as two's complement bytes
+    pub fn to_bytes(self) -> [u8; 2] {
+        [self.a as u8, self.b as u8]
+    }
+}
+
+/// -128 has no counterpart in the other direction, the app never sends it
+impl Decode for Velocity {
+    const MIN_LEN: usize = 2;
+    const MAX_LEN: usize = 2;
+
+    fn decode(bytes: &[u8]) -> Result<Velocity, AttError> {
+        let (a, b) = (bytes[0] as i8, bytes[1] as i8);
+        if a == i8::MIN || b == i8::MIN {
+            return Err(AttError::ValueNotAllowed);
         }
-        .u) as *const ble_uuid_t
-    };
-}
-
-macro_rules! leaky_box {
-    ($($items:expr),+) => (
-        Box::into_raw(
-            Box::new(
-                [
-                    $($items),*
-                ]
-            )
-        ) as *const _
-    )
-}
-
-const fn null_ble_gatt_chr_def() -> ble_gatt_chr_def {
-    return ble_gatt_chr_def {
-        uuid: ptr::null(),
-        access_cb: None,
-        arg: (ptr::null_mut()),
-        descriptors: (ptr::null_mut()),
-        flags: 0,
-        min_key_size: 0,
-        val_handle: (ptr::null_mut()),
-    };
-}
-
-const fn null_ble_gatt_svc_def() -> ble_gatt_svc_def {
-    return ble_gatt_svc_def {
-        type_: BLE_GATT_SVC_TYPE_END as u8,
-        uuid: ptr::null(),
-        includes: ptr::null_mut(),
-        characteristics: ptr::null(),
-    };
+        Ok(Velocity { a, b })
+    }
 }
 
-/* Heart-rate configuration */
-const GATT_HRS_UUID: u16 = 0x180D;
-const GATT_HRS_MEASUREMENT_UUID: u16 = 0x2A37;
-const GATT_HRS_BODY_SENSOR_LOC_UUID: u16 = 0x2A38;
-const GATT_DEVICE_INFO_UUID: u16 = 0x180A;
-const GATT_MANUFACTURER_NAME_UUID: u16 = 0x2A29;
-const GATT_MODEL_NUMBER_UUID: u16 = 0x2A24;
-
-fn alloc_svc_def() -> *const ble_gatt_svc_def {
-    leaky_box!(
-        ble_gatt_svc_def {
-            type_: BLE_GATT_SVC_TYPE_PRIMARY as u8,
-            uuid: ble_uuid16_declare!(GATT_HRS_UUID),
-            includes: ptr::null_mut(),
-            characteristics: leaky_box!(
-                ble_gatt_chr_def {
-                    uuid: ble_uuid16_declare!(GATT_HRS_MEASUREMENT_UUID),
-                    access_cb: Some(gatt_svr_chr_access_heart_rate),
-                    arg: (ptr::null_mut()),
-                    descriptors: (ptr::null_mut()),
-                    flags: BLE_GATT_CHR_F_NOTIFY as u16,
-                    min_key_size: 0,
-                    val_handle: (unsafe { &mut HRS_HRM_HANDLE as *mut u16 }),
-                },
-                ble_gatt_chr_def {
-                    uuid: ble_uuid16_declare!(GATT_HRS_BODY_SENSOR_LOC_UUID),
-                    access_cb: Some(gatt_svr_chr_access_heart_rate),
-                    arg: (ptr::null_mut()),
-                    descriptors: (ptr::null_mut()),
-                    flags: BLE_GATT_CHR_F_READ as u16,
-                    min_key_size: 0,
-                    val_handle: ptr::null_mut(),
-                },
-                null_ble_gatt_chr_def()
-            )
-        },
-        ble_gatt_svc_def {
-            type_: BLE_GATT_SVC_TYPE_PRIMARY as u8,
-            uuid: ble_uuid16_declare!(GATT_DEVICE_INFO_UUID),
-            includes: ptr::null_mut(),
-            characteristics: leaky_box!(
-                ble_gatt_chr_def {
-                    uuid: ble_uuid16_declare!(GATT_MANUFACTURER_NAME_UUID),
-                    access_cb: Some(gatt_svr_chr_access_device_info),
-                    arg: (ptr::null_mut()),
-                    descriptors: (ptr::null_mut()),
-                    flags: BLE_GATT_CHR_F_READ as u16,
-                    min_key_size: 0,
-                    val_handle: (ptr::null_mut()),
-                },
-                ble_gatt_chr_def {
-                    uuid: ble_uuid16_declare!(GATT_MODEL_NUMBER_UUID),
-                    access_cb: Some(gatt_svr_chr_access_device_info),
-                    arg: (ptr::null_mut()),
-                    descriptors: (ptr::null_mut()),
-                    flags: BLE_GATT_CHR_F_READ as u16,
-                    min_key_size: 0,
-                    val_handle: (ptr::null_mut()),
-                },
-                null_ble_gatt_chr_def()
-            )
-        },
-        null_ble_gatt_svc_def()
-    )
-}
-
-extern "C" fn gatt_svr_chr_access_heart_rate(
-    _conn_handle: u16,
-    _attr_handle: u16,
-    ctxt: *mut ble_gatt_access_ctxt,
-    _arg: *mut ::core::ffi::c_void,
-) -> i32 {
-    /* Sensor location, set to "Chest" */
-    const BODY_SENS_LOC: u8 = 0x01;
-
-    let uuid: u16 = unsafe { ble_uuid_u16((*(*ctxt).__bindgen_anon_1.chr).uuid) };
-
-    if uuid == GATT_HRS_BODY_SENSOR_LOC_UUID {
-        let rc: i32 = unsafe {
-            os_mbuf_append(
-                (*ctxt).om,
-                &BODY_SENS_LOC as *const u8 as *const c_void,
-                size_of::<u8>() as u16,
-            )
-        };
-
-        return if rc == 0 {
-            0
-        } else {
-            BLE_ATT_ERR_INSUFFICIENT_RES as i32
-        };
+/// Absolute position of both motors in steps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Position {
+    pub a: i32,
+    pub b: i32,
+}
+
+impl Position {
+    /// Wire format of FF03, `a` as a little-endian `i32` like the `long` the C firmware sent
+    pub fn a_to_bytes(self) -> [u8; 4] {
+        self.a.to_le_bytes()
     }
 
-    return BLE_ATT_ERR_UNLIKELY as i32;
+    /// Wire format of FF06, `a` then `b` as little-endian `i32`
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&self.a.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.b.to_le_bytes());
+        bytes
+    }
 }
 
-extern "C" fn gatt_svr_chr_access_device_info(
-    _conn_handle: u16,
-    _attr_handle: u16,
-    ctxt: *mut ble_gatt_access_ctxt,
-    _arg: *mut ::core::ffi::c_void,
-) -> i32 {
-    let uuid: u16 = unsafe { ble_uuid_u16((*(*ctxt).__bindgen_anon_1.chr).uuid) };
-
-    if uuid == GATT_MODEL_NUMBER_UUID {
-        let rc: i32 = unsafe {
-            os_mbuf_append(
-                (*ctxt).om,
-                MODEL_NUM.as_ptr() as *const c_void,
-                MODEL_NUM.len() as u16,
-            )
-        };
-        return if rc == 0 {
-            0
-        } else {
-            BLE_ATT_ERR_INSUFFICIENT_RES as i32
-        };
+/// Velocity the app asks for, written without response by `Polargraph.sendVelocity`
+struct MotorVelocitySetpoint;
+
+impl Access for MotorVelocitySetpoint {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(&VELOCITY_SETPOINT.lock().to_bytes())
+    }
+
+    fn write(&self, request: &mut Request) -> Result<(), AttError> {
+        let velocity = request.decode::<Velocity>()?;
+        debug!(TAG, "velocity setpoint {:?}", velocity);
+        *VELOCITY_SETPOINT.lock() = velocity;
+        Ok(())
+    }
+}
+
+struct MotorVelocity;
+
+impl Access for MotorVelocity {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(&VELOCITY.lock().to_bytes())
+    }
+}
+
+struct MotorPosition;
+
+impl Access for MotorPosition {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(&POSITION.lock().a_to_bytes())
     }
+}
+
+struct MotorPositions;
 
-    if uuid == GATT_MANUFACTURER_NAME_UUID {
-        let rc: i32 = unsafe {
-            os_mbuf_append(
-                (*ctxt).om,
-                MANUF_NAME.as_ptr() as *const c_void,
-                MANUF_NAME.len() as u16,
-            )
-        };
-        return if rc == 0 {
-            0
-        } else {
-            BLE_ATT_ERR_INSUFFICIENT_RES as i32
-        };
+impl Access for MotorPositions {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(&POSITION.lock().to_bytes())
     }
+}
+
+/// Last velocity written by a central
+pub fn velocity_setpoint() -> Velocity {
+    *VELOCITY_SETPOINT.lock()
+}
+
+pub fn position() -> Position {
+    *POSITION.lock()
+}
+
+/// Publishes the velocity the motors actually run at, subscribers are notified of changes
+pub fn report_velocity(velocity: Velocity) {
+    if update(&VELOCITY, velocity) {
+        connections::notify(&MOTOR_VEL_REAL_HANDLE, &velocity.to_bytes());
+    }
+}
+
+/// Publishes the absolute motor position, subscribers are notified of changes
+pub fn report_position(position: Position) {
+    let previous = self::position();
+    if update(&POSITION, position) {
+        if position.a != previous.a {
+            connections::notify(&MOTOR_POS_HANDLE, &position.a_to_bytes());
+        }
+        connections::notify(&MOTOR_POSITIONS_HANDLE, &position.to_bytes());
+    }
+}
+
+fn update<T: Copy + PartialEq>(value: &Mutex<T>, new: T) -> bool {
+    let mut value = value.lock();
+    let changed = *value != new;
+    *value = new;
+    changed
+}
+
+/// Two `i8`, motor A then B
+const VELOCITY_FORMAT: PresentationFormat = PresentationFormat::new(FORMAT_STRUCT, UNIT_UNITLESS);
+/// One little-endian `i32`, motor A
+const POSITION_FORMAT: PresentationFormat = PresentationFormat::new(FORMAT_SINT32, UNIT_UNITLESS);
+/// Two little-endian `i32`, motor A then B
+const POSITIONS_FORMAT: PresentationFormat = PresentationFormat::new(FORMAT_STRUCT, UNIT_UNITLESS);
+
+/// Writes that move the plotter or change it, only from a paired central, see `pairing`
+const WRITE_PROTECTED: u16 = WRITE_ENC | WRITE_AUTHEN;
+
+/// Only ever sent as a notification, see `blehr_tx_hrate`
+struct HeartRateMeasurement;
 
-    return BLE_ATT_ERR_UNLIKELY as i32;
+impl Access for HeartRateMeasurement {}
+
+gatt_services! {
+    static SERVICES = [
+        primary(GATT_GAP_UUID) {
+            characteristic(DEVICE_NAME_UUID, DeviceName) {
+                flags: READ | WRITE | WRITE_PROTECTED,
+            }
+            characteristic(APPEARANCE_UUID, ReadOnly(&APPEARANCE)) {
+                flags: READ,
+            }
+        }
+        primary(GATT_HRS_UUID) {
+            characteristic(GATT_HRS_MEASUREMENT_UUID, HeartRateMeasurement) {
+                flags: NOTIFY,
+                handle: HRS_HRM_HANDLE,
+            }
+            characteristic(GATT_HRS_BODY_SENSOR_LOC_UUID, ReadOnly(&[BODY_SENS_LOC])) {
+                flags: READ,
+            }
+        }
+        primary(POLARGRAPH_UUID) {
+            characteristic(MOTOR_VEL_SET_UUID, MotorVelocitySetpoint) {
+                flags: READ | WRITE | WRITE_NO_RSP | WRITE_PROTECTED,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Velocity setpoint")) {
+                    flags: DSC_READ,
+                }
+                descriptor(PRESENTATION_FORMAT_UUID, VELOCITY_FORMAT) {
+                    flags: DSC_READ,
+                }
+                descriptor(VALID_RANGE_UUID, ValidRange(&[0x81, 0x81], &[0x7f, 0x7f])) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(MOTOR_VEL_REAL_UUID, MotorVelocity) {
+                flags: READ | NOTIFY,
+                handle: MOTOR_VEL_REAL_HANDLE,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Velocity")) {
+                    flags: DSC_READ,
+                }
+                descriptor(PRESENTATION_FORMAT_UUID, VELOCITY_FORMAT) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(MOTOR_POS_UUID, MotorPosition) {
+                flags: READ | NOTIFY,
+                handle: MOTOR_POS_HANDLE,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Position in steps")) {
+                    flags: DSC_READ,
+                }
+                descriptor(PRESENTATION_FORMAT_UUID, POSITION_FORMAT) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(MOTOR_POSITIONS_UUID, MotorPositions) {
+                flags: READ | NOTIFY,
+                handle: MOTOR_POSITIONS_HANDLE,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Positions in steps")) {
+                    flags: DSC_READ,
+                }
+                descriptor(PRESENTATION_FORMAT_UUID, POSITIONS_FORMAT) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(SUPPLY_VOLTAGE_UUID, SupplyVoltage) {
+                flags: READ | NOTIFY,
+                handle: SUPPLY_VOLTAGE_HANDLE,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Motor supply voltage")) {
+                    flags: DSC_READ,
+                }
+                descriptor(PRESENTATION_FORMAT_UUID, SUPPLY_FORMAT) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(LINK_UUID, Link) {
+                flags: READ | NOTIFY,
+                handle: LINK_HANDLE,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Link")) {
+                    flags: DSC_READ,
+                }
+                descriptor(PRESENTATION_FORMAT_UUID, LINK_FORMAT) {
+                    flags: DSC_READ,
+                }
+            }
+        }
+        primary(GATT_JOB_UUID) {
+            characteristic(JOB_CONTROL_UUID, JobControl) {
+                flags: WRITE | WRITE_PROTECTED,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Job control")) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(JOB_DATA_UUID, JobData) {
+                flags: WRITE | WRITE_NO_RSP | WRITE_PROTECTED,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Job data")) {
+                    flags: DSC_READ,
+                }
+            }
+            characteristic(JOB_STATUS_UUID, JobStatus) {
+                flags: READ | NOTIFY,
+                handle: JOB_STATUS_HANDLE,
+                descriptor(USER_DESCRIPTION_UUID, UserDescription("Job status")) {
+                    flags: DSC_READ,
+                }
+            }
+        }
+        primary(GATT_DEVICE_INFO_UUID) {
+            characteristic(MANUFACTURER_NAME_UUID, Info::Manufacturer) {
+                flags: READ,
+            }
+            characteristic(MODEL_NUMBER_UUID, Info::Model) {
+                flags: READ,
+            }
+            characteristic(SERIAL_NUMBER_UUID, Info::SerialNumber) {
+                flags: READ,
+            }
+            characteristic(HARDWARE_REVISION_UUID, Info::HardwareRevision) {
+                flags: READ,
+            }
+            characteristic(FIRMWARE_REVISION_UUID, Info::FirmwareRevision) {
+                flags: READ,
+            }
+            characteristic(PNP_ID_UUID, Info::PnpId) {
+                flags: READ,
+            }
+        }
+        primary(GATT_BATTERY_UUID) {
+            characteristic(BATTERY_LEVEL_UUID, BatteryLevel) {
+                flags: READ | NOTIFY,
+                handle: BATTERY_LEVEL_HANDLE,
+                descriptor(PRESENTATION_FORMAT_UUID, LEVEL_FORMAT) {
+                    flags: DSC_READ,
+                }
+            }
+        }
+    ];
 }
 
 pub unsafe extern "C" fn gatt_svr_register_cb(
     ctxt: *mut ble_gatt_register_ctxt,
     _arg: *mut ::core::ffi::c_void,
 ) {
-    let mut buf_arr: [i8; BLE_UUID_STR_LEN as usize] = [0; BLE_UUID_STR_LEN as usize];
-    let buf = buf_arr.as_mut_ptr();
-
     match (*ctxt).op as u32 {
         BLE_GATT_REGISTER_OP_SVC => {
-            printf(
-                cstr!("registered service %s with handle=%d\n"),
-                ble_uuid_to_str((*(*ctxt).__bindgen_anon_1.svc.svc_def).uuid, buf),
-                (*ctxt).__bindgen_anon_1.svc.handle as i32,
+            info!(
+                TAG,
+                "registered service {} with handle={}",
+                Uuid::from_ptr((*(*ctxt).__bindgen_anon_1.svc.svc_def).uuid),
+                (*ctxt).__bindgen_anon_1.svc.handle,
             );
         }
 
         BLE_GATT_REGISTER_OP_CHR => {
-            printf(
-                cstr!("registering characteristic %s with def_handle=%d val_handle=%d\n"),
-                ble_uuid_to_str((*(*ctxt).__bindgen_anon_1.chr.chr_def).uuid, buf),
-                (*ctxt).__bindgen_anon_1.chr.def_handle as i32,
-                (*ctxt).__bindgen_anon_1.chr.val_handle as i32,
+            info!(
+                TAG,
+                "registering characteristic {} with def_handle={} val_handle={}",
+                Uuid::from_ptr((*(*ctxt).__bindgen_anon_1.chr.chr_def).uuid),
+                (*ctxt).__bindgen_anon_1.chr.def_handle,
+                (*ctxt).__bindgen_anon_1.chr.val_handle,
             );
         }
 
         BLE_GATT_REGISTER_OP_DSC => {
-            printf(
-                cstr!("registering descriptor %s with handle=%d\n"),
-                ble_uuid_to_str((*(*ctxt).__bindgen_anon_1.dsc.dsc_def).uuid, buf),
-                (*ctxt).__bindgen_anon_1.dsc.handle as i32,
+            info!(
+                TAG,
+                "registering descriptor {} of characteristic {} with handle={}",
+                Uuid::from_ptr((*(*ctxt).__bindgen_anon_1.dsc.dsc_def).uuid),
+                Uuid::from_ptr((*(*ctxt).__bindgen_anon_1.dsc.chr_def).uuid),
+                (*ctxt).__bindgen_anon_1.dsc.handle,
             );
         }
         _ => {
-            printf(cstr!("unknown operation: %d\n"), (*ctxt).op as u32);
+            warn!(TAG, "unknown operation: {}", (*ctxt).op);
         }
     }
 }
 
-pub unsafe fn gatt_svr_init() -> i32 {
-    // Leaks the eff out of the svc_def
-    let svcs_ptr = alloc_svc_def();
-    print_svcs(svcs_ptr);
+pub fn gatt_svr_init() -> BleResult<()> {
+    device_info::load();
+    device_name::load();
 
-    ble_svc_gap_init();
-    ble_svc_gatt_init();
+    unsafe {
+        print_svcs(&SERVICES);
 
-    let mut rc;
-
-    rc = ble_gatts_count_cfg(svcs_ptr);
-    esp_assert!(rc == 0, cstr!("RC err after ble_gatts_count_cfg\n"));
-
-    rc = ble_gatts_add_svcs(svcs_ptr);
-    esp_assert!(rc == 0, cstr!("RC err after ble_gatts_add_svcs\n"));
+        /* The GAP service is in SERVICES, NimBLE's has a read-only device name */
+        ble_svc_gatt_init();
+    }
 
-    return 0;
+    SERVICES.register()
 }
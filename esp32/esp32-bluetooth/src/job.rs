@@ -0,0 +1,440 @@
+//! Job upload service, receives plot jobs larger than any single ATT write
+//!
+//! The app writes `BEGIN` with the job's size and CRC-32 to the control characteristic,
+//! then the job in chunks to the data characteristic, and `COMMIT` once all of it is
+//! sent. Each chunk is a little-endian `u16` sequence number, 0 for the first chunk of a
+//! job, followed by the next bytes of the job. A chunk written without response carries
+//! up to MTU - 5 bytes of the job. Written with response it may be longer, NimBLE
+//! reassembles the prepared writes of a long write before the value reaches `JobData`.
+//!
+//! The status characteristic reads and notifies the state, how much was received and the
+//! sequence number expected next. A chunk out of sequence is refused and only changes the
+//! status, so an app streaming without responses notices from the notification. After a
+//! dropped connection the app writes `BEGIN` with the same size and CRC-32 again and
+//! continues at the received offset.
+//!
+//! There is one transfer for all connections. `Transfer` has no BLE in it and runs the
+//! same on the host.
+
+use alloc::vec::Vec;
+use esp32_sys::connections;
+use esp32_sys::gatt::{Access, AttError, Decode, Handle, Request, MAX_VALUE_LEN};
+use esp32_sys::sync::Mutex;
+use esp32_sys::uuid::Uuid;
+use esp32_sys::*;
+
+const TAG: &str = "job\0";
+
+/// Vendor base of the job service, `0000xxxx-8c1d-4f5c-9a4e-5b1e7e3a2b01`
+const JOB_UUID_BASE: u128 = 0x0000_0000_8c1d_4f5c_9a4e_5b1e_7e3a_2b01;
+pub const GATT_JOB_UUID: Uuid = Uuid::from_base(JOB_UUID_BASE, 0x0001);
+pub const JOB_CONTROL_UUID: Uuid = Uuid::from_base(JOB_UUID_BASE, 0x0002);
+pub const JOB_DATA_UUID: Uuid = Uuid::from_base(JOB_UUID_BASE, 0x0003);
+pub const JOB_STATUS_UUID: Uuid = Uuid::from_base(JOB_UUID_BASE, 0x0004);
+
+pub static JOB_STATUS_HANDLE: Handle = Handle::new();
+
+/// Largest job accepted, it is held in RAM until taken
+pub const MAX_JOB_SIZE: u32 = 32 * 1024;
+
+/// Sequence number and job bytes of a chunk, at most as long as any attribute value
+const MAX_CHUNK_LEN: usize = MAX_VALUE_LEN;
+
+/* Control commands */
+pub const BEGIN: u8 = 0x01;
+pub const COMMIT: u8 = 0x02;
+pub const ABORT: u8 = 0x03;
+
+/// Where a transfer is, the first byte of the status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle = 0,
+    Receiving = 1,
+    /// Committed with a matching CRC-32, waiting to be taken
+    Complete = 2,
+    /// Committed with a wrong CRC-32, the data is gone
+    Failed = 3,
+}
+
+/// Why a command or chunk was refused, answered as an ATT application error and kept in
+/// the status until the next command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    /// A chunk or `COMMIT` without a transfer in progress
+    NotReceiving = 0x80,
+    OutOfSequence = 0x81,
+    /// A chunk goes past the size given with `BEGIN`
+    Overflow = 0x82,
+    /// `COMMIT` before everything was received
+    Incomplete = 0x83,
+    CrcMismatch = 0x84,
+    /// `BEGIN` with a size over `MAX_JOB_SIZE`
+    TooLarge = 0x85,
+}
+
+impl From<TransferError> for AttError {
+    fn from(err: TransferError) -> AttError {
+        AttError::Application(err as u8)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Begin { size: u32, crc: u32 },
+    Commit,
+    Abort,
+}
+
+/// The opcode, and size and CRC-32 as little-endian `u32` for `BEGIN`
+impl Decode for Command {
+    const MIN_LEN: usize = 1;
+    const MAX_LEN: usize = 9;
+
+    fn decode(bytes: &[u8]) -> Result<Command, AttError> {
+        let u32_at = |at: usize| {
+            let mut le = [0; 4];
+            le.copy_from_slice(&bytes[at..at + 4]);
+            u32::from_le_bytes(le)
+        };
+        match (bytes[0], bytes.len()) {
+            (BEGIN, 9) => Ok(Command::Begin {
+                size: u32_at(1),
+                crc: u32_at(5),
+            }),
+            (COMMIT, 1) => Ok(Command::Commit),
+            (ABORT, 1) => Ok(Command::Abort),
+            (BEGIN, _) | (COMMIT, _) | (ABORT, _) => Err(AttError::InvalidAttrValueLen),
+            _ => Err(AttError::ValueNotAllowed),
+        }
+    }
+}
+
+/// State machine of an upload
+pub struct Transfer {
+    state: State,
+    size: u32,
+    crc: u32,
+    data: Vec<u8>,
+    next_seq: u16,
+    error: Option<TransferError>,
+}
+
+impl Transfer {
+    pub const fn new() -> Transfer {
+        Transfer {
+            state: State::Idle,
+            size: 0,
+            crc: 0,
+            data: Vec::new(),
+            next_seq: 0,
+            error: None,
+        }
+    }
+
+    /// Bytes received of the job in progress
+    pub fn offset(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn command(&mut self, command: Command) -> Result<(), TransferError> {
+        let result = match command {
+            Command::Begin { size, crc } => self.begin(size, crc),
+            Command::Commit => self.commit(),
+            Command::Abort => {
+                self.reset(State::Idle);
+                Ok(())
+            }
+        };
+        self.error = result.err();
+        result
+    }
+
+    /// Starts a job, or resumes the one in progress if it has the same size and CRC-32
+    fn begin(&mut self, size: u32, crc: u32) -> Result<(), TransferError> {
+        if self.state == State::Receiving && self.size == size && self.crc == crc {
+            return Ok(());
+        }
+        if size > MAX_JOB_SIZE {
+            return Err(TransferError::TooLarge);
+        }
+        self.reset(State::Receiving);
+        self.size = size;
+        self.crc = crc;
+        self.data.reserve_exact(size as usize);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), TransferError> {
+        if self.state != State::Receiving {
+            return Err(TransferError::NotReceiving);
+        }
+        if self.offset() != self.size {
+            return Err(TransferError::Incomplete);
+        }
+        if crc32(&self.data) != self.crc {
+            self.reset(State::Failed);
+            return Err(TransferError::CrcMismatch);
+        }
+        self.state = State::Complete;
+        Ok(())
+    }
+
+    /// Appends the chunk with sequence number `seq`
+    pub fn chunk(&mut self, seq: u16, bytes: &[u8]) -> Result<(), TransferError> {
+        let result = if self.state != State::Receiving {
+            Err(TransferError::NotReceiving)
+        } else if seq != self.next_seq {
+            Err(TransferError::OutOfSequence)
+        } else if bytes.len() as u32 > self.size - self.offset() {
+            Err(TransferError::Overflow)
+        } else {
+            self.data.extend_from_slice(bytes);
+            self.next_seq = self.next_seq.wrapping_add(1);
+            Ok(())
+        };
+        self.error = result.err();
+        result
+    }
+
+    /// The job once committed, the transfer is idle again afterwards
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        if self.state != State::Complete {
+            return None;
+        }
+        let data = core::mem::take(&mut self.data);
+        self.reset(State::Idle);
+        Some(data)
+    }
+
+    /// State, last error or 0, then size and offset as little-endian `u32` and the next
+    /// sequence number as little-endian `u16`
+    pub fn status(&self) -> [u8; 12] {
+        let mut status = [0; 12];
+        status[0] = self.state as u8;
+        status[1] = self.error.map_or(0, |err| err as u8);
+        status[2..6].copy_from_slice(&self.size.to_le_bytes());
+        status[6..10].copy_from_slice(&self.offset().to_le_bytes());
+        status[10..].copy_from_slice(&self.next_seq.to_le_bytes());
+        status
+    }
+
+    fn reset(&mut self, state: State) {
+        self.state = state;
+        self.size = 0;
+        self.crc = 0;
+        self.data = Vec::new();
+        self.next_seq = 0;
+    }
+}
+
+/// CRC-32 as used by zlib and `java.util.zip.CRC32`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+static TRANSFER: Mutex<Transfer> = Mutex::new(Transfer::new());
+
+/// Takes the last committed job
+pub fn take() -> Option<Vec<u8>> {
+    let job = TRANSFER.lock().take();
+    if job.is_some() {
+        notify_status();
+    }
+    job
+}
+
+fn notify_status() {
+    let status = TRANSFER.lock().status();
+    connections::notify(&JOB_STATUS_HANDLE, &status);
+}
+
+/// `BEGIN`, `COMMIT` or `ABORT`
+pub struct JobControl;
+
+impl Access for JobControl {
+    fn write(&self, request: &mut Request) -> Result<(), AttError> {
+        let command = request.decode::<Command>()?;
+        let result = TRANSFER.lock().command(command);
+        match result {
+            Ok(()) => info!(TAG, "{:?}", command),
+            Err(err) => warn!(TAG, "{:?}: {:?}", command, err),
+        }
+        notify_status();
+        result.map_err(AttError::from)
+    }
+}
+
+/// Sequence number and the next part of the job
+pub struct JobData;
+
+impl Access for JobData {
+    fn write(&self, request: &mut Request) -> Result<(), AttError> {
+        let mut buf = [0; MAX_CHUNK_LEN];
+        let chunk = request.value(3, &mut buf)?;
+        let seq = u16::from_le_bytes([chunk[0], chunk[1]]);
+        let result = TRANSFER.lock().chunk(seq, &chunk[2..]);
+        if let Err(err) = result {
+            debug!(TAG, "chunk {}: {:?}", seq, err);
+        }
+        notify_status();
+        result.map_err(AttError::from)
+    }
+}
+
+pub struct JobStatus;
+
+impl Access for JobStatus {
+    fn read(&self, request: &mut Request) -> Result<(), AttError> {
+        request.append(&TRANSFER.lock().status())
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+
+    const JOB: &[u8] = b"G1 X10 Y20\nG1 X0 Y0\n";
+
+    fn begin(transfer: &mut Transfer, job: &[u8]) -> Result<(), TransferError> {
+        transfer.command(Command::Begin {
+            size: job.len() as u32,
+            crc: crc32(job),
+        })
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn upload_and_commit() {
+        let mut transfer = Transfer::new();
+        begin(&mut transfer, JOB).unwrap();
+        transfer.chunk(0, &JOB[..8]).unwrap();
+        transfer.chunk(1, &JOB[8..]).unwrap();
+        assert_eq!(transfer.status(), [1, 0, 20, 0, 0, 0, 20, 0, 0, 0, 2, 0]);
+        assert!(transfer.take().is_none());
+
+        transfer.command(Command::Commit).unwrap();
+        assert_eq!(transfer.status()[..2], [State::Complete as u8, 0]);
+        assert_eq!(transfer.take().unwrap(), JOB.to_vec());
+        assert_eq!(transfer.status(), [0; 12]);
+        assert!(transfer.take().is_none());
+    }
+
+    #[test]
+    fn crc_mismatch_fails() {
+        let mut transfer = Transfer::new();
+        transfer
+            .command(Command::Begin { size: 2, crc: 1 })
+            .unwrap();
+        transfer.chunk(0, b"ab").unwrap();
+        assert_eq!(
+            transfer.command(Command::Commit),
+            Err(TransferError::CrcMismatch)
+        );
+        assert_eq!(transfer.status(), [3, 0x84, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(transfer.take().is_none());
+        assert_eq!(
+            transfer.command(Command::Commit),
+            Err(TransferError::NotReceiving)
+        );
+    }
+
+    #[test]
+    fn chunks_checked() {
+        let mut transfer = Transfer::new();
+        assert_eq!(transfer.chunk(0, b"x"), Err(TransferError::NotReceiving));
+        begin(&mut transfer, JOB).unwrap();
+        transfer.chunk(0, &JOB[..8]).unwrap();
+        assert_eq!(
+            transfer.chunk(0, &JOB[..8]),
+            Err(TransferError::OutOfSequence)
+        );
+        assert_eq!(
+            transfer.chunk(2, &JOB[8..]),
+            Err(TransferError::OutOfSequence)
+        );
+        assert_eq!(transfer.status()[1], 0x81);
+        assert_eq!(transfer.chunk(1, &[0; 13]), Err(TransferError::Overflow));
+        assert_eq!(transfer.status()[1], 0x82);
+
+        // Refused chunks leave the data alone
+        assert_eq!(transfer.offset(), 8);
+        transfer.chunk(1, &JOB[8..]).unwrap();
+        assert_eq!(transfer.status()[1], 0);
+        transfer.command(Command::Commit).unwrap();
+    }
+
+    #[test]
+    fn commit_before_complete() {
+        let mut transfer = Transfer::new();
+        assert_eq!(
+            transfer.command(Command::Commit),
+            Err(TransferError::NotReceiving)
+        );
+        begin(&mut transfer, JOB).unwrap();
+        transfer.chunk(0, &JOB[..8]).unwrap();
+        assert_eq!(
+            transfer.command(Command::Commit),
+            Err(TransferError::Incomplete)
+        );
+        assert_eq!(transfer.status()[..2], [State::Receiving as u8, 0x83]);
+        transfer.chunk(1, &JOB[8..]).unwrap();
+        transfer.command(Command::Commit).unwrap();
+    }
+
+    #[test]
+    fn same_begin_resumes() {
+        let mut transfer = Transfer::new();
+        begin(&mut transfer, JOB).unwrap();
+        transfer.chunk(0, &JOB[..8]).unwrap();
+
+        // The central reconnects and begins the same job again
+        begin(&mut transfer, JOB).unwrap();
+        assert_eq!(transfer.offset(), 8);
+        assert_eq!(transfer.status()[10..], [1, 0]);
+        transfer.chunk(1, &JOB[8..]).unwrap();
+        transfer.command(Command::Commit).unwrap();
+        assert_eq!(transfer.take().unwrap(), JOB.to_vec());
+
+        // Another job starts over
+        begin(&mut transfer, JOB).unwrap();
+        transfer.chunk(0, &JOB[..8]).unwrap();
+        begin(&mut transfer, &JOB[..8]).unwrap();
+        assert_eq!(transfer.offset(), 0);
+        transfer.command(Command::Abort).unwrap();
+        assert_eq!(transfer.status(), [0; 12]);
+    }
+
+    #[test]
+    fn begin_too_large() {
+        let mut transfer = Transfer::new();
+        let begin = Command::Begin {
+            size: MAX_JOB_SIZE + 1,
+            crc: 0,
+        };
+        assert_eq!(transfer.command(begin), Err(TransferError::TooLarge));
+        assert_eq!(transfer.status()[..2], [State::Idle as u8, 0x85]);
+        transfer
+            .command(Command::Begin {
+                size: MAX_JOB_SIZE,
+                crc: 0,
+            })
+            .unwrap();
+        assert_eq!(transfer.status()[..6], [1, 0, 0, 0x80, 0, 0]);
+    }
+}
@@ -1,388 +1,768 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(const_mut_refs)]
 #![feature(const_fn)]
 #![feature(const_transmute)]
 #![feature(alloc_error_handler)]
 
+extern crate alloc;
 extern crate esp32_sys;
+#[cfg(not(test))]
 extern crate esp_idf_alloc;
 
+mod battery;
+mod conn_policy;
 mod debug;
+mod device_info;
+mod device_name;
 mod gatt_svr;
+mod job;
+mod pairing;
+mod stepper;
 
+#[cfg(not(test))]
 use core::alloc::Layout;
 use core::ffi::c_void;
-use core::mem::size_of;
+use core::fmt::{self, Write};
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 use core::ptr;
-use debug::print_addr;
+use debug::Addr;
+use esp32_sys::adc::{Thresholds, VoltageLevel};
+use esp32_sys::adv::{AdvData, Advertising, FLAG_BREDR_UNSUPPORTED, FLAG_GENERAL_DISCOVERABLE};
+use esp32_sys::connections;
+use esp32_sys::error::{BleResult, EspResult};
+use esp32_sys::gap::{GapEvent, GapHandler, TxStatus};
+use esp32_sys::gpio::{OutputPin, Pins};
+use esp32_sys::nvs;
+use esp32_sys::sync::Mutex;
+use esp32_sys::task::{self, Core};
+use esp32_sys::timer::{Timer, TimerMode};
+use esp32_sys::uart::{Uart, UartConfig};
 use esp32_sys::*;
-use gatt_svr::{gatt_svr_init, gatt_svr_register_cb, HRS_HRM_HANDLE};
+use gatt_svr::{
+    gatt_svr_init, gatt_svr_register_cb, Position, Velocity, HRS_HRM_HANDLE, POLARGRAPH_UUID,
+};
+use stepper::StepperPins;
 
 extern "C" {
     fn abort() -> !;
 }
 
+#[cfg(not(test))]
 #[global_allocator]
 static A: esp_idf_alloc::EspIdfAllocator = esp_idf_alloc::EspIdfAllocator;
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    unsafe {
-        printf(cstr!("Panic!\n"));
-        abort();
-    }
+fn panic(info: &PanicInfo) -> ! {
+    error!(BLE_HR_TAG, "{}", info);
+    unsafe { abort() }
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
-fn alloc_error(_layout: Layout) -> ! {
-    unsafe {
-        printf(cstr!("Alloc Error!\n"));
-        abort();
-    }
+fn alloc_error(layout: Layout) -> ! {
+    error!(BLE_HR_TAG, "failed to allocate {} bytes", layout.size());
+    unsafe { abort() }
 }
 
-const BLINK_GPIO: gpio_num_t = gpio_num_t_GPIO_NUM_5;
 const UART_NUM: uart_port_t = uart_port_t_UART_NUM_1;
-const ECHO_TEST_TXD: i32 = gpio_num_t_GPIO_NUM_17 as i32;
-const ECHO_TEST_RXD: i32 = gpio_num_t_GPIO_NUM_16 as i32;
-const ECHO_TEST_RTS: i32 = UART_PIN_NO_CHANGE;
-const ECHO_TEST_CTS: i32 = UART_PIN_NO_CHANGE;
 
-const BUF_SIZE: i32 = 1024;
+const BUF_SIZE: usize = 1024;
 // https://github.com/espressif/esp-idf/tree/0a03a55c1eb44a354c9ad5d91d91da371fe23f84/examples/bluetooth/nimble/blehr
 
-pub const BLE_HR_TAG: *const i8 = cstr!("NimBLE_BLE_HeartRate");
+pub const BLE_HR_TAG: &str = "NimBLE_BLE_HeartRate\0";
 
-static mut BLEHR_TX_TIMER: TimerHandle_t = ptr::null_mut();
+static BLEHR_TX_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
 
-static mut NOTIFY_STATE: bool = false;
+/// Minimum and maximum advertising interval
+const ADV_INTERVAL_MS: (u32, u32) = (100, 150);
 
-static mut CONN_HANDLE: u16 = 0;
+static BLEHR_ADDRESS_TYPE: Mutex<u8> = Mutex::new(0);
 
-const DEVICE_NAME: &str = "blehr_sensor_1.0\0";
+static MOTION_TIMER: Mutex<Option<Timer>> = Mutex::new(None);
 
-static mut BLEHR_ADDRESS_TYPE: u8 = 0;
+const MOTION_PERIOD_MS: u32 = 100;
+
+/* The steppers skip below about 10 V */
+const SUPPLY_THRESHOLDS: Thresholds = Thresholds {
+    low: 10500,
+    critical: 10000,
+    hysteresis: 300,
+};
 
 /* Variable to simulate heart beats */
-static mut HEARTRATE: u8 = 90;
+static HEARTRATE: Mutex<u8> = Mutex::new(90);
 
-unsafe fn blehr_tx_hrate_stop() {
-    xTimerStop(BLEHR_TX_TIMER, 1000 / portTICK_PERIOD_MS);
+fn blehr_tx_hrate_stop() {
+    if let Some(ref timer) = *BLEHR_TX_TIMER.lock() {
+        let _ = timer.stop(1000 / portTICK_PERIOD_MS);
+    }
 }
 
 /* Reset heartrate measurment */
-unsafe fn blehr_tx_hrate_reset() {
-    let rc;
+fn blehr_tx_hrate_reset() {
+    if let Some(ref timer) = *BLEHR_TX_TIMER.lock() {
+        blehr_tx_hrate_restart(timer);
+    }
+}
 
-    if xTimerReset(BLEHR_TX_TIMER, 1000 / portTICK_PERIOD_MS) == pdPASS {
-        rc = 0;
-    } else {
-        rc = 1;
+fn blehr_tx_hrate_restart(timer: &Timer) {
+    if let Err(err) = timer.reset(1000 / portTICK_PERIOD_MS) {
+        error!(BLE_HR_TAG, "error restarting heart rate timer; {:?}", err);
     }
-    assert!(rc == 0);
 }
 
-/* This function simulates heart beat and notifies it to the client */
-unsafe extern "C" fn blehr_tx_hrate(_ev: TimerHandle_t) {
+/* This function simulates heart beat and notifies it to every subscriber */
+fn blehr_tx_hrate(timer: &Timer) {
     let mut hrm: [u8; 2] = [0; 2];
-    let rc;
-    let om: *mut os_mbuf;
 
-    if !NOTIFY_STATE {
-        blehr_tx_hrate_stop();
-        HEARTRATE = 90;
+    if !connections::has_subscribers(HRS_HRM_HANDLE.get()) {
+        let _ = timer.stop(1000 / portTICK_PERIOD_MS);
+        *HEARTRATE.lock() = 90;
         return;
     }
 
-    hrm[0] = 0x06; /* contact of a sensor */
-    hrm[1] = HEARTRATE; /* storing dummy data */
+    {
+        let mut heartrate = HEARTRATE.lock();
+        hrm[0] = 0x06; /* contact of a sensor */
+        hrm[1] = *heartrate; /* storing dummy data */
 
-    /* Simulation of heart beats */
-    HEARTRATE += 1;
-    if HEARTRATE == 160 {
-        HEARTRATE = 90;
+        /* Simulation of heart beats */
+        *heartrate += 1;
+        if *heartrate == 160 {
+            *heartrate = 90;
+        }
     }
 
-    om = ble_hs_mbuf_from_flat(hrm.as_ptr() as *const _, size_of::<[u8; 2]>() as u16);
-    rc = ble_gattc_notify_custom(CONN_HANDLE, HRS_HRM_HANDLE, om);
+    connections::notify(&HRS_HRM_HANDLE, &hrm);
 
-    assert!(rc == 0);
-
-    blehr_tx_hrate_reset();
+    blehr_tx_hrate_restart(timer);
 }
 
-unsafe extern "C" fn blehr_gap_event(
-    event: *mut ble_gap_event,
-    _arg: *mut ::core::ffi::c_void,
-) -> i32 {
-    match (*event).type_ as u32 {
-        BLE_GAP_EVENT_CONNECT => {
-            /* A new connection was established or a connection attempt failed */
-            esp_log!(
-                BLE_HR_TAG,
-                cstr!("connection %s; status=%d\n"),
-                if (*event).__bindgen_anon_1.connect.status == 0 {
-                    cstr!("established")
-                } else {
-                    cstr!("failed")
-                },
-                (*event).__bindgen_anon_1.connect.status
-            );
+/*
+ * Dead reckoning until the stepper driver is ported: the motors are taken to run at the
+ * velocity setpoint, in steps per motion period
+ */
+fn motion_tick(_timer: &Timer) {
+    let velocity = match battery::supply_level() {
+        Some(VoltageLevel::Critical) => Velocity::default(),
+        _ => gatt_svr::velocity_setpoint(),
+    };
+    let position = gatt_svr::position();
+    gatt_svr::report_velocity(velocity);
+    gatt_svr::report_position(Position {
+        a: position.a.wrapping_add(velocity.a as i32),
+        b: position.b.wrapping_add(velocity.b as i32),
+    });
+    conn_policy::motion(velocity != Velocity::default(), MOTION_PERIOD_MS);
+}
 
-            if (*event).__bindgen_anon_1.connect.status != 0 {
-                /* Connection failed; resume advertising */
+struct BlehrGap;
+
+static BLEHR_GAP: BlehrGap = BlehrGap;
+
+impl GapHandler for BlehrGap {
+    fn event(&self, event: GapEvent) -> i32 {
+        match event {
+            GapEvent::Connect {
+                conn_handle,
+                status,
+            } => match status {
+                /* A new connection was established or a connection attempt failed */
+                Ok(()) => {
+                    info!(
+                        BLE_HR_TAG,
+                        "connection established; conn_handle={}", conn_handle
+                    );
+                    conn_policy::connected(conn_handle);
+                }
+                Err(err) => {
+                    info!(BLE_HR_TAG, "connection failed; {}", err);
+                    /* Connection failed; resume advertising */
+                    blehr_advertise();
+                }
+            },
+
+            GapEvent::Disconnect { reason, conn } => {
+                info!(
+                    BLE_HR_TAG,
+                    "disconnect; conn_handle={} peer={} reason={}",
+                    conn.conn_handle,
+                    conn.peer_id_addr,
+                    reason
+                );
+
+                /* Connection terminated; resume advertising */
                 blehr_advertise();
             }
-            CONN_HANDLE = (*event).__bindgen_anon_1.connect.conn_handle;
-        }
-
-        BLE_GAP_EVENT_DISCONNECT => {
-            esp_log!(
-                BLE_HR_TAG,
-                cstr!("disconnect; reason=%d\n"),
-                (*event).__bindgen_anon_1.disconnect.reason
-            );
 
-            /* Connection terminated; resume advertising */
-            blehr_advertise();
-        }
+            GapEvent::ConnUpdate {
+                conn_handle,
+                status,
+            } => match status {
+                Ok(()) => conn_policy::link_changed(conn_handle),
+                Err(err) => warn!(
+                    BLE_HR_TAG,
+                    "connection update failed; conn_handle={} {}", conn_handle, err
+                ),
+            },
+
+            /* The central asks for new parameters, the reply depends on what the plotter does */
+            GapEvent::ConnUpdateReq {
+                conn_handle, peer, ..
+            }
+            | GapEvent::L2capUpdateReq {
+                conn_handle, peer, ..
+            } => return conn_policy::update_requested(conn_handle, &peer),
+
+            GapEvent::PhyUpdateComplete {
+                conn_handle,
+                status: Ok(()),
+                ..
+            } => conn_policy::link_changed(conn_handle),
+
+            /* Pairing, or encryption with the keys of a bond, finished */
+            GapEvent::EncChange {
+                conn_handle,
+                status,
+            } => pairing::enc_change(conn_handle, status),
+
+            GapEvent::PasskeyAction {
+                conn_handle,
+                action,
+            } => pairing::passkey_action(conn_handle, action),
+
+            /* A bonded central pairs again, e.g. after forgetting the plotter */
+            GapEvent::RepeatPairing {
+                conn_handle,
+                current,
+                new,
+            } => return pairing::repeat_pairing(conn_handle, &current, &new),
+
+            GapEvent::AdvComplete { .. } => {
+                info!(BLE_HR_TAG, "adv complete");
+                blehr_advertise();
+            }
 
-        BLE_GAP_EVENT_ADV_COMPLETE => {
-            esp_log!(BLE_HR_TAG, cstr!("adv complete\n"));
-            blehr_advertise();
-        }
+            GapEvent::Subscribe {
+                conn_handle,
+                attr_handle,
+                cur,
+                ..
+            } => {
+                info!(
+                    BLE_HR_TAG,
+                    "subscribe event; conn_handle={} attr_handle={} cur_notify={} cur_indicate={}",
+                    conn_handle,
+                    attr_handle,
+                    cur.notify,
+                    cur.indicate
+                );
+                if attr_handle == HRS_HRM_HANDLE.get() {
+                    if connections::has_subscribers(attr_handle) {
+                        blehr_tx_hrate_reset();
+                    } else {
+                        blehr_tx_hrate_stop();
+                    }
+                }
+            }
 
-        BLE_GAP_EVENT_SUBSCRIBE => {
-            esp_log!(
+            /* The connection registry tracks confirmations, only failures are of interest */
+            GapEvent::NotifyTx {
+                conn_handle,
+                attr_handle,
+                status: TxStatus::Failed(err),
+                ..
+            } => debug!(
                 BLE_HR_TAG,
-                cstr!("subscribe event; cur_notify=%d\n value handle; val_handle=%d\n"),
-                (*event).__bindgen_anon_1.subscribe.cur_notify() as u32,
-                HRS_HRM_HANDLE as u32
-            );
-            if (*event).__bindgen_anon_1.subscribe.attr_handle == HRS_HRM_HANDLE {
-                NOTIFY_STATE = (*event).__bindgen_anon_1.subscribe.cur_notify() != 0;
-                blehr_tx_hrate_reset();
-            } else if (*event).__bindgen_anon_1.subscribe.attr_handle != HRS_HRM_HANDLE {
-                NOTIFY_STATE = (*event).__bindgen_anon_1.subscribe.cur_notify() != 0;
-                blehr_tx_hrate_stop();
-            }
-            esp_log!(
-                cstr!("BLE_GAP_SUBSCRIBE_EVENT"),
-                cstr!("conn_handle from subscribe=%d\n"),
-                CONN_HANDLE as u32,
-            );
-        }
+                "notify_tx event; conn_handle={} attr_handle={} {}", conn_handle, attr_handle, err
+            ),
 
-        BLE_GAP_EVENT_MTU => {
-            esp_log!(
+            GapEvent::Mtu {
+                conn_handle, mtu, ..
+            } => info!(
                 BLE_HR_TAG,
-                cstr!("mtu update event; conn_handle=%d mtu=%d\n"),
-                (*event).__bindgen_anon_1.mtu.conn_handle as u32,
-                (*event).__bindgen_anon_1.mtu.value as u32,
-            );
+                "mtu update event; conn_handle={} mtu={}", conn_handle, mtu
+            ),
+
+            event => debug!(BLE_HR_TAG, "unhandled {:?}", event),
         }
-        _ => esp_log!(
-            BLE_HR_TAG,
-            cstr!("unknown operation: %d\n"),
-            (*event).type_ as u32
-        ),
-    }
 
-    return 0;
+        0
+    }
 }
 
 /*
- * Enables advertising with parameters:
- *     o General discoverable mode
- *     o Undirected connectable mode
+ * Advertises undirected connectable and general discoverable with the polargraph
+ * service, which the Flutter app filters scan results on, and the name. The job service's
+ * 128-bit UUID goes in the scan response, there is no room left for it.
  */
-unsafe fn blehr_advertise() {
-    let mut fields: ble_hs_adv_fields = core::mem::MaybeUninit::zeroed().assume_init();
-    /*
-     * Advertise two flags:
-     *      o Discoverability in forthcoming advertisement (general)
-     *      o BLE-only (BR/EDR unsupported)
-     */
-    fields.flags = BLE_HS_ADV_F_DISC_GEN as u8 | BLE_HS_ADV_F_BREDR_UNSUP as u8;
-
-    /*
-     * Indicate that the TX power level field should be included; have the
-     * stack fill this value automatically.  This is done by assigning the
-     * special value BLE_HS_ADV_TX_PWR_LVL_AUTO.
-     */
-    fields.set_tx_pwr_lvl_is_present(1);
-    fields.tx_pwr_lvl = BLE_HS_ADV_TX_PWR_LVL_AUTO as i8;
-
-    fields.name = DEVICE_NAME.as_ptr() as *mut u8;
-    fields.name_len = DEVICE_NAME.len() as u8;
-    fields.set_name_is_complete(1);
-
-    let mut rc = ble_gap_adv_set_fields(&fields);
-    if rc != 0 {
-        esp_log!(
-            BLE_HR_TAG,
-            cstr!("error setting advertisement data; rc=%d\n"),
-            rc
-        );
-        return;
+fn blehr_advertise() {
+    let data = AdvData::new()
+        .flags(FLAG_GENERAL_DISCOVERABLE | FLAG_BREDR_UNSUPPORTED)
+        .uuid(POLARGRAPH_UUID)
+        .name(&device_name::get());
+    let scan_response = AdvData::new().uuid(job::GATT_JOB_UUID);
+    let own_addr_type = *BLEHR_ADDRESS_TYPE.lock();
+    let rc = Advertising::new(data)
+        .scan_response(scan_response)
+        .interval_ms(ADV_INTERVAL_MS.0, ADV_INTERVAL_MS.1)
+        .start(own_addr_type, &BLEHR_GAP);
+    if let Err(err) = rc {
+        error!(BLE_HR_TAG, "error enabling advertisement; {}", err);
     }
+}
 
-    /* Begin advertising */
-    let mut adv_params: ble_gap_adv_params = std::mem::MaybeUninit::zeroed().assume_init();
-    adv_params.conn_mode = BLE_GAP_CONN_MODE_UND as u8;
-    adv_params.disc_mode = BLE_GAP_DISC_MODE_GEN as u8;
-    rc = ble_gap_adv_start(
-        BLEHR_ADDRESS_TYPE,
-        ptr::null(),
-        core::i32::MAX,
-        &adv_params,
-        Some(blehr_gap_event),
-        ptr::null_mut(),
-    );
-    if rc != 0 {
-        esp_log!(
-            BLE_HR_TAG,
-            cstr!("error enabling advertisement; rc=%d\n"),
-            rc
-        );
-        return;
+/// Advertises again if advertising, so a new name goes out without waiting for the next
+/// disconnect
+pub fn restart_advertising() {
+    unsafe {
+        if ble_gap_adv_active() == 0 {
+            return;
+        }
+        let rc = ble_gap_adv_stop();
+        if rc != 0 {
+            error!(BLE_HR_TAG, "error stopping advertisement; rc={}", rc);
+            return;
+        }
     }
+    blehr_advertise();
 }
 
 unsafe extern "C" fn blehr_on_sync() {
     let mut rc;
 
-    rc = ble_hs_id_infer_auto(0, &mut BLEHR_ADDRESS_TYPE as *mut _);
+    let mut addr_type = 0;
+    rc = ble_hs_id_infer_auto(0, &mut addr_type);
     assert!(rc == 0);
+    *BLEHR_ADDRESS_TYPE.lock() = addr_type;
 
-    let addr_val: *mut u8 = [0; 6].as_mut_ptr();
-    rc = ble_hs_id_copy_addr(BLEHR_ADDRESS_TYPE, addr_val, ptr::null_mut());
+    let mut addr_val = [0u8; 6];
+    rc = ble_hs_id_copy_addr(addr_type, addr_val.as_mut_ptr(), ptr::null_mut());
     assert!(rc == 0);
 
-    esp_log!(BLE_HR_TAG, cstr!("Device Address: "));
-    print_addr(addr_val as *const c_void);
-    esp_log!(BLE_HR_TAG, cstr!("\n"));
+    info!(BLE_HR_TAG, "Device Address: {}", Addr(&addr_val));
 
     /* Begin advertising */
     blehr_advertise();
 }
 
 unsafe extern "C" fn blehr_on_reset(reason: i32) {
-    esp_log!(BLE_HR_TAG, cstr!("Resetting state; reason=%d\n"), reason);
+    warn!(BLE_HR_TAG, "Resetting state; reason={}", reason);
 }
 
 unsafe extern "C" fn blehr_host_task(_param: *mut c_void) {
-    esp_log!(BLE_HR_TAG, cstr!("BLE Host Task Started\n"));
+    info!(BLE_HR_TAG, "BLE Host Task Started");
     /* This function will return only when nimble_port_stop() is executed */
     nimble_port_run();
 
     nimble_port_freertos_deinit();
 }
 
-unsafe fn init_bt() {
+unsafe fn init_bt() -> EspResult<()> {
     /* Initialize NVS — it is used to store PHY calibration data */
-    let mut ret: esp_err_t = nvs_flash_init();
-    if ret == ESP_ERR_NVS_NO_FREE_PAGES as i32 || ret == ESP_ERR_NVS_NEW_VERSION_FOUND as i32 {
-        esp_error_check!(nvs_flash_erase());
-        ret = nvs_flash_init();
-    }
-    esp_error_check!(ret);
+    nvs::init()?;
 
-    esp_error_check!(esp_nimble_hci_and_controller_init());
+    esp!(esp_nimble_hci_and_controller_init())?;
 
     nimble_port_init();
     /* Initialize the NimBLE host configuration */
     ble_hs_cfg.sync_cb = Some(blehr_on_sync);
     ble_hs_cfg.reset_cb = Some(blehr_on_reset);
     ble_hs_cfg.gatts_register_cb = Some(gatt_svr_register_cb);
-
-    /* name, period/time,  auto reload, timer ID, callback */
-    BLEHR_TX_TIMER = xTimerCreate(
-        cstr!("blehr_tx_timer"),
-        pdMS_TO_TICKS!(1000),
-        pdTRUE,
-        ptr::null_mut(),
-        Some(blehr_tx_hrate),
+    pairing::init();
+
+    *BLEHR_TX_TIMER.lock() = Some(
+        Timer::new(
+            "blehr_tx_timer\0",
+            pdMS_TO_TICKS!(1000),
+            TimerMode::AutoReload,
+            blehr_tx_hrate,
+        )
+        .expect("blehr_tx_timer"),
     );
 
-    let mut rc: i32 = 0;
-    rc = gatt_svr_init();
-    esp_assert!(rc == 0, cstr!("gatt_svr_init failed\n"));
-
-    /* Set the default device name */
-    rc = ble_svc_gap_device_name_set(cstr!("Fake Device Name"));
-    esp_assert!(rc == 0, cstr!("ble_svc_gap_device_name_set failed\n"));
+    gatt_svr_init().expect("gatt_svr_init");
 
     /* Start the task */
     nimble_port_freertos_init(Some(blehr_host_task));
+
+    Ok(())
 }
 
 #[no_mangle]
 pub fn app_main() {
     unsafe {
-        esp_log!(BLE_HR_TAG, cstr!("Setting up!\n"));
-        init_bt();
-        esp_log!(BLE_HR_TAG, cstr!("BT init!\n"));
+        info!(BLE_HR_TAG, "Setting up!");
+        abort_on_err(init_bt());
+        info!(BLE_HR_TAG, "BT init!");
+
+        let pins = Pins::take().expect("pins");
+
+        /* Not fatal, the plotter also runs from a bench supply */
+        if let Err(err) = battery::init(pins.gpio34, pins.gpio35, SUPPLY_THRESHOLDS) {
+            error!(BLE_HR_TAG, "battery monitoring: {}", err);
+        }
 
-        rust_blink_and_write();
+        let motion_timer = Timer::new(
+            "motion_timer\0",
+            pdMS_TO_TICKS!(MOTION_PERIOD_MS),
+            TimerMode::AutoReload,
+            motion_tick,
+        )
+        .expect("motion_timer");
+        motion_timer.start(0).expect("motion_timer");
+        *MOTION_TIMER.lock() = Some(motion_timer);
+
+        /* Keep the coils de-energized until motion control takes over */
+        let mut stepper_pins = abort_on_err(StepperPins::new(
+            (pins.gpio13, pins.gpio12, pins.gpio14, pins.gpio27),
+            (pins.gpio26, pins.gpio25, pins.gpio33, pins.gpio32),
+        ));
+        abort_on_err(stepper_pins.release());
+        let status_led = abort_on_err(OutputPin::new(pins.gpio5));
+
+        /* Wired host controller on UART1, TX on GPIO17 and RX on GPIO16 */
+        let uart_config = UartConfig::new(115200)
+            .pins(pins.gpio17, pins.gpio16)
+            .rx_buffer_size(BUF_SIZE * 2);
+        let uart = abort_on_err(Uart::new(UART_NUM, &uart_config));
+
+        /* NimBLE runs on the PRO core, keep the application side on the APP core */
+        abort_on_err(task::spawn(
+            "status\0",
+            4096,
+            1,
+            Some(Core::App),
+            move || {
+                let _stepper_pins = stepper_pins;
+                rust_blink_and_write(status_led, uart)
+            },
+        ));
     }
 }
 
-unsafe fn rust_blink_and_write() {
-    gpio_pad_select_gpio(BLINK_GPIO as u8);
-
-    /* Set the GPIO as a push/pull output */
-    gpio_set_direction(BLINK_GPIO, gpio_mode_t_GPIO_MODE_OUTPUT);
-
-    /* Configure parameters of an UART driver,
-     * communication pins and install the driver */
-    let uart_config = uart_config_t {
-        baud_rate: 115200,
-        data_bits: uart_word_length_t_UART_DATA_8_BITS,
-        parity: uart_parity_t_UART_PARITY_DISABLE,
-        stop_bits: uart_stop_bits_t_UART_STOP_BITS_1,
-        flow_ctrl: uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE,
-        rx_flow_ctrl_thresh: 0,
-        use_ref_tick: false,
-    };
-
-    uart_param_config(UART_NUM, &uart_config);
-    uart_set_pin(
-        UART_NUM,
-        ECHO_TEST_TXD,
-        ECHO_TEST_RXD,
-        ECHO_TEST_RTS,
-        ECHO_TEST_CTS,
-    );
-    uart_driver_install(UART_NUM, BUF_SIZE * 2, 0, 0, ptr::null_mut(), 0);
+unsafe fn abort_on_err<T>(result: EspResult<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            error!(BLE_HR_TAG, "{}", err);
+            abort();
+        }
+    }
+}
 
+unsafe fn rust_blink_and_write(mut status_led: OutputPin, mut uart: Uart) -> ! {
     loop {
         /* Blink off (output low) */
-        gpio_set_level(BLINK_GPIO, 0);
+        let _ = status_led.set_low();
 
         vTaskDelay(1000 / portTICK_PERIOD_MS);
 
         // Write data to UART.
-        //let test_str = "This is a test string.\n";
-        // uart_write_bytes(UART_NUM, test_str.as_ptr() as *const _, test_str.len());
-        let tag = "Rust\0";
-
-        esp_log_write(
-            esp_log_level_t_ESP_LOG_INFO,
-            tag.as_ptr() as *const _,
-            " (%d) %s: %s\n\0".as_ptr() as *const _,
-            esp_log_timestamp(),
-            tag.as_ptr() as *const _,
-            "I live again!.\0".as_ptr() as *const _,
-        );
+        let _ = writeln!(uart, "({}) Rust: I live again!.", esp_log_timestamp());
+        info!("Rust\0", "I live again!.");
 
-        // esp_log!(BLE_TAG, "a string\n",);
+        /* Nothing plots uploaded jobs yet, the host controller hears about them */
+        if let Some(job) = job::take() {
+            info!(BLE_HR_TAG, "job of {} bytes received", job.len());
+            let _ = writeln!(uart, "job {} {:08x}", job.len(), job::crc32(&job));
+        }
+
+        /* The plotter has no screen, the host controller shows the passkey */
+        if let Some(passkey) = pairing::take_passkey() {
+            let _ = writeln!(uart, "passkey {:06}", passkey);
+        }
+
+        while uart.available().map_or(false, |len| len > 0) {
+            serve_command(&mut uart);
+        }
 
         /* Blink on (output high) */
-        gpio_set_level(BLINK_GPIO, 1);
+        let _ = status_led.set_high();
 
         vTaskDelay(1000 / portTICK_PERIOD_MS);
     }
 }
+
+/*
+ * Configuration commands from the host controller, one per line:
+ *     name            replies with the device name
+ *     name <name>     renames the device
+ *     name -          restores the default name
+ *     bonds           replies with the number of bonded centrals
+ *     bonds clear     deletes every bond
+ */
+fn serve_command(uart: &mut Uart) {
+    let mut buf = [0; 64];
+    let line = match uart.read_line(&mut buf, pdMS_TO_TICKS!(100)) {
+        Ok(len) => &buf[..len],
+        Err(err) => {
+            warn!(BLE_HR_TAG, "command: {}", err);
+            return;
+        }
+    };
+    let line = match core::str::from_utf8(line) {
+        Ok(line) => line.trim(),
+        Err(_) => {
+            let _ = writeln!(uart, "error not UTF-8");
+            return;
+        }
+    };
+
+    let mut words = line.splitn(2, ' ');
+    let _ = match (words.next(), words.next().map(str::trim)) {
+        (Some("name"), arg) => {
+            let result = match arg {
+                None => Ok(()),
+                Some("-") => device_name::reset(),
+                Some(name) => device_name::set(name),
+            };
+            match result {
+                Ok(()) => writeln!(uart, "name {}", device_name::get()),
+                Err(err) => writeln!(uart, "error {}", err),
+            }
+        }
+        (Some("bonds"), None) => write_bonds(uart, pairing::bond_count()),
+        (Some("bonds"), Some("clear")) => write_bonds(
+            uart,
+            pairing::clear_bonds().and_then(|()| pairing::bond_count()),
+        ),
+        _ => writeln!(uart, "error unknown command {}", line),
+    };
+}
+
+fn write_bonds(uart: &mut Uart, count: BleResult<usize>) -> fmt::Result {
+    match count {
+        Ok(count) => writeln!(uart, "bonds {}", count),
+        Err(err) => writeln!(uart, "error {}", err),
+    }
+}
+
+#[cfg(all(test, feature = "host-mock"))]
+mod tests {
+    use super::*;
+    use esp32_sys::gap::PairingProps;
+    use esp32_sys::mock;
+
+    /// Runs `init_bt` against the fakes and lets the host sync, returns the heart rate
+    /// measurement value handle
+    fn boot() -> u16 {
+        unsafe { init_bt().unwrap() };
+        mock::nimble::sync();
+        assert!(mock::nimble::is_advertising());
+        mock::nimble::find_chr("0x2a37").unwrap()
+    }
+
+    fn connect_paired(conn_handle: u16) {
+        assert_eq!(mock::nimble::connect(conn_handle), 0);
+        mock::nimble::pair(conn_handle, true);
+    }
+
+    fn hrm_timer_active() -> bool {
+        BLEHR_TX_TIMER.lock().as_ref().unwrap().is_active()
+    }
+
+    #[test]
+    fn heart_rate_timer() {
+        let hrm = boot();
+        assert_eq!(mock::nimble::connect(7), 0);
+        mock::nimble::subscribe(7, hrm, true, false);
+        assert!(hrm_timer_active());
+
+        mock::advance(pdMS_TO_TICKS!(1000));
+        mock::advance(pdMS_TO_TICKS!(1000));
+        let sent = mock::nimble::take_notifications();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].data, vec![0x06, 90]);
+        assert_eq!(sent[1].data, vec![0x06, 91]);
+
+        /* A failed restart is logged and the timer keeps its period */
+        mock::timer::fail_next_commands(1);
+        mock::advance(pdMS_TO_TICKS!(1000));
+        assert!(mock::logging::contains("error restarting heart rate timer"));
+        assert_eq!(mock::nimble::take_notifications().len(), 1);
+        assert!(hrm_timer_active());
+
+        /* The subscription goes with the connection, the next expiry stops the timer */
+        mock::nimble::disconnect(7, 0x213);
+        mock::advance(pdMS_TO_TICKS!(1000));
+        assert!(mock::nimble::take_notifications().is_empty());
+        assert!(!hrm_timer_active());
+        assert_eq!(*HEARTRATE.lock(), 90);
+    }
+
+    #[test]
+    fn gatt_table() {
+        let hrm = boot();
+        assert_eq!(HRS_HRM_HANDLE.get(), hrm);
+        let uuids: Vec<String> = mock::nimble::attributes()
+            .into_iter()
+            .map(|(_, uuid)| uuid)
+            .collect();
+        for uuid in &[
+            "0x2a37", "0x2a38", "0xff01", "0xff02", "0xff03", "0x2a29", "0x2a19",
+        ] {
+            assert!(uuids.iter().any(|u| u == uuid), "{} missing", uuid);
+        }
+
+        let body_sensor_location = mock::nimble::find_chr("0x2a38").unwrap();
+        assert_eq!(mock::nimble::read(1, body_sensor_location), Ok(vec![1]));
+        assert_eq!(
+            mock::nimble::write(1, body_sensor_location, &[2]),
+            Err(BLE_ATT_ERR_WRITE_NOT_PERMITTED as i32)
+        );
+        assert_eq!(
+            mock::nimble::read(1, hrm),
+            Err(BLE_ATT_ERR_READ_NOT_PERMITTED as i32)
+        );
+        let manufacturer = mock::nimble::find_chr("0x2a29").unwrap();
+        assert_eq!(
+            mock::nimble::read(1, manufacturer),
+            Ok(b"Hanging Plotter".to_vec())
+        );
+    }
+
+    #[test]
+    fn gap_callback() {
+        let hrm = boot();
+
+        /* A failed attempt and a timed out advertisement both resume advertising */
+        mock::nimble::connect_failed(BLE_HS_ETIMEOUT as i32);
+        assert!(mock::logging::contains("connection failed"));
+        assert!(mock::nimble::is_advertising());
+        mock::nimble::adv_timeout();
+        assert!(mock::logging::contains("adv complete"));
+        assert!(mock::nimble::is_advertising());
+
+        assert_eq!(mock::nimble::connect(7), 0);
+        assert!(!mock::nimble::is_advertising());
+        assert!(mock::logging::contains(
+            "connection established; conn_handle=7"
+        ));
+        mock::nimble::mtu(7, 185);
+        assert!(mock::logging::contains(
+            "mtu update event; conn_handle=7 mtu=185"
+        ));
+
+        mock::nimble::subscribe(7, hrm, true, false);
+        assert!(mock::logging::contains(&format!(
+            "subscribe event; conn_handle=7 attr_handle={} cur_notify=true cur_indicate=false",
+            hrm
+        )));
+        assert!(hrm_timer_active());
+        mock::nimble::subscribe(7, hrm, false, false);
+        assert!(!hrm_timer_active());
+
+        mock::nimble::disconnect(7, 0x213);
+        assert!(mock::logging::contains(
+            "disconnect; conn_handle=7 peer=c0:11:5e:00:00:07 \
+             reason=remote user terminated connection (0x13)"
+        ));
+        assert!(mock::nimble::is_advertising());
+        assert_eq!(mock::nimble::live_mbufs(), 0);
+    }
+
+    #[test]
+    fn repeat_pairing() {
+        boot();
+        connect_paired(9);
+        let peer = mock::nimble::peer_address(9);
+        assert!(mock::nimble::bonds().contains(&peer));
+
+        let bond = PairingProps {
+            key_size: 16,
+            authenticated: true,
+            secure_connections: true,
+        };
+        /* Anything weaker than the bond is ignored and the bond kept */
+        for &new in &[
+            PairingProps {
+                key_size: 7,
+                ..bond
+            },
+            PairingProps {
+                authenticated: false,
+                ..bond
+            },
+            PairingProps {
+                secure_connections: false,
+                ..bond
+            },
+        ] {
+            assert_eq!(
+                mock::nimble::repeat_pairing(9, bond, new),
+                BLE_GAP_REPEAT_PAIRING_IGNORE as i32
+            );
+        }
+        assert!(mock::logging::contains(
+            "connection 9: ignoring PairingProps"
+        ));
+        assert!(mock::nimble::bonds().contains(&peer));
+
+        /* The old bond goes and the central pairs again, the address is logged */
+        assert_eq!(
+            mock::nimble::repeat_pairing(9, bond, bond),
+            BLE_GAP_REPEAT_PAIRING_RETRY as i32
+        );
+        assert!(mock::logging::contains(
+            "connection 9: deleted the bond with c0:11:5e:00:00:09 to pair again"
+        ));
+        assert!(!mock::nimble::bonds().contains(&peer));
+        mock::nimble::disconnect(9, 0x213);
+    }
+
+    #[test]
+    fn motion_timer() {
+        boot();
+        let setpoint = mock::nimble::find_chr("0xff01").unwrap();
+        let velocity = mock::nimble::find_chr("0xff02").unwrap();
+        let position = mock::nimble::find_chr("0xff03").unwrap();
+        let positions = mock::nimble::find_chr("0xff06").unwrap();
+        connect_paired(3);
+        mock::nimble::subscribe(3, velocity, true, false);
+        mock::nimble::subscribe(3, position, true, false);
+        mock::nimble::subscribe(3, positions, true, false);
+        let start = gatt_svr::position();
+
+        /* Created like app_main does, which the tests don't run */
+        let motion_timer = Timer::new(
+            "motion_timer\0",
+            pdMS_TO_TICKS!(MOTION_PERIOD_MS),
+            TimerMode::AutoReload,
+            motion_tick,
+        )
+        .unwrap();
+        motion_timer.start(0).unwrap();
+
+        assert_eq!(mock::nimble::write(3, setpoint, &[5, 0xfd]), Ok(()));
+        mock::advance(pdMS_TO_TICKS!(MOTION_PERIOD_MS));
+        let sent = mock::nimble::take_notifications();
+        assert_eq!(sent.len(), 3, "{:?}", sent);
+        assert_eq!(sent[0].data, vec![5, 0xfd]);
+        let moved = Position {
+            a: start.a + 5,
+            b: start.b - 3,
+        };
+        /* FF03 carries A alone, four bytes like the C firmware's long */
+        assert_eq!(sent[1].attr_handle, position);
+        assert_eq!(sent[1].data, (start.a + 5).to_le_bytes().to_vec());
+        assert_eq!(sent[2].attr_handle, positions);
+        assert_eq!(sent[2].data, moved.to_bytes().to_vec());
+        assert_eq!(gatt_svr::position(), moved);
+        assert_eq!(
+            mock::nimble::read(3, position),
+            Ok(moved.a_to_bytes().to_vec())
+        );
+
+        /* Stopping is reported once, standing still not at all */
+        mock::nimble::write(3, setpoint, &[0, 0]).unwrap();
+        mock::advance(pdMS_TO_TICKS!(MOTION_PERIOD_MS));
+        assert_eq!(mock::nimble::take_notifications().len(), 1);
+        mock::advance(pdMS_TO_TICKS!(MOTION_PERIOD_MS));
+        assert!(mock::nimble::take_notifications().is_empty());
+        mock::nimble::disconnect(3, 0x213);
+    }
+}
@@ -0,0 +1,140 @@
+//! Who may move the plotter
+//!
+//! Writes that move the plotter or change how it presents itself need an encrypted link to
+//! a central that paired with MITM protection, `gatt_svr` flags them `WRITE_PROTECTED`. A
+//! central writing one without pairing is told its authentication is insufficient and
+//! pairs, the phone then asks the user for the passkey.
+//!
+//! The plotter has no screen, it pairs with LE Secure Connections passkey entry as a
+//! display-only device: the passkey is logged and handed to the host controller on the
+//! UART. Built with `BLE_PASSKEY` set to six digits every pairing uses that fixed PIN
+//! instead, for plotters with nothing attached. Bonds are stored in NVS and kept until
+//! the `bonds clear` UART command deletes them.
+//!
+//! A bonded central that pairs again loses its old bond first, so a phone that forgot the
+//! plotter can pair without `bonds clear`. A pairing weaker than the bond, with a shorter
+//! key, without MITM protection or legacy where the bond used LE Secure Connections, is
+//! ignored and the bond kept, so a device spoofing the identity address of a bonded
+//! central can't swap the bond for one it can get without the passkey. Each deleted bond
+//! is logged as a warning with the peer's address.
+
+use esp32_sys::error::BleResult;
+use esp32_sys::gap::PairingProps;
+use esp32_sys::security::{self, IoCapability, PasskeyAction, Security, MAX_PASSKEY};
+use esp32_sys::sync::Mutex;
+use esp32_sys::*;
+
+const TAG: &str = "pairing\0";
+
+/// Passkey to show on the UART, taken by the status task
+static SHOWN_PASSKEY: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Sets up the security manager, before the host task starts
+pub unsafe fn init() {
+    Security::new(IoCapability::DisplayOnly).configure();
+}
+
+/// `BLE_PASSKEY` if it is six digits
+pub fn fixed_passkey() -> Option<u32> {
+    let passkey = option_env!("BLE_PASSKEY")?;
+    if passkey.len() == 6 && passkey.bytes().all(|b| b.is_ascii_digit()) {
+        passkey.parse().ok()
+    } else {
+        warn!(TAG, "BLE_PASSKEY is not six digits, ignoring it");
+        None
+    }
+}
+
+/// The security manager asks for the passkey the central has to enter
+pub fn passkey_action(conn_handle: u16, action: PasskeyAction) {
+    let passkey = match action {
+        PasskeyAction::Display => match fixed_passkey() {
+            Some(passkey) => passkey,
+            None => {
+                let passkey = unsafe { esp_random() } % (MAX_PASSKEY + 1);
+                info!(TAG, "connection {}: passkey {:06}", conn_handle, passkey);
+                *SHOWN_PASSKEY.lock() = Some(passkey);
+                passkey
+            }
+        },
+        /* Only offered with other IO capabilities */
+        _ => {
+            warn!(TAG, "connection {}: unexpected {:?}", conn_handle, action);
+            return;
+        }
+    };
+    if let Err(err) = security::display_passkey(conn_handle, passkey) {
+        warn!(TAG, "connection {}: {}", conn_handle, err);
+    }
+}
+
+/// The passkey of the pairing in progress, once
+pub fn take_passkey() -> Option<u32> {
+    SHOWN_PASSKEY.lock().take()
+}
+
+/// Pairing, or encryption with the keys of a bond, finished with `status`
+pub fn enc_change(conn_handle: u16, status: BleResult<()>) {
+    *SHOWN_PASSKEY.lock() = None;
+    if let Err(err) = status {
+        warn!(
+            TAG,
+            "connection {}: encryption failed; {}", conn_handle, err
+        );
+        return;
+    }
+    match security::sec_state(conn_handle) {
+        Ok(state) => info!(
+            TAG,
+            "connection {}: encrypted authenticated={} bonded={} key_size={}",
+            conn_handle,
+            state.authenticated,
+            state.bonded,
+            state.key_size
+        ),
+        Err(err) => warn!(TAG, "connection {}: {}", conn_handle, err),
+    }
+}
+
+/// Whether pairing with `new` would leave the link less protected than the bond
+fn weaker(new: &PairingProps, current: &PairingProps) -> bool {
+    new.key_size < current.key_size
+        || (current.authenticated && !new.authenticated)
+        || (current.secure_connections && !new.secure_connections)
+}
+
+/// A bonded central pairs again, most likely it lost its keys, so the old bond goes and
+/// pairing goes ahead unless it would be weaker than the bond
+pub fn repeat_pairing(conn_handle: u16, current: &PairingProps, new: &PairingProps) -> i32 {
+    if weaker(new, current) {
+        warn!(
+            TAG,
+            "connection {}: ignoring {:?} to replace a bond with {:?}", conn_handle, new, current
+        );
+        return BLE_GAP_REPEAT_PAIRING_IGNORE as i32;
+    }
+    match security::forget(conn_handle) {
+        Ok(peer) => {
+            warn!(
+                TAG,
+                "connection {}: deleted the bond with {} to pair again", conn_handle, peer
+            );
+            BLE_GAP_REPEAT_PAIRING_RETRY as i32
+        }
+        Err(err) => {
+            warn!(TAG, "connection {}: {}", conn_handle, err);
+            BLE_GAP_REPEAT_PAIRING_IGNORE as i32
+        }
+    }
+}
+
+pub fn bond_count() -> BleResult<usize> {
+    security::bonded_peers().map(|peers| peers.len())
+}
+
+/// Deletes every bond, connected bonded centrals are disconnected
+pub fn clear_bonds() -> BleResult<()> {
+    security::clear_bonds()?;
+    info!(TAG, "bonds cleared");
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use esp32_sys::error::EspResult;
+use esp32_sys::gpio::*;
+
+pub const NUM_PINS: usize = 4;
+pub const NUM_STEPPERS: usize = 2;
+
+/// Coil outputs of both steppers, wired like the C polargraph firmware
+pub struct StepperPins {
+    coils: [[OutputPin; NUM_PINS]; NUM_STEPPERS],
+}
+
+impl StepperPins {
+    pub fn new(
+        left: (Gpio13, Gpio12, Gpio14, Gpio27),
+        right: (Gpio26, Gpio25, Gpio33, Gpio32),
+    ) -> EspResult<StepperPins> {
+        Ok(StepperPins {
+            coils: [
+                [
+                    OutputPin::new(left.0)?,
+                    OutputPin::new(left.1)?,
+                    OutputPin::new(left.2)?,
+                    OutputPin::new(left.3)?,
+                ],
+                [
+                    OutputPin::new(right.0)?,
+                    OutputPin::new(right.1)?,
+                    OutputPin::new(right.2)?,
+                    OutputPin::new(right.3)?,
+                ],
+            ],
+        })
+    }
+
+    /// Drives the coils of one stepper, bit n of `pattern` is coil n
+    pub fn set_coils(&mut self, stepper: usize, pattern: u8) -> EspResult<()> {
+        for (i, coil) in self.coils[stepper].iter_mut().enumerate() {
+            coil.set_level(pattern & (1 << i) != 0)?;
+        }
+        Ok(())
+    }
+
+    /// De-energizes all coils
+    pub fn release(&mut self) -> EspResult<()> {
+        for stepper in 0..NUM_STEPPERS {
+            self.set_coils(stepper, 0)?;
+        }
+        Ok(())
+    }
+}